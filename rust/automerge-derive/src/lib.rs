@@ -0,0 +1,406 @@
+//! Derive macros for [`automerge::reconcile::Reconcile`] and [`automerge::reconcile::Hydrate`]
+//!
+//! * Structs with named fields are stored as maps with a key for each field
+//! * Newtype structs are stored as their inner value
+//! * Tuple structs are stored as lists
+//! * Enums are stored as a string naming the variant for unit variants, and as a map with a
+//!   single key naming the variant otherwise. The value of that key is stored as for structs.
+//!
+//! Fields and variants can be given a different name in the document with
+//! `#[automerge(rename = "...")]`.
+//!
+//! # Example
+//!
+//! ```
+//! use automerge::reconcile::{hydrate, reconcile, Counter, Text};
+//! use automerge_derive::{Hydrate, Reconcile};
+//!
+//! #[derive(Debug, PartialEq, Reconcile, Hydrate)]
+//! struct Note {
+//!     title: String,
+//!     body: Text,
+//!     #[automerge(rename = "likeCount")]
+//!     likes: Counter,
+//!     state: State,
+//! }
+//!
+//! #[derive(Debug, PartialEq, Reconcile, Hydrate)]
+//! enum State {
+//!     Draft,
+//!     Published { at: i64 },
+//! }
+//!
+//! let mut doc = automerge::AutoCommit::new();
+//! let note = Note {
+//!     title: "hello".to_string(),
+//!     body: Text::from("some text"),
+//!     likes: Counter(0),
+//!     state: State::Published { at: 10 },
+//! };
+//! reconcile(&mut doc, &note).unwrap();
+//! assert_eq!(hydrate::<_, Note>(&doc).unwrap(), note);
+//! ```
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Data, DeriveInput, Fields, Generics, Ident,
+    LitStr,
+};
+
+#[proc_macro_derive(Reconcile, attributes(automerge))]
+pub fn derive_reconcile(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    reconcile_impl(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(Hydrate, attributes(automerge))]
+pub fn derive_hydrate(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    hydrate_impl(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// The name a field or variant is stored under, taking `#[automerge(rename = "...")]` into
+/// account
+fn stored_name(attrs: &[syn::Attribute], ident: &Ident) -> syn::Result<LitStr> {
+    let mut name = None;
+    for attr in attrs.iter().filter(|a| a.path().is_ident("automerge")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = Some(meta.value()?.parse::<LitStr>()?);
+                Ok(())
+            } else {
+                Err(meta.error("unsupported automerge attribute"))
+            }
+        })?;
+    }
+    Ok(name.unwrap_or_else(|| LitStr::new(&ident.unraw_string(), ident.span())))
+}
+
+trait UnrawString {
+    fn unraw_string(&self) -> String;
+}
+
+impl UnrawString for Ident {
+    fn unraw_string(&self) -> String {
+        let s = self.to_string();
+        s.strip_prefix("r#").map(str::to_string).unwrap_or(s)
+    }
+}
+
+fn add_bounds(generics: &Generics, bound: syn::Path) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    generics
+}
+
+/// The fields of a struct or variant bound to local variables
+struct Bindings {
+    /// The pattern which destructures the fields, e.g. `{ a: __f0, b: __f1 }` or `(__f0, __f1)`
+    pattern: TokenStream,
+    /// The local variable bound to each field along with the name it is stored under for named
+    /// fields
+    fields: Vec<(Ident, Option<LitStr>)>,
+    /// Whether the fields are named, unnamed or there are none
+    style: Style,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Style {
+    Named,
+    Newtype,
+    Tuple,
+    Unit,
+}
+
+fn bindings(fields: &Fields) -> syn::Result<Bindings> {
+    match fields {
+        Fields::Named(named) => {
+            let mut pattern = Vec::new();
+            let mut bound = Vec::new();
+            for (i, field) in named.named.iter().enumerate() {
+                let ident = field.ident.as_ref().unwrap();
+                let var = format_ident!("__f{}", i);
+                pattern.push(quote!(#ident: #var));
+                bound.push((var, Some(stored_name(&field.attrs, ident)?)));
+            }
+            Ok(Bindings {
+                pattern: quote!({ #(#pattern),* }),
+                fields: bound,
+                style: Style::Named,
+            })
+        }
+        Fields::Unnamed(unnamed) => {
+            for field in &unnamed.unnamed {
+                if field.attrs.iter().any(|a| a.path().is_ident("automerge")) {
+                    return Err(syn::Error::new(
+                        field.span(),
+                        "automerge attributes are only supported on named fields",
+                    ));
+                }
+            }
+            let vars = (0..unnamed.unnamed.len())
+                .map(|i| format_ident!("__f{}", i))
+                .collect::<Vec<_>>();
+            let style = if vars.len() == 1 {
+                Style::Newtype
+            } else {
+                Style::Tuple
+            };
+            Ok(Bindings {
+                pattern: quote!(( #(#vars),* )),
+                fields: vars.into_iter().map(|v| (v, None)).collect(),
+                style,
+            })
+        }
+        Fields::Unit => Ok(Bindings {
+            pattern: quote!(),
+            fields: Vec::new(),
+            style: Style::Unit,
+        }),
+    }
+}
+
+/// Code which reconciles the bound fields with `slot`
+fn reconcile_fields(bindings: &Bindings, slot: TokenStream) -> TokenStream {
+    match bindings.style {
+        Style::Named => {
+            let entries = bindings
+                .fields
+                .iter()
+                .map(|(var, name)| quote!(__map.entry(#name).reconcile(#var)?;));
+            quote! {
+                let mut __map = #slot.map()?;
+                #(#entries)*
+            }
+        }
+        Style::Newtype => {
+            let var = &bindings.fields[0].0;
+            quote!(#slot.reconcile(#var)?;)
+        }
+        Style::Tuple => {
+            let len = bindings.fields.len();
+            let elems = bindings.fields.iter().enumerate().map(|(i, (var, _))| {
+                quote! {
+                    if #i < __old_len {
+                        __list.element(#i).reconcile(#var)?;
+                    } else {
+                        __list.insert(#i).reconcile(#var)?;
+                    }
+                }
+            });
+            quote! {
+                let mut __list = #slot.list()?;
+                let __old_len = __list.len();
+                #(#elems)*
+                for __i in (#len..__old_len).rev() {
+                    __list.delete(__i)?;
+                }
+            }
+        }
+        Style::Unit => quote!(#slot.map()?;),
+    }
+}
+
+/// An expression which hydrates the bound fields from `value` and constructs `ctor` from them
+fn hydrate_fields(bindings: &Bindings, ctor: TokenStream, value: TokenStream) -> TokenStream {
+    match bindings.style {
+        Style::Named => {
+            let fields = bindings.fields.iter().map(|(var, name)| {
+                quote!(let #var = ::automerge::reconcile::Hydrate::hydrate(__doc, &__obj, #name)?;)
+            });
+            let pattern = &bindings.pattern;
+            quote! {{
+                let __obj = ::automerge::reconcile::expect_map(#value)?;
+                #(#fields)*
+                #ctor #pattern
+            }}
+        }
+        Style::Newtype => {
+            let var = &bindings.fields[0].0;
+            quote! {{
+                let #var = ::automerge::reconcile::Hydrate::hydrate_value(__doc, #value)?;
+                #ctor(#var)
+            }}
+        }
+        Style::Tuple => {
+            let len = bindings.fields.len();
+            let fields = bindings.fields.iter().enumerate().map(|(i, (var, _))| {
+                quote!(let #var = ::automerge::reconcile::Hydrate::hydrate(__doc, &__obj, #i)?;)
+            });
+            let pattern = &bindings.pattern;
+            quote! {{
+                let __obj = ::automerge::reconcile::expect_list(#value)?;
+                let __len = ::automerge::ReadDoc::length(__doc, &__obj);
+                if __len != #len {
+                    return Err(::automerge::reconcile::HydrateTypedError::custom(
+                        format!("expected a list of length {} but found length {}", #len, __len),
+                    ));
+                }
+                #(#fields)*
+                #ctor #pattern
+            }}
+        }
+        Style::Unit => quote!({
+            ::automerge::reconcile::expect_map(#value)?;
+            #ctor
+        }),
+    }
+}
+
+fn reconcile_impl(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let generics = add_bounds(
+        &input.generics,
+        parse_quote!(::automerge::reconcile::Reconcile),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(s) => {
+            let b = bindings(&s.fields)?;
+            let pattern = &b.pattern;
+            let reconcile = reconcile_fields(&b, quote!(__slot));
+            quote! {
+                let #name #pattern = self;
+                #reconcile
+                Ok(())
+            }
+        }
+        Data::Enum(e) => {
+            let mut arms = Vec::new();
+            for variant in &e.variants {
+                let ident = &variant.ident;
+                let stored = stored_name(&variant.attrs, ident)?;
+                let b = bindings(&variant.fields)?;
+                let pattern = &b.pattern;
+                let arm = if b.style == Style::Unit {
+                    quote!(#name::#ident => __slot.put(#stored),)
+                } else {
+                    let reconcile = reconcile_fields(&b, quote!(__variant.entry(#stored)));
+                    quote! {
+                        #name::#ident #pattern => {
+                            let mut __variant = __slot.map()?;
+                            {
+                                #reconcile
+                            }
+                            __variant.retain(|__k| __k == #stored)
+                        }
+                    }
+                };
+                arms.push(arm);
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(u) => {
+            return Err(syn::Error::new(
+                u.union_token.span(),
+                "Reconcile cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::automerge::reconcile::Reconcile for #name #ty_generics #where_clause {
+            fn reconcile<__D: ::automerge::transaction::Transactable>(
+                &self,
+                __slot: ::automerge::reconcile::Slot<'_, __D>,
+            ) -> ::std::result::Result<(), ::automerge::reconcile::ReconcileError> {
+                #body
+            }
+        }
+    })
+}
+
+fn hydrate_impl(input: DeriveInput) -> syn::Result<TokenStream> {
+    let name = &input.ident;
+    let generics = add_bounds(
+        &input.generics,
+        parse_quote!(::automerge::reconcile::Hydrate),
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(s) => {
+            let b = bindings(&s.fields)?;
+            let hydrate = hydrate_fields(&b, quote!(#name), quote!(__value));
+            quote!(Ok(#hydrate))
+        }
+        Data::Enum(e) => {
+            let mut unit_arms = Vec::new();
+            let mut other_arms = Vec::new();
+            for variant in &e.variants {
+                let ident = &variant.ident;
+                let stored = stored_name(&variant.attrs, ident)?;
+                let b = bindings(&variant.fields)?;
+                if b.style == Style::Unit {
+                    unit_arms.push(quote!(Some(#stored) => Ok(#name::#ident),));
+                } else {
+                    let hydrate = hydrate_fields(
+                        &b,
+                        quote!(#name::#ident),
+                        quote!(::automerge::ReadDoc::get(__doc, &__obj, #stored)?),
+                    );
+                    other_arms.push(quote! {
+                        #stored => Ok((|| -> ::std::result::Result<Self, ::automerge::reconcile::HydrateTypedError> {
+                            Ok(#hydrate)
+                        })().map_err(|e| e.at(#stored))?),
+                    });
+                }
+            }
+            let expected = LitStr::new(&format!("a variant of {}", name), Span::call_site());
+            quote! {
+                match __value {
+                    Some((::automerge::Value::Scalar(__s), _)) => match __s.to_str() {
+                        #(#unit_arms)*
+                        _ => Err(::automerge::reconcile::HydrateTypedError::unexpected(
+                            #expected,
+                            Some(::automerge::Value::Scalar(__s.clone())),
+                        )),
+                    },
+                    __other => {
+                        let __obj = ::automerge::reconcile::expect_map(__other)?;
+                        let mut __keys = ::automerge::ReadDoc::keys(__doc, &__obj);
+                        match (__keys.next(), __keys.next()) {
+                            (Some(__key), None) => match __key.as_str() {
+                                #(#other_arms)*
+                                __key => Err(::automerge::reconcile::HydrateTypedError::custom(
+                                    format!("unknown variant {} of {}", __key, stringify!(#name)),
+                                )),
+                            },
+                            _ => Err(::automerge::reconcile::HydrateTypedError::custom(
+                                concat!("expected a map with a single key naming a variant of ", stringify!(#name)),
+                            )),
+                        }
+                    }
+                }
+            }
+        }
+        Data::Union(u) => {
+            return Err(syn::Error::new(
+                u.union_token.span(),
+                "Hydrate cannot be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::automerge::reconcile::Hydrate for #name #ty_generics #where_clause {
+            fn hydrate_value<__R: ::automerge::ReadDoc>(
+                __doc: &__R,
+                __value: ::std::option::Option<(::automerge::Value<'_>, ::automerge::ObjId)>,
+            ) -> ::std::result::Result<Self, ::automerge::reconcile::HydrateTypedError> {
+                #body
+            }
+        }
+    })
+}
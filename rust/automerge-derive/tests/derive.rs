@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+
+use automerge::reconcile::{hydrate, hydrate_prop, reconcile, Counter, Text};
+use automerge::transaction::Transactable;
+use automerge::{AutoCommit, ObjType, Prop, ReadDoc, ROOT};
+use automerge_derive::{Hydrate, Reconcile};
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+struct Board {
+    name: String,
+    #[automerge(rename = "cardList")]
+    cards: Vec<Card>,
+    labels: HashMap<String, Colour>,
+    owner: Option<UserId>,
+}
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+struct Card {
+    title: Text,
+    votes: Counter,
+    status: Status,
+    position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+enum Status {
+    Open,
+    #[automerge(rename = "closed")]
+    Closed {
+        reason: String,
+    },
+    Assigned(UserId),
+}
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+enum Colour {
+    Red,
+    Green,
+}
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+struct UserId(String);
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+struct Position(i64, i64);
+
+#[derive(Debug, Clone, PartialEq, Reconcile, Hydrate)]
+struct Wrapper<T> {
+    inner: T,
+}
+
+fn board() -> Board {
+    Board {
+        name: "sprint".to_string(),
+        cards: vec![Card {
+            title: Text::from("write tests"),
+            votes: Counter(0),
+            status: Status::Open,
+            position: Position(0, 0),
+        }],
+        labels: HashMap::from([("bug".to_string(), Colour::Red)]),
+        owner: Some(UserId("alice".to_string())),
+    }
+}
+
+fn ops_in_last_change(doc: &mut AutoCommit) -> usize {
+    doc.commit();
+    doc.get_last_local_change().map(|c| c.len()).unwrap_or(0)
+}
+
+#[test]
+fn roundtrip() {
+    let mut doc = AutoCommit::new();
+    let board = board();
+    reconcile(&mut doc, &board).unwrap();
+    assert_eq!(hydrate::<_, Board>(&doc).unwrap(), board);
+
+    let (_, cards) = doc.get(ROOT, "cardList").unwrap().unwrap();
+    let (_, card) = doc.get(&cards, 0).unwrap().unwrap();
+    assert_eq!(doc.object_type(&card).unwrap(), ObjType::Map);
+    assert_eq!(
+        doc.get(&card, "status").unwrap().unwrap().0.to_str(),
+        Some("Open")
+    );
+}
+
+#[test]
+fn only_changed_fields_produce_ops() {
+    let mut doc = AutoCommit::new();
+    let mut board = board();
+    reconcile(&mut doc, &board).unwrap();
+    doc.commit();
+
+    reconcile(&mut doc, &board).unwrap();
+    assert_eq!(doc.pending_ops(), 0);
+
+    board.cards[0].votes = Counter(2);
+    board.cards[0].title = Text::from("write more tests");
+    reconcile(&mut doc, &board).unwrap();
+    // one increment and a five character insertion
+    assert_eq!(ops_in_last_change(&mut doc), 6);
+}
+
+#[test]
+fn enum_variants_switch_cleanly() {
+    let mut doc = AutoCommit::new();
+    let mut board = board();
+    reconcile(&mut doc, &board).unwrap();
+
+    board.cards[0].status = Status::Closed {
+        reason: "done".to_string(),
+    };
+    reconcile(&mut doc, &board).unwrap();
+    assert_eq!(hydrate::<_, Board>(&doc).unwrap(), board);
+
+    board.cards[0].status = Status::Assigned(UserId("bob".to_string()));
+    reconcile(&mut doc, &board).unwrap();
+    assert_eq!(hydrate::<_, Board>(&doc).unwrap(), board);
+
+    let (_, cards) = doc.get(ROOT, "cardList").unwrap().unwrap();
+    let (_, card) = doc.get(&cards, 0).unwrap().unwrap();
+    let (_, status) = doc.get(&card, "status").unwrap().unwrap();
+    assert_eq!(doc.keys(&status).collect::<Vec<_>>(), vec!["Assigned"]);
+}
+
+#[test]
+fn concurrent_counter_and_text_edits_merge() {
+    let mut doc1 = AutoCommit::new();
+    let board = board();
+    reconcile(&mut doc1, &board).unwrap();
+    let mut doc2 = doc1.fork();
+
+    let mut board1 = board.clone();
+    board1.cards[0].votes = Counter(1);
+    board1.cards[0].title = Text::from("write unit tests");
+    reconcile(&mut doc1, &board1).unwrap();
+
+    let mut board2 = board.clone();
+    board2.cards[0].votes = Counter(1);
+    board2.cards[0].title = Text::from("write tests now");
+    reconcile(&mut doc2, &board2).unwrap();
+
+    doc1.merge(&mut doc2).unwrap();
+    let merged: Board = hydrate(&doc1).unwrap();
+    assert_eq!(merged.cards[0].votes, Counter(2));
+    assert_eq!(merged.cards[0].title.as_str(), "write unit tests now");
+}
+
+#[test]
+fn generics_and_errors() {
+    let mut doc = AutoCommit::new();
+    let wrapped = Wrapper {
+        inner: Colour::Green,
+    };
+    automerge::reconcile::reconcile_prop(&mut doc, ROOT, "w", &wrapped).unwrap();
+    let read: Wrapper<Colour> = hydrate_prop(&doc, ROOT, "w").unwrap();
+    assert_eq!(read, wrapped);
+
+    let (_, w) = doc.get(ROOT, "w").unwrap().unwrap();
+    doc.put(&w, "inner", "Blue").unwrap();
+    let err = hydrate_prop::<_, Wrapper<Colour>, _, _>(&doc, ROOT, "w").unwrap_err();
+    assert_eq!(
+        err.path(),
+        &[Prop::Map("w".to_string()), Prop::Map("inner".to_string())]
+    );
+}
+
+mod reexported {
+    use automerge::reconcile::{hydrate, reconcile, Hydrate, Reconcile};
+    use automerge::AutoCommit;
+
+    #[derive(Debug, PartialEq, Reconcile, Hydrate)]
+    struct Point {
+        x: i64,
+        y: i64,
+    }
+
+    #[test]
+    fn derive_through_automerge() {
+        let mut doc = AutoCommit::new();
+        let point = Point { x: 1, y: 2 };
+        reconcile(&mut doc, &point).unwrap();
+        assert_eq!(hydrate::<_, Point>(&doc).unwrap(), point);
+    }
+}
@@ -8,6 +8,8 @@ use automerge::ReadDoc;
 
 use serde::ser::{SerializeMap, SerializeSeq};
 
+pub mod sim;
+
 pub fn new_doc() -> automerge::AutoCommit {
     let mut d = automerge::AutoCommit::new();
     d.set_actor(automerge::ActorId::random());
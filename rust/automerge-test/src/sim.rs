@@ -0,0 +1,419 @@
+//! A deterministic simulation of many peers syncing over an unreliable network
+//!
+//! Every peer holds an [`AutoCommit`] and a [`sync::State`] for every other peer. On each step of
+//! the simulation the network delivers the messages which are due and then every peer generates a
+//! message for every other peer. The network can drop, duplicate, delay and reorder messages,
+//! and peers can restart, reloading their document and their sync states from what they saved.
+//!
+//! All randomness comes from the seed passed in the [`Config`], so a failing run can be
+//! reproduced exactly by running it again with the same seed.
+//!
+//! ```
+//! use automerge_test::sim::{Config, NetworkConfig, Simulation};
+//!
+//! let mut sim = Simulation::new(Config {
+//!     seed: 7,
+//!     peers: 3,
+//!     network: NetworkConfig::lossy(),
+//!     ..Config::default()
+//! });
+//! for peer in 0..3 {
+//!     sim.random_edits(peer, 10);
+//! }
+//! let stats = sim.run_until_converged().unwrap();
+//! assert!(stats.messages_sent > 0);
+//! ```
+use std::collections::{BTreeMap, VecDeque};
+
+use automerge::{
+    sync::{self, SyncDoc},
+    transaction::{CommitOptions, Transactable},
+    ActorId, AutoCommit, ChangeHash, ObjType, ReadDoc, ScalarValue, ROOT,
+};
+
+/// How the simulated network treats messages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConfig {
+    /// The probability that a message is lost
+    pub drop: f64,
+    /// The probability that a message is delivered twice
+    pub duplicate: f64,
+    /// The probability that a message may overtake the messages sent before it on the same link
+    pub reorder: f64,
+    /// Messages are delivered after a random number of steps between zero and this
+    pub max_delay: u64,
+}
+
+impl NetworkConfig {
+    /// A network which delivers every message exactly once, in order, on the next step
+    pub fn perfect() -> Self {
+        Self {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            max_delay: 0,
+        }
+    }
+
+    /// A network which misbehaves in every way it can
+    pub fn lossy() -> Self {
+        Self {
+            drop: 0.1,
+            duplicate: 0.1,
+            reorder: 0.2,
+            max_delay: 3,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self::perfect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Config {
+    pub seed: u64,
+    pub peers: usize,
+    pub network: NetworkConfig,
+    /// The probability that a given peer restarts on a given step
+    pub restart: f64,
+    /// Give up if the peers have not converged after this many steps
+    pub max_steps: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            peers: 2,
+            network: NetworkConfig::default(),
+            restart: 0.0,
+            max_steps: 1000,
+        }
+    }
+}
+
+/// Counts of what happened during a simulation
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub steps: u64,
+    pub messages_sent: usize,
+    pub messages_delivered: usize,
+    pub messages_dropped: usize,
+    pub messages_duplicated: usize,
+    pub bytes_sent: usize,
+    pub restarts: usize,
+    /// The number of times every connection was re-established because messages were lost and
+    /// the peers had stopped talking without converging
+    pub reconnects: usize,
+}
+
+#[derive(Debug)]
+pub enum SimError {
+    /// A peer failed to receive a message
+    Receive {
+        from: usize,
+        to: usize,
+        error: Box<automerge::AutomergeError>,
+    },
+    /// A peer could not decode a message or its own saved state
+    Decode(String),
+    /// The peers had not converged after [`Config::max_steps`]
+    DidNotConverge { stats: Stats },
+}
+
+impl std::fmt::Display for SimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimError::Receive { from, to, error } => {
+                write!(f, "peer {} failed to receive from {}: {}", to, from, error)
+            }
+            SimError::Decode(e) => write!(f, "decode error: {}", e),
+            SimError::DidNotConverge { stats } => {
+                write!(f, "did not converge after {} steps", stats.steps)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+/// A small deterministic random number generator (SplitMix64) so that runs are reproducible
+/// regardless of the version of any random number crate
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        p > 0.0 && unit < p
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    actor: ActorId,
+    doc: AutoCommit,
+    states: BTreeMap<usize, sync::State>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    from: usize,
+    to: usize,
+    deliver_at: u64,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Simulation {
+    config: Config,
+    rng: Rng,
+    peers: Vec<Peer>,
+    network: VecDeque<InFlight>,
+    /// The latest step at which an in order message is due on each link
+    link_due: BTreeMap<(usize, usize), u64>,
+    stats: Stats,
+}
+
+impl Simulation {
+    pub fn new(config: Config) -> Self {
+        let mut rng = Rng(config.seed);
+        let peers = (0..config.peers)
+            .map(|index| {
+                let actor = ActorId::from(rng.next_u64().to_be_bytes().to_vec());
+                let mut doc = AutoCommit::new();
+                doc.set_actor(actor.clone());
+                let states = (0..config.peers)
+                    .filter(|other| *other != index)
+                    .map(|other| (other, sync::State::new()))
+                    .collect();
+                Peer { actor, doc, states }
+            })
+            .collect();
+        Self {
+            config,
+            rng,
+            peers,
+            network: VecDeque::new(),
+            link_due: BTreeMap::new(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn doc(&mut self, peer: usize) -> &mut AutoCommit {
+        &mut self.peers[peer].doc
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Make `count` random edits to the document of `peer`, each in its own change
+    pub fn random_edits(&mut self, peer: usize, count: usize) {
+        for _ in 0..count {
+            let key = format!("key{}", self.rng.below(8));
+            let value = self.rng.next_u64() as i64;
+            let action = self.rng.below(4);
+            let rng = &mut self.rng;
+            let doc = &mut self.peers[peer].doc;
+            match action {
+                0 => {
+                    doc.put(ROOT, key, value).unwrap();
+                }
+                1 => {
+                    if doc.get(ROOT, &key).unwrap().is_some() {
+                        doc.delete(ROOT, key).unwrap();
+                    }
+                }
+                _ => {
+                    let list = match doc.get(ROOT, "list").unwrap() {
+                        Some((automerge::Value::Object(ObjType::List), list)) => list,
+                        _ => doc.put_object(ROOT, "list", ObjType::List).unwrap(),
+                    };
+                    let len = doc.length(&list);
+                    if action == 2 || len == 0 {
+                        let index = rng.below(len as u64 + 1) as usize;
+                        doc.insert(&list, index, ScalarValue::Int(value)).unwrap();
+                    } else {
+                        let index = rng.below(len as u64) as usize;
+                        doc.delete(&list, index).unwrap();
+                    }
+                }
+            }
+            // A fixed timestamp keeps change hashes, and so the whole run, deterministic
+            let time = self.stats.steps as i64;
+            self.peers[peer]
+                .doc
+                .commit_with(CommitOptions::default().with_time(time));
+        }
+    }
+
+    /// Restart `peer`, reloading its document and sync states from their saved forms
+    ///
+    /// Messages on the way to the peer are lost, as they would be when a connection closes.
+    pub fn restart(&mut self, peer: usize) -> Result<(), SimError> {
+        let saved_doc = self.peers[peer].doc.save();
+        let saved_states = self.peers[peer]
+            .states
+            .iter()
+            .map(|(other, state)| (*other, state.encode()))
+            .collect::<Vec<_>>();
+
+        let mut doc = AutoCommit::load(&saved_doc).map_err(|e| SimError::Decode(e.to_string()))?;
+        doc.set_actor(self.peers[peer].actor.clone());
+        let mut states = BTreeMap::new();
+        for (other, encoded) in saved_states {
+            let state =
+                sync::State::decode(&encoded).map_err(|e| SimError::Decode(e.to_string()))?;
+            states.insert(other, state);
+        }
+        self.peers[peer].doc = doc;
+        self.peers[peer].states = states;
+        self.network.retain(|msg| msg.to != peer);
+        self.stats.restarts += 1;
+        Ok(())
+    }
+
+    /// Run one step of the simulation, returning whether any message was sent or delivered
+    pub fn step(&mut self) -> Result<bool, SimError> {
+        self.stats.steps += 1;
+        let now = self.stats.steps;
+        let mut active = false;
+
+        for peer in 0..self.peers.len() {
+            if self.rng.chance(self.config.restart) {
+                self.restart(peer)?;
+            }
+        }
+
+        let (due, pending): (Vec<_>, Vec<_>) = self
+            .network
+            .drain(..)
+            .partition(|msg| msg.deliver_at <= now);
+        self.network = pending.into();
+        for msg in due {
+            active = true;
+            self.stats.messages_delivered += 1;
+            let message =
+                sync::Message::decode(&msg.bytes).map_err(|e| SimError::Decode(e.to_string()))?;
+            let peer = &mut self.peers[msg.to];
+            let state = peer.states.get_mut(&msg.from).expect("no state for peer");
+            peer.doc
+                .sync()
+                .receive_sync_message(state, message)
+                .map_err(|error| SimError::Receive {
+                    from: msg.from,
+                    to: msg.to,
+                    error: Box::new(error),
+                })?;
+        }
+
+        for from in 0..self.peers.len() {
+            for to in 0..self.peers.len() {
+                if from == to {
+                    continue;
+                }
+                let peer = &mut self.peers[from];
+                let state = peer.states.get_mut(&to).expect("no state for peer");
+                let message = peer.doc.sync().generate_sync_message(state);
+                if let Some(message) = message {
+                    active = true;
+                    self.send(from, to, message.encode());
+                }
+            }
+        }
+        Ok(active)
+    }
+
+    /// Step until every peer has the same document, reconnecting everyone if the peers stop
+    /// talking before then
+    pub fn run_until_converged(&mut self) -> Result<Stats, SimError> {
+        while self.stats.steps < self.config.max_steps {
+            if self.step()? || !self.network.is_empty() {
+                continue;
+            }
+            if self.converged() {
+                return Ok(self.stats);
+            }
+            // Lost messages can leave both ends of a link waiting for the other. A real
+            // application would notice the silence and reconnect, which starts a new session
+            // from the persisted state.
+            for peer in &mut self.peers {
+                for state in peer.states.values_mut() {
+                    *state = sync::State::decode(&state.encode())
+                        .map_err(|e| SimError::Decode(e.to_string()))?;
+                }
+            }
+            self.stats.reconnects += 1;
+        }
+        Err(SimError::DidNotConverge { stats: self.stats })
+    }
+
+    /// Whether every peer has the same heads and the same document
+    pub fn converged(&mut self) -> bool {
+        let heads = self
+            .peers
+            .iter_mut()
+            .map(|peer| peer.doc.get_heads())
+            .collect::<Vec<Vec<ChangeHash>>>();
+        if heads.windows(2).any(|pair| pair[0] != pair[1]) {
+            return false;
+        }
+        let docs = self
+            .peers
+            .iter()
+            .map(|peer| crate::realize(&peer.doc))
+            .collect::<Vec<_>>();
+        docs.windows(2).all(|pair| pair[0] == pair[1])
+    }
+
+    fn send(&mut self, from: usize, to: usize, bytes: Vec<u8>) {
+        self.stats.messages_sent += 1;
+        self.stats.bytes_sent += bytes.len();
+        if self.rng.chance(self.config.network.drop) {
+            self.stats.messages_dropped += 1;
+            return;
+        }
+        let copies = if self.rng.chance(self.config.network.duplicate) {
+            self.stats.messages_duplicated += 1;
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let now = self.stats.steps;
+            let mut deliver_at = now + 1 + self.rng.below(self.config.network.max_delay + 1);
+            if !self.rng.chance(self.config.network.reorder) {
+                let due = self.link_due.entry((from, to)).or_insert(0);
+                deliver_at = deliver_at.max(*due);
+                *due = deliver_at;
+            }
+            self.network.push_back(InFlight {
+                from,
+                to,
+                deliver_at,
+                bytes: bytes.clone(),
+            });
+        }
+    }
+}
@@ -70,6 +70,7 @@ export type Datatype =
 export type SyncHave = {
   lastSync: Heads,
   bloom: Uint8Array,
+  iblt?: Uint8Array,
 }
 
 export type DecodedSyncMessage = {
@@ -306,6 +307,8 @@ export interface JsSyncState {
     theirHeed: Heads | undefined;
     theirHave: SyncHave[] | undefined;
     sentHashes: Heads;
+    unsentHashes?: Heads;
+    rejectedHashes?: Heads;
 }
 
 export class SyncState {
@@ -53,6 +53,8 @@ impl From<JS> for JsValue {
 
 impl From<am::sync::State> for JS {
     fn from(state: am::sync::State) -> Self {
+        let unsent_hashes: JS = state.unsent_hashes.into();
+        let rejected_hashes: JS = state.rejected_hashes.into();
         let shared_heads: JS = state.shared_heads.into();
         let last_sent_heads: JS = state.last_sent_heads.into();
         let their_heads: JS = state.their_heads.into();
@@ -72,6 +74,8 @@ impl From<am::sync::State> for JS {
         Reflect::set(&result, &"theirNeed".into(), &their_need.0).unwrap();
         Reflect::set(&result, &"theirHave".into(), &their_have).unwrap();
         Reflect::set(&result, &"sentHashes".into(), &sent_hashes.0).unwrap();
+        Reflect::set(&result, &"unsentHashes".into(), &unsent_hashes.0).unwrap();
+        Reflect::set(&result, &"rejectedHashes".into(), &rejected_hashes.0).unwrap();
         Reflect::set(&result, &"inFlight".into(), &state.in_flight.into()).unwrap();
         Reflect::set(&result, &"haveResponded".into(), &have_responded).unwrap();
         if let Some(caps) = state.their_capabilities {
@@ -308,6 +312,26 @@ impl TryFrom<JS> for am::sync::State {
         let sent_hashes = js_get(&value, "sentHashes")?
             .try_into()
             .map_err(error::BadSyncState::BadSentHashes)?;
+        let unsent_hashes = {
+            let unsent = js_get(&value, "unsentHashes")?;
+            if !unsent.is_undefined() {
+                unsent
+                    .try_into()
+                    .map_err(error::BadSyncState::BadUnsentHashes)?
+            } else {
+                BTreeSet::new()
+            }
+        };
+        let rejected_hashes = {
+            let rejected = js_get(&value, "rejectedHashes")?;
+            if !rejected.is_undefined() {
+                rejected
+                    .try_into()
+                    .map_err(error::BadSyncState::BadRejectedHashes)?
+            } else {
+                BTreeSet::new()
+            }
+        };
         let in_flight = js_get(&value, "inFlight")?
             .0
             .as_bool()
@@ -326,17 +350,19 @@ impl TryFrom<JS> for am::sync::State {
                 None
             }
         };
-        Ok(am::sync::State {
-            shared_heads,
-            last_sent_heads,
-            their_heads,
-            their_need,
-            their_have,
-            sent_hashes,
-            in_flight,
-            have_responded,
-            their_capabilities,
-        })
+        let mut state = am::sync::State::new();
+        state.shared_heads = shared_heads;
+        state.last_sent_heads = last_sent_heads;
+        state.their_heads = their_heads;
+        state.their_need = their_need;
+        state.their_have = their_have;
+        state.sent_hashes = sent_hashes;
+        state.unsent_hashes = unsent_hashes;
+        state.rejected_hashes = rejected_hashes;
+        state.in_flight = in_flight;
+        state.have_responded = have_responded;
+        state.their_capabilities = their_capabilities;
+        Ok(state)
     }
 }
 
@@ -350,7 +376,13 @@ impl TryFrom<JS> for am::sync::Have {
         let bloom = js_get(&value.0, "bloom")?
             .try_into()
             .map_err(error::BadHave::BadBloom)?;
-        Ok(am::sync::Have { last_sync, bloom })
+        let have = am::sync::Have::new(last_sync, bloom);
+        let iblt = js_get(&value.0, "iblt")?;
+        if !iblt.is_undefined() {
+            Ok(have.with_iblt(iblt.try_into().map_err(error::BadHave::BadIblt)?))
+        } else {
+            Ok(have)
+        }
     }
 }
 
@@ -397,6 +429,17 @@ impl TryFrom<JS> for am::sync::BloomFilter {
     }
 }
 
+impl TryFrom<JS> for am::sync::Iblt {
+    type Error = error::BadIblt;
+
+    fn try_from(value: JS) -> Result<Self, Self::Error> {
+        let value: Uint8Array = value.0.dyn_into().map_err(|_| error::BadIblt::NotU8Array)?;
+        let value = value.to_vec();
+        let value = value.as_slice().try_into()?;
+        Ok(value)
+    }
+}
+
 impl TryFrom<JS> for am::sync::Message {
     type Error = error::BadSyncMessage;
 
@@ -424,6 +467,7 @@ impl TryFrom<JS> for am::sync::Message {
             Some(s) => match s.as_str() {
                 "v1" => MessageVersion::V1,
                 "v2" => MessageVersion::V2,
+                "v3" => MessageVersion::V3,
                 _ => MessageVersion::V1,
             },
             None => MessageVersion::V1,
@@ -516,6 +560,10 @@ impl From<&[am::sync::Have]> for AR {
                 // we can unwrap here b/c we created the object and know its not frozen
                 Reflect::set(&obj, &"lastSync".into(), &last_sync.into()).unwrap();
                 Reflect::set(&obj, &"bloom".into(), &bloom.into()).unwrap();
+                if let Some(iblt) = &have.iblt {
+                    let iblt = Uint8Array::from(iblt.to_bytes().as_slice());
+                    Reflect::set(&obj, &"iblt".into(), &iblt.into()).unwrap();
+                }
                 obj
             })
             .collect())
@@ -529,7 +577,8 @@ impl From<&[am::sync::Capability]> for AR {
             .filter_map(|c| match c {
                 am::sync::Capability::MessageV1 => Some(JsValue::from_str("message-v1")),
                 am::sync::Capability::MessageV2 => Some(JsValue::from_str("message-v2")),
-                am::sync::Capability::Unknown(_) => None,
+                am::sync::Capability::MessageV3 => Some(JsValue::from_str("message-v3")),
+                _ => None,
             })
             .collect())
     }
@@ -587,6 +636,7 @@ impl TryFrom<JS> for Vec<Capability> {
                 match as_str.as_str() {
                     "message-v1" => Ok(Capability::MessageV1),
                     "message-v2" => Ok(Capability::MessageV2),
+                    "message-v3" => Ok(Capability::MessageV3),
                     other => Err(error::BadCapabilities::ElemNotValid(i, other.to_string())),
                 }
             })
@@ -1788,6 +1838,10 @@ pub(crate) mod error {
         BadTheirHave(BadHaves),
         #[error("bad sentHashes: {0}")]
         BadSentHashes(BadChangeHashSet),
+        #[error("bad unsentHashes: {0}")]
+        BadUnsentHashes(BadChangeHashSet),
+        #[error("bad rejectedHashes: {0}")]
+        BadRejectedHashes(BadChangeHashSet),
         #[error("inFlight not a boolean")]
         InFlightNotBoolean,
         #[error("bad theirCapabilities: {0}")]
@@ -1832,6 +1886,8 @@ pub(crate) mod error {
         BadLastSync(BadChangeHashes),
         #[error("bad bloom: {0}")]
         BadBloom(BadBloom),
+        #[error("bad iblt: {0}")]
+        BadIblt(BadIblt),
         #[error(transparent)]
         GetHaveProp(#[from] GetProp),
     }
@@ -1852,6 +1908,14 @@ pub(crate) mod error {
         Decode(#[from] automerge::sync::DecodeBloomError),
     }
 
+    #[derive(Debug, thiserror::Error)]
+    pub enum BadIblt {
+        #[error("the value was not a Uint8Array")]
+        NotU8Array,
+        #[error("unable to decode: {0}")]
+        Decode(#[from] automerge::sync::DecodeIbltError),
+    }
+
     #[derive(Debug, thiserror::Error)]
     pub enum Export {
         #[error(transparent)]
@@ -1294,6 +1294,10 @@ pub fn decode_sync_message(msg: Uint8Array) -> Result<JsValue, error::BadSyncMes
         am::sync::MessageVersion::V2 => {
             js_set(&obj, "type", JsValue::from_str("v2")).unwrap();
         }
+        am::sync::MessageVersion::V3 => {
+            js_set(&obj, "type", JsValue::from_str("v3")).unwrap();
+        }
+        _ => {}
     };
 
     if let Some(caps) = msg.supported_capabilities {
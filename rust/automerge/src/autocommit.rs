@@ -1,22 +1,26 @@
+use std::collections::HashSet;
 use std::ops::RangeBounds;
 
-use crate::automerge::SaveOptions;
 use crate::automerge::{current_state, diff};
+use crate::automerge::{Redacted, Redaction, SaveOptions};
+use crate::error::{RedactError, SquashError};
 use crate::exid::ExId;
 use crate::iter::Spans;
 use crate::iter::{Keys, ListRange, MapRange, Values};
 use crate::marks::{ExpandMark, Mark, MarkSet};
 use crate::patches::{PatchLog, TextRepresentation};
+use crate::policy::{ChangePolicy, Rejected};
 use crate::sync::SyncDoc;
-use crate::transaction::{CommitOptions, Transactable};
+use crate::transaction::{CommitOptions, Transactable, UpdateOptions};
 use crate::types::Clock;
+use crate::undo::RevertResult;
 use crate::{hydrate, OnPartialLoad};
 use crate::{sync, ObjType, Parents, Patch, ReadDoc, ScalarValue};
 use crate::{
     transaction::TransactionInner, ActorId, Automerge, AutomergeError, Change, ChangeHash, Cursor,
     Prop, Value,
 };
-use crate::{LoadOptions, VerificationMode};
+use crate::{BlameSpan, HeadsOrdering, HistoryEntry, LoadOptions, VerificationMode};
 
 /// An automerge document that automatically manages transactions.
 ///
@@ -62,6 +66,9 @@ pub struct AutoCommit {
     diff_cache: Option<(OpRange, Vec<Patch>)>,
     save_cursor: Vec<ChangeHash>,
     isolation: Option<Vec<ChangeHash>>,
+    /// Changes committed through this document which have not been handed out since, and so can
+    /// still be squashed
+    unpublished: Vec<ChangeHash>,
 }
 
 /// An autocommit document with an inactive [`PatchLog`]
@@ -77,6 +84,7 @@ impl Default for AutoCommit {
             diff_cache: None,
             save_cursor: Vec::new(),
             isolation: None,
+            unpublished: Vec::new(),
         }
     }
 }
@@ -96,6 +104,7 @@ impl AutoCommit {
             diff_cache: None,
             save_cursor: Vec::new(),
             isolation: None,
+            unpublished: Vec::new(),
         })
     }
 
@@ -109,6 +118,7 @@ impl AutoCommit {
             diff_cache: None,
             save_cursor: Vec::new(),
             isolation: None,
+            unpublished: Vec::new(),
         })
     }
 
@@ -139,6 +149,7 @@ impl AutoCommit {
             diff_cache: None,
             save_cursor: Vec::new(),
             isolation: None,
+            unpublished: Vec::new(),
         })
     }
 
@@ -266,6 +277,7 @@ impl AutoCommit {
 
     pub fn fork(&mut self) -> Self {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         Self {
             doc: self.doc.fork(),
             transaction: self.transaction.clone(),
@@ -274,11 +286,13 @@ impl AutoCommit {
             diff_cache: None,
             save_cursor: vec![],
             isolation: None,
+            unpublished: Vec::new(),
         }
     }
 
     pub fn fork_at(&mut self, heads: &[ChangeHash]) -> Result<Self, AutomergeError> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         Ok(Self {
             doc: self.doc.fork_at(heads)?,
             transaction: self.transaction.clone(),
@@ -287,12 +301,23 @@ impl AutoCommit {
             diff_cache: None,
             save_cursor: vec![],
             isolation: None,
+            unpublished: Vec::new(),
         })
     }
 
     /// Get the inner document.
+    ///
+    /// This hands out every change, so they can no longer be squashed by [`Self::squash_local()`].
     #[doc(hidden)]
     pub fn document(&mut self) -> &Automerge {
+        self.ensure_transaction_closed();
+        self.unpublished.clear();
+        &self.doc
+    }
+
+    /// Like [`Self::document()`] but for reading the document internally, so without handing out
+    /// the changes
+    pub(crate) fn committed_doc(&mut self) -> &Automerge {
         self.ensure_transaction_closed();
         &self.doc
     }
@@ -337,6 +362,7 @@ impl AutoCommit {
         if let Some((patch_log, tx)) = self.transaction.take() {
             self.patch_log.merge(patch_log);
             let hash = tx.commit(&mut self.doc, None, None);
+            self.unpublished.extend(hash);
             if self.isolation.is_some() && hash.is_some() {
                 self.isolation = hash.map(|h| vec![h])
             }
@@ -375,10 +401,28 @@ impl AutoCommit {
         }
     }
 
+    /// Like [`Self::apply_changes()`] but only apply the changes which `policy` accepts, see
+    /// [`Automerge::apply_changes_with_policy()`]
+    pub fn apply_changes_with_policy<P: ChangePolicy>(
+        &mut self,
+        changes: impl IntoIterator<Item = Change>,
+        policy: &mut P,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.ensure_transaction_closed();
+        if self.isolation.is_some() {
+            self.doc
+                .apply_changes_with_policy_log_patches(changes, policy, &mut PatchLog::null())
+        } else {
+            self.doc
+                .apply_changes_with_policy_log_patches(changes, policy, &mut self.patch_log)
+        }
+    }
+
     /// Takes all the changes in `other` which are not in `self` and applies them
     pub fn merge(&mut self, other: &mut AutoCommit) -> Result<Vec<ChangeHash>, AutomergeError> {
         self.ensure_transaction_closed();
         other.ensure_transaction_closed();
+        other.unpublished.clear();
         if self.isolation.is_some() {
             self.doc
                 .merge_and_log_patches(&mut other.doc, &mut PatchLog::null())
@@ -395,6 +439,7 @@ impl AutoCommit {
 
     pub fn save_with_options(&mut self, options: SaveOptions) -> Vec<u8> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         let bytes = self.doc.save_with_options(options);
         if !bytes.is_empty() {
             self.save_cursor = self.doc.get_heads()
@@ -425,6 +470,7 @@ impl AutoCommit {
     /// text object).
     pub fn save_incremental(&mut self) -> Vec<u8> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         let bytes = self.doc.save_after(&self.save_cursor);
         if !bytes.is_empty() {
             self.save_cursor = self.doc.get_heads()
@@ -435,6 +481,7 @@ impl AutoCommit {
     /// Save everything which is not a (transitive) dependency of `heads`
     pub fn save_after(&mut self, heads: &[ChangeHash]) -> Vec<u8> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         self.doc.save_after(heads)
     }
 
@@ -446,16 +493,51 @@ impl AutoCommit {
     /// Get the last change made by this documents actor ID
     pub fn get_last_local_change(&mut self) -> Option<&Change> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         self.doc.get_last_local_change()
     }
 
+    /// Determine how the history described by the heads `a` is related to that described by
+    /// `b`, see [`Automerge::compare_heads()`]
+    pub fn compare_heads(
+        &self,
+        a: &[ChangeHash],
+        b: &[ChangeHash],
+    ) -> Result<HeadsOrdering, AutomergeError> {
+        self.doc.compare_heads(a, b)
+    }
+
+    /// The lowest common ancestors of the heads `a` and `b`, see [`Automerge::merge_base()`]
+    pub fn merge_base(
+        &self,
+        a: &[ChangeHash],
+        b: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        self.doc.merge_base(a, b)
+    }
+
+    /// The changes which are in the history of `to` but not in the history of `from`, in
+    /// topological order, see [`Automerge::changes_between()`]
+    ///
+    /// Unlike [`Self::get_changes()`] this does not commit outstanding operations, so the result
+    /// never includes them, and does not mark the changes as handed out, see [`Self::publish()`].
+    pub fn changes_between(
+        &self,
+        from: &[ChangeHash],
+        to: &[ChangeHash],
+    ) -> Result<Vec<&Change>, AutomergeError> {
+        self.doc.changes_between(from, to)
+    }
+
     pub fn get_changes(&mut self, have_deps: &[ChangeHash]) -> Vec<&Change> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         self.doc.get_changes(have_deps)
     }
 
     pub fn get_change_by_hash(&mut self, hash: &ChangeHash) -> Option<&Change> {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         self.doc.get_change_by_hash(hash)
     }
 
@@ -463,6 +545,7 @@ impl AutoCommit {
     pub fn get_changes_added<'a>(&mut self, other: &'a mut Self) -> Vec<&'a Change> {
         self.ensure_transaction_closed();
         other.ensure_transaction_closed();
+        other.unpublished.clear();
         self.doc.get_changes_added(&other.doc)
     }
 
@@ -493,6 +576,40 @@ impl AutoCommit {
         self.doc.visualise_optree(objects)
     }
 
+    /// Create a new change which reverses the effect the change `hash` has on the current state
+    /// of the document, see [`Automerge::revert()`]
+    ///
+    /// Any outstanding operations are committed first.
+    pub fn revert(&mut self, hash: &ChangeHash) -> Result<RevertResult, AutomergeError> {
+        crate::undo::revert(self, hash)
+    }
+
+    /// Create a new change which makes the current state of the document the same as it was at
+    /// `heads`, see [`Automerge::restore_to()`]
+    ///
+    /// Any outstanding operations are committed first.
+    pub fn restore_to(
+        &mut self,
+        heads: &[ChangeHash],
+    ) -> Result<Option<ChangeHash>, AutomergeError> {
+        let restoration = crate::restore::restoration(self.committed_doc(), heads)?;
+        if let Err(e) = crate::restore::apply(self, restoration, heads) {
+            self.rollback();
+            return Err(e);
+        }
+        Ok(self.commit())
+    }
+
+    /// Create a new document with the values selected by `redaction` scrubbed from the history,
+    /// see [`Automerge::redact()`]
+    ///
+    /// Any outstanding operations are committed first.
+    pub fn redact(&mut self, redaction: &Redaction) -> Result<Redacted, RedactError> {
+        self.ensure_transaction_closed();
+        self.unpublished.clear();
+        self.doc.redact(redaction)
+    }
+
     /// Get the current heads of the document.
     ///
     /// This closes the transaction first, if one is in progress.
@@ -505,6 +622,16 @@ impl AutoCommit {
         }
     }
 
+    /// Whether this document was loaded from a shallow save, see [`Automerge::is_shallow()`]
+    pub fn is_shallow(&self) -> bool {
+        self.doc.is_shallow()
+    }
+
+    /// The heads this document was shallow saved at, see [`Automerge::shallow_heads()`]
+    pub fn shallow_heads(&self) -> Option<&[ChangeHash]> {
+        self.doc.shallow_heads()
+    }
+
     pub fn set_text_rep(&mut self, text_rep: TextRepresentation) {
         self.patch_log.set_text_rep(text_rep)
     }
@@ -548,6 +675,7 @@ impl AutoCommit {
         let (patch_log, tx) = self.transaction.take().unwrap();
         self.patch_log.merge(patch_log);
         let hash = tx.commit(&mut self.doc, options.message, options.time);
+        self.unpublished.extend(hash);
         if self.isolation.is_some() && hash.is_some() {
             self.isolation = hash.map(|h| vec![h])
         }
@@ -574,7 +702,122 @@ impl AutoCommit {
     pub fn empty_change(&mut self, options: CommitOptions) -> ChangeHash {
         self.ensure_transaction_closed();
         let args = self.doc.transaction_args(None);
-        TransactionInner::empty(&mut self.doc, args, options.message, options.time)
+        let hash = TransactionInner::empty(&mut self.doc, args, options.message, options.time);
+        self.unpublished.push(hash);
+        hash
+    }
+
+    /// Mark every change committed so far as handed out, so that [`Self::squash_local()`] won't
+    /// replace them
+    ///
+    /// The methods which take `&mut self` and return changes or saved data do this themselves.
+    /// Methods which take `&self`, such as [`Self::changes_between()`] and
+    /// [`ReadDoc::get_change_by_hash()`], can't, so call this before passing on changes obtained
+    /// from them.
+    pub fn publish(&mut self) {
+        self.ensure_transaction_closed();
+        self.unpublished.clear();
+    }
+
+    /// Combine the changes which are not in the history of `since_heads` into a single change
+    ///
+    /// Editing text produces a change per keystroke, so squashing the changes made since the
+    /// document was last synced keeps the change graph small and makes syncing cheaper. The new
+    /// change contains all the ops of the changes it replaces, so the document is otherwise
+    /// unchanged, and takes the sequence number of the first of them. It has the deps of the first
+    /// change, the timestamp of the last and their messages joined by newlines.
+    ///
+    /// The changes must all have been made by this document, one after the other, and not handed
+    /// out since. Changes are handed out by the `&mut self` methods which return changes or saved
+    /// data, by [`Self::sync()`], [`Self::generate_sync_message_at()`], by forking, by being merged
+    /// into another document and by [`Self::publish()`], as afterwards someone else may have them
+    /// under their old hashes.
+    ///
+    /// This rebuilds the document from the history of `since_heads`, so it takes time
+    /// proportional to the size of the history. Any outstanding operations are committed first.
+    ///
+    /// Returns the hash of the new change, or `None` if there were no changes to squash.
+    ///
+    /// # Errors
+    ///
+    /// * [`SquashError::NotLocal`] if any of the changes was made by another actor
+    /// * [`SquashError::Published`] if any of the changes has been handed out, or was loaded
+    ///   rather than made by this document
+    /// * [`SquashError::NotConsecutive`] if the changes don't form a single line, each depending
+    ///   on just the one before
+    /// * [`SquashError::InUse`] if the diff cursor or the isolation heads are part way through the
+    ///   changes
+    /// * [`AutomergeError::InvalidHash`] if any of `since_heads` is not a change in this document
+    pub fn squash_local(
+        &mut self,
+        since_heads: &[ChangeHash],
+    ) -> Result<Option<ChangeHash>, SquashError> {
+        self.ensure_transaction_closed();
+        if let Some(hash) = since_heads.iter().find(|h| !self.doc.has_change(h)) {
+            return Err(AutomergeError::InvalidHash(*hash).into());
+        }
+        let changes = self.doc.get_changes(since_heads);
+        let Some((last, init)) = changes.split_last() else {
+            return Ok(None);
+        };
+        let mut previous: Option<&Change> = None;
+        for change in &changes {
+            if change.actor_id() != self.doc.get_actor() {
+                return Err(SquashError::NotLocal(change.hash()));
+            }
+            if !self.unpublished.contains(&change.hash()) {
+                return Err(SquashError::Published(change.hash()));
+            }
+            if let Some(previous) = previous {
+                if change.deps() != [previous.hash()]
+                    || change.seq() != previous.seq() + 1
+                    || change.start_op().get() != previous.max_op() + 1
+                {
+                    return Err(SquashError::NotConsecutive(change.hash()));
+                }
+            }
+            previous = Some(change);
+        }
+        if init.is_empty() {
+            return Ok(Some(last.hash()));
+        }
+        let mut cursors = self
+            .diff_cursor
+            .iter()
+            .chain(self.isolation.iter().flatten());
+        if let Some(hash) = cursors.find(|h| init.iter().any(|c| c.hash() == **h)) {
+            return Err(SquashError::InUse(*hash));
+        }
+
+        let mut squashed = init[0].decode();
+        for change in changes[1..].iter().map(|c| c.decode()) {
+            squashed.operations.extend(change.operations);
+            squashed.message = match (squashed.message.take(), change.message) {
+                (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+        squashed.time = last.timestamp();
+        squashed.hash = None;
+        let squashed = Change::from(squashed);
+        let hash = squashed.hash();
+        let old_last = last.hash();
+        let replaced = changes.iter().map(|c| c.hash()).collect::<HashSet<_>>();
+
+        self.doc.replace_changes(since_heads, squashed)?;
+        self.unpublished.retain(|h| !replaced.contains(h));
+        self.unpublished.push(hash);
+        for head in self
+            .diff_cursor
+            .iter_mut()
+            .chain(self.isolation.iter_mut().flatten())
+        {
+            if *head == old_last {
+                *head = hash;
+            }
+        }
+        self.diff_cache = None;
+        Ok(Some(hash))
     }
 
     /// An implementation of [`crate::sync::SyncDoc`] for this autocommit
@@ -583,9 +826,48 @@ impl AutoCommit {
     /// taking part in the sync protocol
     pub fn sync(&mut self) -> impl SyncDoc + '_ {
         self.ensure_transaction_closed();
+        self.unpublished.clear();
         SyncWrapper { inner: self }
     }
 
+    /// Like [`SyncDoc::generate_sync_message()`] but only sync `heads` and their ancestors, see
+    /// [`Automerge::generate_sync_message_at()`]
+    pub fn generate_sync_message_at(
+        &mut self,
+        sync_state: &mut sync::State,
+        heads: &[ChangeHash],
+    ) -> Result<Option<sync::Message>, AutomergeError> {
+        self.ensure_transaction_closed();
+        self.unpublished.clear();
+        self.doc.generate_sync_message_at(sync_state, heads)
+    }
+
+    /// Like [`SyncDoc::receive_sync_message()`] but only apply the changes which `policy`
+    /// accepts, see [`Automerge::receive_sync_message_with_policy()`]
+    pub fn receive_sync_message_with_policy<P: ChangePolicy>(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+        policy: &mut P,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.ensure_transaction_closed();
+        if self.isolation.is_some() {
+            self.doc.receive_sync_message_with_policy_log_patches(
+                sync_state,
+                message,
+                policy,
+                &mut PatchLog::null(),
+            )
+        } else {
+            self.doc.receive_sync_message_with_policy_log_patches(
+                sync_state,
+                message,
+                policy,
+                &mut self.patch_log,
+            )
+        }
+    }
+
     /// Get the hash of the change that contains the given `opid`.
     ///
     /// Returns [`None`] if the `opid`:
@@ -817,6 +1099,52 @@ impl ReadDoc for AutoCommit {
             .get_all_for(obj.as_ref(), prop.into(), self.get_scope(Some(heads)))
     }
 
+    fn history<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(&self.doc, obj.as_ref(), prop.into(), self.get_scope(None))
+    }
+
+    fn history_at<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(
+            &self.doc,
+            obj.as_ref(),
+            prop.into(),
+            self.get_scope(Some(heads)),
+        )
+    }
+
+    fn object_changes<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(&self.doc, obj.as_ref(), self.get_scope(None))
+    }
+
+    fn object_changes_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(&self.doc, obj.as_ref(), self.get_scope(Some(heads)))
+    }
+
+    fn blame<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(&self.doc, obj.as_ref(), self.get_scope(None))
+    }
+
+    fn blame_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(&self.doc, obj.as_ref(), self.get_scope(Some(heads)))
+    }
+
     fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
         self.doc.get_missing_deps(heads)
     }
@@ -1017,7 +1345,24 @@ impl Transactable for AutoCommit {
     ) -> Result<(), crate::error::UpdateObjectError> {
         self.ensure_transaction_open();
         let (patch_log, tx) = self.transaction.as_mut().unwrap();
-        tx.update_object(&mut self.doc, patch_log, obj.as_ref(), new_value)
+        tx.update_object(
+            &mut self.doc,
+            patch_log,
+            obj.as_ref(),
+            new_value,
+            &UpdateOptions::default(),
+        )
+    }
+
+    fn update_object_with<O: AsRef<ExId>>(
+        &mut self,
+        obj: O,
+        new_value: &crate::hydrate::Value,
+        options: UpdateOptions<'_>,
+    ) -> Result<(), crate::error::UpdateObjectError> {
+        self.ensure_transaction_open();
+        let (patch_log, tx) = self.transaction.as_mut().unwrap();
+        tx.update_object(&mut self.doc, patch_log, obj.as_ref(), new_value, &options)
     }
 }
 
@@ -1032,6 +1377,16 @@ impl<'a> SyncDoc for SyncWrapper<'a> {
         self.inner.doc.generate_sync_message(sync_state)
     }
 
+    fn generate_sync_message_with_limit(
+        &self,
+        sync_state: &mut sync::State,
+        max_size: usize,
+    ) -> Option<sync::Message> {
+        self.inner
+            .doc
+            .generate_sync_message_with_limit(sync_state, max_size)
+    }
+
     fn receive_sync_message(
         &mut self,
         sync_state: &mut sync::State,
@@ -1098,6 +1453,11 @@ impl OpRange {
 
 #[cfg(test)]
 mod tests {
+    use super::AutoCommit;
+    use crate::error::SquashError;
+    use crate::sync::{self, SyncDoc};
+    use crate::transaction::{CommitOptions, Transactable};
+    use crate::{AutomergeError, ObjType, ReadDoc, ROOT};
 
     fn is_send<S: Send>() {}
 
@@ -1105,4 +1465,222 @@ mod tests {
     fn test_autocommit_is_send() {
         is_send::<super::AutoCommit>();
     }
+
+    fn type_text(doc: &mut AutoCommit, text: &str) {
+        let (_, obj) = doc.get(ROOT, "text").unwrap().unwrap();
+        for c in text.chars() {
+            let len = doc.length(&obj);
+            doc.splice_text(&obj, len, 0, &c.to_string()).unwrap();
+            doc.commit();
+        }
+    }
+
+    fn sync(a: &mut AutoCommit, b: &mut AutoCommit) {
+        let (mut a_state, mut b_state) = (sync::State::new(), sync::State::new());
+        for _ in 0..10 {
+            let a_to_b = a.sync().generate_sync_message(&mut a_state);
+            let b_to_a = b.sync().generate_sync_message(&mut b_state);
+            if a_to_b.is_none() && b_to_a.is_none() {
+                return;
+            }
+            if let Some(msg) = a_to_b {
+                b.sync().receive_sync_message(&mut b_state, msg).unwrap();
+            }
+            if let Some(msg) = b_to_a {
+                a.sync().receive_sync_message(&mut a_state, msg).unwrap();
+            }
+        }
+        panic!("sync did not converge");
+    }
+
+    #[test]
+    fn squash_local_combines_changes() {
+        let mut doc = AutoCommit::new();
+        doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.commit_with(CommitOptions::default().with_message("create"));
+        type_text(&mut doc, "hello");
+        let (_, text) = doc.get(ROOT, "text").unwrap().unwrap();
+        doc.splice_text(&text, 5, 0, " world").unwrap();
+        doc.commit_with(CommitOptions::default().with_message("typed"));
+        doc.splice_text(&text, 11, 0, "!").unwrap();
+
+        let hash = doc.squash_local(&[]).unwrap().unwrap();
+        assert_eq!(doc.get_heads(), vec![hash]);
+        assert_eq!(doc.text(&text).unwrap(), "hello world!");
+        let changes = doc.get_changes(&[]);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].seq(), 1);
+        assert_eq!(changes[0].message().unwrap(), "create\ntyped");
+
+        doc.splice_text(&text, 0, 0, ">").unwrap();
+        doc.commit();
+        assert_eq!(doc.get_last_local_change().unwrap().seq(), 2);
+        let loaded = AutoCommit::load(&doc.save()).unwrap();
+        assert_eq!(loaded.text(&text).unwrap(), ">hello world!");
+        let heads = doc.get_heads();
+        assert!(matches!(doc.squash_local(&heads), Ok(None)));
+    }
+
+    #[test]
+    fn squash_local_since_last_sync() {
+        let mut doc = AutoCommit::new();
+        doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        let mut peer = AutoCommit::new();
+        sync(&mut doc, &mut peer);
+        let synced = doc.get_heads();
+        type_text(&mut doc, "abc");
+        let (_, text) = peer.get(ROOT, "text").unwrap().unwrap();
+        peer.splice_text(&text, 0, 0, "xyz").unwrap();
+        peer.commit();
+        let hash = doc.squash_local(&synced).unwrap().unwrap();
+        assert_eq!(doc.get_change_by_hash(&hash).unwrap().deps(), synced);
+
+        sync(&mut doc, &mut peer);
+        assert_eq!(doc.get_heads(), peer.get_heads());
+        assert_eq!(peer.get_changes(&synced).len(), 2);
+        assert_eq!(doc.text(&text).unwrap(), peer.text(&text).unwrap());
+    }
+
+    #[test]
+    fn squash_local_refuses_published_changes() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        let first = doc.commit().unwrap();
+        let _ = doc.get_changes(&[]);
+        doc.put(ROOT, "b", 2).unwrap();
+        doc.commit();
+        doc.put(ROOT, "c", 3).unwrap();
+        doc.commit();
+        assert!(matches!(
+            doc.squash_local(&[]),
+            Err(SquashError::Published(h)) if h == first
+        ));
+        assert!(doc.squash_local(&[first]).unwrap().is_some());
+
+        let before = doc.get_heads();
+        doc.put(ROOT, "d", 4).unwrap();
+        doc.save_incremental();
+        doc.put(ROOT, "e", 5).unwrap();
+        assert!(matches!(
+            doc.squash_local(&before),
+            Err(SquashError::Published(_))
+        ));
+    }
+
+    #[test]
+    fn changes_read_by_reference_are_published_explicitly() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.commit();
+        doc.put(ROOT, "b", 2).unwrap();
+        let heads = doc.get_heads();
+        assert_eq!(doc.changes_between(&[], &heads).unwrap().len(), 2);
+        doc.publish();
+        assert!(matches!(
+            doc.squash_local(&[]),
+            Err(SquashError::Published(_))
+        ));
+
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.commit();
+        doc.put(ROOT, "b", 2).unwrap();
+        assert_eq!(doc.document().get_changes(&[]).len(), 2);
+        assert!(matches!(
+            doc.squash_local(&[]),
+            Err(SquashError::Published(_))
+        ));
+
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        let first = doc.commit().unwrap();
+        assert!(ReadDoc::get_change_by_hash(&doc, &first).is_some());
+        doc.publish();
+        doc.put(ROOT, "b", 2).unwrap();
+        doc.commit();
+        doc.put(ROOT, "c", 3).unwrap();
+        assert!(matches!(
+            doc.squash_local(&[]),
+            Err(SquashError::Published(h)) if h == first
+        ));
+        assert!(doc.squash_local(&[first]).unwrap().is_some());
+
+        // reading changes by reference doesn't publish them
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        let first = doc.commit().unwrap();
+        doc.put(ROOT, "b", 2).unwrap();
+        let heads = doc.get_heads();
+        assert_eq!(doc.changes_between(&[], &heads).unwrap().len(), 2);
+        assert!(ReadDoc::get_change_by_hash(&doc, &first).is_some());
+        assert!(doc.squash_local(&[]).unwrap().is_some());
+    }
+
+    #[test]
+    fn squash_local_rejects_unknown_heads() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        let mut other = AutoCommit::new();
+        other.put(ROOT, "a", 1).unwrap();
+        let unknown = other.commit().unwrap();
+        assert!(matches!(
+            doc.squash_local(&[unknown]),
+            Err(SquashError::Automerge(AutomergeError::InvalidHash(h))) if h == unknown
+        ));
+    }
+
+    #[test]
+    fn squash_local_refuses_other_changes() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.commit();
+        let mut other = doc.fork();
+        let start = doc.get_heads();
+        doc.put(ROOT, "b", 2).unwrap();
+        doc.commit();
+        other.put(ROOT, "c", 3).unwrap();
+        let remote = other.commit().unwrap();
+        doc.merge(&mut other).unwrap();
+        assert!(matches!(
+            doc.squash_local(&start),
+            Err(SquashError::NotLocal(h)) if h == remote
+        ));
+
+        let start = doc.get_heads();
+        doc.put(ROOT, "d", 4).unwrap();
+        doc.commit();
+        other.put(ROOT, "e", 5).unwrap();
+        let remote = other.commit().unwrap();
+        doc.merge(&mut other).unwrap();
+        doc.put(ROOT, "f", 6).unwrap();
+        let merged = doc.commit().unwrap();
+        let since = [start, vec![remote]].concat();
+        assert!(matches!(
+            doc.squash_local(&since),
+            Err(SquashError::NotConsecutive(h)) if h == merged
+        ));
+    }
+
+    #[test]
+    fn squash_local_keeps_diff_cursor() {
+        let mut doc = AutoCommit::new();
+        doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.commit();
+        doc.update_diff_cursor();
+        type_text(&mut doc, "ab");
+        let middle = doc.get_heads();
+        doc.update_diff_cursor();
+        type_text(&mut doc, "cd");
+        assert!(matches!(
+            doc.squash_local(&[]),
+            Err(SquashError::InUse(h)) if [h] == middle[..]
+        ));
+
+        doc.update_diff_cursor();
+        let hash = doc.squash_local(&[]).unwrap().unwrap();
+        assert_eq!(doc.diff_cursor(), vec![hash]);
+        let (_, text) = doc.get(ROOT, "text").unwrap().unwrap();
+        doc.splice_text(&text, 4, 0, "e").unwrap();
+        assert_eq!(doc.diff_incremental().len(), 1);
+    }
 }
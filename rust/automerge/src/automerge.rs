@@ -6,14 +6,16 @@ use std::ops::RangeBounds;
 
 use itertools::Itertools;
 
-use crate::change_graph::ChangeGraph;
+use crate::change_graph::{ChangeGraph, HeadsOrdering};
 use crate::columnar::Key as EncodedKey;
+use crate::error::RedactError;
 use crate::exid::ExId;
 use crate::iter::{Keys, ListRange, MapRange, Spans, Values};
 use crate::marks::{Mark, MarkAccumulator, MarkSet, MarkStateMachine};
 use crate::op_set::{OpSet, OpSetData};
 use crate::parents::Parents;
 use crate::patches::{Patch, PatchLog, TextRepresentation};
+use crate::policy::{ChangePolicy, IncomingChange, Rejected, Rejection};
 use crate::query;
 use crate::read::ReadDocInternal;
 use crate::storage::{self, load, CompressConfig, VerificationMode};
@@ -24,11 +26,17 @@ use crate::types::{
     ActorId, ChangeHash, Clock, ElemId, Export, Exportable, Key, ListEncoding, MarkData, ObjId,
     ObjMeta, OpBuilder, OpId, OpIds, OpType, Value,
 };
+use crate::undo::RevertResult;
 use crate::{hydrate, ScalarValue};
 use crate::{AutomergeError, Change, Cursor, ObjType, Prop, ReadDoc};
+use crate::{BlameSpan, HistoryEntry};
 
 pub(crate) mod current_state;
 pub(crate) mod diff;
+mod redact;
+pub(crate) mod shallow;
+
+pub use redact::{Redacted, Redaction};
 
 #[cfg(test)]
 mod tests;
@@ -168,6 +176,12 @@ impl std::default::Default for LoadOptions<'static> {
 pub struct Automerge {
     /// The list of unapplied changes that are not causally ready.
     queue: Vec<Change>,
+    /// Changes passed to [`Self::apply_changes_with_policy()`] which are not causally ready. These
+    /// are kept apart from `queue` so that they are only ever applied once a policy accepts them.
+    policy_queue: Vec<Change>,
+    /// Changes which a policy has rejected, along with the changes which depend on them. Changes
+    /// which depend on these are rejected as soon as they arrive.
+    rejected: HashSet<ChangeHash>,
     /// The history of changes that form this document, topologically sorted too.
     history: Vec<Change>,
     /// Mapping from change hash to index into the history list.
@@ -184,6 +198,8 @@ pub struct Automerge {
     actor: Actor,
     /// The maximum operation counter this document has seen.
     max_op: u64,
+    /// Where the history starts if this is a shallow document
+    boundary: Option<shallow::Boundary>,
 }
 
 impl Automerge {
@@ -191,6 +207,8 @@ impl Automerge {
     pub fn new() -> Self {
         Automerge {
             queue: vec![],
+            policy_queue: vec![],
+            rejected: HashSet::new(),
             history: vec![],
             history_index: HashMap::new(),
             change_graph: ChangeGraph::new(),
@@ -199,6 +217,7 @@ impl Automerge {
             deps: Default::default(),
             actor: Actor::Unused(ActorId::random()),
             max_op: 0,
+            boundary: None,
         }
     }
 
@@ -216,7 +235,33 @@ impl Automerge {
 
     /// Whether this document has any operations
     pub fn is_empty(&self) -> bool {
-        self.history.is_empty() && self.queue.is_empty()
+        self.history.is_empty()
+            && self.queue.is_empty()
+            && self.policy_queue.is_empty()
+            && self.boundary.is_none()
+    }
+
+    /// Whether this document was loaded from a shallow save, and so doesn't have the changes
+    /// before [`Self::shallow_heads()`]
+    ///
+    /// See [`SaveOptions::shallow_at()`]
+    pub fn is_shallow(&self) -> bool {
+        self.boundary.is_some()
+    }
+
+    /// The heads this document was shallow saved at, if it is a shallow document
+    ///
+    /// The state of the document as of these heads is available, but the changes which produced
+    /// it are not.
+    pub fn shallow_heads(&self) -> Option<&[ChangeHash]> {
+        self.boundary.as_ref().map(|b| b.heads())
+    }
+
+    /// Whether `hash` is a change in this document or, for a shallow document, a change we know
+    /// is before the boundary
+    pub(crate) fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.history_index.contains_key(hash)
+            || self.boundary.as_ref().is_some_and(|b| b.contains(hash))
     }
 
     pub(crate) fn actor_id(&self) -> ActorId {
@@ -298,10 +343,12 @@ impl Automerge {
             }
             None => {
                 actor_index = self.get_actor_index();
-                seq = self.states.get(&actor_index).map_or(0, |v| v.len()) as u64 + 1;
+                seq = shallow::seq(self, actor_index) + 1;
                 deps = self.get_heads();
                 scope = None;
-                if seq > 1 {
+                // If our last change is before the boundary of a shallow document it is already
+                // in the history of our heads
+                if shallow::state_index(self, actor_index, seq - 1).is_some() {
                     let last_hash = self.get_hash(actor_index, seq - 1).unwrap();
                     if !deps.contains(&last_hash) {
                         deps.push(last_hash);
@@ -453,7 +500,16 @@ impl Automerge {
     /// Fork this document at the given heads
     ///
     /// This will create a new actor ID for the forked document
+    ///
+    /// # Errors
+    ///
+    /// For a shallow document, returns [`AutomergeError::ShallowHistory`] if `heads` are not
+    /// descendants of [`Self::shallow_heads()`]
     pub fn fork_at(&self, heads: &[ChangeHash]) -> Result<Self, AutomergeError> {
+        if let Some(hash) = heads.iter().find(|h| !self.has_change(h)) {
+            return Err(AutomergeError::InvalidHash(*hash));
+        }
+        shallow::check_after_boundary(self, heads)?;
         let mut seen = heads.iter().cloned().collect::<HashSet<_>>();
         let mut heads = heads.to_vec();
         let mut changes = vec![];
@@ -467,16 +523,38 @@ impl Automerge {
                 }
                 changes.push(change);
                 seen.insert(hash);
-            } else {
+            } else if !self.has_change(&hash) {
                 return Err(AutomergeError::InvalidHash(hash));
             }
+            // Otherwise this is a change before the boundary of a shallow document, which the
+            // fork starts from
         }
-        let mut f = Self::new();
+        let mut f = shallow::fork_base(self)?;
         f.set_actor(ActorId::random());
         f.apply_changes(changes.into_iter().rev().cloned())?;
         Ok(f)
     }
 
+    /// Replace every change which is not in the history of `since_heads` with `squashed`, see
+    /// [`crate::AutoCommit::squash_local()`]
+    ///
+    /// `squashed` must contain the ops of the changes it replaces, so that the ops of the document
+    /// are the same afterwards. The document is rebuilt from the history of `since_heads`.
+    pub(crate) fn replace_changes(
+        &mut self,
+        since_heads: &[ChangeHash],
+        squashed: Change,
+    ) -> Result<(), AutomergeError> {
+        let mut doc = self.fork_at(since_heads)?;
+        doc.set_actor(self.get_actor().clone());
+        doc.apply_changes([squashed])?;
+        doc.queue = std::mem::take(&mut self.queue);
+        doc.policy_queue = std::mem::take(&mut self.policy_queue);
+        doc.rejected = std::mem::take(&mut self.rejected);
+        *self = doc;
+        Ok(())
+    }
+
     pub(crate) fn exid_to_opid(&self, id: &ExId) -> Result<OpId, AutomergeError> {
         match id {
             ExId::Root => Ok(OpId::new(0, 0)),
@@ -604,6 +682,11 @@ impl Automerge {
                 first_chunk_was_doc = true;
                 reconstruct_document(&d, options.verification_mode)?
             }
+            storage::Chunk::Shallow(s) => {
+                tracing::trace!("first chunk is shallow document chunk, inflating");
+                first_chunk_was_doc = true;
+                shallow::load(&s)?
+            }
             storage::Chunk::Change(stored_change) => {
                 tracing::trace!("first chunk is change chunk");
                 change = Some(
@@ -723,9 +806,7 @@ impl Automerge {
     fn duplicate_seq(&self, change: &Change) -> bool {
         let mut dup = false;
         if let Some(actor_index) = self.ops.osd.actors.lookup(change.actor_id()) {
-            if let Some(s) = self.states.get(&actor_index) {
-                dup = s.len() >= change.seq() as usize;
-            }
+            dup = shallow::seq(self, actor_index) >= change.seq();
         }
         dup
     }
@@ -734,6 +815,10 @@ impl Automerge {
     ///
     /// This is idempotent in the sense that if a change has already been applied it will be
     /// ignored.
+    ///
+    /// Changes which an earlier call to [`Self::apply_changes_with_policy()`] queued because they
+    /// were not causally ready are applied too once these changes make them ready. A queued change
+    /// is only checked by a policy if it is the call which makes it ready that has one.
     pub fn apply_changes(
         &mut self,
         changes: impl IntoIterator<Item = Change>,
@@ -756,7 +841,11 @@ impl Automerge {
         // empty document right now, once we have logic to produce the diffs between arbitrary
         // states of the OpSet we can make this cleaner.
         for c in changes {
-            if !self.history_index.contains_key(&c.hash()) {
+            if !self.has_change(&c.hash()) {
+                if shallow::before_boundary(self, &c) {
+                    shallow::add_boundary_change(self, &c);
+                    continue;
+                }
                 if self.duplicate_seq(&c) {
                     return Err(AutomergeError::DuplicateSeqNumber(
                         c.seq(),
@@ -770,7 +859,14 @@ impl Automerge {
                 }
             }
         }
-        while let Some(c) = self.pop_next_causally_ready_change() {
+        loop {
+            let c = if let Some(c) = self.pop_next_causally_ready_change() {
+                c
+            } else if let Some(index) = self.next_causally_ready(&self.policy_queue) {
+                self.policy_queue.swap_remove(index)
+            } else {
+                break;
+            };
             if !self.history_index.contains_key(&c.hash()) {
                 self.apply_change(c, patch_log)?;
             }
@@ -778,6 +874,116 @@ impl Automerge {
         Ok(())
     }
 
+    /// Like [`Self::apply_changes()`] but only apply the changes which `policy` accepts
+    ///
+    /// Changes which `policy` rejects, and any changes which depend on them, are not applied and
+    /// are returned instead. The document remembers the rejected changes, so changes which depend
+    /// on them are rejected by later calls as well. See [`crate::policy`].
+    pub fn apply_changes_with_policy<P: ChangePolicy>(
+        &mut self,
+        changes: impl IntoIterator<Item = Change>,
+        policy: &mut P,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.apply_changes_with_policy_log_patches(
+            changes,
+            policy,
+            &mut PatchLog::inactive(TextRepresentation::default()),
+        )
+    }
+
+    /// Like [`Self::apply_changes_with_policy()`] but log the resulting changes to the current
+    /// state of the document to `patch_log`
+    pub fn apply_changes_with_policy_log_patches<
+        P: ChangePolicy,
+        I: IntoIterator<Item = Change>,
+    >(
+        &mut self,
+        changes: I,
+        policy: &mut P,
+        patch_log: &mut PatchLog,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.apply_changes_checked(changes, policy, patch_log)
+    }
+
+    /// Mark `hashes` as rejected, so that changes which depend on them are rejected by
+    /// [`Self::apply_changes_checked()`]
+    pub(crate) fn add_rejected<'a>(&mut self, hashes: impl IntoIterator<Item = &'a ChangeHash>) {
+        self.rejected.extend(hashes);
+    }
+
+    /// Apply the changes which `policy` accepts
+    ///
+    /// Changes which were rejected by an earlier call, and any changes which depend on them, are
+    /// rejected without consulting `policy`.
+    pub(crate) fn apply_changes_checked<P: ChangePolicy, I: IntoIterator<Item = Change>>(
+        &mut self,
+        changes: I,
+        policy: &mut P,
+        patch_log: &mut PatchLog,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        for c in changes {
+            if !self.has_change(&c.hash()) && !self.rejected.contains(&c.hash()) {
+                if shallow::before_boundary(self, &c) {
+                    shallow::add_boundary_change(self, &c);
+                    continue;
+                }
+                if self.duplicate_seq(&c) {
+                    return Err(AutomergeError::DuplicateSeqNumber(
+                        c.seq(),
+                        c.actor_id().clone(),
+                    ));
+                }
+                self.policy_queue.push(c);
+            }
+        }
+        let mut rejected = Vec::new();
+        loop {
+            if let Some(c) = self.pop_next_causally_ready_change() {
+                // This was queued by `apply_changes()` so it doesn't need checking
+                if !self.history_index.contains_key(&c.hash()) {
+                    self.apply_change(c, patch_log)?;
+                }
+            } else if let Some(index) = self.next_causally_ready(&self.policy_queue) {
+                let c = self.policy_queue.swap_remove(index);
+                if self.history_index.contains_key(&c.hash()) {
+                    continue;
+                }
+                // The policy sees the document as it is just before the change is applied
+                match policy.check(&IncomingChange::new(&self.ops, &c)) {
+                    Ok(()) => self.apply_change(c, patch_log)?,
+                    Err(e) => {
+                        self.rejected.insert(c.hash());
+                        rejected.push(Rejected {
+                            hash: c.hash(),
+                            actor: c.actor_id().clone(),
+                            reason: Rejection::Policy(e),
+                        });
+                    }
+                }
+            } else if let Some((index, dep)) =
+                self.policy_queue.iter().enumerate().find_map(|(i, c)| {
+                    c.deps()
+                        .iter()
+                        .find(|dep| self.rejected.contains(dep))
+                        .map(|dep| (i, *dep))
+                })
+            {
+                // A change which depends on a rejected change can never be applied, so rather
+                // than leaving it in the queue reject it too
+                let c = self.policy_queue.swap_remove(index);
+                self.rejected.insert(c.hash());
+                rejected.push(Rejected {
+                    hash: c.hash(),
+                    actor: c.actor_id().clone(),
+                    reason: Rejection::Dependency(dep),
+                });
+            } else {
+                break;
+            }
+        }
+        Ok(rejected)
+    }
+
     fn apply_change(
         &mut self,
         change: Change,
@@ -792,21 +998,16 @@ impl Automerge {
     }
 
     fn is_causally_ready(&self, change: &Change) -> bool {
-        change
-            .deps()
-            .iter()
-            .all(|d| self.history_index.contains_key(d))
+        change.deps().iter().all(|d| self.has_change(d))
     }
 
     fn pop_next_causally_ready_change(&mut self) -> Option<Change> {
-        let mut index = 0;
-        while index < self.queue.len() {
-            if self.is_causally_ready(&self.queue[index]) {
-                return Some(self.queue.swap_remove(index));
-            }
-            index += 1;
-        }
-        None
+        let index = self.next_causally_ready(&self.queue)?;
+        Some(self.queue.swap_remove(index))
+    }
+
+    fn next_causally_ready(&self, queue: &[Change]) -> Option<usize> {
+        queue.iter().position(|c| self.is_causally_ready(c))
     }
 
     fn import_ops(&mut self, change: &Change) -> Vec<(ObjId, OpBuilder, OpIds)> {
@@ -891,7 +1092,22 @@ impl Automerge {
     }
 
     /// Save the entirety of this document in a compact form.
+    ///
+    /// A shallow document is always saved shallow, see [`SaveOptions::shallow_at()`]
     pub fn save_with_options(&self, options: SaveOptions) -> Vec<u8> {
+        if let Some(heads) = shallow::save_heads(self, options.shallow.as_deref()) {
+            let later = self.get_changes(&heads);
+            let mut bytes = shallow::save_chunk(self, &heads, &later, options.deflate);
+            for change in later {
+                bytes.extend(change.raw_bytes());
+            }
+            if options.retain_orphans {
+                for orphaned in self.queue.iter() {
+                    bytes.extend(orphaned.raw_bytes());
+                }
+            }
+            return bytes;
+        }
         let heads = self.get_heads();
         let c = self.history.iter();
         let compress = if options.deflate {
@@ -960,7 +1176,7 @@ impl Automerge {
     ) -> Result<(), AutomergeError> {
         let heads = heads
             .iter()
-            .filter(|hash| self.history_index.contains_key(hash))
+            .filter(|hash| self.has_change(hash))
             .copied()
             .collect::<Vec<_>>();
 
@@ -982,7 +1198,9 @@ impl Automerge {
             if let Some(clock_data) = clock.get_for_actor(actor_index) {
                 // find the change in this actors sequence of changes that corresponds to the max_op
                 // recorded for them in the clock
-                change_indexes.extend(&actor_changes[clock_data.seq as usize..]);
+                let start =
+                    shallow::state_index(self, *actor_index, clock_data.seq + 1).unwrap_or(0);
+                change_indexes.extend(&actor_changes[start..]);
             } else {
                 change_indexes.extend(&actor_changes[..]);
             }
@@ -1033,7 +1251,7 @@ impl Automerge {
             actor_index = self.get_isolated_actor_index(i);
         }
 
-        let seq = self.states.get(&actor_index).map_or(0, |v| v.len()) as u64 + 1;
+        let seq = shallow::seq(self, actor_index) + 1;
 
         Isolation {
             actor_index,
@@ -1043,9 +1261,11 @@ impl Automerge {
     }
 
     fn get_hash(&self, actor: usize, seq: u64) -> Result<ChangeHash, AutomergeError> {
+        let index =
+            shallow::state_index(self, actor, seq).ok_or(AutomergeError::InvalidSeq(seq))?;
         self.states
             .get(&actor)
-            .and_then(|v| v.get(seq as usize - 1))
+            .and_then(|v| v.get(index))
             .and_then(|&i| self.history.get(i))
             .map(|c| c.hash())
             .ok_or(AutomergeError::InvalidSeq(seq))
@@ -1057,7 +1277,7 @@ impl Automerge {
             .and_then(|s| s.last())
             .and_then(|index| self.history.get(*index))
             .map(|change| change.max_op())
-            .unwrap_or(0)
+            .unwrap_or_else(|| shallow::max_op(self, actor_index))
     }
 
     pub(crate) fn update_history(&mut self, change: Change, num_ops: usize) -> usize {
@@ -1253,6 +1473,69 @@ impl Automerge {
         patch_log.make_patches(self)
     }
 
+    /// Create a new change which reverses the effect the change `hash` has on the current state
+    /// of the document, like `git revert`
+    ///
+    /// Map values which the change set are set back to the values they had before it, or deleted
+    /// if there were none. List and text elements which the change inserted are deleted and those
+    /// which it deleted are inserted again as new elements. Increments are negated and marks are
+    /// set back to the values they had.
+    ///
+    /// Values which have been modified again by a later change are left alone and reported in
+    /// [`RevertResult::unrestored`], as are keys and elements which the change modified and which
+    /// have since been deleted.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if `hash` is not a change in this document.
+    pub fn revert(&mut self, hash: &ChangeHash) -> Result<RevertResult, AutomergeError> {
+        crate::undo::revert(self, hash)
+    }
+
+    /// Create a new change which makes the current state of the document the same as it was at
+    /// `heads`, keeping all of the history
+    ///
+    /// This is calculated from [`Self::diff()`] between the current heads and `heads`, so text
+    /// is restored with its marks and blocks, counters are incremented back to their old values
+    /// and objects which have since been deleted are created again with their contents as at
+    /// `heads`. Concurrent changes which are merged later are preserved as usual.
+    ///
+    /// Returns `None` if no operations were needed, for example because `heads` are the current
+    /// heads.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if any of `heads` is not a change in this document.
+    pub fn restore_to(
+        &mut self,
+        heads: &[ChangeHash],
+    ) -> Result<Option<ChangeHash>, AutomergeError> {
+        let restoration = crate::restore::restoration(self, heads)?;
+        let mut tx = self.transaction();
+        crate::restore::apply(&mut tx, restoration, heads)?;
+        Ok(tx.commit().0)
+    }
+
+    /// Create a new document with the same history as this one but with the values selected by
+    /// `redaction` scrubbed from every change
+    ///
+    /// Each change is rewritten with the same actor, sequence number, timestamp and
+    /// dependencies, so the causal structure and authorship of the history are preserved, but the
+    /// rewritten changes have new hashes. The returned [`Redacted`] maps the hashes of this
+    /// document to those of the redacted document so that peers can be moved over to it. See
+    /// [`Redaction`] for what is scrubbed and how.
+    ///
+    /// # Errors
+    ///
+    /// * [`RedactError::Path`] if a path in `redaction` cannot be resolved against the current
+    ///   state of the document
+    /// * [`AutomergeError::InvalidHash`] if a change in `redaction` is not in this document
+    /// * [`AutomergeError::ShallowHistory`] if this is a shallow document, as the changes which
+    ///   would need rewriting are missing
+    pub fn redact(&self, redaction: &Redaction) -> Result<Redacted, RedactError> {
+        redact::redact(self, redaction)
+    }
+
     /// Get the heads of this document.
     pub fn get_heads(&self) -> Vec<ChangeHash> {
         let mut deps: Vec<_> = self.deps.iter().copied().collect();
@@ -1260,6 +1543,73 @@ impl Automerge {
         deps
     }
 
+    /// Determine how the history described by the heads `a` is related to that described by
+    /// `b`
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if any of the heads is not a change in this
+    /// document, like [`Self::fork_at()`] and [`Self::diff()`].
+    pub fn compare_heads(
+        &self,
+        a: &[ChangeHash],
+        b: &[ChangeHash],
+    ) -> Result<HeadsOrdering, AutomergeError> {
+        self.check_heads(a)?;
+        self.check_heads(b)?;
+        Ok(self.change_graph.compare(a, b))
+    }
+
+    /// The lowest common ancestors of the heads `a` and `b`, i.e. the heads of the history the
+    /// two have in common
+    ///
+    /// The result is empty if `a` and `b` have no history in common.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if any of the heads is not a change in this
+    /// document, like [`Self::fork_at()`] and [`Self::diff()`].
+    pub fn merge_base(
+        &self,
+        a: &[ChangeHash],
+        b: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        self.check_heads(a)?;
+        self.check_heads(b)?;
+        Ok(self.change_graph.merge_base(a, b))
+    }
+
+    /// The changes which are in the history of `to` but not in the history of `from`, in
+    /// topological order (every change comes after its dependencies)
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if any of the heads is not a change in this
+    /// document, like [`Self::fork_at()`] and [`Self::diff()`].
+    pub fn changes_between(
+        &self,
+        from: &[ChangeHash],
+        to: &[ChangeHash],
+    ) -> Result<Vec<&Change>, AutomergeError> {
+        self.check_heads(from)?;
+        self.check_heads(to)?;
+        self.change_graph
+            .changes_between(from, to)
+            .into_iter()
+            .map(|hash| {
+                self.get_change_by_hash(&hash)
+                    .ok_or(AutomergeError::ShallowHistory(hash))
+            })
+            .collect()
+    }
+
+    fn check_heads(&self, heads: &[ChangeHash]) -> Result<(), AutomergeError> {
+        match heads.iter().find(|h| !self.has_change(h)) {
+            Some(hash) => Err(AutomergeError::InvalidHash(*hash)),
+            None => Ok(()),
+        }
+    }
+
     pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
         self.get_changes_clock(have_deps)
     }
@@ -1273,7 +1623,7 @@ impl Automerge {
         let mut seen_hashes = HashSet::new();
         let mut added_change_hashes = Vec::new();
         while let Some(hash) = stack.pop() {
-            if !seen_hashes.contains(&hash) && self.get_change_by_hash(&hash).is_none() {
+            if !seen_hashes.contains(&hash) && !self.has_change(&hash) {
                 seen_hashes.insert(hash);
                 added_change_hashes.push(hash);
                 if let Some(change) = other.get_change_by_hash(&hash) {
@@ -1300,30 +1650,50 @@ impl Automerge {
             ExId::Root => None,
             ExId::Id(..) => {
                 let opid = self.exid_to_opid(exid).ok()?;
-                let actor_indices = self.states.get(&opid.actor())?;
-                let change_index_index = actor_indices
-                    .binary_search_by(|change_index| {
-                        let change = self
-                            .history
-                            .get(*change_index)
-                            .expect("State index should refer to a valid change");
-                        let start = change.start_op().get();
-                        let len = change.len() as u64;
-                        if opid.counter() < start {
-                            Ordering::Greater
-                        } else if start + len <= opid.counter() {
-                            Ordering::Less
-                        } else {
-                            Ordering::Equal
-                        }
-                    })
-                    .ok()?;
-                let change_index = actor_indices.get(change_index_index).unwrap();
-                Some(self.history.get(*change_index).unwrap().hash())
+                self.change_for_opid(&opid).map(|change| change.hash())
             }
         }
     }
 
+    /// The index in the history of the change containing `opid`, if it has been committed
+    pub(crate) fn change_index_for_opid(&self, opid: &OpId) -> Option<usize> {
+        let actor_indices = self.states.get(&opid.actor())?;
+        let change_index_index = actor_indices
+            .binary_search_by(|change_index| {
+                let change = self
+                    .history
+                    .get(*change_index)
+                    .expect("State index should refer to a valid change");
+                let start = change.start_op().get();
+                let len = change.len() as u64;
+                if opid.counter() < start {
+                    Ordering::Greater
+                } else if start + len <= opid.counter() {
+                    Ordering::Less
+                } else {
+                    Ordering::Equal
+                }
+            })
+            .ok()?;
+        actor_indices.get(change_index_index).copied()
+    }
+
+    /// The change containing `opid`, if it has been committed
+    pub(crate) fn change_for_opid(&self, opid: &OpId) -> Option<&Change> {
+        self.change_index_for_opid(opid)
+            .map(|index| &self.history[index])
+    }
+
+    /// The change at `index` in the order changes were applied
+    pub(crate) fn change_at_index(&self, index: usize) -> Option<&Change> {
+        self.history.get(index)
+    }
+
+    /// The index in the history of the change with hash `hash`
+    pub(crate) fn change_index_for_hash(&self, hash: &ChangeHash) -> Option<usize> {
+        self.history_index.get(hash).copied()
+    }
+
     fn calculate_marks(
         &self,
         obj: &ExId,
@@ -1914,18 +2284,60 @@ impl ReadDoc for Automerge {
         typ.ok_or_else(|| AutomergeError::InvalidObjId(obj.to_string()))
     }
 
+    fn history<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(self, obj.as_ref(), prop.into(), None)
+    }
+
+    fn history_at<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(self, obj.as_ref(), prop.into(), Some(self.clock_at(heads)))
+    }
+
+    fn object_changes<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(self, obj.as_ref(), None)
+    }
+
+    fn object_changes_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(self, obj.as_ref(), Some(self.clock_at(heads)))
+    }
+
+    fn blame<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(self, obj.as_ref(), None)
+    }
+
+    fn blame_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(self, obj.as_ref(), Some(self.clock_at(heads)))
+    }
+
     fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
-        let in_queue: HashSet<_> = self.queue.iter().map(|change| change.hash()).collect();
+        let queued = || self.queue.iter().chain(self.policy_queue.iter());
+        let in_queue: HashSet<_> = queued().map(|change| change.hash()).collect();
         let mut missing = HashSet::new();
 
-        for head in self.queue.iter().flat_map(|change| change.deps()) {
-            if !self.history_index.contains_key(head) {
+        for head in queued().flat_map(|change| change.deps()) {
+            if !self.has_change(head) && !self.rejected.contains(head) {
                 missing.insert(head);
             }
         }
 
         for head in heads {
-            if !self.history_index.contains_key(head) {
+            if !self.has_change(head) && !self.rejected.contains(head) {
                 missing.insert(head);
             }
         }
@@ -1965,6 +2377,27 @@ pub struct SaveOptions {
     pub deflate: bool,
     /// Whether to save changes which we do not have the dependencies for
     pub retain_orphans: bool,
+    /// See [`Self::shallow_at()`]
+    pub(crate) shallow: Option<Vec<ChangeHash>>,
+}
+
+impl SaveOptions {
+    /// Save a shallow document: the state of the document at `heads`, but not the changes
+    /// which produced it, followed by the changes which are not ancestors of `heads`
+    ///
+    /// Loading the result gives a document for which [`Automerge::is_shallow()`] is true. It can
+    /// make changes and sync with other documents which have the changes in these heads but it
+    /// cannot produce anything which requires older history, such as
+    /// [`Automerge::fork_at()`] heads before the boundary.
+    ///
+    /// Heads which aren't in the document are ignored. A shallow document is always saved
+    /// shallow, at its own boundary unless these heads are descendants of it.
+    pub fn shallow_at(self, heads: Vec<ChangeHash>) -> Self {
+        Self {
+            shallow: Some(heads),
+            ..self
+        }
+    }
 }
 
 impl std::default::Default for SaveOptions {
@@ -1972,6 +2405,7 @@ impl std::default::Default for SaveOptions {
         Self {
             deflate: true,
             retain_orphans: true,
+            shallow: None,
         }
     }
 }
@@ -2009,6 +2443,8 @@ pub(crate) fn reconstruct_document<'a>(
     let history_index = hashes_by_index.into_iter().map(|(k, v)| (v, k)).collect();
     Ok(Automerge {
         queue: vec![],
+        policy_queue: vec![],
+        rejected: HashSet::new(),
         history: changes,
         history_index,
         states: actor_to_history,
@@ -2017,5 +2453,6 @@ pub(crate) fn reconstruct_document<'a>(
         deps: heads.into_iter().collect(),
         actor: Actor::Unused(ActorId::random()),
         max_op,
+        boundary: None,
     })
 }
@@ -15,7 +15,8 @@ use crate::{
     patches::PatchLog,
     types::{Clock, ListEncoding, Op, Prop},
     value::Value,
-    Automerge, AutomergeError, ChangeHash, Cursor, ObjId as ExId, ObjType, OpType, ReadDoc,
+    Automerge, AutomergeError, BlameSpan, ChangeHash, Cursor, HistoryEntry, ObjId as ExId, ObjType,
+    OpType, ReadDoc,
 };
 
 #[derive(Clone, Debug)]
@@ -491,6 +492,47 @@ impl<'a, 'b> ReadDoc for ReadDocAt<'a, 'b> {
         self.doc.parents_at(obj, heads)
     }
 
+    fn history<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        self.doc.history_at(obj, prop, self.heads)
+    }
+
+    fn history_at<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        self.doc.history_at(obj, prop, heads)
+    }
+
+    fn object_changes<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<ChangeHash>, AutomergeError> {
+        self.doc.object_changes_at(obj, self.heads)
+    }
+
+    fn object_changes_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        self.doc.object_changes_at(obj, heads)
+    }
+
+    fn blame<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<BlameSpan>, AutomergeError> {
+        self.doc.blame_at(obj, self.heads)
+    }
+
+    fn blame_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<BlameSpan>, AutomergeError> {
+        self.doc.blame_at(obj, heads)
+    }
+
     fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
         self.doc.get_missing_deps(heads)
     }
@@ -0,0 +1,519 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::error::{PathError, RedactError};
+use crate::exid::ExId;
+use crate::legacy::{self, ElementId, MarkData, ObjectId, OpType};
+use crate::patches::TextRepresentation;
+use crate::path::{self, IntoPath, Path};
+use crate::types::{ChangeHash, Key};
+use crate::{Automerge, AutomergeError, Change, ObjType, Prop, ScalarValue};
+use smol_str::SmolStr;
+
+/// What to remove from a document with [`Automerge::redact()`]
+///
+/// A redaction is a set of paths and a set of changes. Every value which has ever been written
+/// under one of the paths, in any version of the document, is scrubbed. So is every value written
+/// by one of the changes, along with the message of the change.
+///
+/// Map keys in a path match the key in every version of the document, so redacting
+/// `/users/alice` also redacts the contents of an `alice` map which was deleted and created again.
+/// List indices are resolved against the current state of the document to the element which is
+/// currently at that index, and only match that element.
+///
+/// Map keys and mark names inside a redacted object are replaced with placeholders
+/// (`redacted-0`, `redacted-1`, ...), so redacting `/users` also hides the keys of a map of users
+/// keyed by email address. The same name is always given the same placeholder. The last key of
+/// each path is kept, as are the keys written by a redacted change outside of the paths, since
+/// other changes refer to them. Redact the parent object to hide such a key.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    paths: Vec<Path>,
+    changes: HashSet<ChangeHash>,
+}
+
+impl Redaction {
+    /// A redaction which doesn't remove anything yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Redact every value under `path`
+    pub fn path<P: IntoPath>(mut self, path: P) -> Result<Self, PathError> {
+        self.paths.push(path.into_path()?);
+        Ok(self)
+    }
+
+    /// Redact every value written by the change `hash`
+    pub fn change(mut self, hash: ChangeHash) -> Self {
+        self.changes.insert(hash);
+        self
+    }
+}
+
+/// The output of [`Automerge::redact()`]
+///
+/// Redacting a document changes the hash of every change which was redacted and of every change
+/// which depends on one, so peers which still have the original document need to start again from
+/// the redacted one. [`Self::new_heads()`] translates the heads a peer had in the original document
+/// into the matching heads of the redacted document.
+#[derive(Debug, Clone)]
+pub struct Redacted {
+    document: Automerge,
+    hashes: HashMap<ChangeHash, ChangeHash>,
+}
+
+impl Redacted {
+    /// The redacted document
+    pub fn document(&self) -> &Automerge {
+        &self.document
+    }
+
+    /// The redacted document, consuming `self`
+    pub fn into_document(self) -> Automerge {
+        self.document
+    }
+
+    /// The hash in the redacted document of the change `old` from the original document
+    pub fn new_hash(&self, old: &ChangeHash) -> Option<ChangeHash> {
+        self.hashes.get(old).copied()
+    }
+
+    /// Translate `old`, heads of the original document, into heads of the redacted document
+    ///
+    /// Returns `None` if any of `old` is not a change in the original document.
+    pub fn new_heads(&self, old: &[ChangeHash]) -> Option<Vec<ChangeHash>> {
+        let mut heads = old
+            .iter()
+            .map(|h| self.new_hash(h))
+            .collect::<Option<Vec<_>>>()?;
+        heads.sort_unstable();
+        Some(heads)
+    }
+
+    /// The hash of every change in the original document and of the change which replaced it
+    pub fn hashes(&self) -> impl Iterator<Item = (&ChangeHash, &ChangeHash)> {
+        self.hashes.iter()
+    }
+}
+
+/// A segment of a redaction path which has been resolved against the current state of the
+/// document
+#[derive(Debug)]
+enum Segment {
+    Key(String),
+    Elem(legacy::OpId),
+}
+
+/// What we know about an object created somewhere in the history of the document
+#[derive(Debug, Clone)]
+struct Object {
+    typ: ObjType,
+    /// Whether the object is under one of the redaction paths
+    redacted: bool,
+    /// The `(path, depth)` of each path whose first `depth` segments lead to this object
+    matches: Vec<(usize, usize)>,
+}
+
+pub(crate) fn redact(doc: &Automerge, redaction: &Redaction) -> Result<Redacted, RedactError> {
+    if let Some(head) = doc.shallow_heads().and_then(|h| h.first()) {
+        return Err(AutomergeError::ShallowHistory(*head).into());
+    }
+    if let Some(hash) = redaction.changes.iter().find(|h| !doc.has_change(h)) {
+        return Err(AutomergeError::InvalidHash(*hash).into());
+    }
+    let paths = redaction
+        .paths
+        .iter()
+        .map(|p| resolve(doc, p))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut objects = HashMap::new();
+    objects.insert(
+        ObjectId::Root,
+        Object {
+            typ: ObjType::Map,
+            redacted: paths.iter().any(|p| p.is_empty()),
+            matches: (0..paths.len()).map(|p| (p, 0)).collect(),
+        },
+    );
+
+    let mut placeholders = HashMap::new();
+    let mut hashes = HashMap::new();
+    let mut changes = Vec::new();
+    for change in doc.get_changes(&[]) {
+        let redact_change = redaction.changes.contains(&change.hash());
+        let mut expanded = change.decode();
+        for (i, op) in expanded.operations.iter_mut().enumerate() {
+            let id = legacy::OpId::new(expanded.start_op.get() + i as u64, &expanded.actor_id);
+            let parent = objects.get(&op.obj).cloned().unwrap_or(Object {
+                typ: ObjType::Map,
+                redacted: false,
+                matches: Vec::new(),
+            });
+            let target = match &op.key {
+                _ if op.insert => Some(Segment::Elem(id.clone())),
+                legacy::Key::Map(key) => Some(Segment::Key(key.to_string())),
+                legacy::Key::Seq(ElementId::Id(elem)) => Some(Segment::Elem(elem.clone())),
+                legacy::Key::Seq(ElementId::Head) => None,
+            };
+            let mut redacted = parent.redacted;
+            let mut matches = Vec::new();
+            for &(p, depth) in &parent.matches {
+                if !target
+                    .as_ref()
+                    .is_some_and(|t| segment_matches(&paths[p][depth], t))
+                {
+                    continue;
+                }
+                if depth + 1 == paths[p].len() {
+                    redacted = true;
+                } else {
+                    matches.push((p, depth + 1));
+                }
+            }
+            if parent.redacted {
+                if let legacy::Key::Map(key) = &mut op.key {
+                    *key = placeholder(&mut placeholders, key);
+                }
+                if let OpType::MarkBegin(MarkData { name, .. }) = &mut op.action {
+                    *name = placeholder(&mut placeholders, name);
+                }
+            }
+            if redacted || redact_change {
+                scrub(&mut op.action, parent.typ == ObjType::Text);
+            }
+            if let OpType::Make(typ) = op.action {
+                objects.insert(
+                    ObjectId::Id(id),
+                    Object {
+                        typ,
+                        redacted,
+                        matches,
+                    },
+                );
+            }
+        }
+        if redact_change {
+            expanded.message = None;
+        }
+        expanded.deps = expanded.deps.iter().map(|d| hashes[d]).collect();
+        expanded.hash = None;
+        let rewritten = Change::from(expanded);
+        hashes.insert(change.hash(), rewritten.hash());
+        changes.push(rewritten);
+    }
+
+    let mut document = Automerge::new().with_actor(doc.get_actor().clone());
+    document.apply_changes(changes)?;
+    Ok(Redacted { document, hashes })
+}
+
+/// Resolve the list indices in `path` to the elements currently at those indices
+fn resolve(doc: &Automerge, path: &Path) -> Result<Vec<Segment>, RedactError> {
+    path::resolve_segments(doc, path)?
+        .into_iter()
+        .map(|(obj, prop)| match (obj, prop) {
+            (_, Prop::Map(key)) => Ok(Segment::Key(key)),
+            (Some(obj), Prop::Seq(index)) => Ok(Segment::Elem(element_at(doc, &obj, index)?)),
+            (None, Prop::Seq(index)) => Err(AutomergeError::InvalidIndex(index).into()),
+        })
+        .collect()
+}
+
+/// The ID of the element which is currently at `index` in the sequence `obj`
+fn element_at(doc: &Automerge, obj: &ExId, index: usize) -> Result<legacy::OpId, AutomergeError> {
+    let obj = doc.exid_to_obj(obj)?;
+    let found = doc.ops.seek_ops_by_prop(
+        &obj.id,
+        index.into(),
+        TextRepresentation::String.encoding(obj.typ),
+        None,
+    );
+    match found.ops.last().map(|op| op.elemid_or_key()) {
+        Some(Key::Seq(elem)) if !elem.is_head() => Ok(legacy::OpId::new(
+            elem.0.counter(),
+            &doc.ops.osd.actors.cache[elem.0.actor()],
+        )),
+        _ => Err(AutomergeError::InvalidIndex(index)),
+    }
+}
+
+fn segment_matches(segment: &Segment, target: &Segment) -> bool {
+    match (segment, target) {
+        (Segment::Key(a), Segment::Key(b)) => a == b,
+        (Segment::Elem(a), Segment::Elem(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The name which replaces the map key or mark name `name` inside a redacted object
+fn placeholder(placeholders: &mut HashMap<SmolStr, SmolStr>, name: &SmolStr) -> SmolStr {
+    let next = placeholders.len();
+    placeholders
+        .entry(name.clone())
+        .or_insert_with(|| format!("redacted-{}", next).into())
+        .clone()
+}
+
+/// Replace the value of an op with a placeholder which keeps the shape of the document
+///
+/// Characters in text are replaced with U+FFFD so that the length of the text and any cursors into
+/// it are unaffected. Counters are reset to zero so that increments of them still apply, and every
+/// other value becomes null.
+fn scrub(action: &mut OpType, in_text: bool) {
+    match action {
+        OpType::Put(ScalarValue::Str(s)) if in_text => {
+            *s = s.chars().map(|_| char::REPLACEMENT_CHARACTER).collect();
+        }
+        OpType::Put(value @ ScalarValue::Counter(_)) => *value = ScalarValue::counter(0),
+        OpType::Put(value) | OpType::MarkBegin(MarkData { value, .. }) => {
+            *value = ScalarValue::Null
+        }
+        OpType::Increment(by) => *by = 0,
+        OpType::Make(_) | OpType::Delete | OpType::MarkEnd(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Redaction;
+    use crate::error::RedactError;
+    use crate::legacy;
+    use crate::marks::{ExpandMark, Mark};
+    use crate::transaction::{CommitOptions, Transactable};
+    use crate::{AutoCommit, AutomergeError, ObjType, ReadDoc, ScalarValue, Value, ROOT};
+
+    /// Every string value written by any change in `doc`
+    fn strings(doc: &mut AutoCommit) -> Vec<String> {
+        doc.get_changes(&[])
+            .into_iter()
+            .flat_map(|c| c.decode().operations)
+            .filter_map(|op| match op.primitive_value() {
+                Some(ScalarValue::Str(s)) => Some(s.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn redact_path_from_every_version() {
+        let mut doc = AutoCommit::new();
+        let user = doc.put_object(ROOT, "user", ObjType::Map).unwrap();
+        doc.put(&user, "email", "first@example.com").unwrap();
+        doc.put(&user, "name", "alice").unwrap();
+        doc.commit_with(CommitOptions::default().with_message("add alice"));
+        doc.put(&user, "email", "second@example.com").unwrap();
+        doc.commit();
+        doc.delete(ROOT, "user").unwrap();
+        let user = doc.put_object(ROOT, "user", ObjType::Map).unwrap();
+        doc.put(&user, "email", "third@example.com").unwrap();
+        doc.commit();
+
+        let redaction = Redaction::new().path("/user/email").unwrap();
+        let redacted = doc.redact(&redaction).unwrap();
+        let mut new_doc = AutoCommit::load(&redacted.document().save()).unwrap();
+
+        assert!(!strings(&mut new_doc).iter().any(|s| s.contains('@')));
+        assert!(strings(&mut new_doc).contains(&"alice".to_string()));
+        let (_, user) = new_doc.get(ROOT, "user").unwrap().unwrap();
+        assert_eq!(
+            new_doc.get(&user, "email").unwrap().unwrap().0,
+            Value::Scalar(std::borrow::Cow::Owned(ScalarValue::Null))
+        );
+
+        let old_changes = doc
+            .get_changes(&[])
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let new_changes = new_doc.get_changes(&[]);
+        assert_eq!(old_changes.len(), new_changes.len());
+        for (old, new) in old_changes.iter().zip(new_changes) {
+            assert_ne!(old.hash(), new.hash());
+            assert_eq!(redacted.new_hash(&old.hash()), Some(new.hash()));
+            assert_eq!(old.actor_id(), new.actor_id());
+            assert_eq!(old.seq(), new.seq());
+            assert_eq!(old.timestamp(), new.timestamp());
+            assert_eq!(old.message(), new.message());
+            assert_eq!(old.len(), new.len());
+        }
+        assert_eq!(
+            redacted.new_heads(&doc.get_heads()),
+            Some(new_doc.get_heads())
+        );
+    }
+
+    #[test]
+    fn redact_keys_and_mark_names_under_a_path() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "title", "public").unwrap();
+        let users = doc.put_object(ROOT, "users", ObjType::Map).unwrap();
+        let alice = doc
+            .put_object(&users, "alice@example.com", ObjType::Map)
+            .unwrap();
+        let bio = doc.put_object(&alice, "bio", ObjType::Text).unwrap();
+        doc.splice_text(&bio, 0, 0, "hello").unwrap();
+        let mark = Mark::new("confidential".into(), true, 0, 5);
+        doc.mark(&bio, mark, ExpandMark::None).unwrap();
+        doc.put(&users, "bob@example.com", "bob").unwrap();
+        doc.commit();
+        doc.put(&users, "bob@example.com", "robert").unwrap();
+        doc.commit();
+
+        let redaction = Redaction::new().path("/users").unwrap();
+        let redacted = doc.redact(&redaction).unwrap();
+        let new_doc = redacted.document();
+
+        let names = new_doc
+            .get_changes(&[])
+            .into_iter()
+            .flat_map(|c| c.decode().operations)
+            .flat_map(|op| {
+                let key = match op.key {
+                    legacy::Key::Map(key) => Some(key.to_string()),
+                    legacy::Key::Seq(_) => None,
+                };
+                let mark = match op.action {
+                    legacy::OpType::MarkBegin(data) => Some(data.name.to_string()),
+                    _ => None,
+                };
+                key.into_iter().chain(mark)
+            })
+            .collect::<Vec<_>>();
+        assert!(names.contains(&"title".to_string()));
+        assert!(names.contains(&"users".to_string()));
+        assert!(!names
+            .iter()
+            .any(|n| n.contains('@') || n == "bio" || n == "confidential"));
+
+        // Both users are still there, and bob's two values are still on the same key
+        let (_, users) = new_doc.get(ROOT, "users").unwrap().unwrap();
+        assert_eq!(new_doc.length(&users), 2);
+        assert_eq!(
+            new_doc.get(ROOT, "title").unwrap().unwrap().0,
+            Value::str("public")
+        );
+    }
+
+    #[test]
+    fn redact_text_keeps_its_length() {
+        let mut doc = AutoCommit::new();
+        let notes = doc.put_object(ROOT, "notes", ObjType::Text).unwrap();
+        doc.splice_text(&notes, 0, 0, "my secret").unwrap();
+        doc.commit();
+        doc.splice_text(&notes, 3, 6, "password").unwrap();
+        let title = doc.put_object(ROOT, "title", ObjType::Text).unwrap();
+        doc.splice_text(&title, 0, 0, "public").unwrap();
+
+        let redaction = Redaction::new().path("/notes").unwrap();
+        let redacted = doc.redact(&redaction).unwrap();
+        let new_doc = redacted.document();
+
+        let (_, notes) = new_doc.get(ROOT, "notes").unwrap().unwrap();
+        assert_eq!(new_doc.text(&notes).unwrap(), "\u{fffd}".repeat(11));
+        let (_, title) = new_doc.get(ROOT, "title").unwrap().unwrap();
+        assert_eq!(new_doc.text(&title).unwrap(), "public");
+    }
+
+    #[test]
+    fn redact_list_element() {
+        let mut doc = AutoCommit::new();
+        let items = doc.put_object(ROOT, "items", ObjType::List).unwrap();
+        for name in ["keep", "remove"] {
+            let item = doc.insert_object(&items, 0, ObjType::Map).unwrap();
+            doc.put(&item, "name", name).unwrap();
+            doc.commit();
+        }
+
+        let redaction = Redaction::new().path("/items/0").unwrap();
+        let redacted = doc.redact(&redaction).unwrap();
+        let new_doc = redacted.document();
+
+        let (_, items) = new_doc.get(ROOT, "items").unwrap().unwrap();
+        let (_, removed) = new_doc.get(&items, 0).unwrap().unwrap();
+        let keys = new_doc.keys(&removed).collect::<Vec<_>>();
+        assert_eq!(keys, vec!["redacted-0".to_string()]);
+        assert_eq!(
+            new_doc.get(&removed, &keys[0]).unwrap().unwrap().0,
+            Value::Scalar(std::borrow::Cow::Owned(ScalarValue::Null))
+        );
+        let (_, kept) = new_doc.get(&items, 1).unwrap().unwrap();
+        assert_eq!(
+            new_doc.get(&kept, "name").unwrap().unwrap().0,
+            Value::str("keep")
+        );
+    }
+
+    #[test]
+    fn redact_change_across_concurrent_history() {
+        let mut doc1 = AutoCommit::new();
+        doc1.put(ROOT, "a", "before").unwrap();
+        doc1.put(ROOT, "counter", ScalarValue::counter(1)).unwrap();
+        doc1.commit();
+        let mut doc2 = doc1.fork();
+        doc2.put(ROOT, "b", "leaked").unwrap();
+        doc2.increment(ROOT, "counter", 5).unwrap();
+        let leaked = doc2
+            .commit_with(CommitOptions::default().with_message("oops"))
+            .unwrap();
+        doc2.put(ROOT, "c", "later").unwrap();
+        doc1.put(ROOT, "a", "concurrent").unwrap();
+        doc1.commit();
+        let doc1_heads = doc1.get_heads();
+        doc1.merge(&mut doc2).unwrap();
+
+        let redacted = doc1.redact(&Redaction::new().change(leaked)).unwrap();
+        let new_doc = redacted.document();
+
+        assert_eq!(new_doc.get(ROOT, "b").unwrap().unwrap().0, Value::from(()));
+        assert_eq!(
+            new_doc.get(ROOT, "c").unwrap().unwrap().0,
+            Value::str("later")
+        );
+        assert_eq!(
+            new_doc.get(ROOT, "a").unwrap().unwrap().0,
+            Value::str("concurrent")
+        );
+        assert_eq!(
+            new_doc.get(ROOT, "counter").unwrap().unwrap().0,
+            Value::counter(1)
+        );
+        let new_leaked = redacted.new_hash(&leaked).unwrap();
+        assert_eq!(
+            new_doc.get_change_by_hash(&new_leaked).unwrap().message(),
+            None
+        );
+
+        let new_heads = redacted.new_heads(&doc1_heads).unwrap();
+        let at = new_doc.get_changes(&[]).len() - new_doc.get_changes(&new_heads).len();
+        assert_eq!(
+            at,
+            doc1.get_changes(&[]).len() - doc1.get_changes(&doc1_heads).len()
+        );
+        assert_eq!(
+            redacted.new_heads(&doc1.get_heads()),
+            Some(new_doc.get_heads())
+        );
+    }
+
+    #[test]
+    fn redact_errors() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        let hash = doc.commit().unwrap();
+        let mut other = AutoCommit::new();
+        other.put(ROOT, "a", 1).unwrap();
+        let missing = other.commit().unwrap();
+
+        assert!(doc.redact(&Redaction::new().change(hash)).is_ok());
+        assert!(matches!(
+            doc.redact(&Redaction::new().change(missing)),
+            Err(RedactError::Automerge(AutomergeError::InvalidHash(h))) if h == missing
+        ));
+        assert!(matches!(
+            doc.redact(&Redaction::new().path(vec![0.into()]).unwrap()),
+            Err(RedactError::Path(_))
+        ));
+        assert!(Redaction::new().path("no/leading/slash").is_err());
+    }
+}
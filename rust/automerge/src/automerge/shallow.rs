@@ -0,0 +1,473 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::change_graph::{ChangeGraph, HeadsOrdering};
+use crate::clock::ClockData;
+use crate::storage::{self, load, shallow::BoundaryChange, CompressConfig};
+use crate::types::{ActorId, ChangeHash, Clock};
+use crate::{Automerge, AutomergeError, Change};
+
+use super::Actor;
+
+/// The point in the history of a shallow document before which we don't have any changes
+///
+/// The ops of every change in the history of `heads` are in the document but the changes
+/// themselves are not. Changes which are in the history of `heads` can still turn up later, from
+/// a peer which has the full history, in which case we record them in `known` but otherwise
+/// ignore them.
+#[derive(Debug, Clone)]
+pub(crate) struct Boundary {
+    heads: Vec<ChangeHash>,
+    /// The boundary changes we were loaded with and any older changes we have seen since
+    known: HashSet<ChangeHash>,
+    /// The clock of `heads`
+    clock: Clock,
+}
+
+impl Boundary {
+    pub(crate) fn heads(&self) -> &[ChangeHash] {
+        &self.heads
+    }
+
+    pub(crate) fn contains(&self, hash: &ChangeHash) -> bool {
+        self.known.contains(hash)
+    }
+
+    /// The number of changes by `actor` which are before the boundary
+    pub(crate) fn seq(&self, actor: usize) -> u64 {
+        self.clock.get_for_actor(&actor).map_or(0, |d| d.seq)
+    }
+
+    /// The max op of the last change by `actor` which is before the boundary
+    pub(crate) fn max_op(&self, actor: usize) -> u64 {
+        self.clock.get_for_actor(&actor).map_or(0, |d| d.max_op)
+    }
+}
+
+/// Encode the state of `doc` at `heads` as a shallow chunk
+///
+/// `later` must be the changes which are not in the history of `heads` (i.e.
+/// `doc.get_changes(heads)`), the changes they depend on are recorded as boundary changes so that
+/// they can be applied to the loaded document.
+pub(crate) fn save_chunk(
+    doc: &Automerge,
+    heads: &[ChangeHash],
+    later: &[&Change],
+    deflate: bool,
+) -> Vec<u8> {
+    let clock = doc.clock_at(heads);
+
+    let included = later.iter().map(|c| c.hash()).collect::<HashSet<_>>();
+    let mut boundary_hashes = heads.iter().copied().collect::<BTreeSet<_>>();
+    boundary_hashes.extend(
+        later
+            .iter()
+            .flat_map(|c| c.deps())
+            .filter(|dep| !included.contains(dep)),
+    );
+
+    let (document, actor_lookup) = storage::save::save_shallow_document(
+        doc.ops.iter().map(|(objid, _, op)| (objid, op)),
+        &doc.ops.osd.actors,
+        &doc.ops.osd.props,
+        &clock,
+        (!deflate).then_some(CompressConfig::None),
+    );
+
+    let boundary = boundary_hashes
+        .into_iter()
+        .map(|hash| {
+            let (actor, data) = doc
+                .change_graph
+                .change_data(&hash)
+                .expect("boundary changes should be in the change graph");
+            let mut clock = doc
+                .clock_at(&[hash])
+                .iter()
+                .map(|(actor, data)| (actor_lookup[actor], data.seq, data.max_op))
+                .collect::<Vec<_>>();
+            clock.sort_unstable();
+            BoundaryChange {
+                hash,
+                actor: actor_lookup[&actor],
+                seq: data.seq,
+                max_op: data.max_op,
+                clock,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut heads = heads.to_vec();
+    heads.sort_unstable();
+    storage::Shallow::write(&heads, &boundary, &document)
+}
+
+/// Create a document from a shallow chunk
+pub(crate) fn load(shallow: &storage::Shallow<'_>) -> Result<Automerge, AutomergeError> {
+    let (op_set, max_op) = storage::load::reconstruct_ops(shallow.document())
+        .map_err(|e| load::Error::InflateDocument(Box::new(e)))?;
+
+    let mut change_graph = ChangeGraph::new();
+    let mut boundary_clock = Clock::new();
+    for change in shallow.boundary() {
+        let mut clock = Clock::new();
+        for (actor, seq, max_op) in &change.clock {
+            clock.include(
+                *actor,
+                ClockData {
+                    max_op: *max_op,
+                    seq: *seq,
+                },
+            );
+        }
+        boundary_clock = Clock::merge(&boundary_clock, &clock);
+        change_graph.add_boundary(
+            change.hash,
+            change.actor,
+            ClockData {
+                max_op: change.max_op,
+                seq: change.seq,
+            },
+            clock,
+        );
+    }
+    let max_op = boundary_clock
+        .iter()
+        .map(|(_, data)| data.max_op)
+        .fold(max_op, std::cmp::max);
+
+    let heads = shallow.heads().to_vec();
+    Ok(Automerge {
+        queue: vec![],
+        policy_queue: vec![],
+        rejected: HashSet::new(),
+        history: vec![],
+        history_index: HashMap::new(),
+        states: HashMap::new(),
+        change_graph,
+        ops: op_set,
+        deps: heads.iter().copied().collect(),
+        actor: Actor::Unused(ActorId::random()),
+        max_op,
+        boundary: Some(Boundary {
+            heads,
+            known: shallow.boundary().iter().map(|c| c.hash).collect(),
+            clock: boundary_clock,
+        }),
+    })
+}
+
+/// Record a change from before the boundary of a shallow document
+///
+/// We already have the ops of the change so all we need is to know that the change exists, so
+/// that changes which depend on it can be applied.
+pub(crate) fn add_boundary_change(doc: &mut Automerge, change: &Change) {
+    let Some(actor_index) = doc.ops.osd.actors.lookup(change.actor_id()) else {
+        return;
+    };
+    let data = ClockData {
+        max_op: change.max_op(),
+        seq: change.seq(),
+    };
+    // We may not have the ancestors of the change, in which case this underestimates its clock.
+    // That's fine as everything in its history is before the boundary anyway.
+    let mut clock = doc.clock_at(change.deps());
+    clock.include(actor_index, data);
+    doc.change_graph
+        .add_boundary(change.hash(), actor_index, data, clock);
+    if let Some(boundary) = &mut doc.boundary {
+        boundary.known.insert(change.hash());
+    }
+}
+
+/// The number of changes by `actor` in `doc`, including those before the boundary of a shallow
+/// document
+pub(crate) fn seq(doc: &Automerge, actor: usize) -> u64 {
+    doc.states.get(&actor).map_or(0, |v| v.len()) as u64 + boundary_seq(doc, actor)
+}
+
+/// The index in `doc.states` of the change by `actor` with sequence number `seq`, or `None` if
+/// that change is before the boundary (or `seq` is zero)
+pub(crate) fn state_index(doc: &Automerge, actor: usize, seq: u64) -> Option<usize> {
+    seq.checked_sub(boundary_seq(doc, actor) + 1)
+        .map(|index| index as usize)
+}
+
+/// The max op of the last change by `actor` which is before the boundary
+pub(crate) fn max_op(doc: &Automerge, actor: usize) -> u64 {
+    doc.boundary.as_ref().map_or(0, |b| b.max_op(actor))
+}
+
+/// Whether `change` is from before the boundary of a shallow document
+pub(crate) fn before_boundary(doc: &Automerge, change: &Change) -> bool {
+    doc.boundary.is_some()
+        && doc
+            .ops
+            .osd
+            .actors
+            .lookup(change.actor_id())
+            .is_some_and(|actor| change.seq() <= boundary_seq(doc, actor))
+}
+
+fn boundary_seq(doc: &Automerge, actor: usize) -> u64 {
+    doc.boundary.as_ref().map_or(0, |b| b.seq(actor))
+}
+
+/// Check that `heads` are descendants of the boundary of a shallow document
+pub(crate) fn check_after_boundary(
+    doc: &Automerge,
+    heads: &[ChangeHash],
+) -> Result<(), AutomergeError> {
+    let Some(boundary) = &doc.boundary else {
+        return Ok(());
+    };
+    for boundary_head in boundary.heads() {
+        match doc.change_graph.compare(&[*boundary_head], heads) {
+            HeadsOrdering::Ancestor | HeadsOrdering::Equal => {}
+            _ => {
+                let before = heads
+                    .iter()
+                    .find(|h| boundary.contains(h))
+                    .unwrap_or(boundary_head);
+                return Err(AutomergeError::ShallowHistory(*before));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The heads to save a shallow document at given the heads requested with
+/// [`crate::SaveOptions::shallow_at()`], or `None` for a full save
+pub(crate) fn save_heads(
+    doc: &Automerge,
+    requested: Option<&[ChangeHash]>,
+) -> Option<Vec<ChangeHash>> {
+    let requested = requested
+        .unwrap_or_default()
+        .iter()
+        .filter(|h| doc.history_index.contains_key(h))
+        .copied()
+        .collect::<Vec<_>>();
+    match &doc.boundary {
+        Some(boundary)
+            if requested.is_empty() || check_after_boundary(doc, &requested).is_err() =>
+        {
+            Some(boundary.heads().to_vec())
+        }
+        _ if requested.is_empty() => None,
+        _ => Some(requested),
+    }
+}
+
+/// The document a fork of `doc` starts from: empty, or the state at the boundary of a shallow
+/// document
+pub(crate) fn fork_base(doc: &Automerge) -> Result<Automerge, AutomergeError> {
+    let Some(boundary) = &doc.boundary else {
+        return Ok(Automerge::new());
+    };
+    let later = doc.get_changes(boundary.heads());
+    let chunk = save_chunk(doc, boundary.heads(), &later, false);
+    Automerge::load(&chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sync::{self, SyncDoc};
+    use crate::transaction::Transactable;
+    use crate::{
+        AutoCommit, Automerge, AutomergeError, ChangeHash, ObjType, ReadDoc, SaveOptions, ROOT,
+    };
+
+    fn history() -> (AutoCommit, Vec<ChangeHash>) {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        for i in 0..100 {
+            doc.put(ROOT, "note", format!("this is note number {}", i))
+                .unwrap();
+            doc.splice_text(&text, 0, 0, "hello ").unwrap();
+            doc.commit();
+        }
+        doc.splice_text(&text, 0, 30, "").unwrap();
+        doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        doc.commit();
+        let heads = doc.get_heads();
+
+        doc.put(ROOT, "note", "the last note").unwrap();
+        doc.splice_text(&text, 0, 0, "hi ").unwrap();
+        doc.commit();
+        (doc, heads)
+    }
+
+    fn save_shallow(doc: &mut AutoCommit, heads: &[ChangeHash]) -> Vec<u8> {
+        doc.save_with_options(SaveOptions::default().shallow_at(heads.to_vec()))
+    }
+
+    fn sync(a: &mut Automerge, b: &mut Automerge) {
+        let mut a_state = sync::State::new();
+        let mut b_state = sync::State::new();
+        for _ in 0..10 {
+            let a_to_b = a.generate_sync_message(&mut a_state);
+            let b_to_a = b.generate_sync_message(&mut b_state);
+            if a_to_b.is_none() && b_to_a.is_none() {
+                return;
+            }
+            if let Some(msg) = a_to_b {
+                b.receive_sync_message(&mut b_state, msg).unwrap();
+            }
+            if let Some(msg) = b_to_a {
+                a.receive_sync_message(&mut a_state, msg).unwrap();
+            }
+        }
+        panic!("failed to sync");
+    }
+
+    #[test]
+    fn load_shallow_save() {
+        let (mut doc, heads) = history();
+        let saved = save_shallow(&mut doc, &heads);
+        assert!(saved.len() < doc.save().len());
+
+        let loaded = Automerge::load(&saved).unwrap();
+        assert!(loaded.is_shallow());
+        assert_eq!(loaded.shallow_heads(), Some(&heads[..]));
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+        assert_eq!(loaded.hydrate(None), doc.document().hydrate(None));
+        assert_eq!(
+            loaded.hydrate(Some(&heads)),
+            doc.document().hydrate(Some(&heads))
+        );
+        assert_eq!(loaded.get_changes(&[]).len(), 1);
+
+        // Saving again keeps the boundary
+        let reloaded = Automerge::load(&loaded.save()).unwrap();
+        assert_eq!(reloaded.shallow_heads(), Some(&heads[..]));
+        assert_eq!(reloaded.hydrate(None), doc.document().hydrate(None));
+    }
+
+    #[test]
+    fn shallow_document_can_make_changes() {
+        let (mut doc, heads) = history();
+        let mut shallow = AutoCommit::load(&save_shallow(&mut doc, &heads)).unwrap();
+        let list = match shallow.get(ROOT, "list").unwrap() {
+            Some((_, id)) => id,
+            None => panic!("list should exist"),
+        };
+        shallow.insert(&list, 0, "a").unwrap();
+        shallow.put(ROOT, "note", "a shallow note").unwrap();
+        shallow.commit();
+
+        doc.merge(&mut shallow).unwrap();
+        assert_eq!(doc.get_heads(), shallow.get_heads());
+        assert_eq!(
+            doc.document().hydrate(None),
+            shallow.document().hydrate(None)
+        );
+    }
+
+    #[test]
+    fn shallow_document_syncs_with_full_peers() {
+        let (mut doc, heads) = history();
+        let mut shallow = Automerge::load(&save_shallow(&mut doc, &heads)).unwrap();
+        let mut full = doc.document().clone();
+
+        full.transact::<_, _, AutomergeError>(|tx| {
+            tx.put(ROOT, "full", true)?;
+            Ok(())
+        })
+        .unwrap();
+        shallow
+            .transact::<_, _, AutomergeError>(|tx| {
+                tx.put(ROOT, "shallow", true)?;
+                Ok(())
+            })
+            .unwrap();
+
+        sync(&mut shallow, &mut full);
+        assert_eq!(shallow.get_heads(), full.get_heads());
+        assert_eq!(shallow.hydrate(None), full.hydrate(None));
+        // Nothing from before the boundary was sent
+        assert_eq!(shallow.get_changes(&[]).len(), 3);
+
+        // A new peer gets a shallow copy
+        let mut empty = Automerge::new();
+        sync(&mut shallow, &mut empty);
+        assert!(empty.is_shallow());
+        assert_eq!(empty.get_heads(), full.get_heads());
+        assert_eq!(empty.hydrate(None), full.hydrate(None));
+    }
+
+    #[test]
+    fn fork_at_before_boundary_is_an_error() {
+        let (mut doc, heads) = history();
+        let first = doc.get_changes(&[])[0].hash();
+        let shallow = Automerge::load(&save_shallow(&mut doc, &heads)).unwrap();
+
+        assert!(matches!(
+            shallow.fork_at(&[first]),
+            Err(AutomergeError::InvalidHash(_))
+        ));
+        let forked = shallow.fork_at(&heads).unwrap();
+        assert_eq!(forked.hydrate(None), doc.document().hydrate(Some(&heads)));
+        assert_eq!(
+            shallow
+                .changes_between(&heads, &shallow.get_heads())
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // Once we know about an old change it is an error to ask for it
+        let mut shallow = shallow;
+        shallow
+            .apply_changes(vec![doc.get_changes(&[])[0].clone()])
+            .unwrap();
+        assert!(matches!(
+            shallow.fork_at(&[first]),
+            Err(AutomergeError::ShallowHistory(h)) if h == first
+        ));
+        assert!(matches!(
+            shallow.changes_between(&[], &heads),
+            Err(AutomergeError::ShallowHistory(_))
+        ));
+    }
+
+    #[test]
+    fn concurrent_changes_across_the_boundary() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "x", 0).unwrap();
+        doc.commit();
+        let mut other = doc.fork();
+        other.put(ROOT, "x", 2).unwrap();
+        other.commit();
+        doc.put(ROOT, "x", 1).unwrap();
+        doc.commit();
+        let heads = doc.get_heads();
+
+        // The concurrent change depends on a change before the boundary which the shallow
+        // document doesn't know about until it is sent
+        let mut shallow = AutoCommit::load(&save_shallow(&mut doc, &heads)).unwrap();
+        let concurrent = other.get_last_local_change().unwrap().clone();
+        shallow.apply_changes(vec![concurrent.clone()]).unwrap();
+        assert_eq!(shallow.get_heads(), heads);
+        assert_eq!(shallow.get_missing_deps(&[]), concurrent.deps().to_vec());
+        let base = doc
+            .get_change_by_hash(&concurrent.deps()[0])
+            .unwrap()
+            .clone();
+        shallow.apply_changes(vec![base]).unwrap();
+
+        doc.merge(&mut other).unwrap();
+        assert_eq!(shallow.get_heads(), doc.get_heads());
+        assert_eq!(
+            shallow.get_all(ROOT, "x").unwrap(),
+            doc.get_all(ROOT, "x").unwrap()
+        );
+
+        // Saving a document which has both branches at one of them keeps the other
+        let mut reloaded = AutoCommit::load(&save_shallow(&mut doc, &heads)).unwrap();
+        assert_eq!(reloaded.get_heads(), doc.get_heads());
+        assert_eq!(
+            reloaded.get_all(ROOT, "x").unwrap(),
+            doc.get_all(ROOT, "x").unwrap()
+        );
+    }
+}
@@ -2,6 +2,9 @@ use serde::ser::{SerializeMap, SerializeSeq};
 
 use crate::{ObjId, ObjType, ReadDoc, Value};
 
+mod de;
+pub use de::AutoDeserializer;
+
 /// A wrapper type which implements [`serde::Serialize`] for a [`ReadDoc`].
 ///
 /// # Example
@@ -0,0 +1,604 @@
+use serde::de::{self, IntoDeserializer, Visitor};
+
+use crate::error::DeserializeError;
+use crate::{ChangeHash, ObjId, ObjType, Prop, ReadDoc, ScalarValue, Value};
+
+/// A [`serde::Deserializer`] which reads values directly out of a [`ReadDoc`].
+///
+/// Maps, tables and lists are presented to serde as maps and sequences respectively. Text objects
+/// are presented as strings. Scalar values are presented as their natural serde counterparts:
+/// counters and timestamps become `i64`s and byte strings become byte buffers (which can also be
+/// deserialized as a sequence of `u8`). `null` is presented as a unit, or as `None` when
+/// deserializing an `Option`.
+///
+/// Enums are read using serde's "externally tagged" representation, i.e. a unit variant is a
+/// string and any other variant is a map with a single key naming the variant.
+///
+/// # Example
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use automerge::{AutoCommit, AutoDeserializer, ObjType, ScalarValue, transaction::Transactable};
+/// use serde::Deserialize;
+///
+/// #[derive(Deserialize, Debug, PartialEq)]
+/// struct Todo {
+///     title: String,
+///     done: bool,
+///     views: i64,
+/// }
+///
+/// let mut doc = AutoCommit::new();
+/// let todo = doc.put_object(automerge::ROOT, "todo", ObjType::Map)?;
+/// doc.put(&todo, "title", "water the plants")?;
+/// doc.put(&todo, "done", false)?;
+/// doc.put(&todo, "views", ScalarValue::counter(1))?;
+/// doc.increment(&todo, "views", 2)?;
+///
+/// let read = Todo::deserialize(AutoDeserializer::new(&doc).with_obj(todo))?;
+/// assert_eq!(
+///     read,
+///     Todo {
+///         title: "water the plants".to_string(),
+///         done: false,
+///         views: 3,
+///     }
+/// );
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct AutoDeserializer<'a, R> {
+    doc: &'a R,
+    heads: Option<&'a [ChangeHash]>,
+    obj: ObjId,
+}
+
+impl<'a, R: ReadDoc> AutoDeserializer<'a, R> {
+    /// Create a deserializer which reads the root map of `doc`
+    pub fn new(doc: &'a R) -> Self {
+        AutoDeserializer {
+            doc,
+            heads: None,
+            obj: ObjId::Root,
+        }
+    }
+
+    /// Read the document as at `heads` rather than the current state
+    pub fn at(mut self, heads: &'a [ChangeHash]) -> Self {
+        self.heads = Some(heads);
+        self
+    }
+
+    /// Read the object `obj` rather than the root map
+    pub fn with_obj<O: AsRef<ObjId>>(mut self, obj: O) -> Self {
+        self.obj = obj.as_ref().clone();
+        self
+    }
+
+    fn root(&self) -> Result<ValueDeserializer<'a, R>, DeserializeError> {
+        let ctx = Ctx {
+            doc: self.doc,
+            heads: self.heads,
+        };
+        let typ = match self.obj {
+            ObjId::Root => ObjType::Map,
+            _ => self
+                .doc
+                .object_type(&self.obj)
+                .map_err(|e| DeserializeError::from(e).with_path(&[]))?,
+        };
+        Ok(ValueDeserializer {
+            ctx,
+            path: Vec::new(),
+            node: Node::Object(self.obj.clone(), typ),
+        })
+    }
+}
+
+impl<'de, 'a, R: ReadDoc> de::Deserializer<'de> for AutoDeserializer<'a, R> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.root()?.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.root()?.deserialize_option(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.root()?.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.root()?.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.root()?.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// The document and heads which a tree of deserializers is reading from
+struct Ctx<'a, R> {
+    doc: &'a R,
+    heads: Option<&'a [ChangeHash]>,
+}
+
+impl<'a, R> Clone for Ctx<'a, R> {
+    fn clone(&self) -> Self {
+        Ctx {
+            doc: self.doc,
+            heads: self.heads,
+        }
+    }
+}
+
+impl<'a, R: ReadDoc> Ctx<'a, R> {
+    fn get(&self, obj: &ObjId, prop: Prop) -> Result<Option<Node>, crate::AutomergeError> {
+        let value = match self.heads {
+            Some(heads) => self.doc.get_at(obj, prop, heads)?,
+            None => self.doc.get(obj, prop)?,
+        };
+        Ok(value.map(|(value, id)| match value {
+            Value::Object(typ) => Node::Object(id, typ),
+            Value::Scalar(s) => Node::Scalar(s.into_owned()),
+        }))
+    }
+
+    fn keys(&self, obj: &ObjId) -> Vec<String> {
+        match self.heads {
+            Some(heads) => self.doc.keys_at(obj, heads).collect(),
+            None => self.doc.keys(obj).collect(),
+        }
+    }
+
+    fn length(&self, obj: &ObjId) -> usize {
+        match self.heads {
+            Some(heads) => self.doc.length_at(obj, heads),
+            None => self.doc.length(obj),
+        }
+    }
+
+    fn text(&self, obj: &ObjId) -> Result<String, crate::AutomergeError> {
+        match self.heads {
+            Some(heads) => self.doc.text_at(obj, heads),
+            None => self.doc.text(obj),
+        }
+    }
+}
+
+enum Node {
+    Object(ObjId, ObjType),
+    Scalar(ScalarValue),
+}
+
+/// Deserializes a single value at `path`
+struct ValueDeserializer<'a, R> {
+    ctx: Ctx<'a, R>,
+    path: Vec<Prop>,
+    node: Node,
+}
+
+impl<'a, R: ReadDoc> ValueDeserializer<'a, R> {
+    fn child(&self, obj: &ObjId, prop: Prop) -> Result<Self, DeserializeError> {
+        let mut path = self.path.clone();
+        path.push(prop.clone());
+        let node = self
+            .ctx
+            .get(obj, prop)
+            .map_err(|e| DeserializeError::from(e).with_path(&path))?
+            .ok_or_else(|| {
+                DeserializeError::custom_at("value disappeared while deserializing", &path)
+            })?;
+        Ok(ValueDeserializer {
+            ctx: self.ctx.clone(),
+            path,
+            node,
+        })
+    }
+
+    fn visit_any<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeserializeError> {
+        match self.node {
+            Node::Object(ref obj, ObjType::Map | ObjType::Table) => {
+                let keys = self.ctx.keys(obj).into_iter();
+                visitor.visit_map(MapAccess {
+                    obj: obj.clone(),
+                    parent: &self,
+                    keys,
+                    next: None,
+                })
+            }
+            Node::Object(ref obj, ObjType::List) => {
+                let len = self.ctx.length(obj);
+                visitor.visit_seq(SeqAccess {
+                    obj: obj.clone(),
+                    parent: &self,
+                    index: 0,
+                    len,
+                })
+            }
+            Node::Object(ref obj, ObjType::Text) => {
+                let text = self.ctx.text(obj)?;
+                visitor.visit_string(text)
+            }
+            Node::Scalar(ScalarValue::Bytes(b)) => visitor.visit_byte_buf(b),
+            Node::Scalar(ScalarValue::Str(s)) => visitor.visit_string(s.to_string()),
+            Node::Scalar(ScalarValue::Int(i)) => visitor.visit_i64(i),
+            Node::Scalar(ScalarValue::Uint(u)) => visitor.visit_u64(u),
+            Node::Scalar(ScalarValue::F64(f)) => visitor.visit_f64(f),
+            Node::Scalar(ScalarValue::Counter(c)) => visitor.visit_i64(i64::from(c)),
+            Node::Scalar(ScalarValue::Timestamp(t)) => visitor.visit_i64(t),
+            Node::Scalar(ScalarValue::Boolean(b)) => visitor.visit_bool(b),
+            Node::Scalar(ScalarValue::Null) => visitor.visit_unit(),
+            Node::Scalar(ScalarValue::Unknown { bytes, .. }) => visitor.visit_byte_buf(bytes),
+        }
+    }
+}
+
+impl<'de, 'a, R: ReadDoc> de::Deserializer<'de> for ValueDeserializer<'a, R> {
+    type Error = DeserializeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let path = self.path.clone();
+        self.visit_any(visitor).map_err(|e| e.with_path(&path))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.node {
+            Node::Scalar(ScalarValue::Null) => visitor.visit_none(),
+            _ => {
+                let path = self.path.clone();
+                visitor.visit_some(self).map_err(|e| e.with_path(&path))
+            }
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.node {
+            Node::Scalar(ScalarValue::Bytes(b)) => {
+                let mut bytes =
+                    de::value::SeqDeserializer::<_, DeserializeError>::new(b.into_iter());
+                let value = visitor
+                    .visit_seq(&mut bytes)
+                    .and_then(|v| bytes.end().map(|_| v));
+                value.map_err(|e| e.with_path(&self.path))
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let path = self.path.clone();
+        visitor
+            .visit_newtype_struct(self)
+            .map_err(|e| e.with_path(&path))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        let path = self.path.clone();
+        match &self.node {
+            Node::Scalar(ScalarValue::Str(s)) => visitor
+                .visit_enum(s.to_string().into_deserializer())
+                .map_err(|e: DeserializeError| e.with_path(&path)),
+            Node::Object(obj, ObjType::Map | ObjType::Table) => {
+                let mut keys = self.ctx.keys(obj).into_iter();
+                match (keys.next(), keys.next()) {
+                    (Some(variant), None) => {
+                        let value = self.child(obj, Prop::Map(variant.clone()))?;
+                        visitor
+                            .visit_enum(EnumAccess { variant, value })
+                            .map_err(|e| e.with_path(&path))
+                    }
+                    _ => Err(DeserializeError::custom_at(
+                        "expected a map with a single key naming the enum variant",
+                        &path,
+                    )),
+                }
+            }
+            _ => Err(DeserializeError::custom_at(
+                "expected a string or a map with a single key for an enum",
+                &path,
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct MapAccess<'p, 'a, R> {
+    obj: ObjId,
+    parent: &'p ValueDeserializer<'a, R>,
+    keys: std::vec::IntoIter<String>,
+    next: Option<String>,
+}
+
+impl<'de, 'p, 'a, R: ReadDoc> de::MapAccess<'de> for MapAccess<'p, 'a, R> {
+    type Error = DeserializeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.keys.next() {
+            Some(key) => {
+                self.next = Some(key.clone());
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let key = self
+            .next
+            .take()
+            .ok_or_else(|| DeserializeError::custom_at("value requested before key", &[]))?;
+        seed.deserialize(self.parent.child(&self.obj, Prop::Map(key))?)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.keys.len())
+    }
+}
+
+struct SeqAccess<'p, 'a, R> {
+    obj: ObjId,
+    parent: &'p ValueDeserializer<'a, R>,
+    index: usize,
+    len: usize,
+}
+
+impl<'de, 'p, 'a, R: ReadDoc> de::SeqAccess<'de> for SeqAccess<'p, 'a, R> {
+    type Error = DeserializeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        if self.index >= self.len {
+            return Ok(None);
+        }
+        let value = self.parent.child(&self.obj, Prop::Seq(self.index))?;
+        self.index += 1;
+        seed.deserialize(value).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.index)
+    }
+}
+
+struct EnumAccess<'a, R> {
+    variant: String,
+    value: ValueDeserializer<'a, R>,
+}
+
+impl<'de, 'a, R: ReadDoc> de::EnumAccess<'de> for EnumAccess<'a, R> {
+    type Error = DeserializeError;
+    type Variant = ValueDeserializer<'a, R>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let variant: de::value::StringDeserializer<DeserializeError> =
+            self.variant.into_deserializer();
+        let variant = seed.deserialize(variant)?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de, 'a, R: ReadDoc> de::VariantAccess<'de> for ValueDeserializer<'a, R> {
+    type Error = DeserializeError;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        <() as de::Deserialize>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::AutoDeserializer;
+    use crate::{transaction::Transactable, AutoCommit, ObjType, Prop, ReadDoc, ScalarValue, ROOT};
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Status {
+        Todo,
+        Done { at: i64 },
+        Blocked(String),
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Task {
+        title: String,
+        notes: String,
+        status: Status,
+        views: i64,
+        attachment: Vec<u8>,
+        assignee: Option<String>,
+        tags: Vec<String>,
+    }
+
+    fn task_doc() -> AutoCommit {
+        let mut doc = AutoCommit::new();
+        let task = doc.put_object(ROOT, "task", ObjType::Map).unwrap();
+        doc.put(&task, "title", "write docs").unwrap();
+        let notes = doc.put_object(&task, "notes", ObjType::Text).unwrap();
+        doc.splice_text(&notes, 0, 0, "some notes").unwrap();
+        let status = doc.put_object(&task, "status", ObjType::Map).unwrap();
+        let done = doc.put_object(&status, "Done", ObjType::Map).unwrap();
+        doc.put(&done, "at", ScalarValue::Timestamp(1000)).unwrap();
+        doc.put(&task, "views", ScalarValue::counter(1)).unwrap();
+        doc.increment(&task, "views", 4).unwrap();
+        doc.put(&task, "attachment", vec![1_u8, 2, 3]).unwrap();
+        doc.put(&task, "assignee", ()).unwrap();
+        let tags = doc.put_object(&task, "tags", ObjType::List).unwrap();
+        doc.insert(&tags, 0, "docs").unwrap();
+        doc.insert(&tags, 1, "easy").unwrap();
+        doc
+    }
+
+    #[test]
+    fn deserialize_struct_from_root() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Root {
+            task: Task,
+        }
+        let doc = task_doc();
+        let root = Root::deserialize(AutoDeserializer::new(&doc)).unwrap();
+        assert_eq!(
+            root.task,
+            Task {
+                title: "write docs".to_string(),
+                notes: "some notes".to_string(),
+                status: Status::Done { at: 1000 },
+                views: 5,
+                attachment: vec![1, 2, 3],
+                assignee: None,
+                tags: vec!["docs".to_string(), "easy".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn deserialize_from_object_and_heads() {
+        let mut doc = task_doc();
+        let heads = doc.get_heads();
+        let (_, task) = doc.get(ROOT, "task").unwrap().unwrap();
+        doc.put(&task, "title", "write more docs").unwrap();
+        doc.put(&task, "status", "Todo").unwrap();
+
+        let now = Task::deserialize(AutoDeserializer::new(&doc).with_obj(&task)).unwrap();
+        assert_eq!(now.title, "write more docs");
+        assert_eq!(now.status, Status::Todo);
+
+        let then =
+            Task::deserialize(AutoDeserializer::new(&doc).with_obj(&task).at(&heads)).unwrap();
+        assert_eq!(then.title, "write docs");
+        assert_eq!(then.status, Status::Done { at: 1000 });
+    }
+
+    #[test]
+    fn deserialize_newtype_variant_and_maps() {
+        let mut doc = AutoCommit::new();
+        let status = doc.put_object(ROOT, "status", ObjType::Map).unwrap();
+        doc.put(&status, "Blocked", "waiting on review").unwrap();
+        doc.put(ROOT, "count", 3_u64).unwrap();
+
+        #[derive(Debug, Deserialize)]
+        struct Root {
+            status: Status,
+        }
+        let root = Root::deserialize(AutoDeserializer::new(&doc)).unwrap();
+        assert_eq!(
+            root.status,
+            Status::Blocked("waiting on review".to_string())
+        );
+
+        let raw =
+            HashMap::<String, serde_json::Value>::deserialize(AutoDeserializer::new(&doc)).unwrap();
+        assert_eq!(raw["count"], serde_json::json!(3));
+        assert_eq!(
+            raw["status"],
+            serde_json::json!({"Blocked": "waiting on review"})
+        );
+    }
+
+    #[test]
+    fn errors_report_the_path() {
+        let mut doc = task_doc();
+        let (_, task) = doc.get(ROOT, "task").unwrap().unwrap();
+        let (_, tags) = doc.get(&task, "tags").unwrap().unwrap();
+        doc.put(&tags, 1, 42).unwrap();
+
+        #[derive(Debug, Deserialize)]
+        struct Root {
+            #[allow(dead_code)]
+            task: Task,
+        }
+        let err = Root::deserialize(AutoDeserializer::new(&doc)).unwrap_err();
+        assert_eq!(
+            err.path(),
+            &[
+                Prop::Map("task".to_string()),
+                Prop::Map("tags".to_string()),
+                Prop::Seq(1)
+            ]
+        );
+        assert!(err.to_string().starts_with("/task/tags/1: "), "{}", err);
+    }
+
+    #[test]
+    fn missing_fields_report_the_containing_object() {
+        let mut doc = AutoCommit::new();
+        doc.put_object(ROOT, "task", ObjType::Map).unwrap();
+
+        #[derive(Debug, Deserialize)]
+        struct Root {
+            #[allow(dead_code)]
+            task: Task,
+        }
+        let err = Root::deserialize(AutoDeserializer::new(&doc)).unwrap_err();
+        assert_eq!(err.path(), &[Prop::Map("task".to_string())]);
+    }
+}
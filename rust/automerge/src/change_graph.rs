@@ -17,6 +17,9 @@ pub(crate) struct ChangeGraph {
     hashes: Vec<ChangeHash>,
     nodes_by_hash: BTreeMap<ChangeHash, NodeIdx>,
     clock_cache: Vec<Clock>,
+    /// The clocks of changes on the boundary of a shallow document, whose ancestors are not in
+    /// the graph
+    boundary_clocks: BTreeMap<NodeIdx, Clock>,
 }
 
 const CACHE_STEP: u32 = 32;
@@ -55,6 +58,7 @@ impl ChangeGraph {
             nodes_by_hash: BTreeMap::new(),
             hashes: Vec::new(),
             clock_cache: Vec::new(),
+            boundary_clocks: BTreeMap::new(),
         }
     }
 
@@ -85,6 +89,49 @@ impl ChangeGraph {
         Ok(())
     }
 
+    /// Add a change whose ancestors we don't have, along with the clock of its history
+    pub(crate) fn add_boundary(
+        &mut self,
+        hash: ChangeHash,
+        actor_index: usize,
+        data: ClockData,
+        clock: Clock,
+    ) {
+        if self.nodes_by_hash.contains_key(&hash) {
+            return;
+        }
+        let node_idx = NodeIdx(self.nodes.len() as u32);
+        let hash_idx = self.add_hash(hash);
+        self.nodes.push(ChangeNode {
+            hash_idx,
+            actor_index,
+            seq: data.seq,
+            max_op: data.max_op,
+            parents: None,
+        });
+        self.nodes_by_hash.insert(hash, node_idx);
+        self.boundary_clocks.insert(node_idx, clock);
+        if let Some(cached_idx) = Self::node_to_cache(&node_idx, CACHE_STEP) {
+            assert_eq!(cached_idx, self.clock_cache.len());
+            let clock = self.calculate_clock(vec![node_idx]);
+            self.clock_cache.push(clock)
+        }
+    }
+
+    /// The actor index, seq and max op of the change with hash `hash`
+    pub(crate) fn change_data(&self, hash: &ChangeHash) -> Option<(usize, ClockData)> {
+        self.nodes_by_hash.get(hash).map(|idx| {
+            let node = &self.nodes[idx.0 as usize];
+            (
+                node.actor_index,
+                ClockData {
+                    max_op: node.max_op,
+                    seq: node.seq,
+                },
+            )
+        })
+    }
+
     fn add_node(&mut self, actor_index: usize, change: &Change) -> NodeIdx {
         let idx = NodeIdx(self.nodes.len() as u32);
         let hash_idx = self.add_hash(change.hash());
@@ -171,6 +218,9 @@ impl ChangeGraph {
                     seq: node.seq,
                 },
             );
+            if let Some(boundary_clock) = self.boundary_clocks.get(&idx) {
+                clock = Clock::merge(&clock, boundary_clock);
+            }
             if let Some(cached_idx) = Self::node_to_cache(&idx, CACHE_STEP) {
                 if cached_idx < self.clock_cache.len() {
                     let ancestor_clock = &self.clock_cache[cached_idx];
@@ -197,6 +247,92 @@ impl ChangeGraph {
         });
     }
 
+    /// How `a` is related to `b`, see [`crate::Automerge::compare_heads`]
+    pub(crate) fn compare(&self, a: &[ChangeHash], b: &[ChangeHash]) -> HeadsOrdering {
+        let a_nodes = self.heads_to_nodes(a);
+        let b_nodes = self.heads_to_nodes(b);
+        let a_clock = self.calculate_clock(a_nodes.clone());
+        let b_clock = self.calculate_clock(b_nodes.clone());
+        let a_in_b = a_nodes
+            .iter()
+            .all(|idx| self.clock_includes(&b_clock, *idx));
+        let b_in_a = b_nodes
+            .iter()
+            .all(|idx| self.clock_includes(&a_clock, *idx));
+        match (a_in_b, b_in_a) {
+            (true, true) => HeadsOrdering::Equal,
+            (true, false) => HeadsOrdering::Ancestor,
+            (false, true) => HeadsOrdering::Descendant,
+            (false, false) => HeadsOrdering::Concurrent,
+        }
+    }
+
+    /// The lowest common ancestors of `a` and `b`, sorted by hash
+    pub(crate) fn merge_base(&self, a: &[ChangeHash], b: &[ChangeHash]) -> Vec<ChangeHash> {
+        let b_clock = self.calculate_clock(self.heads_to_nodes(b));
+        // The first common ancestors on each path from `a`, some of which may be ancestors of
+        // others
+        let mut common = BTreeSet::new();
+        self.traverse_ancestors(self.heads_to_nodes(a), |_node, idx| {
+            if self.clock_includes(&b_clock, idx) {
+                common.insert(idx);
+                false
+            } else {
+                true
+            }
+        });
+        // Nodes are only ever added after their parents so once we are past the oldest candidate
+        // there is nothing left to remove
+        let parents = common.iter().flat_map(|idx| self.parents(*idx)).collect();
+        self.traverse_ancestors(parents, |_node, idx| match common.first() {
+            Some(oldest) if idx >= *oldest => {
+                common.remove(&idx);
+                true
+            }
+            _ => false,
+        });
+        let mut hashes = common
+            .into_iter()
+            .map(|idx| self.hash(idx))
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes
+    }
+
+    /// The changes which are ancestors of `to` but not of `from`, parents before children
+    pub(crate) fn changes_between(
+        &self,
+        from: &[ChangeHash],
+        to: &[ChangeHash],
+    ) -> Vec<ChangeHash> {
+        let from_clock = self.calculate_clock(self.heads_to_nodes(from));
+        let mut nodes = Vec::new();
+        self.traverse_ancestors(self.heads_to_nodes(to), |_node, idx| {
+            if self.clock_includes(&from_clock, idx) {
+                false
+            } else {
+                nodes.push(idx);
+                true
+            }
+        });
+        // Nodes are only ever added after their parents so index order is a topological order
+        nodes.sort_unstable();
+        nodes.into_iter().map(|idx| self.hash(idx)).collect()
+    }
+
+    fn hash(&self, idx: NodeIdx) -> ChangeHash {
+        self.hashes[self.nodes[idx.0 as usize].hash_idx.0 as usize]
+    }
+
+    /// Whether the node `idx` is in the history described by `clock`
+    fn clock_includes(&self, clock: &Clock, idx: NodeIdx) -> bool {
+        let node = &self.nodes[idx.0 as usize];
+        clock
+            .get_for_actor(&node.actor_index)
+            .map(|data| data.seq >= node.seq)
+            .unwrap_or(false)
+    }
+
     /// Call `f` for each (node, hash) in the graph, starting from the given heads
     ///
     /// No guarantees are made about the order of traversal but each node will only be visited
@@ -222,6 +358,19 @@ impl ChangeGraph {
     }
 }
 
+/// How one set of heads is related to another, see [`crate::Automerge::compare_heads`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadsOrdering {
+    /// The two sets of heads describe the same history
+    Equal,
+    /// Every change in the first history is also in the second, which has more
+    Ancestor,
+    /// Every change in the second history is also in the first, which has more
+    Descendant,
+    /// Each history has changes which the other does not
+    Concurrent,
+}
+
 #[derive(Debug, thiserror::Error)]
 #[error("attempted to derive a clock for a change with dependencies we don't have")]
 pub struct MissingDep(ChangeHash);
@@ -287,6 +436,79 @@ mod tests {
         assert_eq!(changes, expected_changes);
     }
 
+    #[test]
+    fn compare_heads() {
+        let mut builder = TestGraphBuilder::new();
+        let actor1 = builder.actor();
+        let actor2 = builder.actor();
+        let change1 = builder.change(&actor1, 10, &[]);
+        let change2 = builder.change(&actor1, 10, &[change1]);
+        let change3 = builder.change(&actor2, 10, &[change1]);
+        let change4 = builder.change(&actor2, 10, &[change2, change3]);
+        let graph = builder.build();
+
+        assert_eq!(graph.compare(&[change2], &[change2]), HeadsOrdering::Equal);
+        assert_eq!(
+            graph.compare(&[change2, change3], &[change4]),
+            HeadsOrdering::Ancestor
+        );
+        assert_eq!(
+            graph.compare(&[change4], &[change1]),
+            HeadsOrdering::Descendant
+        );
+        assert_eq!(
+            graph.compare(&[change2], &[change3]),
+            HeadsOrdering::Concurrent
+        );
+        assert_eq!(graph.compare(&[], &[change1]), HeadsOrdering::Ancestor);
+    }
+
+    #[test]
+    fn merge_base() {
+        // A criss-cross merge, change4 and change5 both merge change2 and change3 so they are
+        // both lowest common ancestors of change6 and change7
+        let mut builder = TestGraphBuilder::new();
+        let actor1 = builder.actor();
+        let actor2 = builder.actor();
+        let change1 = builder.change(&actor1, 10, &[]);
+        let change2 = builder.change(&actor1, 10, &[change1]);
+        let change3 = builder.change(&actor2, 10, &[change1]);
+        let change4 = builder.change(&actor1, 10, &[change2, change3]);
+        let change5 = builder.change(&actor2, 10, &[change2, change3]);
+        let change6 = builder.change(&actor1, 10, &[change4, change5]);
+        let change7 = builder.change(&actor2, 10, &[change4, change5]);
+        let graph = builder.build();
+
+        assert_eq!(graph.merge_base(&[change2], &[change3]), vec![change1]);
+        assert_eq!(graph.merge_base(&[change4], &[change2]), vec![change2]);
+        let mut expected = vec![change4, change5];
+        expected.sort();
+        assert_eq!(graph.merge_base(&[change6], &[change7]), expected);
+        assert_eq!(graph.merge_base(&[change6], &[]), Vec::<ChangeHash>::new());
+    }
+
+    #[test]
+    fn changes_between() {
+        let mut builder = TestGraphBuilder::new();
+        let actor1 = builder.actor();
+        let actor2 = builder.actor();
+        let change1 = builder.change(&actor1, 10, &[]);
+        let change2 = builder.change(&actor1, 10, &[change1]);
+        let change3 = builder.change(&actor2, 10, &[change1]);
+        let change4 = builder.change(&actor2, 10, &[change2, change3]);
+        let graph = builder.build();
+
+        assert_eq!(
+            graph.changes_between(&[], &[change4]),
+            vec![change1, change2, change3, change4]
+        );
+        assert_eq!(
+            graph.changes_between(&[change2], &[change4]),
+            vec![change3, change4]
+        );
+        assert!(graph.changes_between(&[change4], &[change3]).is_empty());
+    }
+
     struct TestGraphBuilder {
         actors: Vec<ActorId>,
         changes: Vec<Change>,
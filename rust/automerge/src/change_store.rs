@@ -0,0 +1,447 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::change_graph::ChangeGraph;
+use crate::indexed_cache::IndexedCache;
+use crate::patches::PatchLog;
+use crate::storage::{self, load};
+use crate::sync::{self, ChangeHistory, SyncDoc};
+use crate::{ActorId, AutomergeError, Change, ChangeHash};
+
+/// The changes of a document without the document itself
+///
+/// A sync server which only relays and stores changes for its clients has no need to know what
+/// the document they make up looks like, but materialising the document with
+/// [`crate::Automerge`] costs memory proportional to the size of the document. A `ChangeStore`
+/// only keeps the changes, the graph of dependencies between them and the current heads. This is
+/// enough to implement [`SyncDoc`], so a server can sync with many clients using a `ChangeStore`
+/// per document and the clients will not be able to tell the difference.
+///
+/// A `ChangeStore` can be saved and loaded using the same formats as [`crate::Automerge`].
+/// [`Self::save()`] produces a sequence of change chunks, which [`crate::Automerge::load()`]
+/// can load. [`Self::load()`] accepts both change chunks and document chunks, though loading a
+/// document chunk requires temporarily reconstructing its ops in order to recover the changes it
+/// contains.
+///
+/// ## Example
+///
+/// ```
+/// use automerge::{sync::{self, SyncDoc}, transaction::Transactable, AutoCommit, ChangeStore, ReadDoc};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut client = AutoCommit::new();
+/// client.put(automerge::ROOT, "key", "value")?;
+/// let mut server = ChangeStore::new();
+///
+/// let mut client_state = sync::State::new();
+/// let mut server_state = sync::State::new();
+/// loop {
+///     let to_server = client.sync().generate_sync_message(&mut client_state);
+///     if let Some(message) = to_server.clone() {
+///         server.receive_sync_message(&mut server_state, message)?;
+///     }
+///     let to_client = server.generate_sync_message(&mut server_state);
+///     if let Some(message) = to_client.clone() {
+///         client.sync().receive_sync_message(&mut client_state, message)?;
+///     }
+///     if to_server.is_none() && to_client.is_none() {
+///         break;
+///     }
+/// }
+/// assert_eq!(server.get_heads(), client.get_heads());
+///
+/// // The stored changes can be loaded as a document
+/// let doc = automerge::Automerge::load(&server.save())?;
+/// assert_eq!(doc.get(automerge::ROOT, "key")?.unwrap().0.to_str(), Some("value"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChangeStore {
+    /// The list of changes whose dependencies we don't have yet
+    queue: Vec<Change>,
+    /// The changes we have, topologically sorted
+    history: Vec<Change>,
+    /// Mapping from change hash to index into the history list
+    history_index: HashMap<ChangeHash, usize>,
+    change_graph: ChangeGraph,
+    actors: IndexedCache<ActorId>,
+    /// Mapping from actor index to the indexes in `history` of their changes
+    states: HashMap<usize, Vec<usize>>,
+    /// The current heads
+    deps: HashSet<ChangeHash>,
+}
+
+impl Default for ChangeStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangeStore {
+    pub fn new() -> Self {
+        ChangeStore {
+            queue: Vec::new(),
+            history: Vec::new(),
+            history_index: HashMap::new(),
+            change_graph: ChangeGraph::new(),
+            actors: IndexedCache::new(),
+            states: HashMap::new(),
+            deps: HashSet::new(),
+        }
+    }
+
+    /// Load the changes in `data`, which can be the output of [`Self::save()`] or of any of the
+    /// save methods of [`crate::Automerge`]
+    pub fn load(data: &[u8]) -> Result<Self, AutomergeError> {
+        let mut store = Self::new();
+        store.load_incremental(data)?;
+        Ok(store)
+    }
+
+    /// Load the changes in `data` into this store
+    ///
+    /// Returns the number of changes which were added.
+    pub fn load_incremental(&mut self, data: &[u8]) -> Result<usize, AutomergeError> {
+        let changes = match load::load_changes(storage::parse::Input::new(data)) {
+            load::LoadedChanges::Complete(c) => c,
+            load::LoadedChanges::Partial { error, .. } => return Err(error.into()),
+        };
+        let before = self.history.len();
+        self.apply_changes(changes)?;
+        Ok(self.history.len() - before)
+    }
+
+    /// Add changes to this store
+    ///
+    /// Changes we already have are ignored. Changes whose dependencies we don't have yet are held
+    /// until the dependencies arrive.
+    pub fn apply_changes(
+        &mut self,
+        changes: impl IntoIterator<Item = Change>,
+    ) -> Result<(), AutomergeError> {
+        for change in changes {
+            if self.history_index.contains_key(&change.hash()) {
+                continue;
+            }
+            if self.duplicate_seq(&change) {
+                return Err(AutomergeError::DuplicateSeqNumber(
+                    change.seq(),
+                    change.actor_id().clone(),
+                ));
+            }
+            if self.is_causally_ready(&change) {
+                self.add_change(change);
+            } else {
+                self.queue.push(change);
+            }
+        }
+        while let Some(index) = self
+            .queue
+            .iter()
+            .position(|change| self.is_causally_ready(change))
+        {
+            let change = self.queue.swap_remove(index);
+            if !self.history_index.contains_key(&change.hash()) {
+                self.add_change(change);
+            }
+        }
+        Ok(())
+    }
+
+    /// All the changes in this store as a sequence of change chunks
+    pub fn save(&self) -> Vec<u8> {
+        self.save_after(&[])
+    }
+
+    /// The changes in this store which are not ancestors of `heads`, as a sequence of change
+    /// chunks
+    pub fn save_after(&self, heads: &[ChangeHash]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for change in self.get_changes(heads) {
+            bytes.extend(change.raw_bytes());
+        }
+        bytes
+    }
+
+    pub fn get_heads(&self) -> Vec<ChangeHash> {
+        let mut deps = self.deps.iter().copied().collect::<Vec<_>>();
+        deps.sort_unstable();
+        deps
+    }
+
+    /// The changes which are not ancestors of `have_deps`, in topological order
+    pub fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        let clock = self.change_graph.clock_for_heads(have_deps);
+        let mut change_indexes: Vec<usize> = Vec::new();
+        for (actor_index, actor_changes) in &self.states {
+            if let Some(clock_data) = clock.get_for_actor(actor_index) {
+                change_indexes.extend(&actor_changes[clock_data.seq as usize..]);
+            } else {
+                change_indexes.extend(&actor_changes[..]);
+            }
+        }
+        change_indexes.sort_unstable();
+        change_indexes
+            .into_iter()
+            .map(|i| &self.history[i])
+            .collect()
+    }
+
+    pub fn get_change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        self.history_index
+            .get(hash)
+            .and_then(|index| self.history.get(*index))
+    }
+
+    /// The hashes of changes we need in order to have all of `heads` and to apply the changes we
+    /// are holding on to because their dependencies are missing
+    pub fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        let in_queue = self
+            .queue
+            .iter()
+            .map(|change| change.hash())
+            .collect::<HashSet<_>>();
+        let mut missing = self
+            .queue
+            .iter()
+            .flat_map(|change| change.deps())
+            .chain(heads)
+            .filter(|hash| !self.history_index.contains_key(hash) && !in_queue.contains(hash))
+            .copied()
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        missing
+    }
+
+    /// Like [`SyncDoc::generate_sync_message()`] but only sync `heads` and their ancestors, see
+    /// [`crate::Automerge::generate_sync_message_at()`]
+    pub fn generate_sync_message_at(
+        &self,
+        sync_state: &mut sync::State,
+        heads: &[ChangeHash],
+    ) -> Result<Option<sync::Message>, AutomergeError> {
+        self.generate_sync_message_at_inner(sync_state, heads)
+    }
+
+    /// The number of changes in this store, not counting ones whose dependencies are missing
+    pub fn len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Whether there are no changes in this store, not counting ones whose dependencies are
+    /// missing
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of changes being held because their dependencies are missing
+    pub fn queued_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    fn duplicate_seq(&self, change: &Change) -> bool {
+        self.actors
+            .lookup(change.actor_id())
+            .and_then(|actor| self.states.get(&actor))
+            .map(|changes| changes.len() >= change.seq() as usize)
+            .unwrap_or(false)
+    }
+
+    fn is_causally_ready(&self, change: &Change) -> bool {
+        change
+            .deps()
+            .iter()
+            .all(|dep| self.history_index.contains_key(dep))
+    }
+
+    fn add_change(&mut self, change: Change) {
+        for dep in change.deps() {
+            self.deps.remove(dep);
+        }
+        self.deps.insert(change.hash());
+
+        let history_index = self.history.len();
+        let actor_index = self.actors.cache(change.actor_id().clone());
+        self.states
+            .entry(actor_index)
+            .or_default()
+            .push(history_index);
+        self.history_index.insert(change.hash(), history_index);
+        self.change_graph
+            .add_change(&change, actor_index)
+            .expect("Change's deps should already be in the store");
+        self.history.push(change);
+    }
+}
+
+impl ChangeHistory for ChangeStore {
+    fn get_heads(&self) -> Vec<ChangeHash> {
+        ChangeStore::get_heads(self)
+    }
+
+    fn missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        self.get_missing_deps(heads)
+    }
+
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        ChangeStore::get_changes(self, have_deps)
+    }
+
+    fn change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        self.get_change_by_hash(hash)
+    }
+
+    fn change_index_for_hash(&self, hash: &ChangeHash) -> Option<usize> {
+        self.history_index.get(hash).copied()
+    }
+
+    fn filter_changes(
+        &self,
+        heads: &[ChangeHash],
+        changes: &mut BTreeSet<ChangeHash>,
+    ) -> Result<(), AutomergeError> {
+        let heads = heads
+            .iter()
+            .filter(|hash| self.history_index.contains_key(hash))
+            .copied()
+            .collect::<Vec<_>>();
+        self.change_graph.remove_ancestors(changes, &heads);
+        Ok(())
+    }
+
+    fn save_document(&self) -> Option<Vec<u8>> {
+        // Encoding a document chunk requires the ops in document order, which we don't have
+        None
+    }
+}
+
+impl SyncDoc for ChangeStore {
+    fn generate_sync_message(&self, sync_state: &mut sync::State) -> Option<sync::Message> {
+        self.generate_sync_message_inner(sync_state, None)
+    }
+
+    fn generate_sync_message_with_limit(
+        &self,
+        sync_state: &mut sync::State,
+        max_size: usize,
+    ) -> Option<sync::Message> {
+        self.generate_sync_message_inner(sync_state, Some(max_size))
+    }
+
+    fn receive_sync_message(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<(), AutomergeError> {
+        self.receive_sync_message_with(sync_state, message, |store, changes, _| {
+            for chunk in changes.iter() {
+                store.load_incremental(chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// A `ChangeStore` has no document state for changes to be logged against, so this is the
+    /// same as [`Self::receive_sync_message()`] and `patch_log` is left untouched
+    fn receive_sync_message_log_patches(
+        &mut self,
+        sync_state: &mut sync::State,
+        message: sync::Message,
+        _patch_log: &mut PatchLog,
+    ) -> Result<(), AutomergeError> {
+        self.receive_sync_message(sync_state, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, ReadDoc, ROOT};
+
+    fn sync(a: &mut AutoCommit, b: &mut ChangeStore) {
+        let mut a_state = sync::State::new();
+        let mut b_state = sync::State::new();
+        for _ in 0..10 {
+            let a_to_b = a.sync().generate_sync_message(&mut a_state);
+            if let Some(message) = a_to_b.clone() {
+                let message = sync::Message::decode(&message.encode()).unwrap();
+                b.receive_sync_message(&mut b_state, message).unwrap();
+            }
+            let b_to_a = b.generate_sync_message(&mut b_state);
+            if let Some(message) = b_to_a.clone() {
+                let message = sync::Message::decode(&message.encode()).unwrap();
+                a.sync()
+                    .receive_sync_message(&mut a_state, message)
+                    .unwrap();
+            }
+            if a_to_b.is_none() && b_to_a.is_none() {
+                return;
+            }
+        }
+        panic!("failed to sync in 10 rounds");
+    }
+
+    #[test]
+    fn relays_changes_between_clients() {
+        let mut server = ChangeStore::new();
+        let mut alice = AutoCommit::new();
+        let mut bob = AutoCommit::new();
+        for i in 0..10 {
+            alice.put(ROOT, format!("alice {}", i), i).unwrap();
+            alice.commit();
+            bob.put(ROOT, format!("bob {}", i), i).unwrap();
+            bob.commit();
+        }
+
+        sync(&mut alice, &mut server);
+        assert_eq!(server.get_heads(), alice.get_heads());
+        sync(&mut bob, &mut server);
+        sync(&mut alice, &mut server);
+
+        assert_eq!(alice.get_heads(), bob.get_heads());
+        assert_eq!(server.get_heads(), bob.get_heads());
+        assert_eq!(server.len(), 20);
+        assert_eq!(alice.get(ROOT, "bob 9").unwrap().unwrap().0, 9_i64.into());
+
+        // A new client which has lost all its data gets everything from the server
+        let mut carol = AutoCommit::new();
+        sync(&mut carol, &mut server);
+        assert_eq!(carol.get_heads(), server.get_heads());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.commit();
+        let heads = doc.get_heads();
+        doc.put(ROOT, "b", 2).unwrap();
+        doc.commit();
+
+        // from a document chunk
+        let store = ChangeStore::load(&doc.save()).unwrap();
+        assert_eq!(store.get_heads(), doc.get_heads());
+        assert_eq!(store.len(), 2);
+
+        // to change chunks
+        let mut loaded = AutoCommit::load(&store.save()).unwrap();
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+        assert_eq!(loaded.get(ROOT, "b").unwrap().unwrap().0, 2_i64.into());
+
+        let mut incremental = ChangeStore::load(&doc.save_after(&heads)).unwrap();
+        assert!(incremental.get_heads().is_empty());
+        assert!(incremental.is_empty());
+        assert_eq!(incremental.queued_len(), 1);
+        assert_eq!(incremental.get_missing_deps(&[]), heads);
+        assert_eq!(
+            incremental
+                .load_incremental(&store.save_after(&[]))
+                .unwrap(),
+            2
+        );
+        assert_eq!(incremental.get_heads(), doc.get_heads());
+        assert_eq!((incremental.len(), incremental.queued_len()), (2, 0));
+        assert_eq!(store.save_after(&heads), doc.save_after(&heads));
+    }
+}
@@ -101,6 +101,10 @@ impl Clock {
         self.0.get(actor_index)
     }
 
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&usize, &ClockData)> {
+        self.0.iter()
+    }
+
     fn is_greater(&self, other: &Self) -> bool {
         let mut has_greater = false;
 
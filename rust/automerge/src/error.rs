@@ -1,7 +1,7 @@
 use crate::storage::load::Error as LoadError;
 use crate::types::{ActorId, ScalarValue};
 use crate::value::DataType;
-use crate::{ChangeHash, Cursor, LoadChangeError, ObjType, PatchAction};
+use crate::{ChangeHash, Cursor, LoadChangeError, ObjType, PatchAction, Prop};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -55,6 +55,8 @@ pub enum AutomergeError {
     NonChangeCompressed,
     #[error("id was not an object id")]
     NotAnObject,
+    #[error("change {0} is before the boundary of this shallow document")]
+    ShallowHistory(ChangeHash),
     #[error(transparent)]
     HydrateError(#[from] HydrateError),
 }
@@ -135,6 +137,228 @@ pub enum HydrateError {
 pub enum UpdateObjectError {
     #[error("cannot change object type")]
     ChangeType,
+    #[error("this list matching is not supported")]
+    UnsupportedListMatching,
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+}
+
+/// An error produced by [`crate::AutoDeserializer`]
+///
+/// The error records the path from the object being deserialized to the value which could not be
+/// deserialized.
+#[derive(Error, Debug)]
+#[error("{}: {reason}", display_path(.path))]
+pub struct DeserializeError {
+    path: Vec<Prop>,
+    has_path: bool,
+    reason: String,
+}
+
+impl DeserializeError {
+    /// The path to the value which failed to deserialize
+    pub fn path(&self) -> &[Prop] {
+        &self.path
+    }
+
+    /// The reason deserialization failed, without the path
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub(crate) fn custom_at<T: std::fmt::Display>(reason: T, path: &[Prop]) -> Self {
+        DeserializeError {
+            path: path.to_vec(),
+            has_path: true,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Set the path of this error if it does not already have one
+    pub(crate) fn with_path(mut self, path: &[Prop]) -> Self {
+        if !self.has_path {
+            self.path = path.to_vec();
+            self.has_path = true;
+        }
+        self
+    }
+}
+
+impl serde::de::Error for DeserializeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        DeserializeError {
+            path: Vec::new(),
+            has_path: false,
+            reason: msg.to_string(),
+        }
+    }
+}
+
+impl From<AutomergeError> for DeserializeError {
+    fn from(err: AutomergeError) -> Self {
+        serde::de::Error::custom(err)
+    }
+}
+
+fn display_path(path: &[Prop]) -> String {
+    if path.is_empty() {
+        return "/".to_string();
+    }
+    path.iter().map(|p| format!("/{}", p)).collect()
+}
+
+/// An error produced while reconciling a value with a document, see [`crate::reconcile`]
+#[derive(Error, Debug)]
+pub enum ReconcileError {
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+    #[error("the root of a document can only be reconciled with a map")]
+    RootNotMap,
+}
+
+/// An error produced while hydrating a value from a document, see [`crate::reconcile`]
+#[derive(Error, Debug)]
+#[error("{}: {kind}", display_path(.path))]
+pub struct HydrateTypedError {
+    path: Vec<Prop>,
+    kind: Box<HydrateTypedErrorKind>,
+}
+
+#[derive(Error, Debug)]
+enum HydrateTypedErrorKind {
+    #[error(transparent)]
+    Automerge(AutomergeError),
+    #[error("expected {expected} but found {found}")]
+    Unexpected {
+        expected: &'static str,
+        found: String,
+    },
+    #[error("{0}")]
+    Custom(String),
+}
+
+impl HydrateTypedError {
+    /// An error for a value which was not of the `expected` type. `found` is `None` if there was
+    /// no value at all
+    pub fn unexpected(expected: &'static str, found: Option<crate::Value<'_>>) -> Self {
+        let found = match found {
+            None => "nothing".to_string(),
+            Some(crate::Value::Object(typ)) => format!("a {}", typ),
+            Some(crate::Value::Scalar(s)) => format!("the scalar {}", s),
+        };
+        HydrateTypedError {
+            path: Vec::new(),
+            kind: Box::new(HydrateTypedErrorKind::Unexpected { expected, found }),
+        }
+    }
+
+    /// An error with a custom message
+    pub fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        HydrateTypedError {
+            path: Vec::new(),
+            kind: Box::new(HydrateTypedErrorKind::Custom(msg.to_string())),
+        }
+    }
+
+    /// The path from the object being hydrated to the value which could not be hydrated
+    pub fn path(&self) -> &[Prop] {
+        &self.path
+    }
+
+    /// Prefix the path of this error with `prop`
+    pub fn at<P: Into<Prop>>(mut self, prop: P) -> Self {
+        self.path.insert(0, prop.into());
+        self
+    }
+}
+
+impl From<AutomergeError> for HydrateTypedError {
+    fn from(err: AutomergeError) -> Self {
+        HydrateTypedError {
+            path: Vec::new(),
+            kind: Box::new(HydrateTypedErrorKind::Automerge(err)),
+        }
+    }
+}
+
+/// An error produced by the path based methods such as [`crate::ReadDoc::get_path`] and
+/// [`crate::transaction::Transactable::put_path`]
+///
+/// The error records which segment of the path could not be resolved and why.
+#[derive(Error, Debug)]
+#[error("at {at:?}: {kind}")]
+pub struct PathError {
+    at: String,
+    segment: usize,
+    kind: Box<PathErrorKind>,
+}
+
+#[derive(Error, Debug)]
+pub enum PathErrorKind {
+    #[error("invalid JSON pointer: {0}")]
+    InvalidPointer(String),
+    #[error("the path is empty")]
+    EmptyPath,
+    #[error("expected an object but found a scalar value")]
+    NotAnObject,
+    #[error("a {0} cannot be indexed by key")]
+    KeyInSequence(ObjType),
+    #[error("a {0} cannot be indexed by position")]
+    IndexInMap(ObjType),
+    #[error("{0:?} is not a valid list index")]
+    InvalidIndex(String),
+    #[error("index {index} is out of range for a {typ} of length {len}")]
+    IndexOutOfRange {
+        typ: ObjType,
+        index: usize,
+        len: usize,
+    },
+    #[error("the key does not exist")]
+    MissingKey,
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+}
+
+impl PathError {
+    pub(crate) fn new(at: String, segment: usize, kind: PathErrorKind) -> Self {
+        PathError {
+            at,
+            segment,
+            kind: Box::new(kind),
+        }
+    }
+
+    /// The index of the segment of the path which could not be resolved
+    pub fn segment(&self) -> usize {
+        self.segment
+    }
+
+    /// Why the segment could not be resolved
+    pub fn kind(&self) -> &PathErrorKind {
+        &self.kind
+    }
+}
+
+/// An error produced by [`crate::Automerge::redact()`]
+#[derive(Error, Debug)]
+pub enum RedactError {
+    #[error("invalid redaction path: {0}")]
+    Path(#[from] PathError),
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+}
+
+/// An error produced by [`crate::AutoCommit::squash_local()`]
+#[derive(Error, Debug)]
+pub enum SquashError {
+    #[error("change {0} was made by another actor")]
+    NotLocal(ChangeHash),
+    #[error("change {0} may already have been handed out")]
+    Published(ChangeHash),
+    #[error("change {0} does not directly follow the previous local change")]
+    NotConsecutive(ChangeHash),
+    #[error("change {0} would be squashed but is the diff cursor or the isolation heads")]
+    InUse(ChangeHash),
     #[error(transparent)]
     Automerge(#[from] AutomergeError),
 }
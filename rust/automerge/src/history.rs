@@ -0,0 +1,207 @@
+use std::collections::BTreeSet;
+
+use crate::clock::Clock;
+use crate::exid::ExId;
+use crate::op_set::Op;
+use crate::patches::TextRepresentation;
+use crate::types::{Key, OpId, OpType};
+use crate::{ActorId, Automerge, AutomergeError, Change, ChangeHash, ObjType, Prop, Value};
+
+/// A value which a key in a map or an element of a sequence has held, see
+/// [`crate::ReadDoc::history()`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// The value which was set
+    pub value: Value<'static>,
+    /// The ID of the operation which set the value
+    pub id: ExId,
+    /// The change which set the value
+    pub change: ChangeHash,
+    /// The actor which made the change
+    pub actor: ActorId,
+    /// The timestamp of the change
+    pub time: i64,
+    /// The message of the change
+    pub message: Option<String>,
+    /// What happened to the value afterwards, `None` if it is still current (possibly as one of
+    /// several conflicting values)
+    pub replaced: Option<Replaced>,
+}
+
+/// How a value in a [`HistoryEntry`] stopped being current
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replaced {
+    /// The change set a new value
+    Overwritten(ChangeHash),
+    /// The change deleted the key or element
+    Deleted(ChangeHash),
+}
+
+/// Every value `prop` of `obj` has held, in the order they were set
+pub(crate) fn history(
+    doc: &Automerge,
+    obj: &ExId,
+    prop: Prop,
+    clock: Option<Clock>,
+) -> Result<Vec<HistoryEntry>, AutomergeError> {
+    let obj = doc.exid_to_obj(obj)?;
+    let key = match &prop {
+        Prop::Map(name) => match doc.osd().props.lookup(name) {
+            Some(index) => Key::Map(index),
+            None => return Ok(Vec::new()),
+        },
+        Prop::Seq(_) => {
+            let encoding = TextRepresentation::String.encoding(obj.typ);
+            match doc
+                .ops()
+                .seek_ops_by_prop(&obj.id, prop, encoding, clock.as_ref())
+                .ops
+                .first()
+            {
+                Some(op) => op.elemid_or_key(),
+                None => return Ok(Vec::new()),
+            }
+        }
+    };
+    let included = |op: &Op<'_>| clock.as_ref().map(|c| c.covers(op.id())).unwrap_or(true);
+
+    let mut entries = Vec::new();
+    for op in doc.ops().ops_for_key(&obj.id, key) {
+        if op.is_inc() || op.is_mark() || !included(&op) {
+            continue;
+        }
+        let Some(change) = doc.change_for_opid(op.id()) else {
+            continue;
+        };
+        let replaced = op
+            .succ()
+            .filter(|succ| !succ.is_inc() && included(succ))
+            .filter_map(|succ| {
+                let hash = doc.change_for_opid(succ.id())?.hash();
+                Some(match succ.action() {
+                    OpType::Delete => Replaced::Deleted(hash),
+                    _ => Replaced::Overwritten(hash),
+                })
+            })
+            .min_by_key(|replaced| matches!(replaced, Replaced::Deleted(_)));
+        entries.push(HistoryEntry {
+            value: op.value_at(clock.as_ref()).into_owned(),
+            id: op.exid(),
+            change: change.hash(),
+            actor: change.actor_id().clone(),
+            time: change.timestamp(),
+            message: change.message().cloned(),
+            replaced,
+        });
+    }
+    Ok(entries)
+}
+
+/// The changes which modified `obj`, in the order they were applied
+pub(crate) fn object_changes(
+    doc: &Automerge,
+    obj: &ExId,
+    clock: Option<Clock>,
+) -> Result<Vec<ChangeHash>, AutomergeError> {
+    let obj = doc.exid_to_obj(obj)?;
+    let included = |op: &Op<'_>| clock.as_ref().map(|c| c.covers(op.id())).unwrap_or(true);
+    let mut changes = BTreeSet::new();
+    if !obj.id.is_root() {
+        changes.extend(doc.change_index_for_opid(&obj.id.0));
+    }
+    for op in doc.ops().iter_ops(&obj.id) {
+        // Deletions are not stored in the op tree, only as successors of the deleted ops
+        for op in std::iter::once(op).chain(op.succ()) {
+            if included(&op) {
+                changes.extend(doc.change_index_for_opid(op.id()));
+            }
+        }
+    }
+    Ok(changes
+        .into_iter()
+        .filter_map(|index| doc.change_at_index(index).map(|c| c.hash()))
+        .collect())
+}
+
+/// A run of text which was inserted by a single change, see [`crate::ReadDoc::blame()`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlameSpan {
+    /// The index of the start of the span, in the same units as [`crate::ReadDoc::length()`]
+    pub start: usize,
+    /// The index just past the end of the span
+    pub end: usize,
+    /// The text of the span
+    pub text: String,
+    /// The actor which inserted the text
+    pub actor: ActorId,
+    /// The change which inserted the text, `None` if it has not been committed yet
+    pub change: Option<ChangeHash>,
+    /// The timestamp of the change, `0` if it has not been committed yet
+    pub time: i64,
+    /// The message of the change
+    pub message: Option<String>,
+}
+
+/// Attribute each character of the text object `obj` to the change which inserted it
+pub(crate) fn blame(
+    doc: &Automerge,
+    obj: &ExId,
+    clock: Option<Clock>,
+) -> Result<Vec<BlameSpan>, AutomergeError> {
+    let obj = doc.exid_to_obj(obj)?;
+    if obj.typ != ObjType::Text {
+        return Err(AutomergeError::InvalidOp(obj.typ));
+    }
+    let encoding = TextRepresentation::String.encoding(obj.typ);
+    let mut spans: Vec<BlameSpan> = Vec::new();
+    // The change the last character belonged to, most runs of characters are inserted by one
+    // change so this saves looking it up for every character
+    let mut last: Option<(OpId, Option<&Change>)> = None;
+    let mut index = 0;
+    for top in doc.ops().top_ops(&obj.id, clock) {
+        let width = top.op.width(encoding);
+        let text = top.op.as_str();
+        let start = index;
+        index += width;
+        let Key::Seq(elem) = top.op.elemid_or_key() else {
+            continue;
+        };
+        let inserted = elem.0;
+        let same_change = match &last {
+            Some((prev, change)) => {
+                prev.actor() == inserted.actor()
+                    && match change {
+                        Some(c) => {
+                            c.start_op().get() <= inserted.counter()
+                                && inserted.counter() <= c.max_op()
+                        }
+                        None => doc.change_for_opid(&inserted).is_none(),
+                    }
+            }
+            None => false,
+        };
+        if !same_change {
+            last = Some((inserted, doc.change_for_opid(&inserted)));
+        }
+        let change = last.and_then(|(_, change)| change);
+        if same_change {
+            if let Some(span) = spans.last_mut() {
+                if span.end == start {
+                    span.end = index;
+                    span.text.push_str(text);
+                    continue;
+                }
+            }
+        }
+        spans.push(BlameSpan {
+            start,
+            end: index,
+            text: text.to_string(),
+            actor: doc.osd().actors.cache[inserted.actor()].clone(),
+            change: change.map(|c| c.hash()),
+            time: change.map(|c| c.timestamp()).unwrap_or(0),
+            message: change.and_then(|c| c.message().cloned()),
+        });
+    }
+    Ok(spans)
+}
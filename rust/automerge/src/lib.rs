@@ -261,12 +261,14 @@ mod automerge;
 mod autoserde;
 mod change;
 mod change_graph;
+mod change_store;
 mod clock;
 mod columnar;
 mod convert;
 mod cursor;
 pub mod error;
 mod exid;
+mod history;
 pub mod hydrate;
 mod indexed_cache;
 pub mod iter;
@@ -276,8 +278,14 @@ mod op_set;
 pub mod op_tree;
 mod parents;
 pub mod patches;
+pub mod path;
+pub mod persistence;
+pub mod policy;
 mod query;
 mod read;
+pub mod reconcile;
+pub mod repo;
+mod restore;
 mod sequence_tree;
 mod storage;
 pub mod sync;
@@ -285,19 +293,25 @@ mod text_diff;
 mod text_value;
 pub mod transaction;
 mod types;
+mod undo;
 mod value;
 #[cfg(feature = "optree-visualisation")]
 mod visualisation;
 
-pub use crate::automerge::{Automerge, LoadOptions, OnPartialLoad, SaveOptions, StringMigration};
+pub use crate::automerge::{
+    Automerge, LoadOptions, OnPartialLoad, Redacted, Redaction, SaveOptions, StringMigration,
+};
 pub use autocommit::AutoCommit;
-pub use autoserde::AutoSerde;
+pub use autoserde::{AutoDeserializer, AutoSerde};
 pub use change::{Change, LoadError as LoadChangeError};
+pub use change_graph::HeadsOrdering;
+pub use change_store::ChangeStore;
 pub use cursor::Cursor;
 pub use error::AutomergeError;
 pub use error::InvalidActorId;
 pub use error::InvalidChangeHashSlice;
 pub use exid::{ExId as ObjId, ObjIdFromBytesError};
+pub use history::{BlameSpan, HistoryEntry, Replaced};
 pub use legacy::Change as ExpandedChange;
 pub use parents::{Parent, Parents};
 pub use patches::{Patch, PatchAction, PatchLog};
@@ -306,6 +320,7 @@ pub use sequence_tree::SequenceTree;
 pub use storage::VerificationMode;
 pub use transaction::BlockOrText;
 pub use types::{ActorId, ChangeHash, ObjType, OpType, ParseChangeHashError, Prop};
+pub use undo::{RevertResult, UndoDoc, UndoManager, Unrestored, UnrestoredReason};
 pub use value::{ScalarValue, Value};
 
 /// The object ID for the root map of a document
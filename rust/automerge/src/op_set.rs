@@ -204,6 +204,13 @@ impl OpSetInternal {
             .unwrap_or_default()
     }
 
+    pub(crate) fn ops_for_key(&self, obj: &ObjId, key: Key) -> Vec<Op<'_>> {
+        self.trees
+            .get(obj)
+            .map(|tree| tree.internal.ops_for_key(&self.osd, key))
+            .unwrap_or_default()
+    }
+
     pub(crate) fn op_iter<'a>(&'a self, obj: &ObjId) -> Option<OpIter<'a>> {
         self.trees.get(obj).map(|tree| OpIter {
             iter: tree.iter(),
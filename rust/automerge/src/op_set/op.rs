@@ -171,6 +171,10 @@ impl<'a> Op<'a> {
         if let Some(clock) = clock {
             if self.is_inc() || self.is_mark() {
                 false
+            } else if self.is_counter() {
+                // Increments are successors of the counter but don't overwrite it
+                clock.covers(&self.op().id)
+                    && self.succ().all(|i| i.is_inc() || !clock.covers(i.id()))
             } else {
                 clock.covers(&self.op().id) && !self.succ().any(|i| clock.covers(i.id()))
             }
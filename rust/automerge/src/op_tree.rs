@@ -464,6 +464,29 @@ impl OpTreeInternal {
         })
     }
 
+    /// Every op for `key`, including ones which are no longer visible, in the order they are
+    /// stored
+    pub(crate) fn ops_for_key<'a>(&'a self, osd: &'a OpSetData, key: Key) -> Vec<Op<'a>> {
+        // The ops for a key are stored next to each other, for a sequence element starting with
+        // the op which inserted it
+        let pos = match key {
+            Key::Map(_) => self.binary_search_by(osd, |o| o.key_cmp(&key)),
+            Key::Seq(elem) if elem.is_head() => return Vec::new(),
+            Key::Seq(elem) => {
+                let query = query::OpIdSearch::opid(elem.0, ListEncoding::List, None);
+                match self.search(query, osd).found() {
+                    Some(pos) => pos,
+                    None => return Vec::new(),
+                }
+            }
+        };
+        self.iter()
+            .skip(pos)
+            .map(|idx| idx.as_op(osd))
+            .take_while(|op| op.elemid_or_key() == key)
+            .collect()
+    }
+
     pub(crate) fn seek_ops_by_index<'a>(
         &'a self,
         osd: &'a OpSetData,
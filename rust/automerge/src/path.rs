@@ -0,0 +1,569 @@
+//! Paths which address values nested inside a document
+//!
+//! A [`Path`] is a sequence of segments, each of which is either a key in a map or an index into a
+//! list. Paths can be constructed from a slice of [`Prop`]s or parsed from an
+//! [RFC 6901](https://www.rfc-editor.org/rfc/rfc6901) JSON pointer such as `/todos/0/title`. Any
+//! type which implements [`IntoPath`] can be passed to [`crate::ReadDoc::get_path`] and to the
+//! path based methods on [`crate::transaction::Transactable`].
+//!
+//! Segments of a JSON pointer are resolved against the object they are applied to. In a map the
+//! segment is always a key, in a list or text object it must be a decimal index (without leading
+//! zeros) or `-`, which refers to the position just past the end of the sequence. Writing to `-`
+//! therefore appends to the sequence.
+//!
+//! ```
+//! # use automerge::{AutoCommit, ReadDoc, ROOT, transaction::Transactable};
+//! # use automerge::path::Intermediates;
+//! let mut doc = AutoCommit::new();
+//! doc.put_path(ROOT, "/config/name", "bob", Intermediates::CreateMaps)?;
+//! let (name, _) = doc.get_path(ROOT, "/config/name")?.unwrap();
+//! assert_eq!(name.into_string().unwrap(), "bob");
+//! # Ok::<(), automerge::error::PathError>(())
+//! ```
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::{PathError, PathErrorKind};
+use crate::exid::ExId;
+use crate::transaction::Transactable;
+use crate::{ObjType, Prop, ReadDoc, ScalarValue, Value};
+
+/// A path from an object to a value nested inside it, see the [module documentation](self)
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Path {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    /// A segment which was given as a `Prop` and so must match the type of the object
+    Prop(Prop),
+    /// A segment of a JSON pointer, which is interpreted according to the type of the object
+    Token(String),
+}
+
+/// What to do when an intermediate object on the path to a value being written does not exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Intermediates {
+    /// Fail with [`PathErrorKind::MissingKey`] or [`PathErrorKind::IndexOutOfRange`]
+    #[default]
+    MustExist,
+    /// Create missing keys in maps as empty maps. Missing list elements are still an error.
+    CreateMaps,
+}
+
+impl Path {
+    /// Parse an RFC 6901 JSON pointer
+    ///
+    /// The empty string is the empty path, every other pointer must start with `/`.
+    pub fn from_pointer(pointer: &str) -> Result<Self, PathError> {
+        if pointer.is_empty() {
+            return Ok(Path::default());
+        }
+        let Some(rest) = pointer.strip_prefix('/') else {
+            return Err(PathError::new(
+                pointer.to_string(),
+                0,
+                PathErrorKind::InvalidPointer("a non empty pointer must start with '/'".into()),
+            ));
+        };
+        let segments = rest
+            .split('/')
+            .enumerate()
+            .map(|(i, token)| {
+                unescape(token).map(Segment::Token).ok_or_else(|| {
+                    PathError::new(
+                        pointer.to_string(),
+                        i,
+                        PathErrorKind::InvalidPointer(format!(
+                            "{:?} contains a '~' which is not followed by '0' or '1'",
+                            token
+                        )),
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Path { segments })
+    }
+
+    /// The number of segments in this path
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The pointer for the first `len` segments of this path, used to say where an error occurred
+    fn prefix(&self, len: usize) -> String {
+        self.segments[..len.min(self.segments.len())]
+            .iter()
+            .map(|s| format!("/{}", s))
+            .collect()
+    }
+
+    fn error(&self, segment: usize, kind: PathErrorKind) -> PathError {
+        PathError::new(self.prefix(segment + 1), segment, kind)
+    }
+}
+
+impl fmt::Display for Path {
+    /// Formats the path as a JSON pointer
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.prefix(self.segments.len()))
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Segment::Prop(Prop::Seq(i)) => write!(f, "{}", i),
+            Segment::Prop(Prop::Map(s)) | Segment::Token(s) => {
+                write!(f, "{}", s.replace('~', "~0").replace('/', "~1"))
+            }
+        }
+    }
+}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Path::from_pointer(s)
+    }
+}
+
+impl<P: Into<Prop>> FromIterator<P> for Path {
+    fn from_iter<T: IntoIterator<Item = P>>(iter: T) -> Self {
+        Path {
+            segments: iter.into_iter().map(|p| Segment::Prop(p.into())).collect(),
+        }
+    }
+}
+
+impl From<Vec<Prop>> for Path {
+    fn from(props: Vec<Prop>) -> Self {
+        props.into_iter().collect()
+    }
+}
+
+impl From<&[Prop]> for Path {
+    fn from(props: &[Prop]) -> Self {
+        props.iter().cloned().collect()
+    }
+}
+
+fn unescape(token: &str) -> Option<String> {
+    let mut result = String::with_capacity(token.len());
+    let mut chars = token.chars();
+    while let Some(c) = chars.next() {
+        if c == '~' {
+            match chars.next() {
+                Some('0') => result.push('~'),
+                Some('1') => result.push('/'),
+                _ => return None,
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    Some(result)
+}
+
+/// Types which can be converted into a [`Path`]
+///
+/// This is implemented for slices, arrays and vectors of [`Prop`], which are used as is, and for
+/// strings, which are parsed as JSON pointers.
+pub trait IntoPath {
+    fn into_path(self) -> Result<Path, PathError>;
+}
+
+impl IntoPath for Path {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self)
+    }
+}
+
+impl IntoPath for &Path {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.clone())
+    }
+}
+
+impl IntoPath for &str {
+    fn into_path(self) -> Result<Path, PathError> {
+        Path::from_pointer(self)
+    }
+}
+
+impl IntoPath for &String {
+    fn into_path(self) -> Result<Path, PathError> {
+        Path::from_pointer(self)
+    }
+}
+
+impl IntoPath for String {
+    fn into_path(self) -> Result<Path, PathError> {
+        Path::from_pointer(&self)
+    }
+}
+
+impl IntoPath for Vec<Prop> {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.into())
+    }
+}
+
+impl IntoPath for &Vec<Prop> {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.as_slice().into())
+    }
+}
+
+impl IntoPath for &[Prop] {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.into())
+    }
+}
+
+impl<const N: usize> IntoPath for [Prop; N] {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.into_iter().collect())
+    }
+}
+
+impl<const N: usize> IntoPath for &[Prop; N] {
+    fn into_path(self) -> Result<Path, PathError> {
+        Ok(self.as_slice().into())
+    }
+}
+
+/// Convert `segment` into a `Prop` for the object `obj` of type `typ`
+fn resolve<R: ReadDoc + ?Sized>(
+    doc: &R,
+    obj: &ExId,
+    typ: ObjType,
+    segment: &Segment,
+) -> Result<Prop, PathErrorKind> {
+    match (typ, segment) {
+        (ObjType::Map | ObjType::Table, Segment::Prop(Prop::Map(key)))
+        | (ObjType::Map | ObjType::Table, Segment::Token(key)) => Ok(Prop::Map(key.clone())),
+        (ObjType::Map | ObjType::Table, Segment::Prop(Prop::Seq(_))) => {
+            Err(PathErrorKind::IndexInMap(typ))
+        }
+        (ObjType::List | ObjType::Text, Segment::Prop(Prop::Seq(index))) => Ok(Prop::Seq(*index)),
+        (ObjType::List | ObjType::Text, Segment::Prop(Prop::Map(_))) => {
+            Err(PathErrorKind::KeyInSequence(typ))
+        }
+        (ObjType::List | ObjType::Text, Segment::Token(token)) => {
+            if token == "-" {
+                return Ok(Prop::Seq(doc.length(obj)));
+            }
+            let valid = !token.is_empty()
+                && token.bytes().all(|b| b.is_ascii_digit())
+                && (token == "0" || !token.starts_with('0'));
+            match token.parse() {
+                Ok(index) if valid => Ok(Prop::Seq(index)),
+                _ => Err(PathErrorKind::InvalidIndex(token.clone())),
+            }
+        }
+    }
+}
+
+/// Step from the object `obj` through `prop` to the object it contains
+fn descend<R: ReadDoc + ?Sized>(
+    doc: &R,
+    obj: &ExId,
+    prop: Prop,
+) -> Result<(ExId, ObjType), PathErrorKind> {
+    match doc.get(obj, prop)? {
+        Some((Value::Object(typ), id)) => Ok((id, typ)),
+        Some((Value::Scalar(_), _)) => Err(PathErrorKind::NotAnObject),
+        None => Err(PathErrorKind::MissingKey),
+    }
+}
+
+fn check_index<R: ReadDoc + ?Sized>(
+    doc: &R,
+    obj: &ExId,
+    typ: ObjType,
+    prop: &Prop,
+    allow_end: bool,
+) -> Result<(), PathErrorKind> {
+    if let Prop::Seq(index) = prop {
+        let len = doc.length(obj);
+        if *index > len || (*index == len && !allow_end) {
+            return Err(PathErrorKind::IndexOutOfRange {
+                typ,
+                index: *index,
+                len,
+            });
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn get_path<'a, R: ReadDoc + ?Sized, O: AsRef<ExId>, P: IntoPath>(
+    doc: &'a R,
+    obj: O,
+    path: P,
+) -> Result<Option<(Value<'a>, ExId)>, PathError> {
+    let path = path.into_path()?;
+    let mut obj = obj.as_ref().clone();
+    let mut typ = doc
+        .object_type(&obj)
+        .map_err(|e| PathError::new(String::new(), 0, e.into()))?;
+    let Some((last, init)) = path.segments.split_last() else {
+        return Ok(Some((Value::Object(typ), obj)));
+    };
+    for (i, segment) in init.iter().enumerate() {
+        (obj, typ) = resolve(doc, &obj, typ, segment)
+            .and_then(|prop| check_index(doc, &obj, typ, &prop, false).map(|_| prop))
+            .and_then(|prop| descend(doc, &obj, prop))
+            .map_err(|kind| path.error(i, kind))?;
+    }
+    let prop = resolve(doc, &obj, typ, last).map_err(|kind| path.error(init.len(), kind))?;
+    doc.get(&obj, prop)
+        .map_err(|e| path.error(init.len(), e.into()))
+}
+
+/// Resolve every segment of `path` against the current state of `doc`, starting from the root
+///
+/// Each prop is returned along with the object it was resolved against. Once the path reaches a
+/// key which does not currently hold an object there is nothing to resolve against, so the
+/// remaining segments must be map keys.
+pub(crate) fn resolve_segments<R: ReadDoc + ?Sized>(
+    doc: &R,
+    path: &Path,
+) -> Result<Vec<(Option<ExId>, Prop)>, PathError> {
+    let mut current = Some((ExId::Root, ObjType::Map));
+    let mut result = Vec::with_capacity(path.len());
+    for (i, segment) in path.segments.iter().enumerate() {
+        let prop = match (&current, segment) {
+            (Some((obj, typ)), segment) => resolve(doc, obj, *typ, segment)
+                .and_then(|prop| check_index(doc, obj, *typ, &prop, false).map(|_| prop))
+                .map_err(|kind| path.error(i, kind))?,
+            (None, Segment::Prop(Prop::Map(key)) | Segment::Token(key)) => Prop::Map(key.clone()),
+            (None, Segment::Prop(Prop::Seq(_))) => {
+                return Err(path.error(i - 1, PathErrorKind::MissingKey))
+            }
+        };
+        let obj = current.take().map(|(obj, _)| obj);
+        if let Some(obj) = &obj {
+            current = match doc.get(obj, prop.clone()) {
+                Ok(Some((Value::Object(typ), id))) => Some((id, typ)),
+                _ => None,
+            };
+        }
+        result.push((obj, prop));
+    }
+    Ok(result)
+}
+
+/// Walk to the parent of the value at `path`, returning the parent and the final segment of the
+/// path resolved against it
+fn parent<T: Transactable + ?Sized>(
+    doc: &mut T,
+    obj: &ExId,
+    path: &Path,
+    intermediates: Intermediates,
+) -> Result<(ExId, ObjType, Prop), PathError> {
+    let Some((last, init)) = path.segments.split_last() else {
+        return Err(PathError::new(String::new(), 0, PathErrorKind::EmptyPath));
+    };
+    let mut obj = obj.clone();
+    let mut typ = doc
+        .object_type(&obj)
+        .map_err(|e| PathError::new(String::new(), 0, e.into()))?;
+    for (i, segment) in init.iter().enumerate() {
+        let prop = resolve(doc, &obj, typ, segment)
+            .and_then(|prop| check_index(doc, &obj, typ, &prop, false).map(|_| prop))
+            .map_err(|kind| path.error(i, kind))?;
+        (obj, typ) = match descend(doc, &obj, prop.clone()) {
+            Err(PathErrorKind::MissingKey) if intermediates == Intermediates::CreateMaps => {
+                let id = doc
+                    .put_object(&obj, prop, ObjType::Map)
+                    .map_err(|e| path.error(i, e.into()))?;
+                (id, ObjType::Map)
+            }
+            other => other.map_err(|kind| path.error(i, kind))?,
+        };
+    }
+    let prop = resolve(doc, &obj, typ, last).map_err(|kind| path.error(init.len(), kind))?;
+    Ok((obj, typ, prop))
+}
+
+pub(crate) fn put_path<T: Transactable + ?Sized, O: AsRef<ExId>, P: IntoPath>(
+    doc: &mut T,
+    obj: O,
+    path: P,
+    value: ScalarValue,
+    intermediates: Intermediates,
+) -> Result<(), PathError> {
+    let path = path.into_path()?;
+    let (obj, typ, prop) = parent(doc, obj.as_ref(), &path, intermediates)?;
+    let last = path.len() - 1;
+    check_index(doc, &obj, typ, &prop, true).map_err(|kind| path.error(last, kind))?;
+    match prop {
+        Prop::Seq(index) if index == doc.length(&obj) => doc.insert(&obj, index, value),
+        prop => doc.put(&obj, prop, value),
+    }
+    .map_err(|e| path.error(last, e.into()))
+}
+
+pub(crate) fn put_object_path<T: Transactable + ?Sized, O: AsRef<ExId>, P: IntoPath>(
+    doc: &mut T,
+    obj: O,
+    path: P,
+    object: ObjType,
+    intermediates: Intermediates,
+) -> Result<ExId, PathError> {
+    let path = path.into_path()?;
+    let (obj, typ, prop) = parent(doc, obj.as_ref(), &path, intermediates)?;
+    let last = path.len() - 1;
+    check_index(doc, &obj, typ, &prop, true).map_err(|kind| path.error(last, kind))?;
+    match prop {
+        Prop::Seq(index) if index == doc.length(&obj) => doc.insert_object(&obj, index, object),
+        prop => doc.put_object(&obj, prop, object),
+    }
+    .map_err(|e| path.error(last, e.into()))
+}
+
+pub(crate) fn delete_path<T: Transactable + ?Sized, O: AsRef<ExId>, P: IntoPath>(
+    doc: &mut T,
+    obj: O,
+    path: P,
+) -> Result<(), PathError> {
+    let path = path.into_path()?;
+    let (obj, typ, prop) = parent(doc, obj.as_ref(), &path, Intermediates::MustExist)?;
+    let last = path.len() - 1;
+    check_index(doc, &obj, typ, &prop, false).map_err(|kind| path.error(last, kind))?;
+    if let Prop::Map(_) = prop {
+        let exists = doc
+            .get(&obj, prop.clone())
+            .map_err(|e| path.error(last, e.into()))?
+            .is_some();
+        if !exists {
+            return Err(path.error(last, PathErrorKind::MissingKey));
+        }
+    }
+    doc.delete(&obj, prop)
+        .map_err(|e| path.error(last, e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AutoCommit, ROOT};
+
+    #[test]
+    fn parse_pointer() {
+        let path = Path::from_pointer("/a~1b/m~0n/0/").unwrap();
+        assert_eq!(path.len(), 4);
+        assert_eq!(path.to_string(), "/a~1b/m~0n/0/");
+        assert!(Path::from_pointer("").unwrap().is_empty());
+        assert!(Path::from_pointer("a").is_err());
+        assert_eq!(Path::from_pointer("/a/~2").unwrap_err().segment(), 1);
+    }
+
+    #[test]
+    fn get_nested_values() {
+        let mut doc = AutoCommit::new();
+        let todos = doc.put_object(ROOT, "todos", ObjType::List).unwrap();
+        let todo = doc.insert_object(&todos, 0, ObjType::Map).unwrap();
+        doc.put(&todo, "title", "write tests").unwrap();
+
+        let (title, _) = doc.get_path(ROOT, "/todos/0/title").unwrap().unwrap();
+        assert_eq!(title.into_string().unwrap(), "write tests");
+        let (_, id) = doc
+            .get_path(ROOT, [Prop::from("todos"), Prop::from(0)])
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, todo);
+        assert_eq!(doc.get_path(&todos, "").unwrap().unwrap().1, todos);
+        assert!(doc.get_path(ROOT, "/todos/0/done").unwrap().is_none());
+        assert!(doc.get_path(ROOT, "/todos/1").unwrap().is_none());
+    }
+
+    #[test]
+    fn errors_name_the_failing_segment() {
+        let mut doc = AutoCommit::new();
+        let todos = doc.put_object(ROOT, "todos", ObjType::List).unwrap();
+        doc.insert(&todos, 0, "one").unwrap();
+
+        let err = doc.get_path(ROOT, "/todos/3/title").unwrap_err();
+        assert_eq!(err.segment(), 1);
+        assert!(matches!(
+            err.kind(),
+            PathErrorKind::IndexOutOfRange {
+                index: 3,
+                len: 1,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "at \"/todos/3\": index 3 is out of range for a list of length 1"
+        );
+
+        let err = doc.get_path(ROOT, "/todos/0/title").unwrap_err();
+        assert_eq!(err.segment(), 1);
+        assert!(matches!(err.kind(), PathErrorKind::NotAnObject));
+
+        let err = doc.get_path(ROOT, "/todos/first").unwrap_err();
+        assert!(matches!(err.kind(), PathErrorKind::InvalidIndex(_)));
+
+        let err = doc.get_path(ROOT, "/missing/a").unwrap_err();
+        assert_eq!(err.segment(), 0);
+        assert!(matches!(err.kind(), PathErrorKind::MissingKey));
+
+        let err = doc
+            .get_path(ROOT, [Prop::from(0), Prop::from("a")])
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            PathErrorKind::IndexInMap(ObjType::Map)
+        ));
+    }
+
+    #[test]
+    fn writes_create_intermediate_maps() {
+        let mut doc = AutoCommit::new();
+        let err = doc
+            .put_path(ROOT, "/a/b/c", 1, Intermediates::MustExist)
+            .unwrap_err();
+        assert!(matches!(err.kind(), PathErrorKind::MissingKey));
+
+        doc.put_path(ROOT, "/a/b/c", 1, Intermediates::CreateMaps)
+            .unwrap();
+        let list = doc
+            .put_object_path(ROOT, "/a/list", ObjType::List, Intermediates::MustExist)
+            .unwrap();
+        doc.put_path(ROOT, "/a/list/-", "x", Intermediates::MustExist)
+            .unwrap();
+        doc.put_path(ROOT, "/a/list/1", "y", Intermediates::MustExist)
+            .unwrap();
+        doc.put_path(ROOT, "/a/list/0", "z", Intermediates::MustExist)
+            .unwrap();
+        assert_eq!(doc.length(&list), 2);
+
+        let err = doc
+            .put_path(ROOT, "/a/list/5", "y", Intermediates::MustExist)
+            .unwrap_err();
+        assert!(matches!(err.kind(), PathErrorKind::IndexOutOfRange { .. }));
+
+        doc.delete_path(ROOT, "/a/list/0").unwrap();
+        doc.delete_path(ROOT, "/a/b/c").unwrap();
+        let err = doc.delete_path(ROOT, "/a/b/c").unwrap_err();
+        assert!(matches!(err.kind(), PathErrorKind::MissingKey));
+        assert!(matches!(
+            doc.delete_path(ROOT, "").unwrap_err().kind(),
+            PathErrorKind::EmptyPath
+        ));
+
+        let (value, _) = doc.get_path(ROOT, "/a/list/0").unwrap().unwrap();
+        assert_eq!(value.into_string().unwrap(), "y");
+        assert!(doc.get_path(ROOT, "/a/b").unwrap().is_some());
+    }
+}
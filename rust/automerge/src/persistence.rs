@@ -0,0 +1,231 @@
+//! Persisting documents as chunks in some storage
+//!
+//! Automerge documents are saved as a sequence of chunks: [`crate::Automerge::save()`] produces
+//! a single document chunk containing everything, while [`crate::AutoCommit::save_incremental()`]
+//! produces change chunks containing only what is new. Loading the concatenation of any set of
+//! these chunks produces a document with all the changes in them. This module builds on that to
+//! provide a [`Storage`] abstraction which stores the chunks for many documents, along with
+//! [`FsStorage`], an implementation on top of the filesystem, and [`MemoryStorage`], which is
+//! useful in tests.
+//!
+//! ## Example
+//!
+//! ```
+//! use automerge::{persistence::{self, FsStorage}, transaction::Transactable, AutoCommit, ReadDoc};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # let dir = std::env::temp_dir().join(format!("automerge-doc-{}", std::process::id()));
+//! let mut storage = FsStorage::open(&dir)?;
+//!
+//! let mut doc = AutoCommit::new();
+//! doc.put(automerge::ROOT, "key", "value")?;
+//! persistence::append(&mut storage, "my-doc", &doc.save_incremental())?;
+//!
+//! let loaded = persistence::load(&storage, "my-doc")?.expect("document was saved");
+//! assert_eq!(loaded.get(automerge::ROOT, "key")?.unwrap().0.to_str(), Some("value"));
+//! # std::fs::remove_dir_all(&dir)?;
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use sha2::{Digest, Sha256};
+
+use crate::storage::{parse, Chunk};
+use crate::{Automerge, AutomergeError};
+
+mod fs;
+pub use fs::{FsError, FsStorage};
+
+/// What a stored chunk contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChunkKind {
+    /// The output of [`crate::Automerge::save()`], which contains every change in the document
+    /// at the time it was saved
+    Snapshot,
+    /// Some changes, usually the output of [`crate::AutoCommit::save_incremental()`]
+    Incremental,
+}
+
+/// The key a chunk is stored under
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChunkKey {
+    pub document: String,
+    pub kind: ChunkKind,
+    /// Distinguishes the chunks of a given kind for a document. [`append()`] and [`compact()`]
+    /// use the hex encoded SHA-256 hash of the chunk.
+    pub name: String,
+}
+
+impl ChunkKey {
+    /// The key for `data` when stored as a chunk of `kind` for `document`
+    pub fn for_data(document: &str, kind: ChunkKind, data: &[u8]) -> Self {
+        Self {
+            document: document.to_string(),
+            kind,
+            name: hex::encode(Sha256::digest(data)),
+        }
+    }
+}
+
+/// Somewhere to keep the chunks which make up documents
+///
+/// Implementations need not support concurrent access from several processes, but must not lose
+/// a chunk once [`Self::put()`] has returned successfully.
+pub trait Storage {
+    type Error: std::error::Error + 'static;
+
+    fn get(&self, key: &ChunkKey) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Store `data` under `key`, replacing anything already stored there
+    fn put(&mut self, key: &ChunkKey, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// The keys of all the chunks stored for `document`, in no particular order
+    fn list(&self, document: &str) -> Result<Vec<ChunkKey>, Self::Error>;
+
+    /// Remove the chunk stored under `key`, if there is one
+    fn delete(&mut self, key: &ChunkKey) -> Result<(), Self::Error>;
+}
+
+/// A [`Storage`] which keeps everything in memory
+///
+/// Clones share the same chunks, so a clone can be used to look at what has been stored, or to
+/// load documents again in a test which simulates restarting.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    chunks: Arc<Mutex<BTreeMap<ChunkKey, Vec<u8>>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn chunks(&self) -> MutexGuard<'_, BTreeMap<ChunkKey, Vec<u8>>> {
+        self.chunks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    type Error = Infallible;
+
+    fn get(&self, key: &ChunkKey) -> Result<Option<Vec<u8>>, Infallible> {
+        Ok(self.chunks().get(key).cloned())
+    }
+
+    fn put(&mut self, key: &ChunkKey, data: &[u8]) -> Result<(), Infallible> {
+        self.chunks().insert(key.clone(), data.to_vec());
+        Ok(())
+    }
+
+    fn list(&self, document: &str) -> Result<Vec<ChunkKey>, Infallible> {
+        Ok(self
+            .chunks()
+            .keys()
+            .filter(|key| key.document == document)
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&mut self, key: &ChunkKey) -> Result<(), Infallible> {
+        self.chunks().remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoadError<E: std::error::Error + 'static> {
+    #[error("error reading from storage: {0}")]
+    Storage(#[source] E),
+    #[error("error loading document: {0}")]
+    Automerge(#[from] AutomergeError),
+}
+
+/// Load `document` from `storage`, returning [`None`] if there are no chunks for it
+pub fn load<S: Storage>(
+    storage: &S,
+    document: &str,
+) -> Result<Option<Automerge>, LoadError<S::Error>> {
+    let data = match load_bytes(storage, document).map_err(LoadError::Storage)? {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    Ok(Some(Automerge::load(&data)?))
+}
+
+/// Store `changes`, which are usually the output of [`crate::AutoCommit::save_incremental()`],
+/// as an incremental chunk of `document`
+///
+/// Does nothing if `changes` is empty.
+pub fn append<S: Storage>(storage: &mut S, document: &str, changes: &[u8]) -> Result<(), S::Error> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    storage.put(
+        &ChunkKey::for_data(document, ChunkKind::Incremental, changes),
+        changes,
+    )
+}
+
+/// Replace all the chunks of `document` with a single snapshot
+///
+/// The new snapshot is stored before anything is deleted, so if this fails part way through the
+/// document can still be loaded, possibly from more chunks than necessary.
+pub fn compact<S: Storage>(storage: &mut S, document: &str) -> Result<(), LoadError<S::Error>> {
+    let keys = storage.list(document).map_err(LoadError::Storage)?;
+    let doc = match load(storage, document)? {
+        Some(doc) => doc,
+        None => return Ok(()),
+    };
+    let snapshot = doc.save();
+    let snapshot_key = ChunkKey::for_data(document, ChunkKind::Snapshot, &snapshot);
+    storage
+        .put(&snapshot_key, &snapshot)
+        .map_err(LoadError::Storage)?;
+    for key in keys.into_iter().filter(|key| *key != snapshot_key) {
+        storage.delete(&key).map_err(LoadError::Storage)?;
+    }
+    Ok(())
+}
+
+/// The concatenation of all the chunks of `document`, snapshots first
+pub(crate) fn load_bytes<S: Storage>(
+    storage: &S,
+    document: &str,
+) -> Result<Option<Vec<u8>>, S::Error> {
+    let mut keys = storage.list(document)?;
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    keys.sort();
+    let mut data = Vec::new();
+    for key in keys {
+        if let Some(chunk) = storage.get(&key)? {
+            data.extend(chunk);
+        }
+    }
+    Ok(Some(data))
+}
+
+/// The ranges of the complete chunks with valid checksums at the start of `data`
+///
+/// Parsing stops at the first chunk which is truncated or whose checksum doesn't match, which is
+/// what the tail of a file looks like if the process writing to it died part way through.
+pub(crate) fn valid_chunks(data: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut input = parse::Input::new(data);
+    while !input.is_empty() {
+        let start = data.len() - input.unconsumed_bytes().len();
+        match Chunk::parse(input) {
+            Ok((rest, chunk)) if chunk.checksum_valid() => {
+                let end = data.len() - rest.unconsumed_bytes().len();
+                ranges.push(start..end);
+                input = rest.reset();
+            }
+            _ => break,
+        }
+    }
+    ranges
+}
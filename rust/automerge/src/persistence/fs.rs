@@ -0,0 +1,546 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::{valid_chunks, ChunkKey, ChunkKind, Storage};
+use crate::storage::{parse, Header, HeaderError};
+use crate::{Automerge, AutomergeError};
+
+const LOG_FILE: &str = "incremental";
+const SNAPSHOT_DIR: &str = "snapshots";
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FsError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("incremental data must be a sequence of complete chunks")]
+    InvalidChunks,
+    #[error("the log of document {document} is corrupt at byte {offset}")]
+    Corrupt { document: String, offset: u64 },
+    #[error("failed to compact document {document}: {error}")]
+    Compact {
+        document: String,
+        error: Box<AutomergeError>,
+    },
+}
+
+/// A [`Storage`] which keeps chunks in a directory
+///
+/// Each document gets a subdirectory, named after the hex encoding of its ID, containing:
+///
+/// * `snapshots/`, a file per [`ChunkKind::Snapshot`] chunk. These are written to a temporary
+///   file and renamed into place, so they are either there in full or not at all.
+/// * `incremental`, a log which [`ChunkKind::Incremental`] chunks are appended to. An
+///   incremental chunk is always named after its contents as in [`ChunkKey::for_data()`],
+///   whatever name it was stored with, and data containing several chunks is listed as several
+///   keys.
+///
+/// If the process dies part way through appending to the log, the chunk being written is left
+/// incomplete. The checksums of the chunks in the log are verified when it is read, and a last
+/// chunk which is incomplete or doesn't match its checksum is ignored. This damaged tail is
+/// truncated before anything else is appended. A damaged chunk with more data after it can't be
+/// the result of a torn write, so it is reported as [`FsError::Corrupt`] and the log is left
+/// alone.
+///
+/// The log is only read and verified by the first append to it, after which its length is kept
+/// in memory, so the directory must not be written to by anything else while it is open.
+///
+/// Once the log is larger than the compaction threshold, the document is loaded from all of its
+/// chunks and saved as a single new snapshot, which replaces the log and the old snapshots. If
+/// this fails the append has still succeeded, so the error is logged and compaction is tried
+/// again on the next append.
+#[derive(Debug)]
+pub struct FsStorage {
+    root: PathBuf,
+    compaction_threshold: u64,
+    logs: HashMap<String, LogState>,
+}
+
+/// The verified part of the log of a document
+#[derive(Debug, Clone, Copy)]
+struct LogState {
+    len: u64,
+    chunks: usize,
+}
+
+/// The chunks in a log and where the damaged tail, if any, starts
+struct Log {
+    chunks: Vec<Vec<u8>>,
+    valid_len: u64,
+    file_len: u64,
+}
+
+impl FsStorage {
+    /// Use the directory at `root`, creating it if it doesn't exist
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self, FsError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(Self {
+            root,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            logs: HashMap::new(),
+        })
+    }
+
+    /// Compact a document once its incremental log is larger than `bytes`. Defaults to 1MiB.
+    pub fn with_compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Replace all the chunks of `document` with a single snapshot now
+    pub fn compact(&mut self, document: &str) -> Result<(), FsError> {
+        let data = match super::load_bytes(self, document)? {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+        let doc = Automerge::load(&data).map_err(|error| FsError::Compact {
+            document: document.to_string(),
+            error: Box::new(error),
+        })?;
+        let snapshot = doc.save();
+        let key = ChunkKey::for_data(document, ChunkKind::Snapshot, &snapshot);
+        self.write_snapshot(&key, &snapshot)?;
+
+        // Everything is in the new snapshot now, so if we die while removing the old chunks we
+        // just load some changes twice
+        for entry in self.snapshot_names(document)? {
+            if entry != key.name {
+                remove_if_exists(&self.snapshot_dir(document).join(entry))?;
+            }
+        }
+        self.logs.remove(document);
+        remove_if_exists(&self.log_path(document))?;
+        Ok(())
+    }
+
+    fn document_dir(&self, document: &str) -> PathBuf {
+        self.root.join(hex::encode(document.as_bytes()))
+    }
+
+    fn snapshot_dir(&self, document: &str) -> PathBuf {
+        self.document_dir(document).join(SNAPSHOT_DIR)
+    }
+
+    fn log_path(&self, document: &str) -> PathBuf {
+        self.document_dir(document).join(LOG_FILE)
+    }
+
+    fn snapshot_names(&self, document: &str) -> Result<Vec<String>, FsError> {
+        let entries = match fs::read_dir(self.snapshot_dir(document)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".tmp") {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn write_snapshot(&self, key: &ChunkKey, data: &[u8]) -> Result<(), FsError> {
+        let dir = self.snapshot_dir(&key.document);
+        fs::create_dir_all(&dir)?;
+        let tmp = dir.join(format!("{}.tmp", key.name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(tmp, dir.join(&key.name))?;
+        Ok(())
+    }
+
+    /// The valid chunks in the log of `document`
+    ///
+    /// Fails with [`FsError::Corrupt`] if a damaged chunk is followed by more data.
+    fn read_log(&self, document: &str) -> Result<Log, FsError> {
+        let data = match fs::read(self.log_path(document)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Log {
+                    chunks: Vec::new(),
+                    valid_len: 0,
+                    file_len: 0,
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let ranges = valid_chunks(&data);
+        let valid_len = ranges.last().map(|r| r.end).unwrap_or(0);
+        if !is_torn_tail(&data[valid_len..]) {
+            return Err(FsError::Corrupt {
+                document: document.to_string(),
+                offset: valid_len as u64,
+            });
+        }
+        Ok(Log {
+            chunks: ranges.into_iter().map(|r| data[r].to_vec()).collect(),
+            valid_len: valid_len as u64,
+            file_len: data.len() as u64,
+        })
+    }
+
+    fn append(&mut self, document: &str, data: &[u8]) -> Result<(), FsError> {
+        let ranges = valid_chunks(data);
+        if ranges.last().map(|r| r.end).unwrap_or(0) != data.len() {
+            return Err(FsError::InvalidChunks);
+        }
+
+        fs::create_dir_all(self.document_dir(document))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(document))?;
+        let state = match self.logs.get(document) {
+            Some(state) => *state,
+            None => {
+                let log = self.read_log(document)?;
+                if log.valid_len < log.file_len {
+                    tracing::warn!(
+                        document,
+                        discarded = log.file_len - log.valid_len,
+                        "discarding incomplete chunk at the end of the log"
+                    );
+                    file.set_len(log.valid_len)?;
+                }
+                LogState {
+                    len: log.valid_len,
+                    chunks: log.chunks.len(),
+                }
+            }
+        };
+        // If the write fails we don't know how much of it made it to disk, so forget the state
+        // and read the log again next time
+        self.logs.remove(document);
+        file.write_all(data)?;
+        file.sync_data()?;
+        let state = LogState {
+            len: state.len + data.len() as u64,
+            chunks: state.chunks + ranges.len(),
+        };
+        self.logs.insert(document.to_string(), state);
+
+        if state.len > self.compaction_threshold {
+            tracing::debug!(
+                document,
+                chunks = state.chunks,
+                bytes = state.len,
+                "compacting incremental log"
+            );
+            if let Err(error) = self.compact(document) {
+                tracing::warn!(document, %error, "failed to compact document");
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_from_log(&mut self, document: &str, name: &str) -> Result<(), FsError> {
+        self.logs.remove(document);
+        let chunks = self.read_log(document)?.chunks;
+        let remaining = chunks
+            .into_iter()
+            .filter(|chunk| {
+                ChunkKey::for_data(document, ChunkKind::Incremental, chunk).name != name
+            })
+            .collect::<Vec<_>>();
+        let path = self.log_path(document);
+        if remaining.is_empty() {
+            return remove_if_exists(&path);
+        }
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for chunk in remaining {
+            file.write_all(&chunk)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Storage for FsStorage {
+    type Error = FsError;
+
+    fn get(&self, key: &ChunkKey) -> Result<Option<Vec<u8>>, FsError> {
+        match key.kind {
+            ChunkKind::Snapshot => {
+                match fs::read(self.snapshot_dir(&key.document).join(&key.name)) {
+                    Ok(data) => Ok(Some(data)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            ChunkKind::Incremental => {
+                let chunks = self.read_log(&key.document)?.chunks;
+                Ok(chunks.into_iter().find(|chunk| {
+                    ChunkKey::for_data(&key.document, ChunkKind::Incremental, chunk) == *key
+                }))
+            }
+        }
+    }
+
+    fn put(&mut self, key: &ChunkKey, data: &[u8]) -> Result<(), FsError> {
+        match key.kind {
+            ChunkKind::Snapshot => self.write_snapshot(key, data),
+            ChunkKind::Incremental => self.append(&key.document, data),
+        }
+    }
+
+    fn list(&self, document: &str) -> Result<Vec<ChunkKey>, FsError> {
+        let mut keys = self
+            .snapshot_names(document)?
+            .into_iter()
+            .map(|name| ChunkKey {
+                document: document.to_string(),
+                kind: ChunkKind::Snapshot,
+                name,
+            })
+            .collect::<Vec<_>>();
+        let chunks = self.read_log(document)?.chunks;
+        let incremental = chunks
+            .iter()
+            .map(|chunk| ChunkKey::for_data(document, ChunkKind::Incremental, chunk))
+            .collect::<BTreeSet<_>>();
+        keys.extend(incremental);
+        Ok(keys)
+    }
+
+    fn delete(&mut self, key: &ChunkKey) -> Result<(), FsError> {
+        match key.kind {
+            ChunkKind::Snapshot => {
+                remove_if_exists(&self.snapshot_dir(&key.document).join(&key.name))
+            }
+            ChunkKind::Incremental => self.remove_from_log(&key.document, &key.name),
+        }
+    }
+}
+
+/// Whether `rest`, the data after the last valid chunk in a log, is a single chunk which was
+/// being written when the process died
+///
+/// A torn write leaves a chunk which is cut short, or which runs to the end of the file but is
+/// damaged because not all of it reached the disk.
+fn is_torn_tail(rest: &[u8]) -> bool {
+    if rest.is_empty() {
+        return true;
+    }
+    match Header::parse::<HeaderError>(parse::Input::new(rest)) {
+        Err(parse::ParseError::Incomplete(_)) => true,
+        Ok((_, header)) => header.data_bytes().end == rest.len(),
+        Err(parse::ParseError::Error(_)) => false,
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), FsError> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{self, compact, load};
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, ReadDoc, ROOT};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "automerge-fs-storage-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn edit(doc: &mut AutoCommit, storage: &mut FsStorage, value: i64) {
+        doc.put(ROOT, "value", value).unwrap();
+        persistence::append(storage, "doc", &doc.save_incremental()).unwrap();
+    }
+
+    fn value(storage: &FsStorage) -> i64 {
+        let doc = load(storage, "doc").unwrap().unwrap();
+        doc.get(ROOT, "value").unwrap().unwrap().0.to_i64().unwrap()
+    }
+
+    #[test]
+    fn changes_survive_reopening() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        assert!(load(&storage, "doc").unwrap().is_none());
+
+        let mut doc = AutoCommit::new();
+        for i in 0..3 {
+            edit(&mut doc, &mut storage, i);
+        }
+        drop(storage);
+
+        let storage = FsStorage::open(&dir.0).unwrap();
+        assert_eq!(value(&storage), 2);
+        let keys = storage.list("doc").unwrap();
+        assert_eq!(keys.len(), 3);
+        assert!(keys.iter().all(|k| k.kind == ChunkKind::Incremental));
+        for key in keys {
+            assert_eq!(
+                ChunkKey::for_data("doc", key.kind, &storage.get(&key).unwrap().unwrap()),
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn torn_writes_are_discarded() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        let mut doc = AutoCommit::new();
+        edit(&mut doc, &mut storage, 1);
+        let log = storage.log_path("doc");
+        let valid = fs::read(&log).unwrap();
+
+        // Simulate dying half way through appending a change
+        doc.put(ROOT, "value", 2).unwrap();
+        let torn = doc.save_incremental();
+        fs::write(&log, [&valid[..], &torn[..torn.len() / 2]].concat()).unwrap();
+        let storage = FsStorage::open(&dir.0).unwrap();
+        assert_eq!(value(&storage), 1);
+        assert_eq!(storage.list("doc").unwrap().len(), 1);
+
+        // A last chunk with a bad checksum is treated the same way
+        let mut corrupt = torn.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        fs::write(&log, [&valid[..], &corrupt[..]].concat()).unwrap();
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        assert_eq!(value(&storage), 1);
+
+        // The next append truncates the damage so the new chunk can be read
+        persistence::append(&mut storage, "doc", &torn).unwrap();
+        assert_eq!(value(&storage), 2);
+        assert_eq!(storage.list("doc").unwrap().len(), 2);
+
+        assert!(matches!(
+            persistence::append(&mut storage, "doc", &torn[..torn.len() - 1]),
+            Err(FsError::InvalidChunks)
+        ));
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_an_error() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        let mut doc = AutoCommit::new();
+        edit(&mut doc, &mut storage, 1);
+        let first_len = fs::metadata(storage.log_path("doc")).unwrap().len();
+        edit(&mut doc, &mut storage, 2);
+
+        let log = storage.log_path("doc");
+        let mut data = fs::read(&log).unwrap();
+        data[first_len as usize - 1] ^= 0xff;
+        fs::write(&log, &data).unwrap();
+
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        assert!(matches!(
+            storage.list("doc"),
+            Err(FsError::Corrupt { offset: 0, .. })
+        ));
+        doc.put(ROOT, "value", 3).unwrap();
+        assert!(matches!(
+            persistence::append(&mut storage, "doc", &doc.save_incremental()),
+            Err(FsError::Corrupt { offset: 0, .. })
+        ));
+        assert_eq!(fs::read(&log).unwrap(), data);
+    }
+
+    #[test]
+    fn failed_compaction_keeps_the_append() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0)
+            .unwrap()
+            .with_compaction_threshold(0);
+        let snapshots = storage.snapshot_dir("doc");
+        fs::create_dir_all(storage.document_dir("doc")).unwrap();
+        fs::write(&snapshots, b"not a directory").unwrap();
+
+        let mut doc = AutoCommit::new();
+        edit(&mut doc, &mut storage, 1);
+        edit(&mut doc, &mut storage, 2);
+        assert_eq!(storage.read_log("doc").unwrap().chunks.len(), 2);
+
+        fs::remove_file(&snapshots).unwrap();
+        edit(&mut doc, &mut storage, 3);
+        let keys = storage.list("doc").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kind, ChunkKind::Snapshot);
+        assert_eq!(value(&storage), 3);
+    }
+
+    #[test]
+    fn compacts_once_the_log_is_large() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0)
+            .unwrap()
+            .with_compaction_threshold(1000);
+        let mut doc = AutoCommit::new();
+        for i in 0..100 {
+            edit(&mut doc, &mut storage, i);
+        }
+        assert_eq!(value(&storage), 99);
+        let keys = storage.list("doc").unwrap();
+        let snapshots = keys
+            .iter()
+            .filter(|k| k.kind == ChunkKind::Snapshot)
+            .count();
+        assert_eq!(snapshots, 1);
+        assert!(keys.len() < 100);
+        assert!(fs::metadata(storage.log_path("doc"))
+            .map(|m| m.len() <= 1000)
+            .unwrap_or(true));
+
+        let loaded = load(&storage, "doc").unwrap().unwrap();
+        assert_eq!(loaded.get_heads(), doc.get_heads());
+    }
+
+    #[test]
+    fn explicit_compaction_through_the_storage_trait() {
+        let dir = TempDir::new();
+        let mut storage = FsStorage::open(&dir.0).unwrap();
+        let mut doc = AutoCommit::new();
+        for i in 0..5 {
+            edit(&mut doc, &mut storage, i);
+        }
+        let mut other = AutoCommit::new();
+        other.put(ROOT, "other", true).unwrap();
+        persistence::append(&mut storage, "other", &other.save_incremental()).unwrap();
+
+        compact(&mut storage, "doc").unwrap();
+        let keys = storage.list("doc").unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kind, ChunkKind::Snapshot);
+        assert_eq!(value(&storage), 4);
+
+        // Other documents are untouched
+        assert_eq!(storage.list("other").unwrap().len(), 1);
+        let loaded = load(&storage, "other").unwrap().unwrap();
+        assert_eq!(loaded.get_heads(), other.get_heads());
+    }
+}
@@ -0,0 +1,530 @@
+//! Deciding which incoming changes to accept
+//!
+//! By default [`crate::Automerge::apply_changes()`] and
+//! [`crate::sync::SyncDoc::receive_sync_message()`] accept any well formed change from any actor.
+//! Applications which need to enforce permissions, for example peers which may only read a
+//! document or may only edit part of it, can instead pass a [`ChangePolicy`] to
+//! [`crate::Automerge::apply_changes_with_policy()`] or
+//! [`crate::Automerge::receive_sync_message_with_policy()`].
+//!
+//! The policy is called with an [`IncomingChange`] for every change just before it is applied,
+//! at which point every change it depends on is already in the document. If the policy rejects
+//! the change then neither it nor any change which depends on it is applied, and they are
+//! returned to the caller as a list of [`Rejected`] changes.
+//!
+//! Changes which are not causally ready are queued until the changes they depend on arrive, and
+//! are checked by the policy of the call which delivers those. If that is a call without a policy,
+//! such as [`crate::Automerge::apply_changes()`], the queued changes are accepted.
+//!
+//! ## Example
+//!
+//! ```
+//! use automerge::{policy::{IncomingChange, Rejection}, transaction::Transactable, AutoCommit, Prop};
+//!
+//! let mut doc = AutoCommit::new();
+//! doc.put(automerge::ROOT, "title", "shared")?;
+//! let mut other = doc.fork();
+//! other.put(automerge::ROOT, "title", "overwritten")?;
+//! let change = other.get_last_local_change().unwrap().clone();
+//!
+//! // Nobody may change the title
+//! let mut policy = |change: &IncomingChange<'_>| {
+//!     if change.ops().iter().any(|op| op.prop == Some(Prop::from("title"))) {
+//!         Err("the title is read only")
+//!     } else {
+//!         Ok(())
+//!     }
+//! };
+//! let rejected = doc.apply_changes_with_policy([change], &mut policy)?;
+//! assert_eq!(rejected[0].reason, Rejection::Policy("the title is read only"));
+//! # Ok::<(), automerge::AutomergeError>(())
+//! ```
+use std::collections::HashMap;
+use std::convert::Infallible;
+
+use crate::columnar::Key as EncodedKey;
+use crate::op_set::OpSet;
+use crate::patches::TextRepresentation;
+use crate::types::{ListEncoding, ObjId, OpId};
+use crate::{ActorId, Change, ChangeHash, ObjType, OpType, Prop};
+
+/// Decides whether to accept incoming changes
+///
+/// This is implemented for closures which take an [`IncomingChange`] and return a
+/// `Result<(), E>`.
+pub trait ChangePolicy {
+    /// The reason a change was rejected
+    type Error;
+
+    /// Accept `change` by returning `Ok(())` or reject it by returning an error
+    fn check(&mut self, change: &IncomingChange<'_>) -> Result<(), Self::Error>;
+}
+
+impl<F, E> ChangePolicy for F
+where
+    F: FnMut(&IncomingChange<'_>) -> Result<(), E>,
+{
+    type Error = E;
+
+    fn check(&mut self, change: &IncomingChange<'_>) -> Result<(), E> {
+        self(change)
+    }
+}
+
+/// The policy used when the caller didn't provide one
+pub(crate) struct AcceptAll;
+
+impl ChangePolicy for AcceptAll {
+    type Error = Infallible;
+
+    fn check(&mut self, _change: &IncomingChange<'_>) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// A change which was not applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected<E> {
+    pub hash: ChangeHash,
+    pub actor: ActorId,
+    pub reason: Rejection<E>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection<E> {
+    /// The policy rejected the change
+    Policy(E),
+    /// The change depends on this change, which was rejected
+    Dependency(ChangeHash),
+}
+
+/// A change which is about to be applied, see [`ChangePolicy`]
+#[derive(Debug, Clone)]
+pub struct IncomingChange<'a> {
+    change: &'a Change,
+    ops: Vec<IncomingOp>,
+}
+
+/// An operation in an [`IncomingChange`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct IncomingOp {
+    /// The path from the root of the document to the object this operation modifies, or `None` if
+    /// the object doesn't exist in the document
+    ///
+    /// Indexes in the path are the indexes of elements in the document before the change is
+    /// applied. Objects created by this change have a path made from the path of the object they
+    /// are created in and the key or (estimated) index they are created at.
+    pub path: Option<Vec<Prop>>,
+    /// The key in a map, or the index in a list or text object, this operation modifies
+    ///
+    /// For an insertion this is the index the new element is inserted at. Like the indexes in
+    /// `path` this doesn't take account of the rest of the change or of concurrent changes, so
+    /// it is only an estimate of where the element will end up. It is `None` if the element
+    /// this operation refers to can't be found.
+    pub prop: Option<Prop>,
+    pub action: OpType,
+    /// Whether this operation inserts a new element into a list or text object
+    pub insert: bool,
+}
+
+impl<'a> IncomingChange<'a> {
+    /// Describe `change` relative to the current state of `ops`
+    ///
+    /// Every dependency of `change` must already have been applied to `ops`.
+    pub(crate) fn new(ops: &OpSet, change: &'a Change) -> Self {
+        let actors = std::iter::once(change.actor_id())
+            .chain(change.other_actor_ids())
+            .collect::<Vec<_>>();
+        let author = actors[0];
+        let start_op = change.start_op().get();
+        // The objects and sequence elements created by earlier operations in this change
+        let mut created = HashMap::<u64, (Option<Vec<Prop>>, ObjType)>::new();
+        let mut inserted = HashMap::<u64, usize>::new();
+
+        let mut incoming = Vec::new();
+        for (i, op) in change.iter_ops().enumerate() {
+            let counter = start_op + i as u64;
+            let (path, obj, typ) = if op.obj.is_root() {
+                (Some(Vec::new()), Some(ObjId::root()), Some(ObjType::Map))
+            } else {
+                let id = op.obj.opid();
+                let actor = actors[id.actor()];
+                if actor == author && created.contains_key(&id.counter()) {
+                    let (path, typ) = created[&id.counter()].clone();
+                    (path, None, Some(typ))
+                } else {
+                    let obj = ops
+                        .osd
+                        .actors
+                        .lookup(actor)
+                        .map(|idx| ObjId(OpId::new(id.counter(), idx)))
+                        .filter(|obj| ops.obj_type(obj).is_some());
+                    let typ = obj.and_then(|obj| ops.obj_type(&obj));
+                    let path = obj.map(|obj| {
+                        ops.parents(obj, TextRepresentation::default(), None)
+                            .path()
+                            .into_iter()
+                            .map(|(_, prop)| prop)
+                            .collect()
+                    });
+                    (path, obj, typ)
+                }
+            };
+
+            let prop = match &op.key {
+                EncodedKey::Prop(key) => Some(Prop::Map(key.to_string())),
+                EncodedKey::Elem(elem) => {
+                    let index = if elem.is_head() {
+                        Some(0)
+                    } else {
+                        let id = elem.0;
+                        let actor = actors[id.actor()];
+                        if actor == author && inserted.contains_key(&id.counter()) {
+                            Some(inserted[&id.counter()] + usize::from(op.insert))
+                        } else {
+                            let encoding = typ
+                                .map(|typ| TextRepresentation::default().encoding(typ))
+                                .unwrap_or(ListEncoding::List);
+                            obj.zip(ops.osd.actors.lookup(actor))
+                                .and_then(|(obj, idx)| {
+                                    ops.seek_list_opid(
+                                        &obj,
+                                        OpId::new(id.counter(), idx),
+                                        encoding,
+                                        None,
+                                    )
+                                })
+                                .map(|found| found.index + usize::from(op.insert))
+                        }
+                    };
+                    if op.insert {
+                        if let Some(index) = index {
+                            inserted.insert(counter, index);
+                        }
+                    }
+                    index.map(Prop::Seq)
+                }
+            };
+
+            let action = OpType::from_action_and_value(op.action, op.val, op.mark_name, op.expand);
+            if let OpType::Make(typ) = action {
+                let child_path = path.clone().zip(prop.clone()).map(|(mut path, prop)| {
+                    path.push(prop);
+                    path
+                });
+                created.insert(counter, (child_path, typ));
+            }
+            incoming.push(IncomingOp {
+                path,
+                prop,
+                action,
+                insert: op.insert,
+            });
+        }
+
+        IncomingChange {
+            change,
+            ops: incoming,
+        }
+    }
+
+    /// The actor who made this change
+    pub fn actor_id(&self) -> &ActorId {
+        self.change.actor_id()
+    }
+
+    pub fn hash(&self) -> ChangeHash {
+        self.change.hash()
+    }
+
+    /// The change itself, for access to its metadata
+    pub fn change(&self) -> &Change {
+        self.change
+    }
+
+    /// The operations in this change, in the order they will be applied
+    pub fn ops(&self) -> &[IncomingOp] {
+        &self.ops
+    }
+
+    /// The distinct paths of the objects this change modifies
+    ///
+    /// Operations on objects which don't exist in the document are not included, see
+    /// [`Self::modifies_unknown_objects()`].
+    pub fn paths(&self) -> Vec<&[Prop]> {
+        let mut paths = self
+            .ops
+            .iter()
+            .filter_map(|op| op.path.as_deref())
+            .collect::<Vec<_>>();
+        paths.sort_by(|a, b| a.iter().cmp(b.iter()));
+        paths.dedup();
+        paths
+    }
+
+    /// Whether any operation in this change modifies an object which doesn't exist in the
+    /// document
+    pub fn modifies_unknown_objects(&self) -> bool {
+        self.ops.iter().any(|op| op.path.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{self, SyncDoc};
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, Automerge, ReadDoc, ROOT};
+
+    #[derive(Debug, PartialEq)]
+    struct ReadOnly(ActorId);
+
+    /// Only `writer` may make changes, and only underneath `/public`
+    fn subtree_policy(writer: ActorId) -> impl FnMut(&IncomingChange<'_>) -> Result<(), ReadOnly> {
+        move |change: &IncomingChange<'_>| {
+            if change.actor_id() != &writer {
+                return Err(ReadOnly(change.actor_id().clone()));
+            }
+            let in_public = |op: &IncomingOp| {
+                let path = op.path.as_deref().unwrap_or_default();
+                path.first() == Some(&Prop::from("public"))
+                    || (path.is_empty() && op.prop == Some(Prop::from("public")))
+            };
+            if change.ops().iter().all(in_public) {
+                Ok(())
+            } else {
+                Err(ReadOnly(change.actor_id().clone()))
+            }
+        }
+    }
+
+    #[test]
+    fn incoming_changes_describe_paths() {
+        let mut doc = AutoCommit::new();
+        let list = doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        doc.insert(&list, 0, "a").unwrap();
+        doc.insert(&list, 1, "b").unwrap();
+        doc.commit();
+        let base = doc.get_heads();
+
+        let map = doc.insert_object(&list, 2, ObjType::Map).unwrap();
+        doc.put(&map, "key", "value").unwrap();
+        doc.put(&list, 0, "c").unwrap();
+        doc.commit();
+        let change = doc.get_last_local_change().unwrap().clone();
+
+        let mut before = doc.fork_at(&base).unwrap();
+        let incoming = IncomingChange::new(before.document().ops(), &change);
+        let ops = incoming
+            .ops()
+            .iter()
+            .map(|op| {
+                (
+                    op.path.clone().unwrap(),
+                    op.prop.clone().unwrap(),
+                    op.insert,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                (vec![Prop::from("list")], Prop::Seq(2), true),
+                (
+                    vec![Prop::from("list"), Prop::Seq(2)],
+                    Prop::from("key"),
+                    false
+                ),
+                (vec![Prop::from("list")], Prop::Seq(0), false),
+            ]
+        );
+        assert_eq!(
+            incoming.paths(),
+            vec![
+                &[Prop::from("list")][..],
+                &[Prop::from("list"), Prop::Seq(2)][..]
+            ]
+        );
+        assert!(!incoming.modifies_unknown_objects());
+    }
+
+    #[test]
+    fn rejected_changes_and_their_dependents_are_not_applied() {
+        let mut doc = Automerge::new();
+        let mut writer = AutoCommit::new();
+        writer.put_object(ROOT, "public", ObjType::Map).unwrap();
+        writer.commit();
+        let allowed = writer.get_last_local_change().unwrap().clone();
+
+        let mut other = writer.fork();
+        other.put(ROOT, "private", "secret").unwrap();
+        other.commit();
+        let forbidden = other.get_last_local_change().unwrap().clone();
+        other.put(ROOT, "more", "stuff").unwrap();
+        other.commit();
+        let dependent = other.get_last_local_change().unwrap().clone();
+
+        let mut policy = subtree_policy(writer.get_actor().clone());
+        // deliver the dependent first so that it is queued when its dependency is rejected
+        let rejected = doc
+            .apply_changes_with_policy(
+                [dependent.clone(), forbidden.clone(), allowed.clone()],
+                &mut policy,
+            )
+            .unwrap();
+        assert_eq!(
+            rejected,
+            vec![
+                Rejected {
+                    hash: forbidden.hash(),
+                    actor: other.get_actor().clone(),
+                    reason: Rejection::Policy(ReadOnly(other.get_actor().clone())),
+                },
+                Rejected {
+                    hash: dependent.hash(),
+                    actor: other.get_actor().clone(),
+                    reason: Rejection::Dependency(forbidden.hash()),
+                },
+            ]
+        );
+        assert_eq!(doc.get_heads(), vec![allowed.hash()]);
+        assert!(doc.get(ROOT, "private").unwrap().is_none());
+        // nothing was left in the queue
+        assert!(doc.get_missing_deps(&[]).is_empty());
+    }
+
+    #[test]
+    fn queued_changes_are_checked_by_the_call_which_makes_them_ready() {
+        let mut writer = AutoCommit::new();
+        writer.put_object(ROOT, "public", ObjType::Map).unwrap();
+        writer.commit();
+        let allowed = writer.get_last_local_change().unwrap().clone();
+
+        let mut other = writer.fork();
+        other.put(ROOT, "private", "secret").unwrap();
+        other.commit();
+        let forbidden = other.get_last_local_change().unwrap().clone();
+
+        // the forbidden change arrives before its dependency and is queued
+        let mut policy = subtree_policy(writer.get_actor().clone());
+        let mut checked = Automerge::new();
+        let rejected = checked
+            .apply_changes_with_policy([forbidden.clone()], &mut policy)
+            .unwrap();
+        assert!(rejected.is_empty());
+        assert_eq!(checked.get_missing_deps(&[]), vec![allowed.hash()]);
+        let mut unchecked = checked.clone();
+
+        // delivering the dependency with a policy checks the queued change
+        let rejected = checked
+            .apply_changes_with_policy([allowed.clone()], &mut policy)
+            .unwrap();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].hash, forbidden.hash());
+        assert_eq!(checked.get_heads(), vec![allowed.hash()]);
+
+        // delivering it without a policy accepts the queued change
+        unchecked.apply_changes([allowed.clone()]).unwrap();
+        assert_eq!(unchecked.get_heads(), vec![forbidden.hash()]);
+        assert!(unchecked.get_missing_deps(&[]).is_empty());
+    }
+
+    #[test]
+    fn dependents_of_changes_rejected_by_an_earlier_call_are_rejected() {
+        let mut doc = Automerge::new();
+        let mut writer = AutoCommit::new();
+        writer.put_object(ROOT, "public", ObjType::Map).unwrap();
+        writer.commit();
+        let allowed = writer.get_last_local_change().unwrap().clone();
+
+        let mut other = writer.fork();
+        other.put(ROOT, "private", "secret").unwrap();
+        other.commit();
+        let forbidden = other.get_last_local_change().unwrap().clone();
+        other.put(ROOT, "more", "stuff").unwrap();
+        other.commit();
+        let dependent = other.get_last_local_change().unwrap().clone();
+
+        let mut policy = subtree_policy(writer.get_actor().clone());
+        let rejected = doc
+            .apply_changes_with_policy([allowed.clone(), forbidden.clone()], &mut policy)
+            .unwrap();
+        assert_eq!(rejected.len(), 1);
+
+        let rejected = doc
+            .apply_changes_with_policy([dependent.clone()], &mut policy)
+            .unwrap();
+        assert_eq!(
+            rejected,
+            vec![Rejected {
+                hash: dependent.hash(),
+                actor: other.get_actor().clone(),
+                reason: Rejection::Dependency(forbidden.hash()),
+            }]
+        );
+        // we don't ask for the rejected change
+        assert!(doc.get_missing_deps(&[dependent.hash()]).is_empty());
+    }
+
+    #[test]
+    fn sync_with_a_policy_converges() {
+        let mut reader = AutoCommit::new();
+        let mut writer = AutoCommit::new();
+        writer.put_object(ROOT, "public", ObjType::Map).unwrap();
+        let public = writer.get(ROOT, "public").unwrap().unwrap().1;
+        writer.put(&public, "title", "hello").unwrap();
+        writer.commit();
+        let mut intruder = writer.fork();
+        intruder.put(&public, "title", "defaced").unwrap();
+        intruder.put(ROOT, "private", "secret").unwrap();
+        intruder.commit();
+        writer.merge(&mut intruder).unwrap();
+        writer.put(&public, "body", "world").unwrap();
+        writer.commit();
+
+        let mut policy = subtree_policy(writer.get_actor().clone());
+        let mut reader_state = sync::State::new();
+        let mut writer_state = sync::State::new();
+        let mut all_rejected = Vec::new();
+        let mut rounds = 0;
+        loop {
+            let to_reader = writer.sync().generate_sync_message(&mut writer_state);
+            if let Some(message) = to_reader.clone() {
+                all_rejected.extend(
+                    reader
+                        .receive_sync_message_with_policy(&mut reader_state, message, &mut policy)
+                        .unwrap(),
+                );
+            }
+            let to_writer = reader.sync().generate_sync_message(&mut reader_state);
+            if let Some(message) = to_writer.clone() {
+                writer
+                    .sync()
+                    .receive_sync_message(&mut writer_state, message)
+                    .unwrap();
+            }
+            if to_reader.is_none() && to_writer.is_none() {
+                break;
+            }
+            rounds += 1;
+            assert!(rounds < 10, "sync did not terminate");
+        }
+
+        // the intruder's change was rejected, and so was the writer's change which depends on it
+        assert_eq!(all_rejected.len(), 2);
+        assert_eq!(all_rejected[0].actor, intruder.get_actor().clone());
+        assert!(matches!(all_rejected[1].reason, Rejection::Dependency(_)));
+        assert_eq!(reader_state.rejected_hashes.len(), 2);
+        // the rejections survive persisting the sync state, in an encoding which versions that
+        // don't know about them refuse to decode
+        let encoded = reader_state.encode();
+        assert_ne!(encoded[0], sync::State::new().encode()[0]);
+        let decoded = sync::State::decode(&encoded).unwrap();
+        assert_eq!(decoded.rejected_hashes, reader_state.rejected_hashes);
+        assert!(reader.get(ROOT, "private").unwrap().is_none());
+        assert_eq!(
+            reader.get(&public, "title").unwrap().unwrap().0.to_str(),
+            Some("hello")
+        );
+    }
+}
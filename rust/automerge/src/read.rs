@@ -1,11 +1,13 @@
 use crate::{
-    error::AutomergeError,
+    error::{AutomergeError, PathError},
     exid::ExId,
+    history::{BlameSpan, HistoryEntry},
     hydrate,
     iter::Spans,
     iter::{Keys, ListRange, MapRange, Values},
     marks::{Mark, MarkSet},
     parents::Parents,
+    path::IntoPath,
     Change, ChangeHash, Cursor, ObjType, Prop, Value,
 };
 
@@ -218,6 +220,26 @@ pub trait ReadDoc {
         prop: P,
     ) -> Result<Option<(Value<'_>, ExId)>, AutomergeError>;
 
+    /// Get a value nested inside `obj` by following `path`
+    ///
+    /// `path` is either a sequence of [`Prop`]s or an RFC 6901 JSON pointer such as
+    /// `"/todos/0/title"`, see [`crate::path`]. The empty path refers to `obj` itself.
+    ///
+    /// Like [`Self::get()`] this returns `None` if the final segment of the path does not exist.
+    ///
+    /// ### Errors
+    ///
+    /// Returns an error identifying the segment of the path which could not be resolved if an
+    /// intermediate value is missing or is not an object, if an index is out of range, or if a
+    /// segment cannot be used with the type of object it is applied to.
+    fn get_path<O: AsRef<ExId>, P: IntoPath>(
+        &self,
+        obj: O,
+        path: P,
+    ) -> Result<Option<(Value<'_>, ExId)>, PathError> {
+        crate::path::get_path(self, obj, path)
+    }
+
     /// Get the value of the given key as at `heads`, see [`Self::get()`]
     fn get_at<O: AsRef<ExId>, P: Into<Prop>>(
         &self,
@@ -253,6 +275,63 @@ pub trait ReadDoc {
         heads: &[ChangeHash],
     ) -> Result<Vec<(Value<'_>, ExId)>, AutomergeError>;
 
+    /// Get every value which `prop` in `obj` has held, in the order they were set
+    ///
+    /// For a sequence `prop` is the index of an element and the result starts with the value the
+    /// element was inserted with. Each entry records the change which set the value and whether
+    /// it has since been overwritten or deleted. Increments of counters are not separate entries.
+    /// Operations which have not been committed yet are not included.
+    fn history<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+    ) -> Result<Vec<HistoryEntry>, AutomergeError>;
+
+    /// Get every value which `prop` in `obj` had held as at `heads`
+    ///
+    /// See [`Self::history()`]
+    fn history_at<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<HistoryEntry>, AutomergeError>;
+
+    /// Get the hashes of the changes which created or modified `obj`, in the order they were
+    /// applied to this document
+    ///
+    /// Changes which only modified objects nested inside `obj` are not included.
+    fn object_changes<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<ChangeHash>, AutomergeError>;
+
+    /// Get the hashes of the changes which created or modified `obj` as at `heads`
+    ///
+    /// See [`Self::object_changes()`]
+    fn object_changes_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError>;
+
+    /// Attribute the text in `obj` to the changes which inserted it
+    ///
+    /// The result is a list of spans covering the whole text, in order, where each span is a run
+    /// of characters inserted by the same change. Text which has not been committed yet is
+    /// attributed to no change.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `obj` is not a text object.
+    fn blame<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<BlameSpan>, AutomergeError>;
+
+    /// Attribute the text in `obj` as at `heads` to the changes which inserted it
+    ///
+    /// See [`Self::blame()`]
+    fn blame_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<BlameSpan>, AutomergeError>;
+
     /// Get the hashes of the changes in this document that aren't transitive dependencies of the
     /// given `heads`.
     fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash>;
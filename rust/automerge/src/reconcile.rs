@@ -0,0 +1,815 @@
+//! Typed reconciliation of Rust values with a document
+//!
+//! The [`Reconcile`] trait writes a Rust value into a document by comparing it with what is
+//! already there and emitting only the operations needed to make the document match. The
+//! [`Hydrate`] trait is the inverse, reading a Rust value out of a document.
+//!
+//! Both traits can be derived for structs and enums using the `automerge-derive` crate, which is
+//! re-exported from this module when the `derive` feature is enabled. Structs with named fields
+//! are stored as maps, newtype structs are stored as their inner value and enums are stored as a
+//! string for unit variants or a map with a single key naming the variant otherwise.
+//!
+//! Reconciliation tries hard to preserve the identity of existing objects so that concurrent
+//! changes merge well:
+//!
+//! * Maps and lists which already exist at a location are updated in place rather than replaced
+//! * [`Text`] values are updated with a diff which is turned into `splice_text` calls
+//! * [`Counter`] values are updated with `increment` rather than being overwritten
+//! * Scalars are only written if they differ from the current value
+//!
+//! # Example
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use automerge::reconcile::{hydrate, reconcile, Counter, Hydrate, Reconcile, Text};
+//! use automerge::AutoCommit;
+//! # use automerge::reconcile::{HydrateTypedError, ReconcileError, Slot};
+//! # use automerge::transaction::Transactable;
+//! # use automerge::{ObjId, ReadDoc, Value};
+//!
+//! // Usually you would `#[derive(Reconcile, Hydrate)]` using the `automerge-derive` crate
+//! #[derive(Debug, PartialEq)]
+//! struct Note {
+//!     body: Text,
+//!     likes: Counter,
+//! }
+//! # impl Reconcile for Note {
+//! #     fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+//! #         let mut map = slot.map()?;
+//! #         map.entry("body").reconcile(&self.body)?;
+//! #         map.entry("likes").reconcile(&self.likes)?;
+//! #         Ok(())
+//! #     }
+//! # }
+//! # impl Hydrate for Note {
+//! #     fn hydrate_value<R: ReadDoc>(
+//! #         doc: &R,
+//! #         value: Option<(Value<'_>, ObjId)>,
+//! #     ) -> Result<Self, HydrateTypedError> {
+//! #         let obj = automerge::reconcile::expect_map(value)?;
+//! #         Ok(Note {
+//! #             body: Hydrate::hydrate(doc, &obj, "body")?,
+//! #             likes: Hydrate::hydrate(doc, &obj, "likes")?,
+//! #         })
+//! #     }
+//! # }
+//!
+//! let mut doc = AutoCommit::new();
+//! let mut note = Note {
+//!     body: Text::from("hello"),
+//!     likes: Counter(0),
+//! };
+//! reconcile(&mut doc, &note)?;
+//!
+//! note.body = Text::from("hello world");
+//! note.likes = Counter(1);
+//! // Produces a single splice and a single increment
+//! reconcile(&mut doc, &note)?;
+//!
+//! let read: Note = hydrate(&doc)?;
+//! assert_eq!(read, note);
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+
+use crate::exid::ExId;
+use crate::transaction::Transactable;
+use crate::{ObjType, Prop, ReadDoc, ScalarValue, Value, ROOT};
+
+pub use crate::error::{HydrateTypedError, ReconcileError};
+
+#[cfg(feature = "derive")]
+pub use automerge_derive::{Hydrate, Reconcile};
+
+/// A value which can be written into a document with a minimal set of operations
+pub trait Reconcile {
+    /// Make the value at `slot` match `self`
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError>;
+}
+
+/// A value which can be read out of a document
+pub trait Hydrate: Sized {
+    /// Read the value of `prop` in `obj`
+    fn hydrate<R: ReadDoc, P: Into<Prop>>(
+        doc: &R,
+        obj: &ExId,
+        prop: P,
+    ) -> Result<Self, HydrateTypedError> {
+        let prop = prop.into();
+        let value = doc
+            .get(obj, prop.clone())
+            .map_err(|e| HydrateTypedError::from(e).at(prop.clone()))?;
+        Self::hydrate_value(doc, value).map_err(|e| e.at(prop))
+    }
+
+    /// Read a value given the result of [`ReadDoc::get`] for the location it is stored at
+    ///
+    /// `value` is `None` if there is nothing stored at the location
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError>;
+}
+
+/// Reconcile `value` with the root map of `doc`
+pub fn reconcile<D: Transactable, T: Reconcile + ?Sized>(
+    doc: &mut D,
+    value: &T,
+) -> Result<(), ReconcileError> {
+    value.reconcile(Slot {
+        doc,
+        location: Location::Root,
+    })
+}
+
+/// Reconcile `value` with the property `prop` of `obj`
+pub fn reconcile_prop<D: Transactable, T: Reconcile + ?Sized, O: AsRef<ExId>, P: Into<Prop>>(
+    doc: &mut D,
+    obj: O,
+    prop: P,
+    value: &T,
+) -> Result<(), ReconcileError> {
+    value.reconcile(Slot {
+        doc,
+        location: Location::Put(obj.as_ref().clone(), prop.into()),
+    })
+}
+
+/// Read a value from the root map of `doc`
+pub fn hydrate<R: ReadDoc, T: Hydrate>(doc: &R) -> Result<T, HydrateTypedError> {
+    T::hydrate_value(doc, Some((Value::Object(ObjType::Map), ROOT)))
+}
+
+/// Read a value from the property `prop` of `obj`
+pub fn hydrate_prop<R: ReadDoc, T: Hydrate, O: AsRef<ExId>, P: Into<Prop>>(
+    doc: &R,
+    obj: O,
+    prop: P,
+) -> Result<T, HydrateTypedError> {
+    T::hydrate(doc, obj.as_ref(), prop)
+}
+
+/// Where in the document a [`Slot`] will write its value
+#[derive(Debug, Clone)]
+enum Location {
+    /// The root map of the document
+    Root,
+    /// Overwrite the value of a property in an object
+    Put(ExId, Prop),
+    /// Insert a new element into a list
+    Insert(ExId, usize),
+}
+
+/// A location in a document which a [`Reconcile`] implementation writes to
+///
+/// Each of the methods on a slot consumes it, a slot can only be written to once.
+#[derive(Debug)]
+pub struct Slot<'a, D> {
+    doc: &'a mut D,
+    location: Location,
+}
+
+impl<'a, D: Transactable> Slot<'a, D> {
+    /// The value currently stored at this location
+    ///
+    /// This is always `None` for a slot which inserts into a list
+    pub fn current(&self) -> Result<Option<(Value<'_>, ExId)>, ReconcileError> {
+        match &self.location {
+            Location::Root => Ok(Some((Value::Object(ObjType::Map), ROOT))),
+            Location::Put(obj, prop) => Ok(self.doc.get(obj, prop.clone())?),
+            Location::Insert(_, _) => Ok(None),
+        }
+    }
+
+    /// Reconcile `value` with this location
+    ///
+    /// This is a convenience for `value.reconcile(slot)`
+    pub fn reconcile<T: Reconcile + ?Sized>(self, value: &T) -> Result<(), ReconcileError> {
+        value.reconcile(self)
+    }
+
+    /// Store a scalar value, unless the current value is already equal to it
+    pub fn put<V: Into<ScalarValue>>(self, value: V) -> Result<(), ReconcileError> {
+        let value = value.into();
+        match self.location {
+            Location::Root => Err(ReconcileError::RootNotMap),
+            Location::Put(obj, prop) => {
+                if let Some((Value::Scalar(current), _)) = self.doc.get(&obj, prop.clone())? {
+                    if current.as_ref() == &value {
+                        return Ok(());
+                    }
+                }
+                Ok(self.doc.put(&obj, prop, value)?)
+            }
+            Location::Insert(obj, index) => Ok(self.doc.insert(&obj, index, value)?),
+        }
+    }
+
+    /// Store a counter, incrementing the existing counter if there is one
+    pub fn counter(self, value: i64) -> Result<(), ReconcileError> {
+        let current = match self.current()? {
+            Some((Value::Scalar(s), _)) => match s.as_ref() {
+                ScalarValue::Counter(c) => Some(i64::from(c)),
+                _ => None,
+            },
+            _ => None,
+        };
+        match (current, &self.location) {
+            (Some(current), Location::Put(obj, prop)) => {
+                if current != value {
+                    self.doc.increment(obj, prop.clone(), value - current)?;
+                }
+                Ok(())
+            }
+            _ => self.put(ScalarValue::counter(value)),
+        }
+    }
+
+    /// Store a text object, updating the existing text object with a diff if there is one
+    pub fn text(mut self, text: &str) -> Result<(), ReconcileError> {
+        let existing = match self.current()? {
+            Some((Value::Object(ObjType::Text), id)) => Some(id),
+            _ => None,
+        };
+        match existing {
+            Some(id) => Ok(self.doc.update_text(&id, text)?),
+            None => {
+                let id = self.create(ObjType::Text)?;
+                Ok(self.doc.splice_text(&id, 0, 0, text)?)
+            }
+        }
+    }
+
+    /// Obtain a map at this location, creating it if the current value is not a map
+    pub fn map(mut self) -> Result<MapReconciler<'a, D>, ReconcileError> {
+        let obj = match self.current()? {
+            Some((Value::Object(ObjType::Map), id)) => id,
+            _ => self.create(ObjType::Map)?,
+        };
+        Ok(MapReconciler { doc: self.doc, obj })
+    }
+
+    /// Obtain a list at this location, creating it if the current value is not a list
+    pub fn list(mut self) -> Result<ListReconciler<'a, D>, ReconcileError> {
+        let obj = match self.current()? {
+            Some((Value::Object(ObjType::List), id)) => id,
+            _ => self.create(ObjType::List)?,
+        };
+        Ok(ListReconciler { doc: self.doc, obj })
+    }
+
+    fn create(&mut self, typ: ObjType) -> Result<ExId, ReconcileError> {
+        match &self.location {
+            Location::Root => Err(ReconcileError::RootNotMap),
+            Location::Put(obj, prop) => Ok(self.doc.put_object(obj, prop.clone(), typ)?),
+            Location::Insert(obj, index) => Ok(self.doc.insert_object(obj, *index, typ)?),
+        }
+    }
+}
+
+/// Reconciles the entries of a map, obtained from [`Slot::map`]
+#[derive(Debug)]
+pub struct MapReconciler<'a, D> {
+    doc: &'a mut D,
+    obj: ExId,
+}
+
+impl<'a, D: Transactable> MapReconciler<'a, D> {
+    /// The ID of the map being reconciled
+    pub fn obj(&self) -> &ExId {
+        &self.obj
+    }
+
+    /// The slot for the value of `key`
+    pub fn entry<S: Into<String>>(&mut self, key: S) -> Slot<'_, D> {
+        Slot {
+            doc: &mut *self.doc,
+            location: Location::Put(self.obj.clone(), Prop::Map(key.into())),
+        }
+    }
+
+    /// Delete every key in the map for which `keep` returns false
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) -> Result<(), ReconcileError> {
+        let delenda = self
+            .doc
+            .keys(&self.obj)
+            .filter(|k| !keep(k))
+            .collect::<Vec<_>>();
+        for key in delenda {
+            self.doc.delete(&self.obj, key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reconciles the elements of a list, obtained from [`Slot::list`]
+#[derive(Debug)]
+pub struct ListReconciler<'a, D> {
+    doc: &'a mut D,
+    obj: ExId,
+}
+
+impl<'a, D: Transactable> ListReconciler<'a, D> {
+    /// The ID of the list being reconciled
+    pub fn obj(&self) -> &ExId {
+        &self.obj
+    }
+
+    /// The current length of the list
+    pub fn len(&self) -> usize {
+        self.doc.length(&self.obj)
+    }
+
+    /// Whether the list is currently empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The slot for the existing element at `index`
+    pub fn element(&mut self, index: usize) -> Slot<'_, D> {
+        Slot {
+            doc: &mut *self.doc,
+            location: Location::Put(self.obj.clone(), Prop::Seq(index)),
+        }
+    }
+
+    /// A slot which inserts a new element at `index`
+    pub fn insert(&mut self, index: usize) -> Slot<'_, D> {
+        Slot {
+            doc: &mut *self.doc,
+            location: Location::Insert(self.obj.clone(), index),
+        }
+    }
+
+    /// Delete the element at `index`
+    pub fn delete(&mut self, index: usize) -> Result<(), ReconcileError> {
+        Ok(self.doc.delete(&self.obj, index)?)
+    }
+
+    /// Reconcile the list positionally with `items`
+    ///
+    /// Elements which exist in both the list and `items` are reconciled in place, new elements
+    /// are inserted at the end and surplus elements are deleted from the end.
+    pub fn reconcile_all<'i, T: Reconcile + 'i, I: IntoIterator<Item = &'i T>>(
+        mut self,
+        items: I,
+    ) -> Result<(), ReconcileError> {
+        let old_len = self.len();
+        let mut index = 0;
+        for item in items {
+            if index < old_len {
+                item.reconcile(self.element(index))?;
+            } else {
+                item.reconcile(self.insert(index))?;
+            }
+            index += 1;
+        }
+        for i in (index..old_len).rev() {
+            self.delete(i)?;
+        }
+        Ok(())
+    }
+}
+
+/// A string which is stored as a text object rather than a string scalar
+///
+/// Reconciling a `Text` with an existing text object performs a diff and applies it using
+/// `splice_text`, so concurrent edits to the text merge well.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Text(pub String);
+
+impl Text {
+    /// The contents of the text
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Text {
+    fn from(s: &str) -> Self {
+        Text(s.to_string())
+    }
+}
+
+impl From<String> for Text {
+    fn from(s: String) -> Self {
+        Text(s)
+    }
+}
+
+impl std::fmt::Display for Text {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// An integer which is stored as a counter
+///
+/// Reconciling a `Counter` with an existing counter increments the existing counter by the
+/// difference, so concurrent changes to the counter are summed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Counter(pub i64);
+
+/// Check that `value` is a map and return its ID
+pub fn expect_map(value: Option<(Value<'_>, ExId)>) -> Result<ExId, HydrateTypedError> {
+    match value {
+        Some((Value::Object(ObjType::Map | ObjType::Table), id)) => Ok(id),
+        other => Err(HydrateTypedError::unexpected(
+            "a map",
+            other.map(|(v, _)| v),
+        )),
+    }
+}
+
+/// Check that `value` is a list and return its ID
+pub fn expect_list(value: Option<(Value<'_>, ExId)>) -> Result<ExId, HydrateTypedError> {
+    match value {
+        Some((Value::Object(ObjType::List), id)) => Ok(id),
+        other => Err(HydrateTypedError::unexpected(
+            "a list",
+            other.map(|(v, _)| v),
+        )),
+    }
+}
+
+/// Check that `value` is a scalar and return it
+pub fn expect_scalar(value: Option<(Value<'_>, ExId)>) -> Result<ScalarValue, HydrateTypedError> {
+    match value {
+        Some((Value::Scalar(s), _)) => Ok(s.into_owned()),
+        other => Err(HydrateTypedError::unexpected(
+            "a scalar",
+            other.map(|(v, _)| v),
+        )),
+    }
+}
+
+impl<T: Reconcile + ?Sized> Reconcile for &T {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        (*self).reconcile(slot)
+    }
+}
+
+impl<T: Reconcile + ?Sized> Reconcile for Box<T> {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        self.as_ref().reconcile(slot)
+    }
+}
+
+impl<T: Hydrate> Hydrate for Box<T> {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        T::hydrate_value(doc, value).map(Box::new)
+    }
+}
+
+impl Reconcile for str {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.put(self)
+    }
+}
+
+impl Reconcile for String {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.put(self.as_str())
+    }
+}
+
+impl Hydrate for String {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        match value {
+            Some((Value::Scalar(s), _)) if s.is_str() => Ok(s.into_owned().into_string().unwrap()),
+            Some((Value::Object(ObjType::Text), id)) => Ok(doc.text(id)?),
+            other => Err(HydrateTypedError::unexpected(
+                "a string",
+                other.map(|(v, _)| v),
+            )),
+        }
+    }
+}
+
+impl Reconcile for Text {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.text(&self.0)
+    }
+}
+
+impl Hydrate for Text {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        String::hydrate_value(doc, value).map(Text)
+    }
+}
+
+impl Reconcile for Counter {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.counter(self.0)
+    }
+}
+
+impl Hydrate for Counter {
+    fn hydrate_value<R: ReadDoc>(
+        _doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        match value {
+            Some((Value::Scalar(s), _)) => match s.as_ref() {
+                ScalarValue::Counter(c) => Ok(Counter(i64::from(c))),
+                ScalarValue::Int(i) => Ok(Counter(*i)),
+                _ => Err(HydrateTypedError::unexpected(
+                    "a counter",
+                    Some(Value::Scalar(s)),
+                )),
+            },
+            other => Err(HydrateTypedError::unexpected(
+                "a counter",
+                other.map(|(v, _)| v),
+            )),
+        }
+    }
+}
+
+macro_rules! scalar_impls {
+    ($($ty:ty => $expected:literal, $to:ident, $conv:expr;)*) => {
+        $(
+            impl Reconcile for $ty {
+                fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+                    slot.put(*self)
+                }
+            }
+
+            impl Hydrate for $ty {
+                fn hydrate_value<R: ReadDoc>(
+                    _doc: &R,
+                    value: Option<(Value<'_>, ExId)>,
+                ) -> Result<Self, HydrateTypedError> {
+                    let scalar = expect_scalar(value)?;
+                    scalar
+                        .$to()
+                        .and_then($conv)
+                        .ok_or_else(|| HydrateTypedError::unexpected($expected, Some(Value::Scalar(std::borrow::Cow::Owned(scalar)))))
+                }
+            }
+        )*
+    };
+}
+
+scalar_impls! {
+    bool => "a boolean", to_bool, Some;
+    i64 => "an integer", to_i64, Some;
+    i32 => "an integer", to_i64, |i| i32::try_from(i).ok();
+    u64 => "an unsigned integer", to_u64, Some;
+    u32 => "an unsigned integer", to_u64, |u| u32::try_from(u).ok();
+    f64 => "a float", to_f64, Some;
+}
+
+impl<T: Reconcile> Reconcile for Option<T> {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        match self {
+            Some(v) => v.reconcile(slot),
+            None => slot.put(ScalarValue::Null),
+        }
+    }
+}
+
+impl<T: Hydrate> Hydrate for Option<T> {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        match value {
+            None => Ok(None),
+            Some((Value::Scalar(s), _)) if s.is_null() => Ok(None),
+            other => T::hydrate_value(doc, other).map(Some),
+        }
+    }
+}
+
+impl<T: Reconcile> Reconcile for Vec<T> {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.list()?.reconcile_all(self)
+    }
+}
+
+impl<T: Reconcile> Reconcile for [T] {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        slot.list()?.reconcile_all(self)
+    }
+}
+
+impl<T: Hydrate> Hydrate for Vec<T> {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        let list = expect_list(value)?;
+        (0..doc.length(&list))
+            .map(|i| T::hydrate(doc, &list, i))
+            .collect()
+    }
+}
+
+fn reconcile_map<'i, D, T, I>(slot: Slot<'_, D>, entries: I) -> Result<(), ReconcileError>
+where
+    D: Transactable,
+    T: Reconcile + 'i,
+    I: IntoIterator<Item = (&'i String, &'i T)>,
+{
+    let mut map = slot.map()?;
+    let mut keys = std::collections::HashSet::new();
+    for (key, value) in entries {
+        value.reconcile(map.entry(key.as_str()))?;
+        keys.insert(key.as_str());
+    }
+    map.retain(|k| keys.contains(k))
+}
+
+fn hydrate_map<R: ReadDoc, T: Hydrate, C: FromIterator<(String, T)>>(
+    doc: &R,
+    value: Option<(Value<'_>, ExId)>,
+) -> Result<C, HydrateTypedError> {
+    let map = expect_map(value)?;
+    doc.keys(&map)
+        .map(|k| T::hydrate(doc, &map, k.as_str()).map(|v| (k, v)))
+        .collect()
+}
+
+impl<T: Reconcile, S: BuildHasher> Reconcile for HashMap<String, T, S> {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        reconcile_map(slot, self)
+    }
+}
+
+impl<T: Hydrate, S: BuildHasher + Default> Hydrate for HashMap<String, T, S> {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        hydrate_map(doc, value)
+    }
+}
+
+impl<T: Reconcile> Reconcile for BTreeMap<String, T> {
+    fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+        reconcile_map(slot, self)
+    }
+}
+
+impl<T: Hydrate> Hydrate for BTreeMap<String, T> {
+    fn hydrate_value<R: ReadDoc>(
+        doc: &R,
+        value: Option<(Value<'_>, ExId)>,
+    ) -> Result<Self, HydrateTypedError> {
+        hydrate_map(doc, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{AutoCommit, ReadDoc};
+
+    #[derive(Debug, PartialEq, Clone)]
+    struct Note {
+        title: String,
+        body: Text,
+        likes: Counter,
+        tags: Vec<String>,
+        extra: HashMap<String, i64>,
+    }
+
+    impl Reconcile for Note {
+        fn reconcile<D: Transactable>(&self, slot: Slot<'_, D>) -> Result<(), ReconcileError> {
+            let mut map = slot.map()?;
+            map.entry("title").reconcile(&self.title)?;
+            map.entry("body").reconcile(&self.body)?;
+            map.entry("likes").reconcile(&self.likes)?;
+            map.entry("tags").reconcile(&self.tags)?;
+            map.entry("extra").reconcile(&self.extra)?;
+            Ok(())
+        }
+    }
+
+    impl Hydrate for Note {
+        fn hydrate_value<R: ReadDoc>(
+            doc: &R,
+            value: Option<(Value<'_>, ExId)>,
+        ) -> Result<Self, HydrateTypedError> {
+            let obj = expect_map(value)?;
+            Ok(Note {
+                title: Hydrate::hydrate(doc, &obj, "title")?,
+                body: Hydrate::hydrate(doc, &obj, "body")?,
+                likes: Hydrate::hydrate(doc, &obj, "likes")?,
+                tags: Hydrate::hydrate(doc, &obj, "tags")?,
+                extra: Hydrate::hydrate(doc, &obj, "extra")?,
+            })
+        }
+    }
+
+    fn note() -> Note {
+        Note {
+            title: "shopping".to_string(),
+            body: Text::from("eggs and milk"),
+            likes: Counter(1),
+            tags: vec!["food".to_string()],
+            extra: HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]),
+        }
+    }
+
+    fn ops_in_last_change(doc: &mut AutoCommit) -> usize {
+        doc.commit();
+        doc.get_last_local_change().map(|c| c.len()).unwrap_or(0)
+    }
+
+    #[test]
+    fn reconcile_and_hydrate_roundtrip() {
+        let mut doc = AutoCommit::new();
+        let note = note();
+        reconcile(&mut doc, &note).unwrap();
+        let read: Note = hydrate(&doc).unwrap();
+        assert_eq!(read, note);
+    }
+
+    #[test]
+    fn reconciling_an_unchanged_value_produces_no_ops() {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &note()).unwrap();
+        doc.commit();
+        reconcile(&mut doc, &note()).unwrap();
+        assert_eq!(doc.pending_ops(), 0);
+    }
+
+    #[test]
+    fn text_and_counters_are_updated_in_place() {
+        let mut doc = AutoCommit::new();
+        let mut note = note();
+        reconcile(&mut doc, &note).unwrap();
+        doc.commit();
+        let (_, body) = doc.get(ROOT, "body").unwrap().unwrap();
+
+        let mut fork = doc.fork();
+        reconcile_prop(&mut fork, ROOT, "likes", &Counter(3)).unwrap();
+        fork.commit();
+
+        note.body = Text::from("eggs and oat milk");
+        note.likes = Counter(2);
+        reconcile(&mut doc, &note).unwrap();
+        // one splice of "oat " (four insert ops) and one increment
+        assert_eq!(ops_in_last_change(&mut doc), 5);
+        assert_eq!(doc.get(ROOT, "body").unwrap().unwrap().1, body);
+
+        doc.merge(&mut fork).unwrap();
+        let merged: Note = hydrate(&doc).unwrap();
+        assert_eq!(merged.likes, Counter(4));
+        assert_eq!(merged.body.as_str(), "eggs and oat milk");
+    }
+
+    #[test]
+    fn lists_and_maps_are_trimmed() {
+        let mut doc = AutoCommit::new();
+        let mut note = note();
+        note.tags.push("dairy".to_string());
+        reconcile(&mut doc, &note).unwrap();
+        doc.commit();
+
+        note.tags = vec!["food".to_string()];
+        note.extra.remove("b");
+        reconcile(&mut doc, &note).unwrap();
+        assert_eq!(ops_in_last_change(&mut doc), 2);
+        assert_eq!(hydrate::<_, Note>(&doc).unwrap(), note);
+    }
+
+    #[test]
+    fn hydrate_errors_report_path() {
+        let mut doc = AutoCommit::new();
+        reconcile(&mut doc, &note()).unwrap();
+        let (_, tags) = doc.get(ROOT, "tags").unwrap().unwrap();
+        doc.put(&tags, 0, 5).unwrap();
+        let err = hydrate::<_, Note>(&doc).unwrap_err();
+        assert_eq!(err.path(), &[Prop::Map("tags".to_string()), Prop::Seq(0)]);
+    }
+
+    #[test]
+    fn option_none_is_null() {
+        let mut doc = AutoCommit::new();
+        reconcile_prop(&mut doc, ROOT, "maybe", &Option::<i64>::None).unwrap();
+        assert_eq!(
+            doc.get(ROOT, "maybe").unwrap().unwrap().0,
+            Value::Scalar(std::borrow::Cow::Owned(ScalarValue::Null))
+        );
+        let read: Option<i64> = hydrate_prop(&doc, ROOT, "maybe").unwrap();
+        assert_eq!(read, None);
+        let read: Option<i64> = hydrate_prop(&doc, ROOT, "missing").unwrap();
+        assert_eq!(read, None);
+    }
+}
@@ -0,0 +1,665 @@
+//! Managing many documents which are kept saved and in sync with other peers
+//!
+//! A [`Repo`] holds a set of [`AutoCommit`] documents, each identified by a [`DocumentId`], along
+//! with a [`Storage`] to save them to and any number of connected peers. Documents are accessed
+//! through a [`DocHandle`], which can be cloned and shared between threads. Whenever a document
+//! is changed through its handle, or by a message from a peer, the new changes are appended to
+//! storage, subscribers are sent the resulting patches and every connected peer is sent a sync
+//! message.
+//!
+//! Every document in the repo is shared with every connected peer, and a peer receiving a message
+//! about a document it doesn't have yet creates it.
+//!
+//! Peers are connected with a [`NetworkAdapter`], which carries the [`sync::Batch`]es of a
+//! [`sync::Multiplexer`]. Incoming messages are processed by calling [`Repo::poll()`], so the
+//! application decides which thread does the work.
+//!
+//! ## Example
+//!
+//! ```
+//! use automerge::{
+//!     persistence::MemoryStorage,
+//!     repo::{MemoryAdapter, Repo},
+//!     transaction::Transactable,
+//!     ReadDoc,
+//! };
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let alice = Repo::new(MemoryStorage::new());
+//! let bob = Repo::new(MemoryStorage::new());
+//! let (to_bob, to_alice) = MemoryAdapter::pair();
+//! alice.connect("bob", to_bob);
+//! bob.connect("alice", to_alice);
+//!
+//! let handle = alice.create();
+//! handle.with_doc_mut(|doc| doc.put(automerge::ROOT, "key", "value"))??;
+//!
+//! while alice.poll()? + bob.poll()? > 0 {}
+//!
+//! let found = bob.find(&handle.id())?.expect("bob has the document");
+//! found.with_doc(|doc| {
+//!     assert_eq!(doc.get(automerge::ROOT, "key").unwrap().unwrap().0.to_str(), Some("value"));
+//! });
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::persistence::{self, Storage};
+use crate::sync::{self, DocumentId, Multiplexer};
+use crate::{AutoCommit, AutomergeError, ChangeHash, Patch};
+
+mod network;
+pub use network::{MemoryAdapter, NetworkAdapter};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The name a [`Repo`] knows a connected peer by
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId(String);
+
+impl PeerId {
+    /// The name as a string
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for PeerId {
+    fn from(s: &str) -> Self {
+        Self(s.to_string())
+    }
+}
+
+impl From<String> for PeerId {
+    fn from(s: String) -> Self {
+        Self(s)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Errors from loading, saving or changing a document in a [`Repo`]
+#[derive(Debug, thiserror::Error)]
+pub enum RepoError {
+    /// The [`Storage`] failed to load or save the document
+    #[error("storage error: {0}")]
+    Storage(#[source] BoxError),
+    /// The document could not be loaded from the data in storage
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+}
+
+/// The messages which [`Repo::poll()`] failed to process
+///
+/// Every other message was processed as usual.
+#[derive(Debug, thiserror::Error)]
+#[error("failed to process {} of {} messages", .errors.len(), .processed)]
+pub struct PollError {
+    /// How many messages there were, including the ones which failed
+    pub processed: usize,
+    /// The peer and document of each message which failed, and why
+    pub errors: Vec<(PeerId, DocumentId, RepoError)>,
+}
+
+/// [`Storage`] with the error type erased, so that [`Repo`] doesn't need a type parameter
+trait DynStorage: Send {
+    fn load(&self, document: &str) -> Result<Option<Vec<u8>>, BoxError>;
+    fn append(&mut self, document: &str, changes: &[u8]) -> Result<(), BoxError>;
+}
+
+impl<S> DynStorage for S
+where
+    S: Storage + Send,
+    S::Error: Send + Sync,
+{
+    fn load(&self, document: &str) -> Result<Option<Vec<u8>>, BoxError> {
+        persistence::load_bytes(self, document).map_err(Into::into)
+    }
+
+    fn append(&mut self, document: &str, changes: &[u8]) -> Result<(), BoxError> {
+        persistence::append(self, document, changes).map_err(Into::into)
+    }
+}
+
+struct Inner {
+    documents: HashMap<DocumentId, DocHandle>,
+    peers: BTreeMap<PeerId, Box<dyn NetworkAdapter>>,
+    sync: Multiplexer<PeerId>,
+}
+
+/// A collection of documents which are saved to storage and synced with peers
+///
+/// Cloning a `Repo` gives another reference to the same repo.
+#[derive(Clone)]
+pub struct Repo {
+    inner: Arc<Mutex<Inner>>,
+    /// Kept apart from `inner` so that storage I/O doesn't block every other document and peer
+    storage: Arc<Mutex<dyn DynStorage>>,
+}
+
+impl fmt::Debug for Repo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = lock(&self.inner);
+        f.debug_struct("Repo")
+            .field("documents", &inner.documents.keys().collect::<Vec<_>>())
+            .field("peers", &inner.peers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Repo {
+    /// Create a repo with no documents or peers which saves documents to `storage`
+    ///
+    /// Documents already in `storage` are loaded when they are first asked for with
+    /// [`Self::find()`].
+    pub fn new<S>(storage: S) -> Self
+    where
+        S: Storage + Send + 'static,
+        S::Error: Send + Sync,
+    {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                documents: HashMap::new(),
+                peers: BTreeMap::new(),
+                sync: Multiplexer::new(),
+            })),
+            storage: Arc::new(Mutex::new(storage)),
+        }
+    }
+
+    /// Create a new empty document with a random ID
+    pub fn create(&self) -> DocHandle {
+        let handle = self.insert(DocumentId::random(), AutoCommit::new(), Vec::new());
+        handle.announce();
+        handle
+    }
+
+    /// Find a document which is either already in memory or in storage
+    ///
+    /// Documents which only exist on other peers are not requested from them, but they will be
+    /// found once a connected peer has sent them.
+    pub fn find(&self, id: &DocumentId) -> Result<Option<DocHandle>, RepoError> {
+        if let Some(handle) = lock(&self.inner).documents.get(id) {
+            return Ok(Some(handle.clone()));
+        }
+        let data = lock(&self.storage)
+            .load(&id.to_string())
+            .map_err(RepoError::Storage)?;
+        let data = match data {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let mut doc = AutoCommit::load(&data)?;
+        let saved = doc.get_heads();
+        let handle = self.insert(id.clone(), doc, saved);
+        handle.announce();
+        Ok(Some(handle))
+    }
+
+    /// The IDs of the documents which are currently in memory
+    pub fn documents(&self) -> Vec<DocumentId> {
+        lock(&self.inner).documents.keys().cloned().collect()
+    }
+
+    /// Start syncing every document with `peer`
+    ///
+    /// If `peer` was already connected the old connection is replaced and syncing starts over.
+    pub fn connect<P: Into<PeerId>, A: NetworkAdapter + 'static>(&self, peer: P, adapter: A) {
+        let peer = peer.into();
+        let handles = {
+            let mut inner = lock(&self.inner);
+            inner.sync.remove_peer(&peer);
+            inner.peers.insert(peer, Box::new(adapter));
+            inner.documents.values().cloned().collect::<Vec<_>>()
+        };
+        for handle in handles {
+            handle.announce();
+        }
+    }
+
+    /// Stop syncing with `peer`
+    pub fn disconnect(&self, peer: &PeerId) {
+        let mut inner = lock(&self.inner);
+        inner.peers.remove(peer);
+        inner.sync.remove_peer(peer);
+    }
+
+    /// The peers which are currently connected
+    pub fn peers(&self) -> Vec<PeerId> {
+        lock(&self.inner).peers.keys().cloned().collect()
+    }
+
+    /// Process every sync message in the batches waiting on the connected peers' adapters,
+    /// returning how many there were
+    ///
+    /// Messages which can't be applied are logged and dropped. If a message can't be processed
+    /// because its document can't be loaded or saved, the rest are still processed and the
+    /// failures are returned together as a [`PollError`]. Presence messages are ignored.
+    pub fn poll(&self) -> Result<usize, PollError> {
+        let incoming = {
+            let mut inner = lock(&self.inner);
+            let mut incoming = Vec::new();
+            for (peer, adapter) in inner.peers.iter_mut() {
+                while let Some(batch) = adapter.try_recv() {
+                    incoming.extend(batch.messages.into_iter().map(|m| (peer.clone(), m)));
+                }
+            }
+            incoming
+        };
+        let processed = incoming.len();
+        let mut errors = Vec::new();
+        for (peer, (document, message)) in incoming {
+            let result = self.find(&document).and_then(|handle| {
+                let handle = match handle {
+                    Some(handle) => handle,
+                    None => self.insert(document.clone(), AutoCommit::new(), Vec::new()),
+                };
+                handle.receive(&peer, message)
+            });
+            if let Err(error) = result {
+                errors.push((peer, document, error));
+            }
+        }
+        if errors.is_empty() {
+            Ok(processed)
+        } else {
+            Err(PollError { processed, errors })
+        }
+    }
+
+    /// Add a document, or return the existing handle if another thread got there first
+    fn insert(&self, id: DocumentId, mut doc: AutoCommit, saved: Vec<ChangeHash>) -> DocHandle {
+        doc.update_diff_cursor();
+        let handle = DocHandle {
+            id: id.clone(),
+            state: Arc::new(Mutex::new(DocState {
+                doc,
+                saved,
+                subscribers: Vec::new(),
+            })),
+            repo: Arc::downgrade(&self.inner),
+            storage: Arc::downgrade(&self.storage),
+        };
+        lock(&self.inner)
+            .documents
+            .entry(id)
+            .or_insert(handle)
+            .clone()
+    }
+}
+
+struct DocState {
+    doc: AutoCommit,
+    /// The heads of the document as of the last time it was written to storage
+    saved: Vec<ChangeHash>,
+    subscribers: Vec<Sender<Vec<Patch>>>,
+}
+
+/// A reference to a document in a [`Repo`]
+///
+/// Handles can be cloned and sent to other threads, all clones refer to the same document.
+#[derive(Clone)]
+pub struct DocHandle {
+    id: DocumentId,
+    state: Arc<Mutex<DocState>>,
+    repo: Weak<Mutex<Inner>>,
+    storage: Weak<Mutex<dyn DynStorage>>,
+}
+
+impl fmt::Debug for DocHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DocHandle").field("id", &self.id).finish()
+    }
+}
+
+impl DocHandle {
+    /// The ID of the document, which peers and storage know it by
+    pub fn id(&self) -> DocumentId {
+        self.id.clone()
+    }
+
+    /// Call `f` with the document
+    pub fn with_doc<R, F: FnOnce(&AutoCommit) -> R>(&self, f: F) -> R {
+        f(&lock(&self.state).doc)
+    }
+
+    /// Call `f` to change the document, then save the changes and send them to subscribers and
+    /// peers
+    pub fn with_doc_mut<R, F: FnOnce(&mut AutoCommit) -> R>(&self, f: F) -> Result<R, RepoError> {
+        let mut state = lock(&self.state);
+        let result = f(&mut state.doc);
+        self.changed(&mut state)?;
+        Ok(result)
+    }
+
+    /// The current heads of the document
+    pub fn heads(&self) -> Vec<ChangeHash> {
+        lock(&self.state).doc.get_heads()
+    }
+
+    /// Receive the patches for every subsequent change to the document, whether made locally or
+    /// by a peer
+    pub fn subscribe(&self) -> Receiver<Vec<Patch>> {
+        let (tx, rx) = mpsc::channel();
+        lock(&self.state).subscribers.push(tx);
+        rx
+    }
+
+    fn receive(&self, peer: &PeerId, message: sync::Message) -> Result<(), RepoError> {
+        let mut state = lock(&self.state);
+        let repo = match self.repo.upgrade() {
+            Some(repo) => repo,
+            None => return Ok(()),
+        };
+        let result =
+            lock(&repo)
+                .sync
+                .receive_sync_message(peer, &self.id, &mut state.doc.sync(), message);
+        if let Err(error) = result {
+            tracing::warn!(%peer, document = %self.id, %error, "failed to apply sync message");
+        }
+        self.changed(&mut state)
+    }
+
+    /// Send every connected peer a sync message, if there is anything to say
+    fn announce(&self) {
+        let mut state = lock(&self.state);
+        if let Some(repo) = self.repo.upgrade() {
+            self.sync_peers(&mut state, &mut lock(&repo));
+        }
+    }
+
+    fn changed(&self, state: &mut DocState) -> Result<(), RepoError> {
+        let patches = state.doc.diff_incremental();
+        if !patches.is_empty() {
+            state
+                .subscribers
+                .retain(|subscriber| subscriber.send(patches.clone()).is_ok());
+        }
+        let (repo, storage) = match (self.repo.upgrade(), self.storage.upgrade()) {
+            (Some(repo), Some(storage)) => (repo, storage),
+            _ => return Ok(()),
+        };
+
+        // Peers are only told about changes once they are saved
+        let heads = state.doc.get_heads();
+        if heads != state.saved {
+            let changes = state.doc.save_after(&state.saved);
+            lock(&storage)
+                .append(&self.id.to_string(), &changes)
+                .map_err(RepoError::Storage)?;
+            state.saved = heads;
+        }
+        self.sync_peers(state, &mut lock(&repo));
+        Ok(())
+    }
+
+    fn sync_peers(&self, state: &mut DocState, inner: &mut Inner) {
+        let Inner { peers, sync, .. } = inner;
+        for (peer, adapter) in peers.iter_mut() {
+            let docs = [(&self.id, state.doc.sync())];
+            if let Some(batch) = sync.generate_batch(peer, docs) {
+                adapter.send(batch);
+            }
+        }
+    }
+}
+
+/// Locks are only held while a document or the repo is being updated, so if a thread panicked
+/// while holding one the state is still usable
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persistence::{ChunkKey, MemoryStorage};
+    use crate::transaction::Transactable;
+    use crate::{ReadDoc, ROOT};
+
+    /// A [`MemoryStorage`] which fails to store chunks of one document
+    struct FailingStorage {
+        inner: MemoryStorage,
+        broken: String,
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("storage is broken")]
+    struct Broken;
+
+    impl Storage for FailingStorage {
+        type Error = Broken;
+
+        fn get(&self, key: &ChunkKey) -> Result<Option<Vec<u8>>, Broken> {
+            Ok(self.inner.get(key).unwrap())
+        }
+
+        fn put(&mut self, key: &ChunkKey, data: &[u8]) -> Result<(), Broken> {
+            if key.document == self.broken {
+                return Err(Broken);
+            }
+            self.inner.put(key, data).unwrap();
+            Ok(())
+        }
+
+        fn list(&self, document: &str) -> Result<Vec<ChunkKey>, Broken> {
+            Ok(self.inner.list(document).unwrap())
+        }
+
+        fn delete(&mut self, key: &ChunkKey) -> Result<(), Broken> {
+            self.inner.delete(key).unwrap();
+            Ok(())
+        }
+    }
+
+    fn settle(repos: &[&Repo]) {
+        for _ in 0..100 {
+            let processed = repos.iter().map(|r| r.poll().unwrap()).sum::<usize>();
+            if processed == 0 {
+                return;
+            }
+        }
+        panic!("repos did not settle");
+    }
+
+    fn connect(left: &Repo, left_name: &str, right: &Repo, right_name: &str) {
+        let (to_right, to_left) = MemoryAdapter::pair();
+        left.connect(right_name, to_right);
+        right.connect(left_name, to_left);
+    }
+
+    fn get_str(handle: &DocHandle, key: &str) -> Option<String> {
+        handle.with_doc(|doc| {
+            doc.get(ROOT, key)
+                .unwrap()
+                .and_then(|(v, _)| v.to_str().map(String::from))
+        })
+    }
+
+    #[test]
+    fn changes_reach_peers_and_subscribers() {
+        let alice = Repo::new(MemoryStorage::new());
+        let bob = Repo::new(MemoryStorage::new());
+        connect(&alice, "alice", &bob, "bob");
+
+        let handle = alice.create();
+        let local = handle.subscribe();
+        handle
+            .with_doc_mut(|doc| doc.put(ROOT, "greeting", "hello"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(local.try_iter().count(), 1);
+
+        settle(&[&alice, &bob]);
+        let found = bob.find(&handle.id()).unwrap().unwrap();
+        assert_eq!(get_str(&found, "greeting").as_deref(), Some("hello"));
+
+        let remote = handle.subscribe();
+        found
+            .with_doc_mut(|doc| doc.put(ROOT, "reply", "hi"))
+            .unwrap()
+            .unwrap();
+        settle(&[&alice, &bob]);
+        let patches = remote.try_iter().flatten().collect::<Vec<_>>();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(get_str(&handle, "reply").as_deref(), Some("hi"));
+        assert_eq!(handle.heads(), found.heads());
+    }
+
+    #[test]
+    fn documents_are_loaded_from_storage() {
+        let storage = MemoryStorage::new();
+        let repo = Repo::new(storage.clone());
+        let handle = repo.create();
+        for value in ["one", "two", "three"] {
+            handle
+                .with_doc_mut(|doc| doc.put(ROOT, "value", value))
+                .unwrap()
+                .unwrap();
+        }
+        drop(repo);
+
+        let restarted = Repo::new(storage.clone());
+        assert!(restarted.documents().is_empty());
+        let found = restarted.find(&handle.id()).unwrap().unwrap();
+        assert_eq!(get_str(&found, "value").as_deref(), Some("three"));
+        assert_eq!(found.heads(), handle.heads());
+        assert!(restarted.find(&DocumentId::random()).unwrap().is_none());
+
+        // Loading doesn't write the document out again
+        let chunks = storage.list(&handle.id().to_string()).unwrap().len();
+        found
+            .with_doc_mut(|doc| doc.put(ROOT, "value", "four"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            storage.list(&handle.id().to_string()).unwrap().len(),
+            chunks + 1
+        );
+    }
+
+    #[test]
+    fn changes_are_relayed_and_resynced_after_reconnecting() {
+        let alice = Repo::new(MemoryStorage::new());
+        let bob = Repo::new(MemoryStorage::new());
+        let carol = Repo::new(MemoryStorage::new());
+        connect(&alice, "alice", &bob, "bob");
+        connect(&bob, "bob", &carol, "carol");
+
+        let handle = alice.create();
+        handle
+            .with_doc_mut(|doc| doc.put(ROOT, "from", "alice"))
+            .unwrap()
+            .unwrap();
+        settle(&[&alice, &bob, &carol]);
+        let at_carol = carol.find(&handle.id()).unwrap().unwrap();
+        assert_eq!(get_str(&at_carol, "from").as_deref(), Some("alice"));
+
+        bob.disconnect(&PeerId::from("carol"));
+        carol.disconnect(&PeerId::from("bob"));
+        at_carol
+            .with_doc_mut(|doc| doc.put(ROOT, "offline", "carol"))
+            .unwrap()
+            .unwrap();
+        settle(&[&alice, &bob, &carol]);
+        assert_eq!(get_str(&handle, "offline"), None);
+
+        connect(&bob, "bob", &carol, "carol");
+        settle(&[&alice, &bob, &carol]);
+        assert_eq!(get_str(&handle, "offline").as_deref(), Some("carol"));
+        assert_eq!(handle.heads(), at_carol.heads());
+    }
+
+    #[test]
+    fn handles_can_be_used_from_other_threads() {
+        let alice = Repo::new(MemoryStorage::new());
+        let bob = Repo::new(MemoryStorage::new());
+        connect(&alice, "alice", &bob, "bob");
+        let handle = alice.create();
+
+        let threads = (0..4)
+            .map(|i| {
+                let handle = handle.clone();
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        handle
+                            .with_doc_mut(|doc| doc.put(ROOT, format!("{}-{}", i, j), j))
+                            .unwrap()
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        settle(&[&alice, &bob]);
+        let found = bob.find(&handle.id()).unwrap().unwrap();
+        assert_eq!(found.with_doc(|doc| doc.length(ROOT)), 40);
+    }
+
+    #[test]
+    fn changes_which_fail_to_save_are_not_sent_to_peers() {
+        let id = DocumentId::random();
+        let alice = Repo::new(FailingStorage {
+            inner: MemoryStorage::new(),
+            broken: id.to_string(),
+        });
+        let bob = Repo::new(MemoryStorage::new());
+        connect(&alice, "alice", &bob, "bob");
+
+        let handle = alice.insert(id.clone(), AutoCommit::new(), Vec::new());
+        let result = handle.with_doc_mut(|doc| doc.put(ROOT, "key", "value"));
+        assert!(matches!(result, Err(RepoError::Storage(_))));
+        settle(&[&alice, &bob]);
+        assert!(bob.find(&id).unwrap().is_none());
+    }
+
+    #[test]
+    fn poll_processes_the_other_messages_when_one_fails() {
+        let alice = Repo::new(MemoryStorage::new());
+        let first = alice.create();
+        let second = alice.create();
+        for handle in [&first, &second] {
+            handle
+                .with_doc_mut(|doc| doc.put(ROOT, "key", "value"))
+                .unwrap()
+                .unwrap();
+        }
+        let bob = Repo::new(FailingStorage {
+            inner: MemoryStorage::new(),
+            broken: first.id().to_string(),
+        });
+        connect(&alice, "alice", &bob, "bob");
+
+        let mut failed = Vec::new();
+        for _ in 0..100 {
+            let processed = match bob.poll() {
+                Ok(processed) => processed,
+                Err(error) => {
+                    failed.extend(error.errors.into_iter().map(|(peer, id, _)| (peer, id)));
+                    error.processed
+                }
+            };
+            if processed + alice.poll().unwrap() == 0 {
+                break;
+            }
+        }
+        assert!(!failed.is_empty());
+        assert!(failed
+            .iter()
+            .all(|(peer, id)| peer.as_str() == "alice" && *id == first.id()));
+        let found = bob.find(&second.id()).unwrap().unwrap();
+        assert_eq!(found.heads(), second.heads());
+        assert_eq!(get_str(&found, "key").as_deref(), Some("value"));
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::sync::Batch;
+
+/// A connection to another peer
+///
+/// A [`super::Repo`] sends a [`Batch`] with [`Self::send()`] whenever a document changes, and
+/// picks up incoming batches with [`Self::try_recv()`] from [`super::Repo::poll()`]. An adapter
+/// for a real connection can send them as [`Batch::encode()`] and read them back with
+/// [`Batch::decode()`], dropping any which can't be decoded. Batches must be delivered in order.
+/// If the connection breaks, batches may be lost, in which case the peer should be connected
+/// again with [`super::Repo::connect()`] so that syncing starts over.
+pub trait NetworkAdapter: Send {
+    fn send(&mut self, batch: Batch);
+
+    /// The next batch from the peer, or [`None`] if nothing is waiting
+    fn try_recv(&mut self) -> Option<Batch>;
+}
+
+/// A [`NetworkAdapter`] which passes batches over a channel, for connecting repos in the same
+/// process
+#[derive(Debug)]
+pub struct MemoryAdapter {
+    outgoing: Sender<Batch>,
+    incoming: Receiver<Batch>,
+}
+
+impl MemoryAdapter {
+    /// Two adapters connected to each other
+    pub fn pair() -> (Self, Self) {
+        let (left_tx, right_rx) = mpsc::channel();
+        let (right_tx, left_rx) = mpsc::channel();
+        (
+            Self {
+                outgoing: left_tx,
+                incoming: left_rx,
+            },
+            Self {
+                outgoing: right_tx,
+                incoming: right_rx,
+            },
+        )
+    }
+}
+
+impl NetworkAdapter for MemoryAdapter {
+    fn send(&mut self, batch: Batch) {
+        // The other end has gone away, which is no different to the batch being lost in transit
+        let _ = self.outgoing.send(batch);
+    }
+
+    fn try_recv(&mut self) -> Option<Batch> {
+        self.incoming.try_recv().ok()
+    }
+}
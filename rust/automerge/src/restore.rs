@@ -0,0 +1,464 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::exid::ExId;
+use crate::iter::Span;
+use crate::marks::{ExpandMark, Mark};
+use crate::patches::TextRepresentation;
+use crate::transaction::Transactable;
+use crate::types::OpType;
+use crate::{
+    hydrate, Automerge, AutomergeError, ChangeHash, ObjType, Patch, PatchAction, Prop, ReadDoc,
+    ScalarValue, Value, ROOT,
+};
+
+/// What [`apply`] needs in order to restore a document to the state at some heads
+pub(crate) struct Restoration {
+    /// The patches which take the current state of the document to the state at the heads
+    patches: Vec<Patch>,
+    /// The expand setting of the marks in each text object as at the heads, with later marks
+    /// after earlier ones
+    expands: HashMap<ExId, Vec<(String, ScalarValue, ExpandMark)>>,
+}
+
+impl Restoration {
+    /// The expand setting of the last mark in `obj` named `name` with `value`
+    fn expand(&self, obj: &ExId, name: &str, value: &ScalarValue) -> ExpandMark {
+        self.expands
+            .get(obj)
+            .and_then(|marks| marks.iter().rev().find(|(n, v, _)| n == name && v == value))
+            .map(|(_, _, expand)| *expand)
+            .unwrap_or_default()
+    }
+}
+
+/// Calculate the patches which take the current state of `doc` to the state at `heads`, see
+/// [`Automerge::restore_to`]
+///
+/// Patches to objects which are not currently visible are dropped. Those objects are recreated
+/// from scratch when the patch which makes them visible again is applied.
+pub(crate) fn restoration(
+    doc: &Automerge,
+    heads: &[ChangeHash],
+) -> Result<Restoration, AutomergeError> {
+    for hash in heads {
+        doc.get_change_by_hash(hash)
+            .ok_or(AutomergeError::InvalidHash(*hash))?;
+    }
+    let mut visible = HashMap::new();
+    let mut patches = Vec::new();
+    for patch in doc.diff(&doc.get_heads(), heads, TextRepresentation::String) {
+        let is_visible = match visible.get(&patch.obj) {
+            Some(v) => *v,
+            None => {
+                let v = patch.obj == ROOT || doc.parents(&patch.obj)?.visible_path().is_some();
+                visible.insert(patch.obj.clone(), v);
+                v
+            }
+        };
+        if is_visible {
+            patches.push(patch);
+        }
+    }
+    Ok(Restoration {
+        patches,
+        expands: mark_expands(doc, heads),
+    })
+}
+
+/// Read the expand setting of every mark in every text object as at `heads`
+///
+/// [`Mark`] doesn't carry its expand setting so this comes from the mark operations themselves.
+fn mark_expands(
+    doc: &Automerge,
+    heads: &[ChangeHash],
+) -> HashMap<ExId, Vec<(String, ScalarValue, ExpandMark)>> {
+    let clock = doc.clock_at(heads);
+    let mut result = HashMap::new();
+    for (obj, _) in doc.ops().iter_objs() {
+        if obj.typ != ObjType::Text {
+            continue;
+        }
+        let mut begins = Vec::new();
+        let mut ends = HashMap::new();
+        for op in doc.ops().iter_ops(&obj.id) {
+            if !clock.covers(op.id()) {
+                continue;
+            }
+            match op.action() {
+                OpType::MarkBegin(before, data) => begins.push((*op.id(), *before, data)),
+                // The end of a mark is always the operation after its beginning
+                OpType::MarkEnd(after) => {
+                    ends.insert(op.id().prev(), *after);
+                }
+                _ => {}
+            }
+        }
+        if begins.is_empty() {
+            continue;
+        }
+        begins.sort_by(|a, b| doc.ops().osd.lamport_cmp(a.0, b.0));
+        let marks = begins
+            .into_iter()
+            .map(|(id, before, data)| {
+                let after = ends.get(&id).copied().unwrap_or(false);
+                (
+                    data.name.to_string(),
+                    data.value.clone(),
+                    ExpandMark::from(before, after),
+                )
+            })
+            .collect();
+        result.insert(doc.id_to_exid(obj.id.0), marks);
+    }
+    result
+}
+
+/// Apply the output of [`restoration`] to `tx`
+pub(crate) fn apply<T: Transactable>(
+    tx: &mut T,
+    mut restoration: Restoration,
+    heads: &[ChangeHash],
+) -> Result<(), AutomergeError> {
+    let mut restore = Restore {
+        heads,
+        texts: Vec::new(),
+    };
+    for Patch { obj, action, .. } in std::mem::take(&mut restoration.patches) {
+        match action {
+            PatchAction::PutMap {
+                key,
+                value: (value, id),
+                ..
+            } => restore.put(tx, &obj, key.into(), value, &id)?,
+            PatchAction::PutSeq {
+                index,
+                value: (value, id),
+                ..
+            } => restore.put(tx, &obj, index.into(), value, &id)?,
+            PatchAction::Insert { index, values } => {
+                for (i, (value, id, _)) in values.iter().enumerate() {
+                    restore.insert(tx, &obj, index + i, value.clone(), id)?;
+                }
+            }
+            PatchAction::SpliceText { index, value, .. } => {
+                tx.splice_text(&obj, index, 0, &value.make_string())?;
+                restore.touch(&obj, &obj);
+            }
+            PatchAction::Increment { prop, value } => tx.increment(&obj, prop, value)?,
+            PatchAction::DeleteMap { key } => tx.delete(&obj, key.as_str())?,
+            PatchAction::DeleteSeq { index, length } => {
+                if tx.object_type(&obj)? == ObjType::Text {
+                    tx.splice_text(&obj, index, length as isize, "")?;
+                    restore.touch(&obj, &obj);
+                } else {
+                    tx.splice(&obj, index, length as isize, std::iter::empty())?;
+                }
+            }
+            // Marks are compared as a whole once the text is back in place
+            PatchAction::Mark { .. } => restore.touch(&obj, &obj),
+            PatchAction::Conflict { .. } => {}
+        }
+    }
+    for (src, dst) in std::mem::take(&mut restore.texts) {
+        restore_marks(tx, &src, &dst, heads, &restoration)?;
+    }
+    Ok(())
+}
+
+struct Restore<'a> {
+    heads: &'a [ChangeHash],
+    /// Pairs of (text object as at `heads`, text object in the new change) whose marks need to be
+    /// restored
+    texts: Vec<(ExId, ExId)>,
+}
+
+impl<'a> Restore<'a> {
+    fn touch(&mut self, src: &ExId, dst: &ExId) {
+        let pair = (src.clone(), dst.clone());
+        if !self.texts.contains(&pair) {
+            self.texts.push(pair);
+        }
+    }
+
+    fn put<T: Transactable>(
+        &mut self,
+        tx: &mut T,
+        obj: &ExId,
+        prop: Prop,
+        value: Value<'_>,
+        src: &ExId,
+    ) -> Result<(), AutomergeError> {
+        match value {
+            Value::Scalar(s) => {
+                if let Some((Value::Scalar(current), _)) = tx.get(obj, prop.clone())? {
+                    if current == s {
+                        return Ok(());
+                    }
+                }
+                tx.put(obj, prop, s.into_owned())
+            }
+            Value::Object(typ) => {
+                let dst = tx.put_object(obj, prop, typ)?;
+                self.fill(tx, src, &dst, typ)
+            }
+        }
+    }
+
+    fn insert<T: Transactable>(
+        &mut self,
+        tx: &mut T,
+        obj: &ExId,
+        index: usize,
+        value: Value<'_>,
+        src: &ExId,
+    ) -> Result<(), AutomergeError> {
+        match value {
+            Value::Scalar(s) => tx.insert(obj, index, s.into_owned()),
+            Value::Object(ObjType::Map) if tx.object_type(obj)? == ObjType::Text => {
+                let dst = tx.split_block(obj, index)?;
+                self.fill(tx, src, &dst, ObjType::Map)
+            }
+            Value::Object(typ) => {
+                let dst = tx.insert_object(obj, index, typ)?;
+                self.fill(tx, src, &dst, typ)
+            }
+        }
+    }
+
+    /// Fill the new object `dst` with the contents of `src` as at the target heads
+    fn fill<T: Transactable>(
+        &mut self,
+        tx: &mut T,
+        src: &ExId,
+        dst: &ExId,
+        typ: ObjType,
+    ) -> Result<(), AutomergeError> {
+        match typ {
+            ObjType::Map | ObjType::Table => {
+                let items = tx
+                    .map_range_at(src, .., self.heads)
+                    .map(|item| (item.key.to_string(), item.value.into_owned(), item.id))
+                    .collect::<Vec<_>>();
+                for (key, value, id) in items {
+                    self.put(tx, dst, key.into(), value, &id)?;
+                }
+            }
+            ObjType::List => {
+                let items = tx
+                    .list_range_at(src, .., self.heads)
+                    .map(|item| (item.value.into_owned(), item.id))
+                    .collect::<Vec<_>>();
+                for (index, (value, id)) in items.into_iter().enumerate() {
+                    self.insert(tx, dst, index, value, &id)?;
+                }
+            }
+            ObjType::Text => {
+                let spans = tx.spans_at(src, self.heads)?.collect::<Vec<_>>();
+                for span in spans {
+                    let index = tx.length(dst);
+                    match span {
+                        Span::Text(text, _) => tx.splice_text(dst, index, 0, &text)?,
+                        Span::Block(block) => {
+                            let id = tx.split_block(dst, index)?;
+                            crate::transaction::fill(tx, &id, &hydrate::Value::Map(block))?;
+                        }
+                    }
+                }
+                self.touch(src, dst);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Set the marks on `dst` to match the marks on `src` as at `heads`, assuming the two have the
+/// same text
+fn restore_marks<T: Transactable>(
+    tx: &mut T,
+    src: &ExId,
+    dst: &ExId,
+    heads: &[ChangeHash],
+    restoration: &Restoration,
+) -> Result<(), AutomergeError> {
+    let target = owned_marks(tx.marks_at(src, heads)?);
+    let current = owned_marks(tx.marks(dst)?);
+    let names = target
+        .iter()
+        .chain(current.iter())
+        .map(|m| m.0.clone())
+        .collect::<BTreeSet<_>>();
+    for name in names {
+        let value_at = |marks: &[(String, ScalarValue, usize, usize)], index: usize| {
+            marks
+                .iter()
+                .find(|(n, _, start, end)| *n == name && *start <= index && index < *end)
+                .map(|(_, value, ..)| value.clone())
+        };
+        let mut points = target
+            .iter()
+            .chain(current.iter())
+            .filter(|m| m.0 == name)
+            .flat_map(|(_, _, start, end)| [*start, *end])
+            .collect::<Vec<_>>();
+        points.sort_unstable();
+        points.dedup();
+
+        // Runs of the text where the mark has the wrong value, with the value it should have
+        let mut runs: Vec<(usize, usize, Option<ScalarValue>)> = Vec::new();
+        for range in points.windows(2) {
+            let (start, end) = (range[0], range[1]);
+            let want = value_at(&target, start);
+            if want == value_at(&current, start) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some(last) if last.1 == start && last.2 == want => last.1 = end,
+                _ => runs.push((start, end, want)),
+            }
+        }
+        for (start, end, value) in runs {
+            match value {
+                Some(value) => {
+                    let expand = restoration.expand(src, &name, &value);
+                    tx.mark(dst, Mark::new(name.clone(), value, start, end), expand)?
+                }
+                None => tx.unmark(dst, &name, start, end, ExpandMark::None)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn owned_marks(marks: Vec<Mark<'_>>) -> Vec<(String, ScalarValue, usize, usize)> {
+    marks
+        .into_iter()
+        .map(|m| (m.name().to_string(), m.value().clone(), m.start, m.end))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::marks::{ExpandMark, Mark};
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, Automerge, AutomergeError, ChangeHash, ObjType, ReadDoc, Value, ROOT};
+
+    fn assert_restored(doc: &mut AutoCommit, heads: &[ChangeHash]) {
+        assert_eq!(
+            doc.hydrate(ROOT, None).unwrap(),
+            doc.hydrate(ROOT, Some(heads)).unwrap()
+        );
+    }
+
+    #[test]
+    fn restore_maps_lists_and_counters() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.put(ROOT, "count", crate::ScalarValue::counter(5))
+            .unwrap();
+        let list = doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        doc.splice(&list, 0, 0, [1.into(), 2.into(), 3.into()])
+            .unwrap();
+        let heads = doc.get_heads();
+
+        doc.put(ROOT, "a", 2).unwrap();
+        doc.put(ROOT, "b", true).unwrap();
+        doc.increment(ROOT, "count", 3).unwrap();
+        doc.delete(&list, 0).unwrap();
+        doc.insert(&list, 2, 4).unwrap();
+        doc.put(&list, 0, 20).unwrap();
+        doc.commit();
+        let changes = doc.get_changes(&[]).len();
+
+        assert!(doc.restore_to(&heads).unwrap().is_some());
+        assert_restored(&mut doc, &heads);
+        assert_eq!(
+            doc.get(ROOT, "count").unwrap().unwrap().0,
+            Value::counter(5)
+        );
+        assert_eq!(doc.get_changes(&[]).len(), changes + 1);
+        let current = doc.get_heads();
+        assert_eq!(doc.restore_to(&current).unwrap(), None);
+    }
+
+    #[test]
+    fn restore_text_with_marks_and_blocks() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello world").unwrap();
+        doc.split_block(&text, 6).unwrap();
+        doc.mark(
+            &text,
+            Mark::new("bold".to_string(), true, 0, 5),
+            ExpandMark::After,
+        )
+        .unwrap();
+        let heads = doc.get_heads();
+        let string = doc.text(&text).unwrap();
+
+        doc.splice_text(&text, 7, 5, "there").unwrap();
+        doc.join_block(&text, 6).unwrap();
+        doc.split_block(&text, 2).unwrap();
+        doc.unmark(&text, "bold", 0, 3, ExpandMark::None).unwrap();
+        doc.mark(
+            &text,
+            Mark::new("italic".to_string(), true, 3, 8),
+            ExpandMark::After,
+        )
+        .unwrap();
+        doc.commit();
+
+        doc.restore_to(&heads).unwrap();
+        assert_eq!(doc.text(&text).unwrap(), string);
+        assert!(matches!(
+            doc.get(&text, 6).unwrap(),
+            Some((Value::Object(ObjType::Map), _))
+        ));
+        assert_eq!(
+            doc.marks(&text).unwrap(),
+            doc.marks_at(&text, &heads).unwrap()
+        );
+    }
+
+    #[test]
+    fn restore_deleted_objects() {
+        let mut doc = Automerge::new();
+        let mut tx = doc.transaction();
+        let todo = tx.put_object(ROOT, "todo", ObjType::Map).unwrap();
+        let title = tx.put_object(&todo, "title", ObjType::Text).unwrap();
+        tx.splice_text(&title, 0, 0, "buy milk").unwrap();
+        tx.mark(
+            &title,
+            Mark::new("bold".to_string(), true, 4, 8),
+            ExpandMark::None,
+        )
+        .unwrap();
+        let tags = tx.put_object(&todo, "tags", ObjType::List).unwrap();
+        tx.insert(&tags, 0, "shopping").unwrap();
+        tx.commit();
+        let heads = doc.get_heads();
+
+        let mut tx = doc.transaction();
+        tx.delete(ROOT, "todo").unwrap();
+        tx.commit();
+
+        doc.restore_to(&heads).unwrap();
+        assert_eq!(doc.hydrate(None), doc.hydrate(Some(&heads)));
+        let (_, todo) = doc.get(ROOT, "todo").unwrap().unwrap();
+        let (_, title) = doc.get(&todo, "title").unwrap().unwrap();
+        let marks = doc.marks(&title).unwrap();
+        assert_eq!(marks, vec![Mark::new("bold".to_string(), true, 4, 8)]);
+
+        // The restored mark keeps its original expand setting
+        let mut tx = doc.transaction();
+        tx.splice_text(&title, 8, 0, "!").unwrap();
+        tx.commit();
+        let marks = doc.marks(&title).unwrap();
+        assert_eq!(marks, vec![Mark::new("bold".to_string(), true, 4, 8)]);
+
+        let missing = ChangeHash([1; 32]);
+        assert!(matches!(
+            doc.restore_to(&[missing]),
+            Err(AutomergeError::InvalidHash(_))
+        ));
+    }
+}
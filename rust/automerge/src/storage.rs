@@ -8,13 +8,15 @@ mod document;
 pub(crate) mod load;
 pub(crate) mod parse;
 pub(crate) mod save;
+pub(crate) mod shallow;
 
 pub use load::VerificationMode;
 pub(crate) use {
     change::{AsChangeOp, Change, ChangeOp, Compressed, ReadChangeOpError},
-    chunk::{CheckSum, Chunk, ChunkType, Header},
+    chunk::{error::Header as HeaderError, CheckSum, Chunk, ChunkType, Header},
     columns::{Columns, MismatchingColumn, RawColumn, RawColumns},
     document::{AsChangeMeta, AsDocOp, ChangeMetadata, CompressConfig, DocOp, Document},
+    shallow::Shallow,
 };
 
 fn shift_range(range: Range<usize>, by: usize) -> Range<usize> {
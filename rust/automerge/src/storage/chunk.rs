@@ -7,18 +7,19 @@ use std::{
 
 use sha2::{Digest, Sha256};
 
-use super::{change::Unverified, parse, Change, Compressed, Document, MAGIC_BYTES};
+use super::{change::Unverified, parse, Change, Compressed, Document, Shallow, MAGIC_BYTES};
 use crate::{columnar::encoding::leb128::ulebsize, ChangeHash};
 
 pub(crate) enum Chunk<'a> {
     Document(Document<'a>),
     Change(Change<'a, Unverified>),
     CompressedChange(Change<'static, Unverified>, Compressed<'a>),
+    Shallow(Shallow<'a>),
 }
 
 pub(crate) mod error {
     use super::parse;
-    use crate::storage::{change, document};
+    use crate::storage::{change, document, shallow};
 
     #[derive(thiserror::Error, Debug)]
     pub(crate) enum Chunk {
@@ -32,6 +33,8 @@ pub(crate) mod error {
         Change(#[from] change::ParseError),
         #[error("bad document chunk: {0}")]
         Document(#[from] document::ParseError),
+        #[error("bad shallow document chunk: {0}")]
+        Shallow(#[from] shallow::ParseError),
         #[error("unable to decompresse compressed chunk")]
         Deflate,
     }
@@ -74,6 +77,14 @@ impl<'a> Chunk<'a> {
                 }
                 Chunk::Document(doc)
             }
+            ChunkType::Shallow => {
+                let (remaining, shallow) =
+                    Shallow::parse(chunk_input, header).map_err(|e| e.lift())?;
+                if !remaining.is_empty() {
+                    return Err(parse::ParseError::Error(error::Chunk::LeftoverData));
+                }
+                Chunk::Shallow(shallow)
+            }
             ChunkType::Compressed => {
                 let compressed = &input.unconsumed_bytes()[header.data_bytes()];
                 let mut decoder = flate2::bufread::DeflateDecoder::new(compressed);
@@ -106,6 +117,7 @@ impl<'a> Chunk<'a> {
             Self::CompressedChange(change, compressed) => {
                 compressed.checksum() == change.checksum() && change.checksum_valid()
             }
+            Self::Shallow(s) => s.checksum_valid(),
         }
     }
 }
@@ -115,6 +127,7 @@ pub(crate) enum ChunkType {
     Document,
     Change,
     Compressed,
+    Shallow,
 }
 
 impl TryFrom<u8> for ChunkType {
@@ -125,6 +138,7 @@ impl TryFrom<u8> for ChunkType {
             0 => Ok(Self::Document),
             1 => Ok(Self::Change),
             2 => Ok(Self::Compressed),
+            3 => Ok(Self::Shallow),
             other => Err(other),
         }
     }
@@ -136,6 +150,7 @@ impl From<ChunkType> for u8 {
             ChunkType::Document => 0,
             ChunkType::Change => 1,
             ChunkType::Compressed => 2,
+            ChunkType::Shallow => 3,
         }
     }
 }
@@ -274,6 +289,10 @@ impl Header {
         self.hash
     }
 
+    pub(crate) fn chunk_type(&self) -> ChunkType {
+        self.chunk_type
+    }
+
     pub(crate) fn checksum_valid(&self) -> bool {
         CheckSum(self.hash.checksum()) == self.checksum
     }
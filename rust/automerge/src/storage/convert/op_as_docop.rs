@@ -4,7 +4,7 @@ use crate::{
     convert,
     indexed_cache::IndexedCache,
     storage::AsDocOp,
-    types::{ElemId, Key, MarkData, Op, OpId, OpType, ScalarValue},
+    types::{Clock, ElemId, Key, MarkData, Op, OpId, OpType, ScalarValue},
 };
 
 /// Create an [`AsDocOp`] implementation for a [`crate::types::Op`]
@@ -14,8 +14,8 @@ use crate::{
 ///            we are saving and the value is the index of that same actor in the
 ///            order the actors will be encoded in the saved document
 /// * props - An indexed cache containing the properties in this op_as_docop
-/// * obj - The object ID this op refers too
 /// * op - The op itself
+/// * clock - If present, only the successors of `op` which this clock covers are encoded
 ///
 /// # Panics
 ///
@@ -25,11 +25,13 @@ pub(crate) fn op_as_docop<'a>(
     actors: &'a HashMap<usize, usize>,
     props: &'a IndexedCache<String>,
     op: Op<'a>,
+    clock: Option<&'a Clock>,
 ) -> OpAsDocOp<'a> {
     OpAsDocOp {
         op,
         actor_lookup: actors,
         props,
+        clock,
     }
 }
 
@@ -37,6 +39,7 @@ pub(crate) struct OpAsDocOp<'a> {
     op: Op<'a>,
     actor_lookup: &'a HashMap<usize, usize>,
     props: &'a IndexedCache<String>,
+    clock: Option<&'a Clock>,
 }
 
 #[derive(Debug)]
@@ -98,6 +101,7 @@ impl<'a> AsDocOp<'a> for OpAsDocOp<'a> {
             op: self.op,
             offset: 0,
             actor_index: self.actor_lookup,
+            clock: self.clock,
         }
     }
 
@@ -130,6 +134,13 @@ pub(crate) struct OpAsDocOpSuccIter<'a> {
     op: Op<'a>,
     offset: usize,
     actor_index: &'a HashMap<usize, usize>,
+    clock: Option<&'a Clock>,
+}
+
+impl<'a> OpAsDocOpSuccIter<'a> {
+    fn included(&self, id: &OpId) -> bool {
+        self.clock.map_or(true, |c| c.covers(id))
+    }
 }
 
 impl<'a> Iterator for OpAsDocOpSuccIter<'a> {
@@ -137,18 +148,27 @@ impl<'a> Iterator for OpAsDocOpSuccIter<'a> {
 
     fn next(&mut self) -> Option<Self::Item> {
         // FIXME - nth() is no longer fast - rewrite to replace offset with a Op iterator
-        if let Some(s) = self.op.succ().nth(self.offset).map(|op| op.id()) {
+        while let Some(s) = self.op.succ().nth(self.offset).map(|op| op.id()) {
             self.offset += 1;
-            Some(translate(self.actor_index, s))
-        } else {
-            None
+            if self.included(s) {
+                return Some(translate(self.actor_index, s));
+            }
         }
+        None
     }
 }
 
 impl<'a> ExactSizeIterator for OpAsDocOpSuccIter<'a> {
     fn len(&self) -> usize {
-        self.op.succ().len()
+        if self.clock.is_none() {
+            self.op.succ().len() - self.offset
+        } else {
+            self.op
+                .succ()
+                .skip(self.offset)
+                .filter(|op| self.included(op.id()))
+                .count()
+        }
     }
 }
 
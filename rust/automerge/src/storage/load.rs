@@ -8,7 +8,7 @@ use crate::{
 pub(crate) mod change_collector;
 mod reconstruct_document;
 pub use reconstruct_document::VerificationMode;
-pub(crate) use reconstruct_document::{reconstruct_opset, ReconOpSet};
+pub(crate) use reconstruct_document::{reconstruct_ops, reconstruct_opset, ReconOpSet};
 
 #[derive(Debug, thiserror::Error)]
 #[allow(unreachable_pub)]
@@ -25,6 +25,8 @@ pub enum Error {
     InflateDocument(Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error("bad checksum")]
     BadChecksum,
+    #[error("a shallow document can only be loaded as the first chunk of an empty document")]
+    UnexpectedShallow,
 }
 
 pub(crate) enum LoadedChanges<'a> {
@@ -105,6 +107,7 @@ fn load_next_change<'a>(
                     .map_err(|e| Error::InvalidChangeColumns(Box::new(e)))?;
             changes.push(change);
         }
+        storage::Chunk::Shallow(_) => return Err(Error::UnexpectedShallow),
     };
     Ok(remaining)
 }
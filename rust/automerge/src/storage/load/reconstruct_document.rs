@@ -90,20 +90,28 @@ struct ReconstructionState<'a> {
     last_key: Option<Key>,
     pred: HashMap<OpId, Vec<OpIdx>>,
     ops_collecter: Vec<OpIdx>,
-    change_collector: ChangeCollector<'a>,
+    /// `None` when the document is the base of a shallow document, which has no changes
+    change_collector: Option<ChangeCollector<'a>>,
 }
 
 impl<'a> ReconstructionState<'a> {
-    fn new(doc: &'a Document<'a>) -> Result<Self, Error> {
-        Ok(Self {
+    fn new(doc: &'a Document<'a>, change_collector: Option<ChangeCollector<'a>>) -> Self {
+        Self {
             op_set: OpSet::from_actors(doc.actors().to_vec()),
             max_op: 0,
             last_obj: None,
             last_key: None,
             pred: HashMap::default(),
             ops_collecter: Vec::default(),
-            change_collector: ChangeCollector::new(doc.iter_changes())?,
-        })
+            change_collector,
+        }
+    }
+
+    fn collect(&mut self, opid: OpId, idx: OpIdx) -> Result<(), Error> {
+        if let Some(collector) = &mut self.change_collector {
+            collector.collect(opid, idx)?;
+        }
+        Ok(())
     }
 }
 
@@ -111,7 +119,35 @@ pub(crate) fn reconstruct_opset<'a>(
     doc: &'a Document<'a>,
     mode: VerificationMode,
 ) -> Result<ReconOpSet, Error> {
-    let mut state = ReconstructionState::new(doc)?;
+    let collector = ChangeCollector::new(doc.iter_changes())?;
+    let mut state = ReconstructionState::new(doc, Some(collector));
+    load_ops(doc, &mut state)?;
+
+    let op_set = state.op_set;
+    let max_op = state.max_op;
+    // we always pass a collector above
+    let change_collector = state.change_collector.unwrap();
+
+    let (changes, heads) = flush_changes(change_collector, doc, mode, &op_set.osd)?;
+
+    Ok(ReconOpSet {
+        changes,
+        max_op,
+        op_set,
+        heads,
+    })
+}
+
+/// Load the ops in `doc` without reconstructing any changes, returning the op set and max op
+///
+/// This is for the document in a shallow chunk, which doesn't have changes for its ops
+pub(crate) fn reconstruct_ops<'a>(doc: &'a Document<'a>) -> Result<(OpSet, u64), Error> {
+    let mut state = ReconstructionState::new(doc, None);
+    load_ops(doc, &mut state)?;
+    Ok((state.op_set, state.max_op))
+}
+
+fn load_ops<'a>(doc: &'a Document<'a>, state: &mut ReconstructionState<'a>) -> Result<(), Error> {
     let mut iter_ops = doc.iter_ops();
     let mut next = next_op(&mut iter_ops, &mut state.op_set)?;
     while let Some(NextDocOp {
@@ -142,30 +178,18 @@ pub(crate) fn reconstruct_opset<'a>(
         }
 
         state.ops_collecter.push(idx);
-        state.change_collector.collect(opid, idx)?;
+        state.collect(opid, idx)?;
 
         state.last_key = Some(key);
         state.last_obj = Some(obj);
 
         next = next_op(&mut iter_ops, &mut state.op_set)?;
 
-        flush_ops(&obj, next.as_ref(), &mut state)?;
+        flush_ops(&obj, next.as_ref(), state)?;
     }
 
     state.op_set.add_indexes();
-
-    let op_set = state.op_set;
-    let change_collector = state.change_collector;
-    let max_op = state.max_op;
-
-    let (changes, heads) = flush_changes(change_collector, doc, mode, &op_set.osd)?;
-
-    Ok(ReconOpSet {
-        changes,
-        max_op,
-        op_set,
-        heads,
-    })
+    Ok(())
 }
 
 // create all binary changes
@@ -222,7 +246,9 @@ fn flush_ops(
             for p in preds {
                 state.op_set.osd.add_dep(*p, del_idx);
             }
-            state.change_collector.collect(*opid, del_idx)?;
+            if let Some(collector) = &mut state.change_collector {
+                collector.collect(*opid, del_idx)?;
+            }
         }
         state.pred.clear();
 
@@ -8,7 +8,7 @@ use crate::{
     storage::{
         change::DEFLATE_MIN_SIZE, convert::op_as_docop, AsChangeMeta, CompressConfig, Document,
     },
-    types::{ActorId, ObjId, Op},
+    types::{ActorId, Clock, ObjId, Op, OpType},
     Change, ChangeHash,
 };
 
@@ -44,7 +44,7 @@ where
 
     let doc_ops = ops
         .clone()
-        .map(|(_obj, op)| op_as_docop(&actor_lookup, props, op));
+        .map(|(_obj, op)| op_as_docop(&actor_lookup, props, op, None));
 
     let hash_graph = HashGraph::new(changes.clone());
     let changes = changes.map(|c| ChangeWithGraph {
@@ -64,6 +64,63 @@ where
     doc.into_bytes()
 }
 
+/// Save the ops in `ops` which are covered by `clock` as a document chunk with no changes, for use
+/// as the base of a shallow document. Returns the document and the index of each actor in it.
+///
+/// # Panics
+///
+/// * If any of the ops covered by `clock` reference an actor which is not in `clock`
+/// * If any of ops in `ops` reference a property which is not in `props`
+pub(crate) fn save_shallow_document<'a, O>(
+    ops: O,
+    actors: &IndexedCache<ActorId>,
+    props: &IndexedCache<String>,
+    clock: &Clock,
+    config: Option<CompressConfig>,
+) -> (Vec<u8>, HashMap<usize, usize>)
+where
+    O: Iterator<Item = (&'a ObjId, Op<'a>)> + Clone,
+{
+    let mut actor_indices = clock.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+    actor_indices.sort_by(|a, b| actors.get(*a).cmp(actors.get(*b)));
+    let actor_lookup = actor_indices
+        .iter()
+        .enumerate()
+        .map(|(new_index, index)| (*index, new_index))
+        .collect::<HashMap<_, _>>();
+    let actor_ids = actor_indices
+        .iter()
+        .map(|index| actors.get(*index).clone())
+        .collect();
+
+    // Everything needed to apply changes made after `clock` is kept: inserts (which later ops
+    // may reference as elements), objects, marks and increments. Other ops are only kept if they
+    // were visible as of `clock`.
+    let kept = ops
+        .filter(|(_obj, op)| {
+            clock.covers(op.id())
+                && (op.insert()
+                    || matches!(op.action(), OpType::Make(_))
+                    || op.is_inc()
+                    || op.is_mark()
+                    || op.visible_at(Some(clock)))
+        })
+        .map(|(_obj, op)| op)
+        .collect::<Vec<_>>();
+    let doc_ops = kept
+        .iter()
+        .map(|op| op_as_docop(&actor_lookup, props, *op, Some(clock)));
+
+    let doc = Document::new(
+        actor_ids,
+        Vec::new(),
+        doc_ops,
+        std::iter::empty::<ChangeWithGraph<'_>>(),
+        config.unwrap_or(CompressConfig::Threshold(DEFLATE_MIN_SIZE)),
+    );
+    (doc.into_bytes(), actor_lookup)
+}
+
 struct HashGraph {
     index_by_hash: HashMap<ChangeHash, usize, FxBuildHasher>,
 }
@@ -0,0 +1,171 @@
+use super::{chunk, parse, ChunkType, Document, Header};
+use crate::ChangeHash;
+
+/// A change on the boundary of a shallow document
+///
+/// The ops from the change and its ancestors are in the document, but the changes themselves are
+/// not. What we do keep is enough to work out the clock of any history which includes the change
+/// and to recognise changes which are part of its history.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BoundaryChange {
+    pub(crate) hash: ChangeHash,
+    /// The index of the author of the change in the actors of the document
+    pub(crate) actor: usize,
+    pub(crate) seq: u64,
+    pub(crate) max_op: u64,
+    /// The `(actor, seq, max_op)` of the latest change by each actor in the history of this
+    /// change, including the change itself
+    pub(crate) clock: Vec<(usize, u64, u64)>,
+}
+
+/// A shallow document chunk
+///
+/// This is a document chunk containing the ops of the document as of `heads`, but none of the
+/// changes which produced them, preceded by the boundary information:
+///
+/// ```text
+/// heads:    uLEB count, then that many 32 byte hashes
+/// boundary: uLEB count, then for each change
+///           hash, uLEB actor, uLEB seq, uLEB max_op,
+///           uLEB count, then that many (uLEB actor, uLEB seq, uLEB max_op)
+/// document: an entire document chunk, including its header
+/// ```
+#[derive(Debug, Clone)]
+pub(crate) struct Shallow<'a> {
+    header: Header,
+    heads: Vec<ChangeHash>,
+    boundary: Vec<BoundaryChange>,
+    document: Document<'a>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ParseError {
+    #[error(transparent)]
+    Leb128(#[from] parse::leb128::Error),
+    #[error("failed to parse document header: {0}")]
+    Header(#[from] chunk::error::Header),
+    #[error("bad document chunk: {0}")]
+    Document(#[from] super::document::ParseError),
+    #[error("shallow chunk did not contain a document chunk")]
+    NotADocument,
+    #[error("there was data left over after the document chunk")]
+    LeftoverData,
+    #[error("a boundary change referenced a missing actor")]
+    MissingActor,
+}
+
+impl<'a> Shallow<'a> {
+    /// Parse a shallow chunk, `input` is the chunk following `header` as in
+    /// [`Document::parse()`]
+    pub(crate) fn parse(
+        input: parse::Input<'a>,
+        header: Header,
+    ) -> parse::ParseResult<'a, Shallow<'a>, ParseError> {
+        let (i, heads) = parse::length_prefixed(parse::change_hash)(input)?;
+        let (i, boundary) = parse::length_prefixed(boundary_change)(i)?;
+        let (i, doc_bytes) = parse::take_rest(i)?;
+
+        let (doc_input, doc_header) = Header::parse::<ParseError>(parse::Input::new(doc_bytes))?;
+        if doc_header.chunk_type() != ChunkType::Document {
+            return Err(parse::ParseError::Error(ParseError::NotADocument));
+        }
+        let parse::Split {
+            first: doc_input,
+            remaining,
+        } = doc_input.split(doc_header.data_bytes().len());
+        if !remaining.is_empty() {
+            return Err(parse::ParseError::Error(ParseError::LeftoverData));
+        }
+        let (_, document) = Document::parse(doc_input, doc_header).map_err(|e| e.lift())?;
+
+        let num_actors = document.actors().len();
+        let actor_in_range = |b: &BoundaryChange| {
+            b.actor < num_actors && b.clock.iter().all(|(a, _, _)| *a < num_actors)
+        };
+        if !boundary.iter().all(actor_in_range) {
+            return Err(parse::ParseError::Error(ParseError::MissingActor));
+        }
+
+        Ok((
+            i,
+            Shallow {
+                header,
+                heads,
+                boundary,
+                document,
+            },
+        ))
+    }
+
+    /// Encode a shallow chunk, `document` must be an entire document chunk
+    pub(crate) fn write(
+        heads: &[ChangeHash],
+        boundary: &[BoundaryChange],
+        document: &[u8],
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        leb128::write::unsigned(&mut data, heads.len() as u64).unwrap();
+        for head in heads {
+            data.extend(head.as_bytes());
+        }
+        leb128::write::unsigned(&mut data, boundary.len() as u64).unwrap();
+        for change in boundary {
+            data.extend(change.hash.as_bytes());
+            leb128::write::unsigned(&mut data, change.actor as u64).unwrap();
+            leb128::write::unsigned(&mut data, change.seq).unwrap();
+            leb128::write::unsigned(&mut data, change.max_op).unwrap();
+            leb128::write::unsigned(&mut data, change.clock.len() as u64).unwrap();
+            for (actor, seq, max_op) in &change.clock {
+                leb128::write::unsigned(&mut data, *actor as u64).unwrap();
+                leb128::write::unsigned(&mut data, *seq).unwrap();
+                leb128::write::unsigned(&mut data, *max_op).unwrap();
+            }
+        }
+        data.extend(document);
+
+        let header = Header::new(ChunkType::Shallow, &data);
+        let mut bytes = Vec::with_capacity(header.len() + data.len());
+        header.write(&mut bytes);
+        bytes.extend(data);
+        bytes
+    }
+
+    pub(crate) fn checksum_valid(&self) -> bool {
+        self.header.checksum_valid() && self.document.checksum_valid()
+    }
+
+    pub(crate) fn heads(&self) -> &[ChangeHash] {
+        &self.heads
+    }
+
+    pub(crate) fn boundary(&self) -> &[BoundaryChange] {
+        &self.boundary
+    }
+
+    pub(crate) fn document(&self) -> &Document<'a> {
+        &self.document
+    }
+}
+
+fn boundary_change(input: parse::Input<'_>) -> parse::ParseResult<'_, BoundaryChange, ParseError> {
+    let (i, hash) = parse::change_hash(input)?;
+    let (i, actor) = parse::leb128_u64::<ParseError>(i)?;
+    let (i, seq) = parse::leb128_u64::<ParseError>(i)?;
+    let (i, max_op) = parse::leb128_u64::<ParseError>(i)?;
+    let (i, clock) = parse::length_prefixed(|i| {
+        let (i, actor) = parse::leb128_u64::<ParseError>(i)?;
+        let (i, seq) = parse::leb128_u64::<ParseError>(i)?;
+        let (i, max_op) = parse::leb128_u64::<ParseError>(i)?;
+        Ok((i, (actor as usize, seq, max_op)))
+    })(i)?;
+    Ok((
+        i,
+        BoundaryChange {
+            hash,
+            actor: actor as usize,
+            seq,
+            max_op,
+            clock,
+        },
+    ))
+}
@@ -69,25 +69,38 @@
 
 use itertools::Itertools;
 use serde::ser::SerializeMap;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use crate::{
+    columnar::encoding::leb128::ulebsize,
     patches::{PatchLog, TextRepresentation},
+    policy::{AcceptAll, ChangePolicy, Rejected},
+    storage::load,
     storage::{parse, ReadChangeOpError},
     Automerge, AutomergeError, Change, ChangeHash, ReadDoc,
 };
 
 mod bloom;
+#[cfg(feature = "futures")]
+pub mod driver;
+mod iblt;
 mod message_builder;
+pub mod multiplex;
+pub mod presence;
 mod state;
+mod stats;
 use message_builder::MessageBuilder;
 
 #[cfg(test)]
 mod v1_compat_test;
 
 pub use bloom::{BloomFilter, DecodeError as DecodeBloomError};
+pub use iblt::{DecodeError as DecodeIbltError, Iblt};
+pub use multiplex::DecodeError as DecodeBatchError;
+pub use multiplex::{Batch, BatchMessage, BatchMessageRef, BatchVersion, DocumentId, Multiplexer};
 pub use state::DecodeError as DecodeStateError;
 pub use state::{Have, State};
+pub use stats::{MessageStats, Stats};
 
 /// A document which can take part in the sync protocol
 ///
@@ -105,6 +118,26 @@ pub trait SyncDoc {
     ///                 current state of the document due to the received sync message
     fn generate_sync_message(&self, sync_state: &mut State) -> Option<Message>;
 
+    /// Generate a sync message for the remote peer represented by `sync_state` which encodes to
+    /// at most `max_size` bytes
+    ///
+    /// This behaves like [`Self::generate_sync_message()`] except that if the changes the remote
+    /// needs don't fit in `max_size` bytes then only as many as fit are sent, in dependency order,
+    /// and the rest are recorded in [`State::unsent_hashes`] and sent in subsequent messages once
+    /// the remote has acknowledged this one. A single change which is larger than `max_size` is
+    /// still sent on its own, so the limit may be exceeded in that case.
+    ///
+    /// The default implementation ignores `max_size` and calls
+    /// [`Self::generate_sync_message()`].
+    fn generate_sync_message_with_limit(
+        &self,
+        sync_state: &mut State,
+        max_size: usize,
+    ) -> Option<Message> {
+        let _ = max_size;
+        self.generate_sync_message(sync_state)
+    }
+
     /// Apply a received sync message to this document and `sync_state`
     fn receive_sync_message(
         &mut self,
@@ -135,11 +168,18 @@ pub trait SyncDoc {
 
 const MESSAGE_TYPE_SYNC: u8 = 0x42; // first byte of a sync message, for identification
 const MESSAGE_TYPE_SYNC_V2: u8 = 0x43; // first byte of a sync message, for identification
+const MESSAGE_TYPE_SYNC_V3: u8 = 0x44; // first byte of a sync message, for identification
+
+// Below this many new changes a bloom filter is unlikely to hide any from the other end, so we
+// only send an IBLT if a previous round has failed
+const IBLT_THRESHOLD: usize = 100;
 
 #[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
 pub enum MessageVersion {
     V1,
     V2,
+    V3,
 }
 
 impl MessageVersion {
@@ -148,8 +188,13 @@ impl MessageVersion {
         match first_byte {
             MESSAGE_TYPE_SYNC => Ok((i, Self::V1)),
             MESSAGE_TYPE_SYNC_V2 => Ok((i, Self::V2)),
+            MESSAGE_TYPE_SYNC_V3 => Ok((i, Self::V3)),
             _ => Err(parse::ParseError::Error(ReadMessageError::WrongType {
-                expected_one_of: vec![MESSAGE_TYPE_SYNC, MESSAGE_TYPE_SYNC_V2],
+                expected_one_of: vec![
+                    MESSAGE_TYPE_SYNC,
+                    MESSAGE_TYPE_SYNC_V2,
+                    MESSAGE_TYPE_SYNC_V3,
+                ],
                 found: first_byte,
             })),
         }
@@ -159,24 +204,129 @@ impl MessageVersion {
         match self {
             Self::V1 => MESSAGE_TYPE_SYNC,
             Self::V2 => MESSAGE_TYPE_SYNC_V2,
+            Self::V3 => MESSAGE_TYPE_SYNC_V3,
         }
     }
 }
 
 impl SyncDoc for Automerge {
     fn generate_sync_message(&self, sync_state: &mut State) -> Option<Message> {
+        self.generate_sync_message_inner(sync_state, None)
+    }
+
+    fn generate_sync_message_with_limit(
+        &self,
+        sync_state: &mut State,
+        max_size: usize,
+    ) -> Option<Message> {
+        self.generate_sync_message_inner(sync_state, Some(max_size))
+    }
+
+    fn receive_sync_message(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+    ) -> Result<(), AutomergeError> {
+        let mut patch_log = PatchLog::inactive(TextRepresentation::default());
+        self.receive_sync_message_inner(sync_state, message, &mut patch_log)
+    }
+
+    fn receive_sync_message_log_patches(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+        patch_log: &mut PatchLog,
+    ) -> Result<(), AutomergeError> {
+        self.receive_sync_message_inner(sync_state, message, patch_log)
+    }
+}
+
+/// The parts of a document the sync protocol needs
+///
+/// This is implemented by [`Automerge`], which applies the changes it receives to its op set, and
+/// by [`crate::ChangeStore`], which only stores them.
+pub(crate) trait ChangeHistory {
+    fn get_heads(&self) -> Vec<ChangeHash>;
+
+    fn missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash>;
+
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change>;
+
+    fn change_by_hash(&self, hash: &ChangeHash) -> Option<&Change>;
+
+    /// Whether `hash` is in our history, even if we don't have the change itself
+    fn has_change(&self, hash: &ChangeHash) -> bool {
+        self.change_by_hash(hash).is_some()
+    }
+
+    /// The boundary of a shallow history, see [`Automerge::shallow_heads()`]
+    fn shallow_heads(&self) -> Option<Vec<ChangeHash>> {
+        None
+    }
+
+    /// The position of the change in a topological ordering of all the changes
+    fn change_index_for_hash(&self, hash: &ChangeHash) -> Option<usize>;
+
+    /// Remove the changes in `changes` which are ancestors of `heads`
+    fn filter_changes(
+        &self,
+        heads: &[ChangeHash],
+        changes: &mut BTreeSet<ChangeHash>,
+    ) -> Result<(), AutomergeError>;
+
+    /// The whole document as a single document chunk, if we can produce one
+    fn save_document(&self) -> Option<Vec<u8>>;
+
+    /// The heads this history has been limited to, see [`HistoryAt`]
+    fn restricted_heads(&self) -> Option<Vec<ChangeHash>> {
+        None
+    }
+
+    fn generate_sync_message_at_inner(
+        &self,
+        sync_state: &mut State,
+        heads: &[ChangeHash],
+    ) -> Result<Option<Message>, AutomergeError>
+    where
+        Self: Sized,
+    {
+        let at = HistoryAt::new(self, heads)?;
+        Ok(at.generate_sync_message_inner(sync_state, None))
+    }
+
+    fn generate_sync_message_inner(
+        &self,
+        sync_state: &mut State,
+        max_size: Option<usize>,
+    ) -> Option<Message> {
+        sync_state.restricted_heads = self.restricted_heads();
         let our_heads = self.get_heads();
 
-        let our_need = self.get_missing_deps(sync_state.their_heads.as_ref().unwrap_or(&vec![]));
+        let mut our_need = self.missing_deps(sync_state.their_heads.as_ref().unwrap_or(&vec![]));
+        our_need.retain(|hash| !sync_state.rejected_hashes.contains(hash));
 
         let their_heads_set = if let Some(ref heads) = sync_state.their_heads {
             heads.iter().collect::<HashSet<_>>()
         } else {
             HashSet::new()
         };
-        let our_have = if our_need.iter().all(|hash| their_heads_set.contains(hash)) {
-            vec![self.make_bloom_filter(sync_state.shared_heads.clone())]
+        let mut our_have = if our_need.iter().all(|hash| their_heads_set.contains(hash)) {
+            // A shallow history can't offer anything before its boundary so there's no point
+            // in the other end sending it
+            let last_sync = if sync_state.shared_heads.is_empty() {
+                self.shallow_heads().unwrap_or_default()
+            } else {
+                sync_state.shared_heads.clone()
+            };
+            let have = self.make_have(last_sync, sync_state);
+            if have.iblt.is_some() {
+                sync_state.bloom_failed = false;
+            }
+            vec![have]
         } else {
+            // The other end's bloom filter hid changes we needed from them, so let them know
+            // exactly what we have next time
+            sync_state.bloom_failed = true;
             Vec::new()
         };
 
@@ -185,7 +335,7 @@ impl SyncDoc for Automerge {
                 if !first_have
                     .last_sync
                     .iter()
-                    .all(|hash| self.get_change_by_hash(hash).is_some())
+                    .all(|hash| self.has_change(hash))
                 {
                     let reset_msg = Message {
                         heads: our_heads,
@@ -195,6 +345,7 @@ impl SyncDoc for Automerge {
                         supported_capabilities: Some(vec![
                             Capability::MessageV1,
                             Capability::MessageV2,
+                            Capability::MessageV3,
                         ]),
                         version: MessageVersion::V1,
                     };
@@ -203,54 +354,150 @@ impl SyncDoc for Automerge {
             }
         }
 
-        let (message_builder, sent_hashes) = if let (Some(their_have), Some(their_need)) = (
-            sync_state.their_have.as_ref(),
-            sync_state.their_need.as_ref(),
-        ) {
-            let send_doc = sync_state
-                .their_heads
-                .as_ref()
-                .map(|h| h.is_empty())
-                .unwrap_or(false)
-                && !sync_state.have_responded
-                && sync_state.supports_v2_messages();
-
-            if send_doc {
-                let hashes = self
-                    .get_changes(&[])
-                    .iter()
-                    .map(|c| c.hash())
-                    .collect::<Vec<_>>();
-                (MessageBuilder::new_v2(self.save()), hashes)
+        // Only send the supported capabilities in the first message, the other end will store them
+        // in it's sync state and use them for subsequent messages
+        let supported_capabilities = if sync_state.have_responded {
+            None
+        } else {
+            Some(vec![
+                Capability::MessageV1,
+                Capability::MessageV2,
+                Capability::MessageV3,
+            ])
+        };
+
+        // V3 messages encode changes the same way as V2 messages
+        let v3 = sync_state.supports_v3_messages();
+        let compressed = move |changes: Vec<u8>| {
+            if v3 {
+                MessageBuilder::new_v3(changes)
             } else {
-                let all_changes = self
-                    .get_changes_to_send(their_have, their_need)
-                    .expect("Should have only used hashes that are in the document");
-                // deduplicate the changes to send with those we have already sent and clone it now
-                let changes = all_changes
-                    .into_iter()
-                    .filter(|change| !sync_state.sent_hashes.contains(&change.hash()));
-                let hashes = changes.clone().map(|c| c.hash()).collect::<Vec<_>>();
-                if sync_state.supports_v2_messages() {
-                    let encoded = changes
-                        .into_iter()
-                        .flat_map(|c| c.raw_bytes().to_vec())
-                        .collect::<Vec<_>>();
-                    (MessageBuilder::new_v2(encoded), hashes)
+                MessageBuilder::new_v2(changes)
+            }
+        };
+
+        // If we have a size limit then work out how much of it is left for changes once the rest
+        // of the message is encoded
+        let overhead = |have: &[Have]| {
+            let empty = Message {
+                heads: our_heads.clone(),
+                need: our_need.clone(),
+                have: have.to_vec(),
+                changes: ChunkList::empty(),
+                supported_capabilities: supported_capabilities.clone(),
+                version: if v3 {
+                    MessageVersion::V3
                 } else {
-                    (MessageBuilder::new_v1(changes), hashes)
+                    MessageVersion::V1
+                },
+            };
+            // The encoded empty message includes the length of the (empty) change list, which
+            // `encoded_chunks_len` accounts for
+            empty.encode().len() - 1
+        };
+        let budget = if let Some(max_size) = max_size {
+            // The IBLT only saves round trips, drop it rather than go over the limit
+            if overhead(&our_have) > max_size {
+                for have in &mut our_have {
+                    have.iblt = None;
                 }
             }
-        } else if sync_state.supports_v2_messages() {
-            (MessageBuilder::new_v2(Vec::new()), Vec::new())
+            Some(max_size.saturating_sub(overhead(&our_have)))
         } else {
-            (MessageBuilder::new_v1(std::iter::empty()), Vec::new())
+            None
         };
 
+        let (message_builder, sent_hashes, unsent_hashes) =
+            if let (Some(their_have), Some(their_need)) = (
+                sync_state.their_have.as_ref(),
+                sync_state.their_need.as_ref(),
+            ) {
+                // A shallow history can't send the changes before its boundary, so the only way
+                // to bring an empty peer up to date is with the document
+                let send_doc = sync_state
+                    .their_heads
+                    .as_ref()
+                    .map(|h| h.is_empty())
+                    .unwrap_or(false)
+                    && (!sync_state.have_responded || self.shallow_heads().is_some())
+                    && sync_state.supports_v2_messages();
+
+                // The whole document is only sent if it fits, otherwise we fall back to sending
+                // changes which can be split across several messages
+                let doc = if send_doc {
+                    self.save_document().filter(|doc| {
+                        budget
+                            .map(|budget| encoded_chunks_len(std::iter::once(doc.len())) <= budget)
+                            .unwrap_or(true)
+                    })
+                } else {
+                    None
+                };
+
+                if let Some(doc) = doc {
+                    let hashes = self
+                        .get_changes(&[])
+                        .iter()
+                        .map(|c| c.hash())
+                        .collect::<Vec<_>>();
+                    (compressed(doc), hashes, BTreeSet::new())
+                } else {
+                    let all_changes = self
+                        .get_changes_to_send(their_have, their_need)
+                        .expect("Should have only used hashes that are in the document");
+                    // deduplicate the changes to send with those we have already sent
+                    let mut changes = all_changes
+                        .into_iter()
+                        .filter(|change| !sync_state.sent_hashes.contains(&change.hash()))
+                        .collect::<Vec<_>>();
+                    // add anything which didn't fit in the last message, the bloom filter may
+                    // (falsely) claim the other end has it now
+                    let mut to_send = changes.iter().map(|c| c.hash()).collect::<HashSet<_>>();
+                    for hash in &sync_state.unsent_hashes {
+                        if !sync_state.sent_hashes.contains(hash) && to_send.insert(*hash) {
+                            changes.extend(self.change_by_hash(hash));
+                        }
+                    }
+                    let unsent = if let Some(budget) = budget {
+                        changes.sort_by_key(|c| self.change_index_for_hash(&c.hash()));
+                        let fits = fitting_changes(
+                            budget,
+                            sync_state.supports_v2_messages(),
+                            changes.iter().map(|c| c.raw_bytes().len()),
+                        );
+                        changes.drain(fits..).map(|c| c.hash()).collect()
+                    } else {
+                        BTreeSet::new()
+                    };
+                    let hashes = changes.iter().map(|c| c.hash()).collect::<Vec<_>>();
+                    if sync_state.supports_v2_messages() {
+                        let encoded = changes
+                            .into_iter()
+                            .flat_map(|c| c.raw_bytes().to_vec())
+                            .collect::<Vec<_>>();
+                        (compressed(encoded), hashes, unsent)
+                    } else {
+                        (MessageBuilder::new_v1(changes.into_iter()), hashes, unsent)
+                    }
+                }
+            } else if sync_state.supports_v2_messages() {
+                (
+                    compressed(Vec::new()),
+                    Vec::new(),
+                    sync_state.unsent_hashes.clone(),
+                )
+            } else {
+                (
+                    MessageBuilder::new_v1(std::iter::empty()),
+                    Vec::new(),
+                    sync_state.unsent_hashes.clone(),
+                )
+            };
+
         let heads_unchanged = sync_state.last_sent_heads == our_heads;
 
         let heads_equal = if let Some(their_heads) = sync_state.their_heads.as_ref() {
-            their_heads == &our_heads
+            their_heads == &our_heads || self.equal_but_for_rejected(sync_state, their_heads)
         } else {
             false
         };
@@ -264,17 +511,11 @@ impl SyncDoc for Automerge {
             }
         }
 
-        // Only send the supported capabilities in the first message, the other end will store them
-        // in it's sync state and use them for subsequent messages
-        let supported_capabilities = if sync_state.have_responded {
-            None
-        } else {
-            Some(vec![Capability::MessageV1, Capability::MessageV2])
-        };
-
         sync_state.have_responded = true;
         sync_state.last_sent_heads.clone_from(&our_heads);
+        let changes_sent = sent_hashes.len();
         sync_state.sent_hashes.extend(sent_hashes);
+        sync_state.unsent_hashes = unsent_hashes;
 
         let sync_message = message_builder
             .heads(our_heads)
@@ -283,36 +524,32 @@ impl SyncDoc for Automerge {
             .supported_capabilities(supported_capabilities)
             .build();
 
+        if stats::wanted(sync_state) {
+            stats::record_sent(sync_state, MessageStats::new(&sync_message, changes_sent));
+        }
+
         sync_state.in_flight = true;
         Some(sync_message)
     }
 
-    fn receive_sync_message(
-        &mut self,
-        sync_state: &mut State,
-        message: Message,
-    ) -> Result<(), AutomergeError> {
-        let mut patch_log = PatchLog::inactive(TextRepresentation::default());
-        self.receive_sync_message_inner(sync_state, message, &mut patch_log)
-    }
-
-    fn receive_sync_message_log_patches(
-        &mut self,
-        sync_state: &mut State,
-        message: Message,
-        patch_log: &mut PatchLog,
-    ) -> Result<(), AutomergeError> {
-        self.receive_sync_message_inner(sync_state, message, patch_log)
-    }
-}
-
-impl Automerge {
-    fn make_bloom_filter(&self, last_sync: Vec<ChangeHash>) -> Have {
+    fn make_have(&self, last_sync: Vec<ChangeHash>, sync_state: &State) -> Have {
         let new_changes = self.get_changes(&last_sync);
         let hashes = new_changes.iter().map(|change| change.hash());
+        // The bloom filter rarely misses anything for small divergences, so the IBLT is only
+        // worth its size when we have a lot of new changes or the bloom filter has already let
+        // us down
+        let with_iblt = sync_state.supports_v3_messages()
+            && !new_changes.is_empty()
+            && (new_changes.len() >= IBLT_THRESHOLD || sync_state.bloom_failed);
+        let iblt = if with_iblt {
+            Some(Iblt::from_hashes(hashes.clone()))
+        } else {
+            None
+        };
         Have {
             last_sync,
             bloom: BloomFilter::from_hashes(hashes),
+            iblt,
         }
     }
 
@@ -324,14 +561,16 @@ impl Automerge {
         if have.is_empty() {
             Ok(need
                 .iter()
-                .filter_map(|hash| self.get_change_by_hash(hash))
+                .filter_map(|hash| self.change_by_hash(hash))
                 .collect())
         } else {
             let mut last_sync_hashes = HashSet::new();
             let mut bloom_filters = Vec::with_capacity(have.len());
 
             for h in have {
-                let Have { last_sync, bloom } = h;
+                let Have {
+                    last_sync, bloom, ..
+                } = h;
                 last_sync_hashes.extend(last_sync);
                 bloom_filters.push(bloom);
             }
@@ -339,6 +578,20 @@ impl Automerge {
 
             let changes = self.get_changes(&last_sync_hashes);
 
+            // If the other end sent an IBLT of its changes since the last sync then subtracting
+            // one of ours gives exactly the changes they are missing, without the false positives
+            // of the bloom filter. If the difference is too large to decode we fall back to the
+            // bloom filter.
+            let missing = match have {
+                [Have {
+                    iblt: Some(theirs), ..
+                }] => theirs
+                    .same_size(changes.iter().map(|c| c.hash()))
+                    .difference(theirs)
+                    .map(|diff| diff.ours.into_iter().collect::<HashSet<_>>()),
+                _ => None,
+            };
+
             let mut change_hashes = HashSet::with_capacity(changes.len());
             let mut dependents: HashMap<ChangeHash, Vec<ChangeHash>> = HashMap::new();
             let mut hashes_to_send = HashSet::new();
@@ -350,10 +603,13 @@ impl Automerge {
                     dependents.entry(*dep).or_default().push(change.hash());
                 }
 
-                if bloom_filters
-                    .iter()
-                    .all(|bloom| !bloom.contains_hash(&change.hash()))
-                {
+                let is_missing = match &missing {
+                    Some(missing) => missing.contains(&iblt::key(&change.hash())),
+                    None => bloom_filters
+                        .iter()
+                        .all(|bloom| !bloom.contains_hash(&change.hash())),
+                };
+                if is_missing {
                     hashes_to_send.insert(change.hash());
                 }
             }
@@ -372,7 +628,7 @@ impl Automerge {
             let mut changes_to_send = Vec::new();
             for hash in need {
                 if !hashes_to_send.contains(hash) {
-                    if let Some(change) = self.get_change_by_hash(hash) {
+                    if let Some(change) = self.change_by_hash(hash) {
                         changes_to_send.push(change);
                     }
                 }
@@ -387,15 +643,39 @@ impl Automerge {
         }
     }
 
-    pub(crate) fn receive_sync_message_inner(
+    /// Whether the only changes the other end has which we don't are ones we rejected, and they
+    /// have all of our changes
+    fn equal_but_for_rejected(&self, sync_state: &State, their_heads: &[ChangeHash]) -> bool {
+        if sync_state.rejected_hashes.is_empty() {
+            return false;
+        }
+        let our_heads = self.get_heads();
+        their_heads
+            .iter()
+            .all(|head| our_heads.contains(head) || sync_state.rejected_hashes.contains(head))
+            && our_heads
+                .iter()
+                .all(|head| their_heads.contains(head) || sync_state.shared_heads.contains(head))
+    }
+
+    /// Update `sync_state` with a received message, calling `apply` to apply the changes in it
+    fn receive_sync_message_with<R, F>(
         &mut self,
         sync_state: &mut State,
         message: Message,
-        patch_log: &mut PatchLog,
-    ) -> Result<(), AutomergeError> {
+        apply: F,
+    ) -> Result<R, AutomergeError>
+    where
+        Self: Sized,
+        R: Default,
+        F: FnOnce(&mut Self, ChunkList, &mut State) -> Result<R, AutomergeError>,
+    {
+        let round_trip = sync_state.in_flight;
         sync_state.in_flight = false;
         let before_heads = self.get_heads();
 
+        let received = stats::wanted(sync_state).then(|| self.message_stats(&message));
+
         let Message {
             heads: message_heads,
             changes: message_changes,
@@ -409,11 +689,28 @@ impl Automerge {
             sync_state.their_capabilities = Some(caps);
         }
 
+        if let Some(limit) = sync_state.restricted_heads.as_ref() {
+            let newer = self
+                .get_changes(limit)
+                .iter()
+                .map(|c| c.hash())
+                .collect::<HashSet<_>>();
+            let withheld = message_need
+                .iter()
+                .filter(|hash| newer.contains(hash))
+                .collect::<Vec<_>>();
+            if !withheld.is_empty() {
+                tracing::warn!(
+                    ?withheld,
+                    "peer asked for changes which are not ancestors of the heads we are syncing"
+                );
+            }
+        }
+
         let changes_is_empty = message_changes.is_empty();
+        let mut result = R::default();
         if !changes_is_empty {
-            for change in &message_changes.0 {
-                self.load_incremental_log_patches(change, patch_log)?;
-            }
+            result = apply(self, message_changes, sync_state)?;
             sync_state.shared_heads = advance_heads(
                 &before_heads.iter().collect(),
                 &self.get_heads().into_iter().collect(),
@@ -423,6 +720,7 @@ impl Automerge {
 
         // trim down the sent hashes to those that we know they haven't seen
         self.filter_changes(&message_heads, &mut sync_state.sent_hashes)?;
+        self.filter_changes(&message_heads, &mut sync_state.unsent_hashes)?;
 
         if changes_is_empty && message_heads == before_heads {
             sync_state.last_sent_heads.clone_from(&message_heads);
@@ -430,7 +728,7 @@ impl Automerge {
 
         let known_heads = message_heads
             .iter()
-            .filter(|head| self.get_change_by_hash(head).is_some())
+            .filter(|head| self.has_change(head))
             .collect::<Vec<_>>();
         if known_heads.len() == message_heads.len() {
             sync_state.shared_heads.clone_from(&message_heads);
@@ -438,6 +736,8 @@ impl Automerge {
             if message_heads.is_empty() {
                 sync_state.last_sent_heads = Default::default();
                 sync_state.sent_hashes = Default::default();
+                sync_state.unsent_hashes = Default::default();
+                sync_state.rejected_hashes = Default::default();
             }
         } else {
             sync_state.shared_heads = sync_state
@@ -454,7 +754,266 @@ impl Automerge {
         sync_state.their_heads = Some(message_heads);
         sync_state.their_need = Some(message_need);
 
-        Ok(())
+        if let Some(received) = received {
+            stats::record_received(sync_state, received, round_trip);
+        }
+
+        Ok(result)
+    }
+
+    /// Statistics about a message we have received but not yet applied
+    fn message_stats(&self, message: &Message) -> MessageStats {
+        let mut seen = HashSet::new();
+        let mut changes = 0;
+        let mut duplicate_changes = 0;
+        for chunk in message.changes.iter() {
+            let loaded = match load::load_changes(parse::Input::new(chunk)) {
+                load::LoadedChanges::Complete(c) => c,
+                load::LoadedChanges::Partial { loaded, .. } => loaded,
+            };
+            for change in loaded {
+                let hash = change.hash();
+                changes += 1;
+                if !seen.insert(hash) || self.change_by_hash(&hash).is_some() {
+                    duplicate_changes += 1;
+                }
+            }
+        }
+        MessageStats {
+            duplicate_changes,
+            ..MessageStats::new(message, changes)
+        }
+    }
+}
+
+impl Automerge {
+    /// Like [`SyncDoc::generate_sync_message()`] but as if this document only contained `heads`
+    /// and their ancestors
+    ///
+    /// The message advertises `heads` as our heads and only carries changes which are ancestors
+    /// of them, so a peer can be sent a document as of some point in its history without seeing
+    /// anything newer. Keep calling this method rather than
+    /// [`SyncDoc::generate_sync_message()`] for the rest of the session. If the peer asks for one
+    /// of the changes we are withholding a warning is logged when its message is received.
+    ///
+    /// Returns [`AutomergeError::InvalidHash`] if any of `heads` is not in this document.
+    pub fn generate_sync_message_at(
+        &self,
+        sync_state: &mut State,
+        heads: &[ChangeHash],
+    ) -> Result<Option<Message>, AutomergeError> {
+        self.generate_sync_message_at_inner(sync_state, heads)
+    }
+
+    /// Like [`SyncDoc::receive_sync_message()`] but only apply the changes which `policy`
+    /// accepts
+    ///
+    /// Changes which `policy` rejects, and any changes which depend on them, are not applied and
+    /// are returned instead. Their hashes are recorded in [`State::rejected_hashes`] so that we
+    /// don't keep asking the other end for them. See [`crate::policy`].
+    pub fn receive_sync_message_with_policy<P: ChangePolicy>(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+        policy: &mut P,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.receive_sync_message_with_policy_log_patches(
+            sync_state,
+            message,
+            policy,
+            &mut PatchLog::inactive(TextRepresentation::default()),
+        )
+    }
+
+    /// Like [`Self::receive_sync_message_with_policy()`] but log any changes that are made to
+    /// the current state of the document to `patch_log`
+    pub fn receive_sync_message_with_policy_log_patches<P: ChangePolicy>(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+        policy: &mut P,
+        patch_log: &mut PatchLog,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.receive_sync_message_checked(sync_state, message, Some(policy), patch_log)
+    }
+
+    pub(crate) fn receive_sync_message_inner(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+        patch_log: &mut PatchLog,
+    ) -> Result<(), AutomergeError> {
+        self.receive_sync_message_checked(sync_state, message, None::<&mut AcceptAll>, patch_log)
+            .map(|_| ())
+    }
+
+    fn receive_sync_message_checked<P: ChangePolicy>(
+        &mut self,
+        sync_state: &mut State,
+        message: Message,
+        policy: Option<&mut P>,
+        patch_log: &mut PatchLog,
+    ) -> Result<Vec<Rejected<P::Error>>, AutomergeError> {
+        self.receive_sync_message_with(sync_state, message, |doc, changes, sync_state| {
+            let Some(policy) = policy else {
+                for change in &changes.0 {
+                    doc.load_incremental_log_patches(change, patch_log)?;
+                }
+                return Ok(Vec::new());
+            };
+            let mut loaded = Vec::new();
+            for chunk in &changes.0 {
+                match load::load_changes(parse::Input::new(chunk)) {
+                    load::LoadedChanges::Complete(c) => loaded.extend(c),
+                    load::LoadedChanges::Partial {
+                        error, loaded: l, ..
+                    } => {
+                        tracing::warn!(successful_chunks=l.len(), err=?error, "partial load");
+                        loaded.extend(l);
+                    }
+                }
+            }
+            doc.add_rejected(&sync_state.rejected_hashes);
+            let rejected = doc.apply_changes_checked(loaded, policy, patch_log)?;
+            sync_state
+                .rejected_hashes
+                .extend(rejected.iter().map(|r| r.hash));
+            Ok(rejected)
+        })
+    }
+}
+
+impl ChangeHistory for Automerge {
+    fn get_heads(&self) -> Vec<ChangeHash> {
+        Automerge::get_heads(self)
+    }
+
+    fn missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        ReadDoc::get_missing_deps(self, heads)
+    }
+
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        Automerge::get_changes(self, have_deps)
+    }
+
+    fn change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        ReadDoc::get_change_by_hash(self, hash)
+    }
+
+    fn has_change(&self, hash: &ChangeHash) -> bool {
+        Automerge::has_change(self, hash)
+    }
+
+    fn shallow_heads(&self) -> Option<Vec<ChangeHash>> {
+        Automerge::shallow_heads(self).map(|h| h.to_vec())
+    }
+
+    fn change_index_for_hash(&self, hash: &ChangeHash) -> Option<usize> {
+        Automerge::change_index_for_hash(self, hash)
+    }
+
+    fn filter_changes(
+        &self,
+        heads: &[ChangeHash],
+        changes: &mut BTreeSet<ChangeHash>,
+    ) -> Result<(), AutomergeError> {
+        Automerge::filter_changes(self, heads, changes)
+    }
+
+    fn save_document(&self) -> Option<Vec<u8>> {
+        Some(self.save())
+    }
+}
+
+/// The part of a history which is an ancestor of some heads
+///
+/// Syncing with this instead of the whole history advertises `heads` as our heads and only
+/// sends changes which are ancestors of them, see [`Automerge::generate_sync_message_at()`].
+struct HistoryAt<'a, H> {
+    inner: &'a H,
+    heads: Vec<ChangeHash>,
+    allowed: HashSet<ChangeHash>,
+}
+
+impl<'a, H: ChangeHistory> HistoryAt<'a, H> {
+    fn new(inner: &'a H, heads: &[ChangeHash]) -> Result<Self, AutomergeError> {
+        if let Some(missing) = heads.iter().find(|h| inner.change_by_hash(h).is_none()) {
+            return Err(AutomergeError::InvalidHash(*missing));
+        }
+        let newer = inner
+            .get_changes(heads)
+            .iter()
+            .map(|c| c.hash())
+            .collect::<HashSet<_>>();
+        let allowed = inner
+            .get_changes(&[])
+            .iter()
+            .map(|c| c.hash())
+            .filter(|hash| !newer.contains(hash))
+            .collect();
+        let mut heads = heads.to_vec();
+        heads.sort();
+        heads.dedup();
+        Ok(Self {
+            inner,
+            heads,
+            allowed,
+        })
+    }
+}
+
+impl<'a, H: ChangeHistory> ChangeHistory for HistoryAt<'a, H> {
+    fn get_heads(&self) -> Vec<ChangeHash> {
+        self.heads.clone()
+    }
+
+    fn missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
+        self.inner.missing_deps(heads)
+    }
+
+    fn get_changes(&self, have_deps: &[ChangeHash]) -> Vec<&Change> {
+        self.inner
+            .get_changes(have_deps)
+            .into_iter()
+            .filter(|c| self.allowed.contains(&c.hash()))
+            .collect()
+    }
+
+    fn change_by_hash(&self, hash: &ChangeHash) -> Option<&Change> {
+        if self.allowed.contains(hash) {
+            self.inner.change_by_hash(hash)
+        } else {
+            None
+        }
+    }
+
+    fn change_index_for_hash(&self, hash: &ChangeHash) -> Option<usize> {
+        if self.allowed.contains(hash) {
+            self.inner.change_index_for_hash(hash)
+        } else {
+            None
+        }
+    }
+
+    fn filter_changes(
+        &self,
+        heads: &[ChangeHash],
+        changes: &mut BTreeSet<ChangeHash>,
+    ) -> Result<(), AutomergeError> {
+        self.inner.filter_changes(heads, changes)
+    }
+
+    fn save_document(&self) -> Option<Vec<u8>> {
+        // A document chunk would include the changes we are withholding
+        None
+    }
+
+    fn shallow_heads(&self) -> Option<Vec<ChangeHash>> {
+        self.inner.shallow_heads()
+    }
+
+    fn restricted_heads(&self) -> Option<Vec<ChangeHash>> {
+        Some(self.heads.clone())
     }
 }
 
@@ -482,6 +1041,12 @@ impl From<bloom::ParseError> for ReadMessageError {
     }
 }
 
+impl From<iblt::ParseError> for ReadMessageError {
+    fn from(e: iblt::ParseError) -> Self {
+        ReadMessageError::Parse(e.to_string())
+    }
+}
+
 impl From<crate::storage::change::ParseError> for ReadMessageError {
     fn from(e: crate::storage::change::ParseError) -> Self {
         ReadMessageError::Parse(format!("error parsing changes: {}", e))
@@ -519,6 +1084,12 @@ impl From<parse::ParseError<ReadMessageError>> for ReadMessageError {
 /// the advertised capabilities on the sync state. This allows new implementations to discover if
 /// the remote peer supports the V2 message format (the `Capability::MessageV2` capability) and if
 /// so send a V2 message.
+///
+/// The V3 message (the `Capability::MessageV3` capability) encodes changes in the same way as V2
+/// but each [`Have`] also carries an invertible Bloom lookup table ([`Iblt`]) of the sender's
+/// changes. The recipient subtracts a table of its own changes from it to find exactly which
+/// changes the sender is missing, rather than relying on the bloom filter, which can take several
+/// round trips to resolve false positives when the peers have diverged a lot.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Message {
     /// The heads of the sender.
@@ -597,11 +1168,31 @@ impl serde::Serialize for Message {
     }
 }
 
-fn parse_have(input: parse::Input<'_>) -> parse::ParseResult<'_, Have, ReadMessageError> {
-    let (i, last_sync) = parse::length_prefixed(parse::change_hash)(input)?;
-    let (i, bloom_bytes) = parse::length_prefixed_bytes(i)?;
-    let (_, bloom) = BloomFilter::parse(parse::Input::new(bloom_bytes)).map_err(|e| e.lift())?;
-    Ok((i, Have { last_sync, bloom }))
+fn parse_have(
+    version: &MessageVersion,
+) -> impl Fn(parse::Input<'_>) -> parse::ParseResult<'_, Have, ReadMessageError> + '_ {
+    move |input| {
+        let (i, last_sync) = parse::length_prefixed(parse::change_hash)(input)?;
+        let (i, bloom_bytes) = parse::length_prefixed_bytes(i)?;
+        let (_, bloom) =
+            BloomFilter::parse(parse::Input::new(bloom_bytes)).map_err(|e| e.lift())?;
+        let (i, iblt) = match version {
+            MessageVersion::V3 => {
+                let (i, iblt_bytes) = parse::length_prefixed_bytes(i)?;
+                let (_, iblt) = Iblt::parse(parse::Input::new(iblt_bytes)).map_err(|e| e.lift())?;
+                (i, Some(iblt).filter(|iblt| !iblt.is_empty()))
+            }
+            _ => (i, None),
+        };
+        Ok((
+            i,
+            Have {
+                last_sync,
+                bloom,
+                iblt,
+            },
+        ))
+    }
 }
 
 impl Message {
@@ -619,7 +1210,7 @@ impl Message {
 
         let (i, heads) = parse::length_prefixed(parse::change_hash)(i)?;
         let (i, need) = parse::length_prefixed(parse::change_hash)(i)?;
-        let (i, have) = parse::length_prefixed(parse_have)(i)?;
+        let (i, have) = parse::length_prefixed(parse_have(&message_version))(i)?;
 
         let (i, changes) = ChunkList::parse(i)?;
         let (i, supported_capabilities) = if !i.is_empty() {
@@ -646,16 +1237,8 @@ impl Message {
 
         encode_hashes(&mut buf, &self.heads);
         encode_hashes(&mut buf, &self.need);
-        encode_many(&mut buf, self.have.iter(), |buf, h| {
-            encode_hashes(buf, &h.last_sync);
-            leb128::write::unsigned(buf, h.bloom.to_bytes().len() as u64).unwrap();
-            buf.extend(h.bloom.to_bytes());
-        });
-
-        encode_many(&mut buf, self.changes.iter(), |buf, change| {
-            leb128::write::unsigned(buf, change.len() as u64).unwrap();
-            buf.extend::<&[u8]>(change.as_ref())
-        });
+        encode_haves(&mut buf, &self.have, &self.version);
+        encode_chunks(&mut buf, &self.changes);
 
         if let Some(supported_capabilities) = self.supported_capabilities {
             encode_many(&mut buf, supported_capabilities.iter(), |buf, cap| {
@@ -667,11 +1250,33 @@ impl Message {
     }
 }
 
+fn encode_haves(buf: &mut Vec<u8>, have: &[Have], version: &MessageVersion) {
+    encode_many(buf, have.iter(), |buf, h| {
+        encode_hashes(buf, &h.last_sync);
+        leb128::write::unsigned(buf, h.bloom.to_bytes().len() as u64).unwrap();
+        buf.extend(h.bloom.to_bytes());
+        if *version == MessageVersion::V3 {
+            let iblt = h.iblt.as_ref().map(|i| i.to_bytes()).unwrap_or_default();
+            leb128::write::unsigned(buf, iblt.len() as u64).unwrap();
+            buf.extend(iblt);
+        }
+    });
+}
+
+fn encode_chunks(buf: &mut Vec<u8>, changes: &ChunkList) {
+    encode_many(buf, changes.iter(), |buf, change| {
+        leb128::write::unsigned(buf, change.len() as u64).unwrap();
+        buf.extend::<&[u8]>(change)
+    });
+}
+
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
 pub enum Capability {
     #[default]
     MessageV1,
     MessageV2,
+    MessageV3,
     Unknown(u8),
 }
 
@@ -680,6 +1285,7 @@ impl Capability {
         match self {
             Capability::MessageV1 => out.push(0x01),
             Capability::MessageV2 => out.push(0x02),
+            Capability::MessageV3 => out.push(0x03),
             Capability::Unknown(v) => out.push(*v),
         }
     }
@@ -689,6 +1295,7 @@ impl Capability {
         match v {
             0x01 => Ok((i, Self::MessageV1)),
             0x02 => Ok((i, Self::MessageV2)),
+            0x03 => Ok((i, Self::MessageV3)),
             _ => Ok((i, Self::Unknown(v))),
         }
     }
@@ -713,6 +1320,37 @@ fn encode_hashes(buf: &mut Vec<u8>, hashes: &[ChangeHash]) {
     encode_many(buf, hashes.iter(), |buf, hash| buf.extend(hash.as_bytes()))
 }
 
+/// The number of bytes used to encode a list of chunks with the given lengths in a message
+fn encoded_chunks_len<I: Iterator<Item = usize>>(lens: I) -> usize {
+    let (count, total) = lens.fold((0, 0), |(count, total), len| {
+        (count + 1, total + ulebsize(len as u64) as usize + len)
+    });
+    ulebsize(count) as usize + total
+}
+
+/// How many of the changes with the given lengths fit within `budget` bytes, always at least one
+/// so that a single change which is larger than the budget doesn't stall the sync
+fn fitting_changes<I: Iterator<Item = usize>>(budget: usize, v2: bool, lens: I) -> usize {
+    let mut count = 0;
+    let mut bytes = 0;
+    for len in lens {
+        // V1 messages encode each change as a chunk, V2 messages concatenate them into one chunk
+        let (next_bytes, size) = if v2 {
+            let next = bytes + len;
+            (next, encoded_chunks_len(std::iter::once(next)))
+        } else {
+            let next = bytes + ulebsize(len as u64) as usize + len;
+            (next, ulebsize(count as u64 + 1) as usize + next)
+        };
+        if count > 0 && size > budget {
+            break;
+        }
+        count += 1;
+        bytes = next_bytes;
+    }
+    count
+}
+
 fn advance_heads(
     my_old_heads: &HashSet<&ChangeHash>,
     my_new_heads: &HashSet<ChangeHash>,
@@ -761,6 +1399,19 @@ mod tests {
             Have {
                 bloom,
                 last_sync,
+                iblt: None,
+            }
+        }
+    }
+
+    prop_compose! {
+        fn gen_have_with_iblt()(
+            have in gen_have(),
+            hashes in proptest::option::of(gen_sorted_hashes(0..10)),
+        ) -> Have {
+            Have {
+                iblt: hashes.map(|h| Iblt::from_hashes(h.iter())),
+                ..have
             }
         }
     }
@@ -820,8 +1471,35 @@ mod tests {
         }
     }
 
+    prop_compose! {
+        fn gen_sync_message_v3()(
+            heads in gen_sorted_hashes(0..10),
+            need in gen_sorted_hashes(0..10),
+            have in proptest::collection::vec(gen_have_with_iblt(), 0..10),
+            raw in proptest::collection::vec(any::<u8>(), 0..100),
+            supported_capabilities in prop_oneof![
+                Just(None),
+                Just(Some(vec![Capability::MessageV1, Capability::MessageV2, Capability::MessageV3])),
+            ],
+        ) -> Message {
+            Message {
+                heads,
+                need,
+                have,
+                changes: ChunkList::from(raw),
+                supported_capabilities,
+                version: MessageVersion::V3,
+            }
+        }
+    }
+
     fn gen_sync_message() -> impl Strategy<Value = Message> {
-        prop_oneof![gen_sync_message_v1(), gen_sync_message_v2(),].boxed()
+        prop_oneof![
+            gen_sync_message_v1(),
+            gen_sync_message_v2(),
+            gen_sync_message_v3(),
+        ]
+        .boxed()
     }
 
     #[test]
@@ -1287,4 +1965,374 @@ mod tests {
         let (_, chunk) = Chunk::parse(Input::new(&changes.0[0])).unwrap();
         assert!(matches!(chunk, Chunk::Document(_)));
     }
+
+    fn sync_with_limit(
+        a: &mut crate::AutoCommit,
+        b: &mut crate::AutoCommit,
+        a_sync_state: &mut State,
+        b_sync_state: &mut State,
+        max_size: usize,
+    ) -> usize {
+        const MAX_ITER: usize = 100;
+        let mut iterations = 0;
+
+        loop {
+            let a_to_b = a
+                .sync()
+                .generate_sync_message_with_limit(a_sync_state, max_size);
+            let b_to_a = b
+                .sync()
+                .generate_sync_message_with_limit(b_sync_state, max_size);
+            if a_to_b.is_none() && b_to_a.is_none() {
+                break;
+            }
+            if iterations > MAX_ITER {
+                panic!("failed to sync in {} iterations", MAX_ITER);
+            }
+            for (msg, doc, state) in [
+                (a_to_b, &mut *b, &mut *b_sync_state),
+                (b_to_a, &mut *a, &mut *a_sync_state),
+            ] {
+                if let Some(msg) = msg {
+                    let encoded = msg.encode();
+                    assert!(
+                        encoded.len() <= max_size,
+                        "message was {} bytes",
+                        encoded.len()
+                    );
+                    let msg = Message::decode(&encoded).unwrap();
+                    doc.sync().receive_sync_message(state, msg).unwrap();
+                }
+            }
+            iterations += 1;
+        }
+        iterations
+    }
+
+    #[test]
+    fn size_limited_messages_split_changes_across_rounds() {
+        let mut doc1 = crate::AutoCommit::new();
+        let list = doc1
+            .put_object(crate::ROOT, "list", crate::ObjType::List)
+            .unwrap();
+        for i in 0..50 {
+            doc1.insert(&list, i, format!("item number {}", i)).unwrap();
+            doc1.commit();
+        }
+        let mut doc2 = crate::AutoCommit::new();
+        doc2.put(crate::ROOT, "other", "value").unwrap();
+
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        let iterations = sync_with_limit(&mut doc1, &mut doc2, &mut s1, &mut s2, 1024);
+
+        assert_eq!(doc1.get_heads(), doc2.get_heads());
+        assert!(s1.unsent_hashes.is_empty());
+        // the limit forced the changes to be spread across several rounds
+        assert!(iterations > 3);
+    }
+
+    #[test]
+    fn size_limited_messages_send_changes_larger_than_the_limit() {
+        let mut doc1 = crate::AutoCommit::new();
+        // something which doesn't compress well
+        let mut seed = 0x2545_f491_u32;
+        let big = (0..2000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                char::from(b'a' + (seed % 26) as u8)
+            })
+            .collect::<String>();
+        doc1.put(crate::ROOT, "big", big).unwrap();
+        doc1.commit();
+        doc1.put(crate::ROOT, "small", "y").unwrap();
+        doc1.commit();
+        let mut doc2 = crate::AutoCommit::new();
+
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        let msg = doc2
+            .sync()
+            .generate_sync_message_with_limit(&mut s2, 512)
+            .unwrap();
+        doc1.sync().receive_sync_message(&mut s1, msg).unwrap();
+        let msg = doc1
+            .sync()
+            .generate_sync_message_with_limit(&mut s1, 512)
+            .unwrap();
+        // The whole document doesn't fit so the first change is sent on its own
+        assert_eq!(s1.unsent_hashes.len(), 1);
+        doc2.sync().receive_sync_message(&mut s2, msg).unwrap();
+        assert_eq!(
+            doc2.get(crate::ROOT, "big")
+                .unwrap()
+                .unwrap()
+                .0
+                .to_str()
+                .map(|s| s.len()),
+            Some(2000)
+        );
+
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+        assert_eq!(doc1.get_heads(), doc2.get_heads());
+        assert!(s1.unsent_hashes.is_empty());
+    }
+
+    /// Make `count` concurrent changes on each of two documents which are in sync
+    fn diverge(doc1: &mut crate::AutoCommit, doc2: &mut crate::AutoCommit, count: usize) {
+        for i in 0..count {
+            doc1.put(crate::ROOT, "x", i as i64).unwrap();
+            doc1.commit();
+            doc2.put(crate::ROOT, "y", i as i64).unwrap();
+            doc2.commit();
+        }
+    }
+
+    #[test]
+    fn v3_messages_reconcile_large_divergences_in_one_round() {
+        let mut doc1 = crate::AutoCommit::new().with_actor(ActorId::try_from("abc123").unwrap());
+        let mut doc2 = crate::AutoCommit::new().with_actor(ActorId::try_from("def456").unwrap());
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+
+        diverge(&mut doc1, &mut doc2, 1000);
+
+        // Each side sends an IBLT of its new changes
+        let m1 = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        let m2 = doc2.sync().generate_sync_message(&mut s2).unwrap();
+        assert_eq!(m1.version, MessageVersion::V3);
+        assert!(m1.have[0].iblt.is_some());
+        let m1 = Message::decode(&m1.encode()).unwrap();
+        let m2 = Message::decode(&m2.encode()).unwrap();
+        doc2.sync().receive_sync_message(&mut s2, m1).unwrap();
+        doc1.sync().receive_sync_message(&mut s1, m2).unwrap();
+
+        // ... which tells the other side exactly which changes to send, so the replies contain
+        // every missing change with no false positives to resolve
+        let m1 = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        let m2 = doc2.sync().generate_sync_message(&mut s2).unwrap();
+        doc2.sync().receive_sync_message(&mut s2, m1).unwrap();
+        doc1.sync().receive_sync_message(&mut s1, m2).unwrap();
+        assert_eq!(doc1.get_heads(), doc2.get_heads());
+        assert!(doc1.get_missing_deps(&[]).is_empty());
+        assert!(doc2.get_missing_deps(&[]).is_empty());
+    }
+
+    #[test]
+    fn v3_messages_fall_back_to_the_bloom_filter_when_the_iblt_does_not_decode() {
+        let mut doc1 = crate::AutoCommit::new().with_actor(ActorId::try_from("abc123").unwrap());
+        let mut doc2 = crate::AutoCommit::new().with_actor(ActorId::try_from("def456").unwrap());
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+
+        // doc1's IBLT is sized for its own 100 new changes, far too small to decode a difference
+        // which includes doc2's 1100
+        diverge(&mut doc1, &mut doc2, 100);
+        for i in 0..1000 {
+            doc2.put(crate::ROOT, "z", i).unwrap();
+            doc2.commit();
+        }
+        let m1 = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        let theirs = m1.have[0].iblt.clone().unwrap();
+        let ours = doc2.get_changes(&m1.have[0].last_sync);
+        assert_eq!(ours.len(), 1100);
+        assert!(theirs
+            .same_size(ours.iter().map(|c| c.hash()))
+            .difference(&theirs)
+            .is_none());
+
+        doc2.sync().receive_sync_message(&mut s2, m1).unwrap();
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+        assert_eq!(doc1.get_heads(), doc2.get_heads());
+        assert!(doc1.get_missing_deps(&[]).is_empty());
+        assert!(doc2.get_missing_deps(&[]).is_empty());
+    }
+
+    #[test]
+    fn v3_messages_only_send_an_iblt_when_the_bloom_filter_might_fail() {
+        let mut doc1 = crate::AutoCommit::new().with_actor(ActorId::try_from("abc123").unwrap());
+        let mut doc2 = crate::AutoCommit::new().with_actor(ActorId::try_from("def456").unwrap());
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+
+        diverge(&mut doc1, &mut doc2, 10);
+        let m1 = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        assert_eq!(m1.version, MessageVersion::V3);
+        assert!(m1.have[0].iblt.is_none());
+
+        // Once a bloom filter has hidden changes from us the next have includes an IBLT
+        s1.in_flight = false;
+        s1.bloom_failed = true;
+        let m1 = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        assert!(m1.have[0].iblt.is_some());
+        assert!(!s1.bloom_failed);
+    }
+
+    #[test]
+    fn v3_capable_peers_fall_back_for_old_peers() {
+        let mut doc1 = crate::AutoCommit::new();
+        let mut doc2 = crate::AutoCommit::new();
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        doc1.put(crate::ROOT, "a", 1).unwrap();
+        doc2.put(crate::ROOT, "b", 2).unwrap();
+        doc1.commit();
+        doc2.commit();
+
+        // doc2 behaves like a peer which doesn't know about V3 messages
+        let old_capabilities = Some(vec![Capability::MessageV1, Capability::MessageV2]);
+        let mut converged = false;
+        for _ in 0..10 {
+            let m1 = doc1.sync().generate_sync_message(&mut s1);
+            let m2 = doc2.sync().generate_sync_message(&mut s2).map(|mut m| {
+                if m.supported_capabilities.is_some() {
+                    m.supported_capabilities.clone_from(&old_capabilities);
+                }
+                m
+            });
+            if m1.is_none() && m2.is_none() {
+                converged = true;
+                break;
+            }
+            if let Some(m1) = m1 {
+                assert_ne!(m1.version, MessageVersion::V3);
+                assert!(m1.have.iter().all(|h| h.iblt.is_none()));
+                doc2.sync().receive_sync_message(&mut s2, m1).unwrap();
+            }
+            if let Some(m2) = m2 {
+                doc1.sync().receive_sync_message(&mut s1, m2).unwrap();
+            }
+        }
+        assert!(converged);
+        assert_eq!(doc1.get_heads(), doc2.get_heads());
+    }
+
+    #[test]
+    fn stats_are_collected_when_enabled() {
+        let mut doc1 = crate::AutoCommit::new();
+        let mut doc2 = crate::AutoCommit::new();
+        diverge(&mut doc1, &mut doc2, 5);
+
+        let mut s1 = State {
+            stats: Some(Stats::default()),
+            ..State::new()
+        };
+        let mut s2 = State::new();
+
+        let msg = doc1.sync().generate_sync_message(&mut s1).unwrap();
+        let last_sent = s1.stats.as_ref().unwrap().last_sent.clone().unwrap();
+        assert_eq!(last_sent.total_bytes, msg.clone().encode().len());
+        assert_eq!(last_sent.heads_bytes, 1 + 32 * doc1.get_heads().len());
+        assert_eq!(last_sent.bloom_entries, 5);
+        assert!(last_sent.bloom_false_positive_rate() > 0.0);
+        assert!(last_sent.bloom_false_positive_rate() < 0.02);
+        doc2.sync().receive_sync_message(&mut s2, msg).unwrap();
+        assert!(s2.stats.is_none());
+
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+        let stats = s1.stats.clone().unwrap();
+        assert_eq!(stats.changes_sent, 5);
+        assert_eq!(stats.changes_received, 5);
+        assert_eq!(stats.duplicate_changes_received, 0);
+        assert_eq!(stats.round_trips, stats.messages_received);
+        assert!(stats.bytes_sent > 0 && stats.bytes_received > 0);
+
+        // Receiving the same changes again counts them as duplicates
+        let mut s3 = State {
+            stats: Some(Stats::default()),
+            ..State::new()
+        };
+        let changes = doc2
+            .get_changes(&[])
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let duplicate = Message {
+            heads: doc2.get_heads(),
+            need: Vec::new(),
+            have: Vec::new(),
+            changes: ChunkList::from(
+                changes
+                    .into_iter()
+                    .map(|mut c| c.bytes().to_vec())
+                    .collect::<Vec<_>>(),
+            ),
+            supported_capabilities: None,
+            version: MessageVersion::V1,
+        };
+        doc1.sync()
+            .receive_sync_message(&mut s3, duplicate)
+            .unwrap();
+        let stats = s3.stats.unwrap();
+        assert_eq!(stats.round_trips, 0);
+        assert_eq!(stats.changes_received, 10);
+        assert_eq!(stats.duplicate_changes_received, 10);
+    }
+
+    #[test]
+    fn sync_at_heads_withholds_newer_changes() {
+        let mut doc1 = crate::AutoCommit::new();
+        let mut doc2 = crate::AutoCommit::new();
+        doc1.put(crate::ROOT, "approved", 1).unwrap();
+        doc1.commit();
+        doc1.put(crate::ROOT, "approved", 2).unwrap();
+        doc1.commit();
+        let approved = doc1.get_heads();
+        doc1.put(crate::ROOT, "draft", 3).unwrap();
+        doc1.commit();
+        let draft = doc1.get_heads()[0];
+
+        let mut s1 = State::new();
+        let mut s2 = State::new();
+        for _ in 0..10 {
+            let one_to_two = doc1.generate_sync_message_at(&mut s1, &approved).unwrap();
+            let two_to_one = doc2.sync().generate_sync_message(&mut s2);
+            if one_to_two.is_none() && two_to_one.is_none() {
+                break;
+            }
+            if let Some(msg) = one_to_two {
+                assert_eq!(msg.heads, approved);
+                doc2.sync().receive_sync_message(&mut s2, msg).unwrap();
+            }
+            if let Some(msg) = two_to_one {
+                doc1.sync().receive_sync_message(&mut s1, msg).unwrap();
+            }
+        }
+        assert_eq!(doc2.get_heads(), approved);
+        assert_eq!(s1.restricted_heads, Some(approved.clone()));
+        assert!(doc2.get(crate::ROOT, "draft").unwrap().is_none());
+
+        // Even a peer which explicitly asks for the draft doesn't get it
+        let asks_for_draft = Message {
+            heads: doc2.get_heads(),
+            need: vec![draft],
+            have: vec![Have::default()],
+            changes: ChunkList::empty(),
+            supported_capabilities: None,
+            version: MessageVersion::V1,
+        };
+        doc1.sync()
+            .receive_sync_message(&mut s1, asks_for_draft)
+            .unwrap();
+        if let Some(msg) = doc1.generate_sync_message_at(&mut s1, &approved).unwrap() {
+            doc2.sync().receive_sync_message(&mut s2, msg).unwrap();
+        }
+        assert_eq!(doc2.get_heads(), approved);
+
+        // Going back to a normal sync sends the rest
+        sync(&mut doc1, &mut doc2, &mut s1, &mut s2);
+        assert_eq!(s1.restricted_heads, None);
+        assert_eq!(doc2.get_heads(), vec![draft]);
+
+        let unknown = ChangeHash([7; 32]);
+        assert!(matches!(
+            doc1.generate_sync_message_at(&mut State::new(), &[unknown]),
+            Err(AutomergeError::InvalidHash(h)) if h == unknown
+        ));
+    }
 }
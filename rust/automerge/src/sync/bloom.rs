@@ -107,6 +107,25 @@ impl BloomFilter {
             .map(|byte| byte & (1 << (probe & 7)))
     }
 
+    /// The number of hashes this filter was built from
+    pub fn num_entries(&self) -> usize {
+        self.num_entries as usize
+    }
+
+    pub(crate) fn num_bits(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    pub(crate) fn num_probes(&self) -> usize {
+        self.num_probes as usize
+    }
+
+    /// The probability that [`Self::contains_hash()`] returns `true` for a hash which was not
+    /// added to the filter
+    pub fn false_positive_rate(&self) -> f64 {
+        false_positive_rate(self.num_entries(), self.num_bits(), self.num_probes())
+    }
+
     pub fn contains_hash(&self, hash: &ChangeHash) -> bool {
         if self.num_entries == 0 {
             false
@@ -145,6 +164,16 @@ fn bits_capacity(num_entries: u32, num_bits_per_entry: u32) -> usize {
     f as usize
 }
 
+/// The standard estimate of the false positive rate of a bloom filter with `num_probes` hash
+/// functions which holds `num_entries` entries in `num_bits` bits
+pub(crate) fn false_positive_rate(num_entries: usize, num_bits: usize, num_probes: usize) -> f64 {
+    if num_entries == 0 || num_bits == 0 {
+        return 0.0;
+    }
+    let k = num_probes as f64;
+    (1.0 - (-k * num_entries as f64 / num_bits as f64).exp()).powf(k)
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("{0}")]
 pub struct DecodeError(String);
@@ -0,0 +1,363 @@
+//! Running the sync protocol over an asynchronous transport
+//!
+//! Driving [`SyncDoc::generate_sync_message()`] and [`SyncDoc::receive_sync_message()`] by hand
+//! means getting a few details right: sending a message before waiting for one, not waiting for
+//! a reply to a message which said nothing new, and recognising when both ends have everything.
+//! A [`Driver`] does this over any [`Sink`] and [`Stream`] of encoded messages, so the transport
+//! can be a websocket, a channel or anything else which moves bytes.
+//!
+//! The document is shared with the rest of the application through an `Arc<Mutex<_>>`. The lock
+//! is only held while generating or receiving a message, never across an `await`.
+//!
+//! This module requires the `futures` feature.
+//!
+//! ## Example
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use futures::{channel::mpsc, executor::block_on, future::try_join};
+//! use automerge::{sync::driver::Driver, transaction::Transactable, AutoCommit, ReadDoc};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut doc = AutoCommit::new();
+//! doc.put(automerge::ROOT, "key", "value")?;
+//! let left = Arc::new(Mutex::new(doc));
+//! let right = Arc::new(Mutex::new(AutoCommit::new()));
+//!
+//! let (left_tx, mut right_rx) = mpsc::unbounded::<Vec<u8>>();
+//! let (right_tx, mut left_rx) = mpsc::unbounded::<Vec<u8>>();
+//! let mut left_driver = Driver::new(left.clone());
+//! let mut right_driver = Driver::new(right.clone());
+//! block_on(try_join(
+//!     left_driver.run_until_synced(&mut &left_tx, &mut left_rx),
+//!     right_driver.run_until_synced(&mut &right_tx, &mut right_rx),
+//! ))?;
+//!
+//! let mut right = right.lock().unwrap();
+//! assert_eq!(right.get(automerge::ROOT, "key")?.unwrap().0.to_str(), Some("value"));
+//! # Ok(())
+//! # }
+//! ```
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::{stream, Sink, SinkExt, Stream, StreamExt};
+
+use super::{Message, ReadMessageError, State, SyncDoc};
+use crate::{AutoCommit, Automerge, AutomergeError, ChangeHash, ChangeStore};
+
+/// Something which can be synced by a [`Driver`]
+pub trait Syncable {
+    /// Call `f` with a [`SyncDoc`] for this document
+    fn with_sync<R>(&mut self, f: impl FnOnce(&mut dyn SyncDoc) -> R) -> R;
+
+    /// The current heads of this document
+    fn heads(&mut self) -> Vec<ChangeHash>;
+}
+
+impl Syncable for Automerge {
+    fn with_sync<R>(&mut self, f: impl FnOnce(&mut dyn SyncDoc) -> R) -> R {
+        f(self)
+    }
+
+    fn heads(&mut self) -> Vec<ChangeHash> {
+        self.get_heads()
+    }
+}
+
+impl Syncable for AutoCommit {
+    fn with_sync<R>(&mut self, f: impl FnOnce(&mut dyn SyncDoc) -> R) -> R {
+        f(&mut self.sync())
+    }
+
+    fn heads(&mut self) -> Vec<ChangeHash> {
+        self.get_heads()
+    }
+}
+
+impl Syncable for ChangeStore {
+    fn with_sync<R>(&mut self, f: impl FnOnce(&mut dyn SyncDoc) -> R) -> R {
+        f(self)
+    }
+
+    fn heads(&mut self) -> Vec<ChangeHash> {
+        self.get_heads()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DriverError<E> {
+    #[error("error sending message: {0}")]
+    Send(E),
+    #[error("the connection closed before we were in sync")]
+    Closed,
+    #[error("invalid message: {0}")]
+    Decode(#[from] ReadMessageError),
+    #[error(transparent)]
+    Receive(#[from] AutomergeError),
+}
+
+/// Runs the sync protocol with one peer over a [`Sink`] and [`Stream`] of encoded messages
+pub struct Driver<D> {
+    doc: Arc<Mutex<D>>,
+    state: State,
+    on_remote_changes: Option<RemoteChangesCallback>,
+}
+
+type RemoteChangesCallback = Box<dyn FnMut(&[ChangeHash]) + Send>;
+
+impl<D> std::fmt::Debug for Driver<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Driver")
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+enum Event {
+    Remote(Vec<u8>),
+    Local,
+    Closed,
+}
+
+impl<D: Syncable> Driver<D> {
+    /// Create a driver with a fresh [`State`]
+    pub fn new(doc: Arc<Mutex<D>>) -> Self {
+        Self::with_state(doc, State::new())
+    }
+
+    /// Create a driver which continues from a [`State`], usually one which was persisted with
+    /// [`State::encode()`] at the end of a previous session
+    pub fn with_state(doc: Arc<Mutex<D>>, state: State) -> Self {
+        Self {
+            doc,
+            state,
+            on_remote_changes: None,
+        }
+    }
+
+    /// Call `f` with the new heads of the document every time a message from the peer adds
+    /// changes to it
+    pub fn on_remote_changes(mut self, f: impl FnMut(&[ChangeHash]) + Send + 'static) -> Self {
+        self.on_remote_changes = Some(Box::new(f));
+        self
+    }
+
+    pub fn doc(&self) -> &Arc<Mutex<D>> {
+        &self.doc
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    pub fn into_state(self) -> State {
+        self.state
+    }
+
+    /// Exchange messages until both ends have the same changes
+    ///
+    /// Returns an error if `incoming` ends before then. Messages the peer sends after we are in
+    /// sync stay in `incoming` and will be handled by the next call to this method or to
+    /// [`Self::run()`].
+    pub async fn run_until_synced<S, R, E>(
+        &mut self,
+        outgoing: &mut S,
+        incoming: &mut R,
+    ) -> Result<(), DriverError<E>>
+    where
+        S: Sink<Vec<u8>, Error = E> + Unpin,
+        R: Stream<Item = Vec<u8>> + Unpin,
+    {
+        loop {
+            if self.send(outgoing).await? {
+                return Ok(());
+            }
+            match incoming.next().await {
+                Some(msg) => self.receive(&msg)?,
+                None => return Err(DriverError::Closed),
+            }
+        }
+    }
+
+    /// Keep the peer up to date until `incoming` ends
+    ///
+    /// Local changes to the document are not noticed by themselves, an item must be sent on
+    /// `local_changes` after making them so that the driver sends them to the peer.
+    pub async fn run<S, R, L, E>(
+        &mut self,
+        outgoing: &mut S,
+        incoming: &mut R,
+        local_changes: L,
+    ) -> Result<(), DriverError<E>>
+    where
+        S: Sink<Vec<u8>, Error = E> + Unpin,
+        R: Stream<Item = Vec<u8>> + Unpin,
+        L: Stream<Item = ()> + Unpin,
+    {
+        let remote = incoming
+            .map(Event::Remote)
+            .chain(stream::iter([Event::Closed]));
+        let mut events = stream::select(remote, local_changes.map(|()| Event::Local));
+        loop {
+            self.send(outgoing).await?;
+            match events.next().await {
+                Some(Event::Remote(msg)) => self.receive(&msg)?,
+                Some(Event::Local) => {}
+                Some(Event::Closed) | None => return Ok(()),
+            }
+        }
+    }
+
+    /// Send a message if we have anything to say and return whether we are in sync with the peer
+    async fn send<S, E>(&mut self, outgoing: &mut S) -> Result<bool, DriverError<E>>
+    where
+        S: Sink<Vec<u8>, Error = E> + Unpin,
+    {
+        let msg = lock(&self.doc).with_sync(|doc| doc.generate_sync_message(&mut self.state));
+        let sent = msg.is_some();
+        if let Some(msg) = msg {
+            outgoing
+                .send(msg.encode())
+                .await
+                .map_err(DriverError::Send)?;
+        }
+        // Either the peer has told us it has exactly what we have, or we had nothing to say after
+        // their last message, which only happens once the heads are equal
+        let synced = self.state.their_heads.as_ref() == Some(&self.state.last_sent_heads)
+            || (!sent && !self.state.in_flight);
+        Ok(synced)
+    }
+
+    fn receive<E>(&mut self, msg: &[u8]) -> Result<(), DriverError<E>> {
+        let msg = Message::decode(msg)?;
+        let mut doc = lock(&self.doc);
+        let before = doc.heads();
+        doc.with_sync(|doc| doc.receive_sync_message(&mut self.state, msg))?;
+        let after = doc.heads();
+        drop(doc);
+        if before != after {
+            if let Some(f) = self.on_remote_changes.as_mut() {
+                f(&after);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn lock<D>(doc: &Mutex<D>) -> MutexGuard<'_, D> {
+    // A panic while holding the lock leaves the document in a consistent state, we just don't
+    // know whether the last operation was applied
+    doc.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transactable;
+    use crate::{ReadDoc, ROOT};
+    use futures::channel::mpsc;
+    use futures::executor::block_on;
+    use futures::future::{join, try_join};
+
+    type Connection = (
+        mpsc::UnboundedSender<Vec<u8>>,
+        mpsc::UnboundedReceiver<Vec<u8>>,
+    );
+
+    fn duplex() -> (Connection, Connection) {
+        let (left_tx, right_rx) = mpsc::unbounded();
+        let (right_tx, left_rx) = mpsc::unbounded();
+        ((left_tx, left_rx), (right_tx, right_rx))
+    }
+
+    fn doc_with(key: &str, value: i64) -> Arc<Mutex<AutoCommit>> {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, key, value).unwrap();
+        Arc::new(Mutex::new(doc))
+    }
+
+    #[test]
+    fn syncs_to_quiescence() {
+        let left = doc_with("left", 1);
+        let right = doc_with("right", 2);
+        let ((mut l_tx, mut l_rx), (mut r_tx, mut r_rx)) = duplex();
+
+        let notified = Arc::new(Mutex::new(Vec::new()));
+        let notified2 = notified.clone();
+        let mut left_driver = Driver::new(left.clone())
+            .on_remote_changes(move |heads| notified2.lock().unwrap().push(heads.to_vec()));
+        let mut right_driver = Driver::new(right.clone());
+
+        block_on(try_join(
+            left_driver.run_until_synced(&mut l_tx, &mut l_rx),
+            right_driver.run_until_synced(&mut r_tx, &mut r_rx),
+        ))
+        .unwrap();
+
+        let left_heads = left.lock().unwrap().get_heads();
+        assert_eq!(left_heads, right.lock().unwrap().get_heads());
+        assert_eq!(
+            right.lock().unwrap().get(ROOT, "left").unwrap().unwrap().0,
+            1_i64.into()
+        );
+        assert_eq!(*notified.lock().unwrap(), vec![left_heads]);
+
+        // Syncing again with the persisted state is a single round trip with no changes
+        let left_state = State::decode(&left_driver.into_state().encode()).unwrap();
+        let right_state = State::decode(&right_driver.into_state().encode()).unwrap();
+        let mut left_driver = Driver::with_state(left, left_state);
+        let mut right_driver = Driver::with_state(right, right_state);
+        block_on(try_join(
+            left_driver.run_until_synced(&mut l_tx, &mut l_rx),
+            right_driver.run_until_synced(&mut r_tx, &mut r_rx),
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn live_updates_reach_the_peer() {
+        let left = doc_with("left", 1);
+        let right = Arc::new(Mutex::new(AutoCommit::new()));
+        let ((l_tx, mut l_rx), (r_tx, mut r_rx)) = duplex();
+        let (mut local_tx, local_rx) = mpsc::unbounded::<()>();
+        let (updates_tx, mut updates_rx) = mpsc::unbounded::<Vec<ChangeHash>>();
+        let (mut l_tx2, mut r_tx2) = (l_tx.clone(), r_tx.clone());
+
+        let mut left_driver = Driver::new(left.clone());
+        let mut right_driver = Driver::new(right.clone())
+            .on_remote_changes(move |heads| updates_tx.unbounded_send(heads.to_vec()).unwrap());
+
+        let edits = async {
+            // wait for the initial sync to complete before editing
+            updates_rx.next().await.unwrap();
+            for i in 0..3 {
+                left.lock().unwrap().put(ROOT, "count", i).unwrap();
+                local_tx.send(()).await.unwrap();
+                let heads = updates_rx.next().await.unwrap();
+                assert_eq!(heads, left.lock().unwrap().get_heads());
+            }
+            // closing the connection ends both drivers
+            l_tx.close_channel();
+            r_tx.close_channel();
+        };
+        let drivers = try_join(
+            left_driver.run(&mut l_tx2, &mut l_rx, local_rx),
+            right_driver.run(&mut r_tx2, &mut r_rx, stream::pending()),
+        );
+        let (result, ()) = block_on(join(drivers, edits));
+        result.unwrap();
+
+        assert_eq!(
+            right.lock().unwrap().get(ROOT, "count").unwrap().unwrap().0,
+            2_i64.into()
+        );
+    }
+
+    #[test]
+    fn closed_connection_is_an_error() {
+        let left = doc_with("left", 1);
+        let ((mut l_tx, _l_rx), (_r_tx, _r_rx)) = duplex();
+        let mut closed = stream::empty();
+        let result = block_on(Driver::new(left).run_until_synced(&mut l_tx, &mut closed));
+        assert!(matches!(result, Err(DriverError::Closed)));
+    }
+}
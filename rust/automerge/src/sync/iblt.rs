@@ -0,0 +1,356 @@
+use std::borrow::Borrow;
+
+use crate::storage::parse;
+use crate::ChangeHash;
+
+// Each hash is added to one cell in each of this many sub tables. As with the bloom filter the
+// sizing parameters can be changed without breaking compatibility because the number of cells is
+// encoded in the wire format and the receiver always builds a table of the same size.
+const NUM_TABLES: usize = 3;
+// With three tables a difference of `d` hashes decodes with high probability if there are at least
+// ~1.3 * d cells. The difference between two peers is at most the size of both their sets, so
+// three cells per entry decodes as long as the other peer doesn't have many more new changes.
+const CELLS_PER_ENTRY: usize = 3;
+const MIN_CELLS_PER_TABLE: usize = 8;
+// Bounds the size of the table for large sets, this still decodes differences of a few thousand
+// changes
+const MAX_CELLS_PER_TABLE: usize = 2048;
+
+/// An invertible Bloom lookup table summarising a set of change hashes
+///
+/// Unlike a [`super::BloomFilter`] two of these tables can be subtracted from each other to
+/// recover exactly which hashes are in one set but not the other, provided the difference is not
+/// too large for the size of the table.
+///
+/// To keep the table small it doesn't store whole hashes but a 64 bit key derived from each hash.
+/// The recipient only needs to recognise which of its own changes the sender is missing, which it
+/// can do by comparing keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, serde::Serialize)]
+pub struct Iblt {
+    cells_per_table: usize,
+    cells: Vec<Cell>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Serialize)]
+struct Cell {
+    count: i64,
+    key_sum: u64,
+    check_sum: u32,
+}
+
+/// The keys of the hashes in one of two sets but not the other, see [`Iblt::difference()`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Difference {
+    /// Keys which are in the left hand table but not the right
+    pub(crate) ours: Vec<u64>,
+    /// Keys which are in the right hand table but not the left
+    pub(crate) theirs: Vec<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum ParseError {
+    #[error(transparent)]
+    Leb128(#[from] parse::leb128::Error),
+    #[error("too many cells")]
+    TooManyCells,
+}
+
+/// The key a hash is stored under in an [`Iblt`]
+pub(crate) fn key(hash: &ChangeHash) -> u64 {
+    u64::from_le_bytes(hash.0[..8].try_into().unwrap())
+}
+
+impl Cell {
+    fn toggle(&mut self, key: u64, count: i64) {
+        self.count += count;
+        self.key_sum ^= key;
+        self.check_sum ^= check(key);
+    }
+
+    fn subtract(&self, other: &Cell) -> Cell {
+        Cell {
+            count: self.count - other.count,
+            key_sum: self.key_sum ^ other.key_sum,
+            check_sum: self.check_sum ^ other.check_sum,
+        }
+    }
+
+    /// If this cell contains exactly one key, that key and which side it was on
+    fn pure(&self) -> Option<(u64, i64)> {
+        if (self.count == 1 || self.count == -1) && check(self.key_sum) == self.check_sum {
+            Some((self.key_sum, self.count))
+        } else {
+            None
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.key_sum == 0 && self.check_sum == 0
+    }
+}
+
+impl Iblt {
+    /// A table sized to decode differences of around the number of `hashes`
+    pub fn from_hashes<H: Borrow<ChangeHash>>(hashes: impl ExactSizeIterator<Item = H>) -> Self {
+        // `usize::div_ceil` needs a newer compiler than our minimum supported version
+        let cells_per_table = ((hashes.len() * CELLS_PER_ENTRY + NUM_TABLES - 1) / NUM_TABLES)
+            .clamp(MIN_CELLS_PER_TABLE, MAX_CELLS_PER_TABLE);
+        Self::with_size(cells_per_table, hashes)
+    }
+
+    /// A table with the same size as this one containing `hashes`
+    pub(crate) fn same_size<H: Borrow<ChangeHash>>(&self, hashes: impl Iterator<Item = H>) -> Self {
+        Self::with_size(self.cells_per_table, hashes)
+    }
+
+    fn with_size<H: Borrow<ChangeHash>>(
+        cells_per_table: usize,
+        hashes: impl Iterator<Item = H>,
+    ) -> Self {
+        let mut table = Iblt {
+            cells_per_table,
+            cells: vec![Cell::default(); cells_per_table * NUM_TABLES],
+        };
+        if cells_per_table > 0 {
+            for hash in hashes {
+                let key = key(hash.borrow());
+                for index in indices(cells_per_table, key) {
+                    table.cells[index].toggle(key, 1);
+                }
+            }
+        }
+        table
+    }
+
+    /// The keys which are in this table but not `other` and vice versa
+    ///
+    /// Returns `None` if the tables are different sizes or if the difference is too large to
+    /// decode.
+    pub(crate) fn difference(&self, other: &Iblt) -> Option<Difference> {
+        if self.cells_per_table != other.cells_per_table || self.cells.is_empty() {
+            return None;
+        }
+        let mut cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(a, b)| a.subtract(b))
+            .collect::<Vec<_>>();
+        let mut result = Difference::default();
+        let mut pure = (0..cells.len())
+            .filter(|i| cells[*i].pure().is_some())
+            .collect::<Vec<_>>();
+        while let Some(index) = pure.pop() {
+            // removing other keys may have changed this cell since it was queued
+            let Some((key, count)) = cells[index].pure() else {
+                continue;
+            };
+            if count > 0 {
+                result.ours.push(key);
+            } else {
+                result.theirs.push(key);
+            }
+            for i in indices(self.cells_per_table, key) {
+                cells[i].toggle(key, -count);
+                if cells[i].pure().is_some() {
+                    pure.push(i);
+                }
+            }
+        }
+        if cells.iter().all(Cell::is_empty) {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    /// Whether this is the empty table sent in place of a missing one
+    pub(crate) fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if !self.cells.is_empty() {
+            leb128::write::unsigned(&mut buf, self.cells_per_table as u64).unwrap();
+            for cell in &self.cells {
+                leb128::write::signed(&mut buf, cell.count).unwrap();
+                buf.extend(cell.key_sum.to_le_bytes());
+                buf.extend(cell.check_sum.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    pub(crate) fn parse(input: parse::Input<'_>) -> parse::ParseResult<'_, Self, ParseError> {
+        if input.is_empty() {
+            return Ok((input, Self::default()));
+        }
+        let (mut i, cells_per_table) = parse::leb128_u64(input)?;
+        // Leave room for future versions to use larger tables but don't let a malicious peer make
+        // us allocate unbounded memory
+        if cells_per_table > MAX_CELLS_PER_TABLE as u64 * 16 {
+            return Err(parse::ParseError::Error(ParseError::TooManyCells));
+        }
+        let cells_per_table = cells_per_table as usize;
+        let mut cells = Vec::new();
+        for _ in 0..cells_per_table * NUM_TABLES {
+            let (rest, count) = parse::leb128_i64(i)?;
+            let (rest, key_sum) = parse::take_n(8, rest)?;
+            let (rest, check_sum) = parse::take4(rest)?;
+            cells.push(Cell {
+                count,
+                key_sum: u64::from_le_bytes(key_sum.try_into().unwrap()),
+                check_sum: u32::from_le_bytes(check_sum),
+            });
+            i = rest;
+        }
+        Ok((
+            i,
+            Iblt {
+                cells_per_table,
+                cells,
+            },
+        ))
+    }
+}
+
+/// The cell `key` is stored in in each of the sub tables
+fn indices(cells_per_table: usize, key: u64) -> impl Iterator<Item = usize> {
+    (0..NUM_TABLES).map(move |table| {
+        let x = mix(key.wrapping_add(table as u64)) as usize;
+        table * cells_per_table + x % cells_per_table
+    })
+}
+
+/// A checksum of `key` used to detect cells which contain a single key
+///
+/// This must not be linear in XOR, otherwise the checksum of a sum of keys would equal the sum of
+/// their checksums and every cell with a count of one would look pure.
+fn check(key: u64) -> u32 {
+    (mix(key ^ 0x5bd1e995) >> 32) as u32
+}
+
+/// The splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("{0}")]
+pub struct DecodeError(String);
+
+impl TryFrom<&[u8]> for Iblt {
+    type Error = DecodeError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse(parse::Input::new(bytes))
+            .map(|(_, b)| b)
+            .map_err(|e| DecodeError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::gen::gen_hash;
+    use proptest::prelude::*;
+    use std::collections::BTreeSet;
+
+    proptest! {
+        #[test]
+        fn decoded_differences_are_exact(
+            hashes in proptest::collection::btree_set(gen_hash(), 40..220),
+            num_ours in 0..10_usize,
+            num_theirs in 0..10_usize,
+        ) {
+            // The sets of hashes must be distinct, and so must their keys. Real change hashes
+            // are uniformly distributed so their keys don't collide, but generated hashes often
+            // share a prefix.
+            let mut hashes = hashes.into_iter().collect::<Vec<_>>();
+            hashes.dedup_by_key(|hash| key(hash));
+            prop_assume!(hashes.len() >= 40);
+            let (ours, rest) = hashes.split_at(num_ours);
+            let (theirs, shared) = rest.split_at(num_theirs);
+            let left = shared.iter().chain(ours.iter()).collect::<Vec<_>>();
+            let right = shared.iter().chain(theirs.iter()).collect::<Vec<_>>();
+            let left = Iblt::from_hashes(left.into_iter());
+            let right = left.same_size(right.into_iter());
+
+            let decoded = Iblt::try_from(left.to_bytes().as_slice()).unwrap();
+            prop_assert_eq!(&decoded, &left);
+
+            // Decoding fails occasionally even for small differences, for example when one of our
+            // keys and one of theirs land in the same cell in every table. The sync protocol falls
+            // back to the bloom filter then, see `decodes_most_small_differences` for how often
+            // that happens. When it does decode the result must be exactly the symmetric
+            // difference of the two sets.
+            let left_keys = shared.iter().chain(ours.iter()).map(key).collect::<BTreeSet<_>>();
+            let right_keys = shared.iter().chain(theirs.iter()).map(key).collect::<BTreeSet<_>>();
+            if let Some(diff) = decoded.difference(&right) {
+                let diff_ours = diff.ours.iter().copied().collect::<BTreeSet<_>>();
+                let diff_theirs = diff.theirs.iter().copied().collect::<BTreeSet<_>>();
+                prop_assert_eq!(diff.ours.len(), diff_ours.len());
+                prop_assert_eq!(diff.theirs.len(), diff_theirs.len());
+                prop_assert_eq!(
+                    diff_ours,
+                    left_keys.difference(&right_keys).copied().collect::<BTreeSet<_>>()
+                );
+                prop_assert_eq!(
+                    diff_theirs,
+                    right_keys.difference(&left_keys).copied().collect::<BTreeSet<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_most_small_differences() {
+        // Deterministic "random" hashes so that the test doesn't flake
+        let hash = |i: u64| {
+            let mut bytes = [0; 32];
+            for (n, chunk) in bytes.chunks_mut(8).enumerate() {
+                chunk.copy_from_slice(&mix(i.wrapping_mul(4).wrapping_add(n as u64)).to_le_bytes());
+            }
+            ChangeHash(bytes)
+        };
+        let trials = 1000;
+        let mut decoded = 0;
+        for trial in 0..trials {
+            let hashes = (0..60).map(|i| hash(trial * 60 + i)).collect::<Vec<_>>();
+            let (ours, rest) = hashes.split_at(5);
+            let (theirs, shared) = rest.split_at(8);
+            let left = Iblt::from_hashes(
+                shared
+                    .iter()
+                    .chain(ours.iter())
+                    .collect::<Vec<_>>()
+                    .into_iter(),
+            );
+            let right = left.same_size(shared.iter().chain(theirs.iter()));
+            if left.difference(&right).is_some() {
+                decoded += 1;
+            }
+        }
+        assert!(
+            decoded >= trials * 99 / 100,
+            "only decoded {} of {}",
+            decoded,
+            trials
+        );
+    }
+
+    #[test]
+    fn too_large_differences_fail_to_decode() {
+        let ours = (0..10u8).map(|i| ChangeHash([i; 32])).collect::<Vec<_>>();
+        let theirs = (100..200u8)
+            .map(|i| ChangeHash([i; 32]))
+            .collect::<Vec<_>>();
+        let left = Iblt::from_hashes(ours.iter());
+        let right = left.same_size(theirs.iter());
+        assert_eq!(left.difference(&right), None);
+    }
+}
@@ -38,6 +38,13 @@ impl MessageBuilder {
         }
     }
 
+    pub(super) fn new_v3(changes: Vec<u8>) -> Self {
+        MessageBuilder {
+            version: MessageVersion::V3,
+            ..Self::new_v2(changes)
+        }
+    }
+
     pub(super) fn heads(mut self, heads: Vec<ChangeHash>) -> Self {
         self.heads = heads;
         self
@@ -0,0 +1,607 @@
+//! Synchronising many documents over a single connection
+//!
+//! The sync protocol in [`crate::sync`] synchronises one document with one peer. Applications
+//! which hold many documents usually want to share a single connection between them. A
+//! [`Multiplexer`] keeps a [`State`] for every (peer, document) pair and bundles the messages for
+//! many documents into a single [`Batch`], tagging each message with the [`DocumentId`] it is for.
+//!
+//! ## Example
+//!
+//! ```
+//! use automerge::{transaction::Transactable, sync::{Batch, DocumentId, Multiplexer}, ReadDoc};
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let doc_id = DocumentId::from("todos");
+//! let mut client_doc = automerge::AutoCommit::new();
+//! client_doc.put(automerge::ROOT, "key", "value")?;
+//! let mut server_doc = automerge::AutoCommit::new();
+//!
+//! // Each end keeps a multiplexer, keyed by whatever identifies the peers on the other end
+//! let mut client = Multiplexer::new();
+//! let mut server = Multiplexer::new();
+//!
+//! loop {
+//!     let to_server = client.generate_batch(&"server", [(&doc_id, client_doc.sync())]);
+//!     if let Some(batch) = to_server.clone() {
+//!         for (id, message) in Batch::decode(&batch.encode())?.messages {
+//!             server.receive_sync_message(&"client", &id, &mut server_doc.sync(), message)?;
+//!         }
+//!     }
+//!     let to_client = server.generate_batch(&"client", [(&doc_id, server_doc.sync())]);
+//!     if let Some(batch) = to_client.clone() {
+//!         for (id, message) in Batch::decode(&batch.encode())?.messages {
+//!             client.receive_sync_message(&"server", &id, &mut client_doc.sync(), message)?;
+//!         }
+//!     }
+//!     if to_server.is_none() && to_client.is_none() {
+//!         break;
+//!     }
+//! }
+//! assert_eq!(server_doc.get(automerge::ROOT, "key")?.unwrap().0.to_str(), Some("value"));
+//! # Ok(())
+//! # }
+//! ```
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use super::{encode_many, presence, Message, ReadMessageError, State, SyncDoc};
+use crate::storage::parse;
+use crate::AutomergeError;
+
+const BATCH_TYPE_V1: u8 = 0x4d; // first byte of a batch of sync messages, for identification
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("wrong type: expected one of {expected_one_of:?} but found {found}")]
+    WrongType { expected_one_of: Vec<u8>, found: u8 },
+    #[error("invalid LEB128 encoded length")]
+    InvalidLength,
+    #[error("invalid message for document {document}: {error}")]
+    Message {
+        document: DocumentId,
+        error: ReadMessageError,
+    },
+    #[error("invalid presence message for document {document}: {error}")]
+    Presence {
+        document: DocumentId,
+        error: presence::DecodeError,
+    },
+    #[error("not enough input")]
+    NotEnoughInput,
+}
+
+impl From<parse::leb128::Error> for DecodeError {
+    fn from(_: parse::leb128::Error) -> Self {
+        DecodeError::InvalidLength
+    }
+}
+
+/// An identifier for a document shared between peers
+///
+/// This is an opaque sequence of bytes, it's up to the application to decide what identifies a
+/// document. [`DocumentId::random()`] generates a new random identifier.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DocumentId(Vec<u8>);
+
+impl DocumentId {
+    pub fn random() -> DocumentId {
+        DocumentId(uuid::Uuid::new_v4().as_bytes().to_vec())
+    }
+
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_hex_string(&self) -> String {
+        hex::encode(&self.0)
+    }
+}
+
+impl fmt::Debug for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DocumentId")
+            .field(&self.to_hex_string())
+            .finish()
+    }
+}
+
+impl fmt::Display for DocumentId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_hex_string())
+    }
+}
+
+impl AsRef<[u8]> for DocumentId {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for DocumentId {
+    fn from(b: &[u8]) -> Self {
+        DocumentId(b.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for DocumentId {
+    fn from(b: Vec<u8>) -> Self {
+        DocumentId(b)
+    }
+}
+
+impl From<&str> for DocumentId {
+    fn from(s: &str) -> Self {
+        DocumentId(s.as_bytes().to_vec())
+    }
+}
+
+impl From<uuid::Uuid> for DocumentId {
+    fn from(u: uuid::Uuid) -> Self {
+        DocumentId(u.as_bytes().to_vec())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchVersion {
+    V1,
+}
+
+impl BatchVersion {
+    fn parse(input: parse::Input<'_>) -> parse::ParseResult<'_, Self, DecodeError> {
+        let (i, first_byte) = parse::take1(input)?;
+        match first_byte {
+            BATCH_TYPE_V1 => Ok((i, Self::V1)),
+            _ => Err(parse::ParseError::Error(DecodeError::WrongType {
+                expected_one_of: vec![BATCH_TYPE_V1],
+                found: first_byte,
+            })),
+        }
+    }
+
+    fn encode(&self) -> u8 {
+        match self {
+            Self::V1 => BATCH_TYPE_V1,
+        }
+    }
+}
+
+/// Sync messages for several documents, to be sent to a peer as a single frame
+///
+/// ## Encoding
+///
+/// A batch is encoded as a version byte followed by a length prefixed list of entries. Each entry
+/// is the length prefixed bytes of the [`DocumentId`] followed by the length prefixed bytes of the
+/// encoded [`Message`]. If the batch contains any presence messages these follow as a second
+/// length prefixed list of entries in the same format, so batches without presence are readable by
+/// older peers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Batch {
+    /// The messages in this batch and the documents they are for
+    pub messages: Vec<(DocumentId, Message)>,
+    /// Ephemeral presence messages to send along with the sync messages, see [`presence`]
+    pub presence: Vec<(DocumentId, presence::Message)>,
+    /// What version to encode this batch as
+    pub version: BatchVersion,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch {
+            messages: Vec::new(),
+            presence: Vec::new(),
+            version: BatchVersion::V1,
+        }
+    }
+
+    pub fn push(&mut self, document: DocumentId, message: Message) {
+        self.messages.push((document, message));
+    }
+
+    pub fn push_presence(&mut self, document: DocumentId, message: presence::Message) {
+        self.presence.push((document, message));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of sync and presence messages in this batch
+    pub fn len(&self) -> usize {
+        self.messages.len() + self.presence.len()
+    }
+
+    /// Iterate over the sync and presence messages in this batch, sync messages first
+    pub fn iter(&self) -> impl Iterator<Item = (&DocumentId, BatchMessageRef<'_>)> {
+        let messages = self
+            .messages
+            .iter()
+            .map(|(id, msg)| (id, BatchMessageRef::Sync(msg)));
+        let presence = self
+            .presence
+            .iter()
+            .map(|(id, msg)| (id, BatchMessageRef::Presence(msg)));
+        messages.chain(presence)
+    }
+
+    pub fn encode(self) -> Vec<u8> {
+        let mut buf = vec![self.version.encode()];
+        encode_entries(&mut buf, self.messages, Message::encode);
+        if !self.presence.is_empty() {
+            encode_entries(&mut buf, self.presence, |msg| msg.encode());
+        }
+        buf
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self, DecodeError> {
+        let input = parse::Input::new(input);
+        match Self::parse(input) {
+            Ok((_, batch)) => Ok(batch),
+            Err(parse::ParseError::Error(e)) => Err(e),
+            Err(parse::ParseError::Incomplete(_)) => Err(DecodeError::NotEnoughInput),
+        }
+    }
+
+    fn parse(input: parse::Input<'_>) -> parse::ParseResult<'_, Self, DecodeError> {
+        let (i, version) = BatchVersion::parse(input)?;
+        let (i, messages) = parse::length_prefixed(parse_entry)(i)?;
+        let (i, presence) = if i.is_empty() {
+            (i, Vec::new())
+        } else {
+            parse::length_prefixed(parse_presence_entry)(i)?
+        };
+        Ok((
+            i,
+            Batch {
+                messages,
+                presence,
+                version,
+            },
+        ))
+    }
+}
+
+/// Encode `entries` as a length prefixed list of document IDs and encoded messages
+fn encode_entries<M>(
+    buf: &mut Vec<u8>,
+    entries: Vec<(DocumentId, M)>,
+    encode: impl Fn(M) -> Vec<u8>,
+) {
+    encode_many(buf, entries.into_iter(), |buf, (id, msg)| {
+        leb128::write::unsigned(buf, id.0.len() as u64).unwrap();
+        buf.extend(&id.0);
+        let encoded = encode(msg);
+        leb128::write::unsigned(buf, encoded.len() as u64).unwrap();
+        buf.extend(encoded);
+    });
+}
+
+fn parse_entry(
+    input: parse::Input<'_>,
+) -> parse::ParseResult<'_, (DocumentId, Message), DecodeError> {
+    let (i, id) = parse::length_prefixed_bytes(input)?;
+    let document = DocumentId::from(id);
+    let (i, message) = parse::length_prefixed_bytes(i)?;
+    match Message::decode(message) {
+        Ok(message) => Ok((i, (document, message))),
+        Err(error) => Err(parse::ParseError::Error(DecodeError::Message {
+            document,
+            error,
+        })),
+    }
+}
+
+fn parse_presence_entry(
+    input: parse::Input<'_>,
+) -> parse::ParseResult<'_, (DocumentId, presence::Message), DecodeError> {
+    let (i, id) = parse::length_prefixed_bytes(input)?;
+    let document = DocumentId::from(id);
+    let (i, message) = parse::length_prefixed_bytes(i)?;
+    match presence::Message::decode(message) {
+        Ok(message) => Ok((i, (document, message))),
+        Err(error) => Err(parse::ParseError::Error(DecodeError::Presence {
+            document,
+            error,
+        })),
+    }
+}
+
+/// A message in a [`Batch`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchMessage {
+    Sync(Message),
+    Presence(presence::Message),
+}
+
+/// A reference to a message in a [`Batch`], see [`Batch::iter()`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchMessageRef<'a> {
+    Sync(&'a Message),
+    Presence(&'a presence::Message),
+}
+
+impl IntoIterator for Batch {
+    type Item = (DocumentId, BatchMessage);
+    type IntoIter = std::vec::IntoIter<(DocumentId, BatchMessage)>;
+
+    /// Iterate over the sync and presence messages in this batch, sync messages first
+    fn into_iter(self) -> Self::IntoIter {
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|(id, msg)| (id, BatchMessage::Sync(msg)));
+        let presence = self
+            .presence
+            .into_iter()
+            .map(|(id, msg)| (id, BatchMessage::Presence(msg)));
+        messages.chain(presence).collect::<Vec<_>>().into_iter()
+    }
+}
+
+/// The sync state for every (peer, document) pair on one end of a set of connections
+///
+/// `P` is whatever the application uses to identify peers, for example a connection ID.
+#[derive(Debug, Clone)]
+pub struct Multiplexer<P> {
+    states: HashMap<P, HashMap<DocumentId, State>>,
+}
+
+impl<P: Eq + Hash> Default for Multiplexer<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P: Eq + Hash> Multiplexer<P> {
+    pub fn new() -> Self {
+        Multiplexer {
+            states: HashMap::new(),
+        }
+    }
+
+    /// The sync state for `document` with `peer`, if we have synced it with them
+    pub fn state(&self, peer: &P, document: &DocumentId) -> Option<&State> {
+        self.states.get(peer)?.get(document)
+    }
+
+    /// The sync state for `document` with `peer`, created if we have not synced it with them yet
+    ///
+    /// This can be used to restore a state which was persisted with [`State::encode()`].
+    pub fn state_mut(&mut self, peer: P, document: DocumentId) -> &mut State {
+        self.states
+            .entry(peer)
+            .or_default()
+            .entry(document)
+            .or_default()
+    }
+
+    /// Forget all the sync states for `peer`, for example because they have disconnected
+    pub fn remove_peer(&mut self, peer: &P) -> Option<HashMap<DocumentId, State>> {
+        self.states.remove(peer)
+    }
+
+    /// Forget the sync states of `document` with every peer
+    pub fn remove_document(&mut self, document: &DocumentId) {
+        for states in self.states.values_mut() {
+            states.remove(document);
+        }
+    }
+
+    /// The peers we have sync states for
+    pub fn peers(&self) -> impl Iterator<Item = &P> {
+        self.states.keys()
+    }
+
+    /// The documents we have sync states for with `peer`
+    pub fn documents(&self, peer: &P) -> impl Iterator<Item = &DocumentId> {
+        self.states
+            .get(peer)
+            .into_iter()
+            .flat_map(|states| states.keys())
+    }
+
+    /// Generate a sync message for `document` for `peer`, see
+    /// [`SyncDoc::generate_sync_message()`]
+    pub fn generate_sync_message<D: SyncDoc + ?Sized>(
+        &mut self,
+        peer: &P,
+        document: &DocumentId,
+        doc: &D,
+    ) -> Option<Message>
+    where
+        P: Clone,
+    {
+        doc.generate_sync_message(self.state_mut(peer.clone(), document.clone()))
+    }
+
+    /// Generate a batch containing a sync message for each of `docs` which has something to
+    /// send to `peer`
+    ///
+    /// Returns [`None`] if there is nothing to send for any of the documents.
+    pub fn generate_batch<'a, D, I>(&mut self, peer: &P, docs: I) -> Option<Batch>
+    where
+        P: Clone,
+        D: SyncDoc,
+        I: IntoIterator<Item = (&'a DocumentId, D)>,
+    {
+        let mut batch = Batch::new();
+        for (document, doc) in docs {
+            if let Some(message) = self.generate_sync_message(peer, document, &doc) {
+                batch.push(document.clone(), message);
+            }
+        }
+        if batch.is_empty() {
+            None
+        } else {
+            Some(batch)
+        }
+    }
+
+    /// Apply a sync message for `document` received from `peer`, see
+    /// [`SyncDoc::receive_sync_message()`]
+    pub fn receive_sync_message<D: SyncDoc + ?Sized>(
+        &mut self,
+        peer: &P,
+        document: &DocumentId,
+        doc: &mut D,
+        message: Message,
+    ) -> Result<(), AutomergeError>
+    where
+        P: Clone,
+    {
+        doc.receive_sync_message(self.state_mut(peer.clone(), document.clone()), message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, ReadDoc};
+    use std::sync::mpsc;
+
+    #[test]
+    fn encode_decode_batch() {
+        let mut doc = AutoCommit::new();
+        doc.put(crate::ROOT, "key", "value").unwrap();
+        let mut state = State::new();
+        let message = doc.sync().generate_sync_message(&mut state).unwrap();
+
+        let mut batch = Batch::new();
+        batch.push(DocumentId::from("one"), message.clone());
+        batch.push(DocumentId::random(), message);
+        let decoded = Batch::decode(&batch.clone().encode()).unwrap();
+        assert_eq!(decoded, batch);
+
+        let mut presence = presence::Presence::new(crate::ActorId::random(), 1000);
+        presence.set("name", "alice");
+        batch.push_presence(
+            DocumentId::from("one"),
+            presence.generate_message(0).unwrap(),
+        );
+        let decoded = Batch::decode(&batch.clone().encode()).unwrap();
+        assert_eq!(decoded, batch);
+
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.iter().count(), 3);
+        let entries = batch.clone().into_iter().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(entries[2], (_, BatchMessage::Presence(_))));
+
+        let mut presence_only = Batch::new();
+        presence_only.push_presence(DocumentId::from("two"), presence.snapshot(0));
+        assert!(!presence_only.is_empty());
+        assert_eq!(presence_only.len(), 1);
+        let decoded = Batch::decode(&presence_only.clone().encode()).unwrap();
+        assert_eq!(decoded, presence_only);
+
+        // A batch can't be mistaken for any of the other messages on the wire, or vice versa
+        let message = doc.sync().generate_sync_message(&mut State::new());
+        for other in [
+            message.unwrap().encode(),
+            State::new().encode(),
+            presence.snapshot(0).encode(),
+        ] {
+            assert!(matches!(
+                Batch::decode(&other),
+                Err(DecodeError::WrongType { .. })
+            ));
+        }
+        for version in [0x42, 0x43, 0x44] {
+            assert!(matches!(
+                Batch::decode(&[version]),
+                Err(DecodeError::WrongType { .. })
+            ));
+        }
+        assert!(Message::decode(&batch.clone().encode()).is_err());
+        assert!(matches!(
+            Batch::decode(&[BATCH_TYPE_V1, 0x01, 0x03, b'o', b'n']),
+            Err(DecodeError::NotEnoughInput)
+        ));
+    }
+
+    #[test]
+    fn sync_many_documents_over_one_channel() {
+        let ids = (0..10)
+            .map(|i| DocumentId::from(format!("doc {}", i).as_str()))
+            .collect::<Vec<_>>();
+        let mut client_docs = HashMap::new();
+        let mut server_docs = HashMap::new();
+        for (i, id) in ids.iter().enumerate() {
+            let mut client = AutoCommit::new();
+            client.put(crate::ROOT, "client", i as i64).unwrap();
+            client_docs.insert(id.clone(), client);
+            let mut server = AutoCommit::new();
+            server.put(crate::ROOT, "server", i as i64).unwrap();
+            server_docs.insert(id.clone(), server);
+        }
+        // The server only syncs the documents the client asks for
+        let private = DocumentId::from("server only");
+        server_docs.insert(private.clone(), AutoCommit::new());
+
+        let (to_server, server_inbox) = mpsc::channel::<Vec<u8>>();
+        let (to_client, client_inbox) = mpsc::channel::<Vec<u8>>();
+        let mut client = Multiplexer::new();
+        let mut server = Multiplexer::new();
+
+        let mut frames = 0;
+        loop {
+            let docs = client_docs.iter_mut().map(|(id, doc)| (id, doc.sync()));
+            let client_sent = client.generate_batch(&"server", docs);
+            if let Some(batch) = client_sent.clone() {
+                to_server.send(batch.encode()).unwrap();
+            }
+            while let Ok(frame) = server_inbox.try_recv() {
+                frames += 1;
+                for (id, message) in Batch::decode(&frame).unwrap().messages {
+                    let doc = server_docs.get_mut(&id).unwrap();
+                    server
+                        .receive_sync_message(&"client", &id, &mut doc.sync(), message)
+                        .unwrap();
+                }
+            }
+
+            let requested = server.documents(&"client").cloned().collect::<Vec<_>>();
+            let docs = server_docs
+                .iter_mut()
+                .filter(|(id, _)| requested.contains(id))
+                .map(|(id, doc)| (id, doc.sync()));
+            let server_sent = server.generate_batch(&"client", docs);
+            if let Some(batch) = server_sent.clone() {
+                to_client.send(batch.encode()).unwrap();
+            }
+            while let Ok(frame) = client_inbox.try_recv() {
+                frames += 1;
+                for (id, message) in Batch::decode(&frame).unwrap().messages {
+                    let doc = client_docs.get_mut(&id).unwrap();
+                    client
+                        .receive_sync_message(&"server", &id, &mut doc.sync(), message)
+                        .unwrap();
+                }
+            }
+
+            if client_sent.is_none() && server_sent.is_none() {
+                break;
+            }
+            assert!(frames < 20, "failed to sync");
+        }
+
+        for (i, id) in ids.iter().enumerate() {
+            let client = client_docs.get_mut(id).unwrap();
+            let server = server_docs.get_mut(id).unwrap();
+            assert_eq!(client.get_heads(), server.get_heads());
+            assert_eq!(
+                client.get(crate::ROOT, "server").unwrap().unwrap().0,
+                (i as i64).into()
+            );
+        }
+        assert!(server.state(&"client", &private).is_none());
+        // every document was synced in the same few frames
+        assert!(frames <= 6, "took {} frames", frames);
+    }
+}
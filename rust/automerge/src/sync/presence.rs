@@ -0,0 +1,861 @@
+//! Ephemeral presence state shared alongside document sync
+//!
+//! Collaborative applications usually want to show who else is looking at a document, where their
+//! caret is, what they have selected and so on. This information changes constantly and is
+//! meaningless once the peer has gone away, so it should not be written into the document.
+//!
+//! A [`Presence`] holds our own state, a map from string keys to [`PresenceValue`]s, along with
+//! the most recent state we have heard from every other peer. Each peer's state is replaced as a
+//! whole by the state with the highest clock (last writer wins) and is forgotten if we haven't
+//! heard from the peer for longer than a time to live. [`Presence::generate_message()`] produces
+//! a [`Message`] whenever our state has changed, and periodically otherwise to keep our state
+//! alive on other peers. These messages can be sent in a [`super::Batch`] next to the sync
+//! messages for the same document.
+//!
+//! Positions in a text or list should be stored as [`Cursor`]s rather than indexes, the receiving
+//! peer can then resolve the position with [`crate::ReadDoc::get_cursor_position()`] and it will
+//! remain correct in the face of concurrent edits.
+//!
+//! Timestamps are milliseconds since the Unix epoch and are passed in explicitly, this module
+//! never reads the clock itself. A peer's clock never falls behind its timestamp so state sent
+//! after a restart still wins over state sent before it.
+//!
+//! ## Example
+//!
+//! ```
+//! use automerge::{sync::presence::{Presence, PresenceValue}, ActorId};
+//! let mut alice = Presence::new(ActorId::random(), 30_000);
+//! let mut bob = Presence::new(ActorId::random(), 30_000);
+//!
+//! alice.set("name", "alice");
+//! let message = alice.generate_message(0).unwrap();
+//! let message = automerge::sync::presence::Message::decode(&message.encode()).unwrap();
+//! bob.receive_message(message, 10);
+//! assert_eq!(
+//!     bob.peer(alice.actor()).unwrap().get("name"),
+//!     Some(&PresenceValue::from("alice"))
+//! );
+//!
+//! // Alice goes away without saying goodbye
+//! assert_eq!(bob.expire(30_010), vec![alice.actor().clone()]);
+//! assert!(bob.peer(alice.actor()).is_none());
+//! ```
+use std::collections::{BTreeMap, HashMap};
+
+use super::encode_many;
+use crate::storage::parse;
+use crate::{ActorId, Cursor};
+
+const MESSAGE_TYPE_PRESENCE: u8 = 0x50; // first byte of a presence message, for identification
+
+// Values can nest, bound the nesting so that a malicious peer can't overflow our stack
+const MAX_DEPTH: usize = 32;
+
+const TAG_NULL: u8 = 0x00;
+const TAG_FALSE: u8 = 0x01;
+const TAG_TRUE: u8 = 0x02;
+const TAG_INT: u8 = 0x03;
+const TAG_UINT: u8 = 0x04;
+const TAG_F64: u8 = 0x05;
+const TAG_STR: u8 = 0x06;
+const TAG_BYTES: u8 = 0x07;
+const TAG_CURSOR: u8 = 0x08;
+const TAG_LIST: u8 = 0x09;
+const TAG_MAP: u8 = 0x0a;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("wrong type: expected one of {expected_one_of:?} but found {found}")]
+    WrongType { expected_one_of: Vec<u8>, found: u8 },
+    #[error("invalid LEB128 encoded integer")]
+    InvalidLeb128,
+    #[error("invalid UTF-8 in string")]
+    InvalidUtf8,
+    #[error("unknown value tag: {0}")]
+    UnknownValueTag(u8),
+    #[error("invalid peer state flag: {0}")]
+    InvalidStateFlag(u8),
+    #[error("values nested more than {MAX_DEPTH} deep")]
+    TooDeep,
+    #[error("invalid cursor")]
+    InvalidCursor,
+    #[error("not enough input")]
+    NotEnoughInput,
+}
+
+impl From<parse::leb128::Error> for DecodeError {
+    fn from(_: parse::leb128::Error) -> Self {
+        DecodeError::InvalidLeb128
+    }
+}
+
+impl From<parse::InvalidUtf8> for DecodeError {
+    fn from(_: parse::InvalidUtf8) -> Self {
+        DecodeError::InvalidUtf8
+    }
+}
+
+/// The state a peer shares with other peers
+pub type PresenceState = BTreeMap<String, PresenceValue>;
+
+/// A value in a peer's presence state
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresenceValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// A position in a text or list object, see [`crate::ReadDoc::get_cursor()`]
+    Cursor(Cursor),
+    List(Vec<PresenceValue>),
+    Map(BTreeMap<String, PresenceValue>),
+}
+
+impl PresenceValue {
+    pub fn to_str(&self) -> Option<&str> {
+        match self {
+            PresenceValue::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn to_cursor(&self) -> Option<&Cursor> {
+        match self {
+            PresenceValue::Cursor(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            PresenceValue::Null => buf.push(TAG_NULL),
+            PresenceValue::Bool(false) => buf.push(TAG_FALSE),
+            PresenceValue::Bool(true) => buf.push(TAG_TRUE),
+            PresenceValue::Int(i) => {
+                buf.push(TAG_INT);
+                leb128::write::signed(buf, *i).unwrap();
+            }
+            PresenceValue::Uint(u) => {
+                buf.push(TAG_UINT);
+                leb128::write::unsigned(buf, *u).unwrap();
+            }
+            PresenceValue::F64(f) => {
+                buf.push(TAG_F64);
+                buf.extend(f.to_le_bytes());
+            }
+            PresenceValue::Str(s) => {
+                buf.push(TAG_STR);
+                encode_bytes(buf, s.as_bytes());
+            }
+            PresenceValue::Bytes(b) => {
+                buf.push(TAG_BYTES);
+                encode_bytes(buf, b);
+            }
+            PresenceValue::Cursor(c) => {
+                buf.push(TAG_CURSOR);
+                encode_bytes(buf, &c.to_bytes());
+            }
+            PresenceValue::List(items) => {
+                buf.push(TAG_LIST);
+                encode_many(buf, items.iter(), |buf, item| item.encode(buf));
+            }
+            PresenceValue::Map(map) => {
+                buf.push(TAG_MAP);
+                encode_map(buf, map);
+            }
+        }
+    }
+
+    fn parse(input: parse::Input<'_>, depth: usize) -> parse::ParseResult<'_, Self, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(parse::ParseError::Error(DecodeError::TooDeep));
+        }
+        let (i, tag) = parse::take1(input)?;
+        match tag {
+            TAG_NULL => Ok((i, PresenceValue::Null)),
+            TAG_FALSE => Ok((i, PresenceValue::Bool(false))),
+            TAG_TRUE => Ok((i, PresenceValue::Bool(true))),
+            TAG_INT => {
+                let (i, v) = parse::leb128_i64(i)?;
+                Ok((i, PresenceValue::Int(v)))
+            }
+            TAG_UINT => {
+                let (i, v) = parse::leb128_u64(i)?;
+                Ok((i, PresenceValue::Uint(v)))
+            }
+            TAG_F64 => {
+                let (i, bytes) = parse::take_n(8, i)?;
+                let v = f64::from_le_bytes(bytes.try_into().unwrap());
+                Ok((i, PresenceValue::F64(v)))
+            }
+            TAG_STR => {
+                let (i, s) = parse_str(i)?;
+                Ok((i, PresenceValue::Str(s)))
+            }
+            TAG_BYTES => {
+                let (i, b) = parse::length_prefixed_bytes(i)?;
+                Ok((i, PresenceValue::Bytes(b.to_vec())))
+            }
+            TAG_CURSOR => {
+                let (i, b) = parse::length_prefixed_bytes(i)?;
+                let cursor = Cursor::try_from(b)
+                    .map_err(|_| parse::ParseError::Error(DecodeError::InvalidCursor))?;
+                Ok((i, PresenceValue::Cursor(cursor)))
+            }
+            TAG_LIST => {
+                let (mut i, count) = parse::leb128_u64(i)?;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    let (rest, item) = Self::parse(i, depth + 1)?;
+                    items.push(item);
+                    i = rest;
+                }
+                Ok((i, PresenceValue::List(items)))
+            }
+            TAG_MAP => {
+                let (i, map) = parse_map(i, depth + 1)?;
+                Ok((i, PresenceValue::Map(map)))
+            }
+            other => Err(parse::ParseError::Error(DecodeError::UnknownValueTag(
+                other,
+            ))),
+        }
+    }
+}
+
+impl From<bool> for PresenceValue {
+    fn from(b: bool) -> Self {
+        PresenceValue::Bool(b)
+    }
+}
+
+impl From<i64> for PresenceValue {
+    fn from(i: i64) -> Self {
+        PresenceValue::Int(i)
+    }
+}
+
+impl From<u64> for PresenceValue {
+    fn from(u: u64) -> Self {
+        PresenceValue::Uint(u)
+    }
+}
+
+impl From<f64> for PresenceValue {
+    fn from(f: f64) -> Self {
+        PresenceValue::F64(f)
+    }
+}
+
+impl From<&str> for PresenceValue {
+    fn from(s: &str) -> Self {
+        PresenceValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for PresenceValue {
+    fn from(s: String) -> Self {
+        PresenceValue::Str(s)
+    }
+}
+
+impl From<Vec<u8>> for PresenceValue {
+    fn from(b: Vec<u8>) -> Self {
+        PresenceValue::Bytes(b)
+    }
+}
+
+impl From<Cursor> for PresenceValue {
+    fn from(c: Cursor) -> Self {
+        PresenceValue::Cursor(c)
+    }
+}
+
+impl From<Vec<PresenceValue>> for PresenceValue {
+    fn from(items: Vec<PresenceValue>) -> Self {
+        PresenceValue::List(items)
+    }
+}
+
+impl From<BTreeMap<String, PresenceValue>> for PresenceValue {
+    fn from(map: BTreeMap<String, PresenceValue>) -> Self {
+        PresenceValue::Map(map)
+    }
+}
+
+/// The state of one peer as of some clock
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerState {
+    pub actor: ActorId,
+    /// Increases every time the peer sends its state, the state with the highest clock wins
+    pub clock: u64,
+    /// How many milliseconds ago the sender of the message last heard from the peer, zero for
+    /// the sender's own state. This lets relayed states expire when they would have on the relay.
+    pub age: u64,
+    /// The state of the peer, or `None` if the peer has left
+    pub state: Option<PresenceState>,
+}
+
+/// A message containing the presence state of one or more peers
+///
+/// Usually a message contains just the sender's state, but a relay which forwards presence
+/// between peers which can't see each other can send the states of everyone it knows about with
+/// [`Presence::snapshot()`].
+///
+/// ## Encoding
+///
+/// A message is encoded as the byte `0x50` followed by a length prefixed list of peer states. Each
+/// peer state is the length prefixed actor ID, the LEB128 encoded clock and age and then either
+/// `0x00` if the peer has left or `0x01` followed by the state. The state is a length prefixed
+/// list of (length prefixed UTF-8 key, value) pairs where each value starts with a tag byte
+/// identifying its type.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Message {
+    pub states: Vec<PeerState>,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![MESSAGE_TYPE_PRESENCE];
+        encode_many(&mut buf, self.states.iter(), |buf, peer| {
+            encode_bytes(buf, peer.actor.to_bytes());
+            leb128::write::unsigned(buf, peer.clock).unwrap();
+            leb128::write::unsigned(buf, peer.age).unwrap();
+            match &peer.state {
+                None => buf.push(0),
+                Some(state) => {
+                    buf.push(1);
+                    encode_map(buf, state);
+                }
+            }
+        });
+        buf
+    }
+
+    pub fn decode(input: &[u8]) -> Result<Self, DecodeError> {
+        let input = parse::Input::new(input);
+        match Self::parse(input) {
+            Ok((_, message)) => Ok(message),
+            Err(parse::ParseError::Error(e)) => Err(e),
+            Err(parse::ParseError::Incomplete(_)) => Err(DecodeError::NotEnoughInput),
+        }
+    }
+
+    fn parse(input: parse::Input<'_>) -> parse::ParseResult<'_, Self, DecodeError> {
+        let (i, message_type) = parse::take1(input)?;
+        if message_type != MESSAGE_TYPE_PRESENCE {
+            return Err(parse::ParseError::Error(DecodeError::WrongType {
+                expected_one_of: vec![MESSAGE_TYPE_PRESENCE],
+                found: message_type,
+            }));
+        }
+        let (i, states) = parse::length_prefixed(parse_peer_state)(i)?;
+        Ok((i, Message { states }))
+    }
+}
+
+fn parse_peer_state(input: parse::Input<'_>) -> parse::ParseResult<'_, PeerState, DecodeError> {
+    let (i, actor) = parse::actor_id(input)?;
+    let (i, clock) = parse::leb128_u64(i)?;
+    let (i, age) = parse::leb128_u64(i)?;
+    let (i, has_state) = parse::take1(i)?;
+    let (i, state) = match has_state {
+        0 => (i, None),
+        1 => {
+            let (i, state) = parse_map(i, 0)?;
+            (i, Some(state))
+        }
+        other => {
+            return Err(parse::ParseError::Error(DecodeError::InvalidStateFlag(
+                other,
+            )))
+        }
+    };
+    Ok((
+        i,
+        PeerState {
+            actor,
+            clock,
+            age,
+            state,
+        },
+    ))
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    leb128::write::unsigned(buf, bytes.len() as u64).unwrap();
+    buf.extend(bytes);
+}
+
+fn encode_map(buf: &mut Vec<u8>, map: &BTreeMap<String, PresenceValue>) {
+    encode_many(buf, map.iter(), |buf, (key, value)| {
+        encode_bytes(buf, key.as_bytes());
+        value.encode(buf);
+    });
+}
+
+fn parse_str(input: parse::Input<'_>) -> parse::ParseResult<'_, String, DecodeError> {
+    let (i, len) = parse::leb128_u64(input)?;
+    parse::utf_8(len as usize, i)
+}
+
+fn parse_map(
+    input: parse::Input<'_>,
+    depth: usize,
+) -> parse::ParseResult<'_, BTreeMap<String, PresenceValue>, DecodeError> {
+    let (mut i, count) = parse::leb128_u64(input)?;
+    let mut map = BTreeMap::new();
+    for _ in 0..count {
+        let (rest, key) = parse_str(i)?;
+        let (rest, value) = PresenceValue::parse(rest, depth)?;
+        map.insert(key, value);
+        i = rest;
+    }
+    Ok((i, map))
+}
+
+#[derive(Debug, Clone)]
+struct PeerEntry {
+    clock: u64,
+    state: Option<PresenceState>,
+    last_seen: u64,
+}
+
+/// Our own presence state and the states we have received from other peers
+///
+/// See the [module documentation](self) for an overview.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    actor: ActorId,
+    ttl: u64,
+    clock: u64,
+    state: PresenceState,
+    left: bool,
+    dirty: bool,
+    last_sent: Option<u64>,
+    peers: HashMap<ActorId, PeerEntry>,
+}
+
+impl Presence {
+    /// Create a presence for the peer `actor`
+    ///
+    /// The states of other peers are forgotten if we haven't heard from them for `ttl`
+    /// milliseconds, and we send our own state at least every `ttl / 2` milliseconds so that other
+    /// peers don't forget us.
+    pub fn new(actor: ActorId, ttl: u64) -> Self {
+        Presence {
+            actor,
+            ttl,
+            clock: 0,
+            state: PresenceState::new(),
+            left: false,
+            dirty: true,
+            last_sent: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// The ID this peer's state is shared under
+    pub fn actor(&self) -> &ActorId {
+        &self.actor
+    }
+
+    /// Our own state
+    pub fn local(&self) -> &PresenceState {
+        &self.state
+    }
+
+    /// Set `key` in our own state
+    ///
+    /// If we had [`left`](Self::leave()) this makes us visible to other peers again.
+    pub fn set<K: Into<String>, V: Into<PresenceValue>>(&mut self, key: K, value: V) {
+        self.state.insert(key.into(), value.into());
+        self.left = false;
+        self.dirty = true;
+    }
+
+    /// Remove `key` from our own state
+    pub fn remove(&mut self, key: &str) -> Option<PresenceValue> {
+        let removed = self.state.remove(key);
+        if removed.is_some() {
+            self.dirty = true;
+        }
+        removed
+    }
+
+    /// Tell other peers to forget our state
+    ///
+    /// The next message from [`Self::generate_message()`] will remove us from other peers rather
+    /// than waiting for our state to expire.
+    pub fn leave(&mut self) {
+        self.left = true;
+        self.dirty = true;
+    }
+
+    /// Generate a message containing our state to send to other peers
+    ///
+    /// Returns `None` if our state hasn't changed and we sent it less than `ttl / 2` milliseconds
+    /// ago.
+    pub fn generate_message(&mut self, now: u64) -> Option<Message> {
+        let heartbeat_due = match self.last_sent {
+            Some(last_sent) => now.saturating_sub(last_sent) >= self.ttl / 2,
+            None => true,
+        };
+        if !self.dirty && (self.left || !heartbeat_due) {
+            return None;
+        }
+        // Following the time means we don't have to remember the clock across restarts
+        self.clock = (self.clock + 1).max(now);
+        self.dirty = false;
+        self.last_sent = Some(now);
+        Some(Message {
+            states: vec![self.own_state()],
+        })
+    }
+
+    /// A message containing the states of every peer we know about, including ourselves if we
+    /// have sent our state
+    ///
+    /// This is useful for relays, which can send a snapshot to a newly connected peer so it
+    /// doesn't have to wait for every other peer to send their state. Each state carries how long
+    /// ago we heard from the peer as of `now`, so the recipient expires it at the same time we do.
+    pub fn snapshot(&self, now: u64) -> Message {
+        let ours = if self.clock > 0 {
+            Some(self.own_state())
+        } else {
+            None
+        };
+        let mut states = ours.into_iter().collect::<Vec<_>>();
+        states.extend(self.peers.iter().map(|(actor, entry)| PeerState {
+            actor: actor.clone(),
+            clock: entry.clock,
+            age: now.saturating_sub(entry.last_seen),
+            state: entry.state.clone(),
+        }));
+        Message { states }
+    }
+
+    /// Apply the states in `message`, received at time `now`
+    ///
+    /// Returns the actors whose state changed as a result, including peers which left. States for
+    /// our own actor and states older than the ones we already have are ignored.
+    ///
+    /// A state relayed by another peer counts as seen when the relay last heard from its origin,
+    /// not when we received it.
+    pub fn receive_message(&mut self, message: Message, now: u64) -> Vec<ActorId> {
+        let mut changed = Vec::new();
+        for peer in message.states {
+            if peer.actor == self.actor {
+                continue;
+            }
+            let seen = now.saturating_sub(peer.age);
+            match self.peers.get_mut(&peer.actor) {
+                Some(entry) if entry.clock > peer.clock => {}
+                Some(entry) if entry.clock == peer.clock => {
+                    entry.last_seen = entry.last_seen.max(seen);
+                }
+                Some(entry) => {
+                    if entry.state != peer.state {
+                        changed.push(peer.actor);
+                    }
+                    entry.clock = peer.clock;
+                    entry.state = peer.state;
+                    entry.last_seen = seen;
+                }
+                None => {
+                    if peer.state.is_some() {
+                        changed.push(peer.actor.clone());
+                    }
+                    self.peers.insert(
+                        peer.actor,
+                        PeerEntry {
+                            clock: peer.clock,
+                            state: peer.state,
+                            last_seen: seen,
+                        },
+                    );
+                }
+            }
+        }
+        changed
+    }
+
+    /// Forget the peers we haven't heard from for longer than the time to live
+    ///
+    /// Returns the actors of the peers which were still present and have now been removed.
+    pub fn expire(&mut self, now: u64) -> Vec<ActorId> {
+        let ttl = self.ttl;
+        let mut expired = Vec::new();
+        self.peers.retain(|actor, entry| {
+            let keep = now.saturating_sub(entry.last_seen) < ttl;
+            if !keep && entry.state.is_some() {
+                expired.push(actor.clone());
+            }
+            keep
+        });
+        expired.sort();
+        expired
+    }
+
+    /// The state of `actor`, if they are present
+    pub fn peer(&self, actor: &ActorId) -> Option<&PresenceState> {
+        self.peers.get(actor)?.state.as_ref()
+    }
+
+    /// The states of all the peers which are present
+    pub fn peers(&self) -> impl Iterator<Item = (&ActorId, &PresenceState)> {
+        self.peers
+            .iter()
+            .filter_map(|(actor, entry)| Some((actor, entry.state.as_ref()?)))
+    }
+
+    fn own_state(&self) -> PeerState {
+        PeerState {
+            actor: self.actor.clone(),
+            clock: self.clock,
+            age: 0,
+            state: if self.left {
+                None
+            } else {
+                Some(self.state.clone())
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transactable;
+    use crate::{AutoCommit, ObjType, ReadDoc};
+
+    fn round_trip(message: Message) -> Message {
+        Message::decode(&message.encode()).unwrap()
+    }
+
+    #[test]
+    fn encode_decode_message() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(crate::ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello").unwrap();
+        let cursor = doc.get_cursor(&text, 2, None).unwrap();
+
+        let mut nested = BTreeMap::new();
+        nested.insert("anchor".to_string(), PresenceValue::from(cursor.clone()));
+        nested.insert("head".to_string(), PresenceValue::from(cursor));
+        let mut state = PresenceState::new();
+        state.insert("null".to_string(), PresenceValue::Null);
+        state.insert("online".to_string(), true.into());
+        state.insert("offset".to_string(), (-12_i64).into());
+        state.insert("color".to_string(), 0xff00ff_u64.into());
+        state.insert("zoom".to_string(), 1.5_f64.into());
+        state.insert("name".to_string(), "alice".into());
+        state.insert("avatar".to_string(), vec![1_u8, 2, 3].into());
+        state.insert("selection".to_string(), nested.into());
+        state.insert(
+            "tags".to_string(),
+            vec![PresenceValue::from("a"), PresenceValue::Null].into(),
+        );
+        let message = Message {
+            states: vec![
+                PeerState {
+                    actor: ActorId::random(),
+                    clock: 3,
+                    age: 0,
+                    state: Some(state),
+                },
+                PeerState {
+                    actor: ActorId::random(),
+                    clock: 7,
+                    age: 250,
+                    state: None,
+                },
+            ],
+        };
+        assert_eq!(round_trip(message.clone()), message);
+
+        assert!(matches!(
+            Message::decode(&[0x42]),
+            Err(DecodeError::WrongType { found: 0x42, .. })
+        ));
+        let mut truncated = message.encode();
+        truncated.pop();
+        assert!(matches!(
+            Message::decode(&truncated),
+            Err(DecodeError::NotEnoughInput)
+        ));
+        // The second peer state ends with the flag saying it has no state
+        let mut bad_flag = message.encode();
+        *bad_flag.last_mut().unwrap() = 2;
+        assert!(matches!(
+            Message::decode(&bad_flag),
+            Err(DecodeError::InvalidStateFlag(2))
+        ));
+
+        let mut deep = PresenceValue::Null;
+        for _ in 0..(MAX_DEPTH + 2) {
+            deep = PresenceValue::List(vec![deep]);
+        }
+        let mut state = PresenceState::new();
+        state.insert("deep".to_string(), deep);
+        let message = Message {
+            states: vec![PeerState {
+                actor: ActorId::random(),
+                clock: 1,
+                age: 0,
+                state: Some(state),
+            }],
+        };
+        assert!(matches!(
+            Message::decode(&message.encode()),
+            Err(DecodeError::TooDeep)
+        ));
+    }
+
+    #[test]
+    fn latest_state_wins_and_expires() {
+        let mut alice = Presence::new(ActorId::random(), 1000);
+        let mut bob = Presence::new(ActorId::random(), 1000);
+
+        alice.set("status", "reading");
+        let first = alice.generate_message(0).unwrap();
+        // nothing has changed and no heartbeat is due yet
+        assert_eq!(alice.generate_message(100), None);
+        alice.set("status", "typing");
+        let second = alice.generate_message(200).unwrap();
+
+        assert_eq!(
+            bob.receive_message(round_trip(second), 200),
+            vec![alice.actor().clone()]
+        );
+        // a stale message delivered late doesn't overwrite the newer state
+        assert!(bob.receive_message(round_trip(first), 300).is_empty());
+        let state = bob.peer(alice.actor()).unwrap();
+        assert_eq!(state.get("status").and_then(|v| v.to_str()), Some("typing"));
+
+        // heartbeats keep alice alive past the time to live
+        assert_eq!(alice.generate_message(600), None);
+        let heartbeat = alice.generate_message(700).unwrap();
+        assert!(bob.receive_message(round_trip(heartbeat), 700).is_empty());
+        assert!(bob.expire(1500).is_empty());
+        assert_eq!(bob.peers().count(), 1);
+
+        assert_eq!(bob.expire(1700), vec![alice.actor().clone()]);
+        assert_eq!(bob.peers().count(), 0);
+
+        // our own state relayed back to us is ignored
+        let mut relay = Presence::new(ActorId::random(), 1000);
+        relay.receive_message(alice.generate_message(2000).unwrap(), 2000);
+        assert!(alice.receive_message(relay.snapshot(2000), 2000).is_empty());
+        assert_eq!(alice.peers().count(), 0);
+    }
+
+    #[test]
+    fn leaving_removes_state_from_peers() {
+        let mut alice = Presence::new(ActorId::random(), 1000);
+        let mut bob = Presence::new(ActorId::random(), 1000);
+        alice.set("name", "alice");
+        bob.receive_message(alice.generate_message(0).unwrap(), 0);
+        assert!(bob.peer(alice.actor()).is_some());
+
+        alice.leave();
+        let goodbye = alice.generate_message(10).unwrap();
+        assert_eq!(
+            bob.receive_message(round_trip(goodbye), 10),
+            vec![alice.actor().clone()]
+        );
+        assert!(bob.peer(alice.actor()).is_none());
+        // no heartbeats once we have left
+        assert_eq!(alice.generate_message(5000), None);
+        // and the tombstone is not reported as expiring
+        assert!(bob.expire(5000).is_empty());
+    }
+
+    #[test]
+    fn relays_forward_snapshots() {
+        let mut alice = Presence::new(ActorId::random(), 1000);
+        let mut bob = Presence::new(ActorId::random(), 1000);
+        let mut relay = Presence::new(ActorId::random(), 1000);
+        alice.set("name", "alice");
+        bob.set("name", "bob");
+        relay.receive_message(alice.generate_message(0).unwrap(), 0);
+        relay.receive_message(bob.generate_message(0).unwrap(), 0);
+
+        let snapshot = round_trip(relay.snapshot(600));
+        assert_eq!(snapshot.states.len(), 2);
+        assert_eq!(
+            alice.receive_message(snapshot, 600),
+            vec![bob.actor().clone()]
+        );
+        assert_eq!(
+            alice.peer(bob.actor()).unwrap().get("name"),
+            Some(&PresenceValue::from("bob"))
+        );
+
+        // bob's state expires when it would have on the relay rather than a time to live after
+        // the snapshot arrived
+        assert!(alice.expire(900).is_empty());
+        assert_eq!(alice.expire(1000), vec![bob.actor().clone()]);
+    }
+
+    #[test]
+    fn state_sent_after_a_restart_wins() {
+        let actor = ActorId::random();
+        let mut bob = Presence::new(ActorId::random(), 10_000);
+
+        let mut alice = Presence::new(actor.clone(), 10_000);
+        alice.set("status", "before");
+        for now in 0..5 {
+            alice.set("now", now);
+            bob.receive_message(
+                round_trip(alice.generate_message(1000 + now).unwrap()),
+                1000,
+            );
+        }
+
+        // alice restarts with a fresh clock
+        let mut alice = Presence::new(actor.clone(), 10_000);
+        alice.set("status", "after");
+        assert_eq!(
+            bob.receive_message(round_trip(alice.generate_message(2000).unwrap()), 2000),
+            vec![actor.clone()]
+        );
+        assert_eq!(
+            bob.peer(&actor).unwrap().get("status"),
+            Some(&PresenceValue::from("after"))
+        );
+    }
+
+    #[test]
+    fn cursors_follow_concurrent_edits() {
+        let mut alice_doc = AutoCommit::new();
+        let text = alice_doc
+            .put_object(crate::ROOT, "text", ObjType::Text)
+            .unwrap();
+        alice_doc.splice_text(&text, 0, 0, "hello world").unwrap();
+        let mut bob_doc = alice_doc.fork();
+
+        // alice puts her caret before "world"
+        let mut alice = Presence::new(ActorId::random(), 1000);
+        alice.set("caret", alice_doc.get_cursor(&text, 6, None).unwrap());
+        let message = round_trip(alice.generate_message(0).unwrap());
+
+        // meanwhile bob inserts text in front of it
+        bob_doc.splice_text(&text, 0, 0, "oh, ").unwrap();
+
+        let mut bob = Presence::new(ActorId::random(), 1000);
+        bob.receive_message(message, 0);
+        let caret = bob
+            .peer(alice.actor())
+            .unwrap()
+            .get("caret")
+            .and_then(PresenceValue::to_cursor)
+            .unwrap();
+        let position = bob_doc.get_cursor_position(&text, caret, None).unwrap();
+        assert_eq!(position, 10);
+        assert_eq!(&bob_doc.text(&text).unwrap()[position..], "world");
+    }
+}
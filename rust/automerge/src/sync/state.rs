@@ -2,11 +2,14 @@ use std::collections::BTreeSet;
 
 #[cfg(doc)]
 use super::SyncDoc;
-use super::{encode_hashes, BloomFilter, Capability};
+use super::{encode_hashes, BloomFilter, Capability, Iblt, Stats};
 use crate::storage::parse;
 use crate::ChangeHash;
 
 const SYNC_STATE_TYPE: u8 = 0x43; // first byte of an encoded sync state, for identification
+                                  // first byte of an encoded sync state which also contains rejected hashes, which older versions
+                                  // don't know about and so refuse to decode
+const SYNC_STATE_TYPE_V2: u8 = 0x44;
 
 #[derive(Debug, thiserror::Error)]
 pub enum DecodeError {
@@ -28,7 +31,7 @@ impl From<parse::leb128::Error> for DecodeError {
 ///
 /// This should be persisted using [`Self::encode()`] when you know you will be interacting with the
 /// same peer in multiple sessions. [`Self::encode()`] only encodes state which should be reused
-/// across connections.
+/// across connections, namely the shared heads and the [rejected changes](Self::rejected_hashes).
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct State {
     /// The hashes which we know both peers have
@@ -43,6 +46,15 @@ pub struct State {
     pub their_have: Option<Vec<Have>>,
     /// The hashes we have sent in this session
     pub sent_hashes: BTreeSet<ChangeHash>,
+    /// Changes we know the other end needs but which did not fit in the last message generated by
+    /// [`SyncDoc::generate_sync_message_with_limit()`]. These are sent in subsequent messages
+    /// until the other end reports heads which include them.
+    pub unsent_hashes: BTreeSet<ChangeHash>,
+    /// Changes the other end sent us which were rejected by the policy passed to
+    /// [`crate::Automerge::receive_sync_message_with_policy()`], along with the changes which
+    /// depend on them. We don't ask for these again, including after the state has been
+    /// [encoded](Self::encode()) and decoded.
+    pub rejected_hashes: BTreeSet<ChangeHash>,
 
     /// [`SyncDoc::generate_sync_message()`] should return [`None`] if there are no new changes
     /// to send. In particular, if there are changes in flight which the other end has not yet
@@ -60,18 +72,54 @@ pub struct State {
 
     /// The capabilities the other side has said they have
     pub their_capabilities: Option<Vec<Capability>>,
+
+    /// The heads passed to the last call to [`crate::Automerge::generate_sync_message_at()`],
+    /// which limit the changes we send, or [`None`] if we are syncing the whole document
+    pub restricted_heads: Option<Vec<ChangeHash>>,
+
+    /// Whether the last bloom filter the other end sent us hid changes we needed, in which case
+    /// we send them an IBLT next time regardless of how many changes we have
+    pub bloom_failed: bool,
+
+    /// Statistics about the messages exchanged using this state. Set this to
+    /// `Some(Stats::default())` to start collecting them.
+    pub stats: Option<Stats>,
 }
 
 /// A summary of the changes that the sender of the message already has.
 /// This is implicitly a request to the recipient to send all changes that the
 /// sender does not already have.
+///
+/// This is `#[non_exhaustive]` so that summaries can be extended without breaking other crates,
+/// use [`Have::new()`] to construct one.
 #[derive(Debug, Clone, Default, PartialEq, Eq, Hash, serde::Serialize)]
+#[non_exhaustive]
 pub struct Have {
     /// The heads at the time of the last successful sync with this recipient.
     pub last_sync: Vec<ChangeHash>,
     /// A bloom filter summarising all of the changes that the sender of the message has added
     /// since the last sync.
     pub bloom: BloomFilter,
+    /// An invertible Bloom lookup table of the same changes as [`Self::bloom`], which lets the
+    /// recipient work out exactly which changes to send. Only sent in V3 messages.
+    pub iblt: Option<Iblt>,
+}
+
+impl Have {
+    /// A summary of the changes since `last_sync` without an IBLT
+    pub fn new(last_sync: Vec<ChangeHash>, bloom: BloomFilter) -> Self {
+        Self {
+            last_sync,
+            bloom,
+            iblt: None,
+        }
+    }
+
+    /// Attach an IBLT of the same changes as [`Self::bloom`]
+    pub fn with_iblt(mut self, iblt: Iblt) -> Self {
+        self.iblt = Some(iblt);
+        self
+    }
 }
 
 impl State {
@@ -79,9 +127,21 @@ impl State {
         Default::default()
     }
 
+    /// Encode the state which should be reused across connections
+    ///
+    /// A state without rejected hashes is encoded in the original format. Otherwise the encoding
+    /// starts with a different type byte, so that versions which would silently drop the rejected
+    /// hashes fail to decode it instead.
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = vec![SYNC_STATE_TYPE];
+        if self.rejected_hashes.is_empty() {
+            let mut buf = vec![SYNC_STATE_TYPE];
+            encode_hashes(&mut buf, &self.shared_heads);
+            return buf;
+        }
+        let mut buf = vec![SYNC_STATE_TYPE_V2];
         encode_hashes(&mut buf, &self.shared_heads);
+        let rejected = self.rejected_hashes.iter().copied().collect::<Vec<_>>();
+        encode_hashes(&mut buf, &rejected);
         buf
     }
 
@@ -96,14 +156,20 @@ impl State {
 
     pub(crate) fn parse(input: parse::Input<'_>) -> parse::ParseResult<'_, Self, DecodeError> {
         let (i, record_type) = parse::take1(input)?;
-        if record_type != SYNC_STATE_TYPE {
+        if record_type != SYNC_STATE_TYPE && record_type != SYNC_STATE_TYPE_V2 {
             return Err(parse::ParseError::Error(DecodeError::WrongType {
-                expected_one_of: vec![SYNC_STATE_TYPE],
+                expected_one_of: vec![SYNC_STATE_TYPE, SYNC_STATE_TYPE_V2],
                 found: record_type,
             }));
         }
 
         let (i, shared_heads) = parse::length_prefixed(parse::change_hash)(i)?;
+        let (i, rejected_hashes) = if record_type == SYNC_STATE_TYPE_V2 {
+            let (i, rejected) = parse::length_prefixed(parse::change_hash)(i)?;
+            (i, rejected.into_iter().collect())
+        } else {
+            (i, BTreeSet::new())
+        };
         Ok((
             i,
             Self {
@@ -113,9 +179,14 @@ impl State {
                 their_need: None,
                 their_have: Some(Vec::new()),
                 sent_hashes: BTreeSet::new(),
+                unsent_hashes: BTreeSet::new(),
+                rejected_hashes,
                 in_flight: false,
                 have_responded: false,
                 their_capabilities: None,
+                restricted_heads: None,
+                bloom_failed: false,
+                stats: None,
             },
         ))
     }
@@ -126,4 +197,11 @@ impl State {
             .map(|caps| caps.contains(&Capability::MessageV2))
             .unwrap_or(false)
     }
+
+    pub(crate) fn supports_v3_messages(&self) -> bool {
+        self.their_capabilities
+            .as_ref()
+            .map(|caps| caps.contains(&Capability::MessageV3))
+            .unwrap_or(false)
+    }
 }
@@ -0,0 +1,172 @@
+use super::{bloom, encode_hashes, Message, State};
+
+/// Statistics about the messages exchanged using a [`super::State`]
+///
+/// These are only collected when [`super::State::stats`] is `Some`, and are not persisted by
+/// [`super::State::encode()`]. The same figures are emitted as `tracing` events at the `DEBUG`
+/// level whenever a subscriber is interested in them, whether or not they are being collected
+/// here.
+///
+/// The counters are totals since the `Stats` was created and are never reset by the sync
+/// protocol. Set [`super::State::stats`] to `Some(Stats::default())` again to start counting from
+/// zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Stats {
+    /// The number of messages generated with this state
+    pub messages_sent: u64,
+    /// The number of messages received with this state
+    pub messages_received: u64,
+    /// The encoded size of the messages we generated, in bytes
+    pub bytes_sent: u64,
+    /// The encoded size of the messages we received, in bytes
+    pub bytes_received: u64,
+    /// The number of changes in the messages we generated
+    pub changes_sent: u64,
+    /// The number of changes in the messages we received, including duplicates
+    pub changes_received: u64,
+    /// Changes we received which we already had
+    pub duplicate_changes_received: u64,
+    /// The number of messages we received in reply to a message we sent
+    pub round_trips: u64,
+    /// The last message we generated
+    pub last_sent: Option<MessageStats>,
+    /// The last message we received
+    pub last_received: Option<MessageStats>,
+}
+
+/// The makeup of a single sync message
+///
+/// The section sizes add up to [`Self::total_bytes`] once the type byte and the capabilities the
+/// first message carries are accounted for. These describe one message only, [`Stats`] replaces
+/// them with each message sent or received.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MessageStats {
+    /// The encoded size of the whole message in bytes
+    pub total_bytes: usize,
+    /// The size of the heads of the sender in bytes
+    pub heads_bytes: usize,
+    /// The size of the hashes of the changes the sender asks for in bytes
+    pub need_bytes: usize,
+    /// The size of the summaries of the changes the sender has (bloom filters, and IBLTs for V3
+    /// messages) in bytes
+    pub have_bytes: usize,
+    /// The size of the changes in the message in bytes
+    pub changes_bytes: usize,
+    /// The number of changes in the message
+    pub changes: usize,
+    /// For a received message, the number of those changes which we already had
+    pub duplicate_changes: usize,
+    /// The number of changes summarised in the bloom filters of the message
+    pub bloom_entries: usize,
+    /// The size of those bloom filters in bits
+    pub bloom_bits: usize,
+    /// The number of hash probes per entry of the bloom filters, the largest if there are several
+    pub bloom_probes: usize,
+}
+
+impl MessageStats {
+    pub(crate) fn new(message: &Message, changes: usize) -> Self {
+        let mut buf = Vec::new();
+        let mut section = |f: &mut dyn FnMut(&mut Vec<u8>)| {
+            buf.clear();
+            f(&mut buf);
+            buf.len()
+        };
+        let heads_bytes = section(&mut |buf| encode_hashes(buf, &message.heads));
+        let need_bytes = section(&mut |buf| encode_hashes(buf, &message.need));
+        let have_bytes =
+            section(&mut |buf| super::encode_haves(buf, &message.have, &message.version));
+        let changes_bytes = section(&mut |buf| super::encode_chunks(buf, &message.changes));
+        let capabilities_bytes = section(&mut |buf| {
+            if let Some(caps) = &message.supported_capabilities {
+                super::encode_many(buf, caps.iter(), |buf, cap| cap.encode(buf));
+            }
+        });
+        MessageStats {
+            total_bytes: 1
+                + heads_bytes
+                + need_bytes
+                + have_bytes
+                + changes_bytes
+                + capabilities_bytes,
+            heads_bytes,
+            need_bytes,
+            have_bytes,
+            changes_bytes,
+            changes,
+            duplicate_changes: 0,
+            bloom_entries: message.have.iter().map(|h| h.bloom.num_entries()).sum(),
+            bloom_bits: message.have.iter().map(|h| h.bloom.num_bits()).sum(),
+            bloom_probes: message
+                .have
+                .iter()
+                .map(|h| h.bloom.num_probes())
+                .max()
+                .unwrap_or(0),
+        }
+    }
+
+    /// The probability that a change which is not in the bloom filters of this message is
+    /// reported as present, in which case it is not sent until the recipient asks for it
+    pub fn bloom_false_positive_rate(&self) -> f64 {
+        bloom::false_positive_rate(self.bloom_entries, self.bloom_bits, self.bloom_probes)
+    }
+
+    /// The expected number of changes which the bloom filters wrongly report as present, if the
+    /// recipient tests `candidates` changes against them
+    pub fn estimated_false_positives(&self, candidates: usize) -> f64 {
+        self.bloom_false_positive_rate() * candidates as f64
+    }
+}
+
+/// Whether statistics about messages exchanged using `state` are wanted, either by the state
+/// itself or by a `tracing` subscriber
+pub(crate) fn wanted(state: &State) -> bool {
+    state.stats.is_some() || tracing::enabled!(tracing::Level::DEBUG)
+}
+
+pub(crate) fn record_sent(state: &mut State, message: MessageStats) {
+    tracing::debug!(
+        total_bytes = message.total_bytes,
+        heads_bytes = message.heads_bytes,
+        need_bytes = message.need_bytes,
+        have_bytes = message.have_bytes,
+        changes_bytes = message.changes_bytes,
+        changes = message.changes,
+        bloom_entries = message.bloom_entries,
+        bloom_false_positive_rate = message.bloom_false_positive_rate(),
+        "generated sync message"
+    );
+    if let Some(stats) = state.stats.as_mut() {
+        stats.messages_sent += 1;
+        stats.bytes_sent += message.total_bytes as u64;
+        stats.changes_sent += message.changes as u64;
+        stats.last_sent = Some(message);
+    }
+}
+
+pub(crate) fn record_received(state: &mut State, message: MessageStats, round_trip: bool) {
+    tracing::debug!(
+        total_bytes = message.total_bytes,
+        heads_bytes = message.heads_bytes,
+        need_bytes = message.need_bytes,
+        have_bytes = message.have_bytes,
+        changes_bytes = message.changes_bytes,
+        changes = message.changes,
+        duplicate_changes = message.duplicate_changes,
+        bloom_entries = message.bloom_entries,
+        bloom_false_positive_rate = message.bloom_false_positive_rate(),
+        round_trip,
+        "received sync message"
+    );
+    if let Some(stats) = state.stats.as_mut() {
+        stats.messages_received += 1;
+        stats.bytes_received += message.total_bytes as u64;
+        stats.changes_received += message.changes as u64;
+        stats.duplicate_changes_received += message.duplicate_changes as u64;
+        if round_trip {
+            stats.round_trips += 1;
+        }
+        stats.last_received = Some(message);
+    }
+}
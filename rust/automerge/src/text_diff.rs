@@ -5,8 +5,9 @@ use crate::{
     iter::{SpanInternal, SpansInternal},
     op_tree::OpTreeOpIter,
     text_value::TextValue,
-    transaction::TransactionInner,
-    Automerge, BlockOrText, ObjId as ExId, PatchLog, ReadDoc,
+    transaction::{ListMatching, TransactionInner, UpdateOptions},
+    Automerge, AutomergeError, BlockOrText, ObjId as ExId, ObjType, PatchLog, Prop, ReadDoc,
+    ScalarValue, Value,
 };
 mod myers;
 mod replace;
@@ -415,7 +416,7 @@ fn split_block(
     block: &crate::hydrate::Map,
 ) -> Result<(), crate::error::AutomergeError> {
     let new_block = tx.split_block(doc, patch_log, obj, index)?;
-    tx.update_map(doc, patch_log, &new_block, block)
+    tx.update_map(doc, patch_log, &new_block, block, &UpdateOptions::default())
 }
 
 fn update_block(
@@ -430,5 +431,237 @@ fn update_block(
         return Err(crate::error::AutomergeError::InvalidIndex(index));
     };
 
-    tx.update_map(doc, patch_log, &block_id, new_block)
+    tx.update_map(
+        doc,
+        patch_log,
+        &block_id,
+        new_block,
+        &UpdateOptions::default(),
+    )
+}
+
+pub(crate) fn myers_list_diff(
+    doc: &mut Automerge,
+    tx: &mut TransactionInner,
+    patch_log: &mut PatchLog,
+    list: &ExId,
+    new: &crate::hydrate::List,
+    options: &UpdateOptions<'_>,
+) -> Result<(), crate::AutomergeError> {
+    let old = doc
+        .list_range(list, ..)
+        .map(|item| (item.value.into_owned(), item.id))
+        .collect::<Vec<_>>();
+    let new = new.iter().map(|v| &v.value).collect::<Vec<_>>();
+
+    // Matching by key only needs the key of each old element, the other matchings need the
+    // whole element
+    let old_hydrated = match options.list_matching {
+        ListMatching::Key(_) => Vec::new(),
+        _ => old
+            .iter()
+            .map(|(value, id)| match value {
+                Value::Object(_) => doc.hydrate_obj(id, None),
+                Value::Scalar(s) => Ok(crate::hydrate::Value::Scalar(s.clone().into_owned())),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let (old_keys, new_keys) = match options.list_matching {
+        ListMatching::Key(key) => (
+            old.iter()
+                .map(|(value, id)| match value {
+                    Value::Object(ObjType::Map | ObjType::Table) => match doc.get(id, key)? {
+                        Some((Value::Scalar(s), _)) => Ok(ListKey::Key(Some(s.into_owned()))),
+                        _ => Ok(ListKey::Key(None)),
+                    },
+                    _ => Ok(ListKey::Key(None)),
+                })
+                .collect::<Result<Vec<_>, AutomergeError>>()?,
+            new.iter()
+                .map(|v| ListKey::Key(options.list_matching.key(v)))
+                .collect::<Vec<_>>(),
+        ),
+        ListMatching::Diff => (
+            old_hydrated.iter().map(ListKey::Value).collect::<Vec<_>>(),
+            new.iter().map(|v| ListKey::Value(v)).collect::<Vec<_>>(),
+        ),
+        _ => {
+            let key = |v: &crate::hydrate::Value| ListKey::Key(options.list_matching.key(v));
+            (
+                old_hydrated.iter().map(key).collect::<Vec<_>>(),
+                new.iter().map(|v| key(v)).collect::<Vec<_>>(),
+            )
+        }
+    };
+    let keyed = !matches!(options.list_matching, ListMatching::Diff);
+
+    let mut hook = replace::Replace::new(ListDiffHook {
+        doc,
+        tx,
+        patch_log,
+        obj: list,
+        idx: 0,
+        old: &old,
+        new: &new,
+        keyed,
+        options,
+    });
+    myers::diff(
+        &mut hook,
+        &old_keys,
+        0..old_keys.len(),
+        &new_keys,
+        0..new_keys.len(),
+    )
+}
+
+/// The thing which is compared to decide whether two list elements are the same element
+enum ListKey<'a> {
+    Value(&'a crate::hydrate::Value),
+    Key(Option<ScalarValue>),
+}
+
+impl<'a> PartialEq for ListKey<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ListKey::Value(a), ListKey::Value(b)) => same_value(a, b),
+            (ListKey::Key(Some(a)), ListKey::Key(Some(b))) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Compare two hydrated values, ignoring conflict flags and marks
+fn same_value(a: &crate::hydrate::Value, b: &crate::hydrate::Value) -> bool {
+    use crate::hydrate::Value as HValue;
+    match (a, b) {
+        (HValue::Scalar(a), HValue::Scalar(b)) => a == b,
+        (HValue::Text(a), HValue::Text(b)) => a == b,
+        (HValue::List(a), HValue::List(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|(a, b)| same_value(&a.value, &b.value))
+        }
+        (HValue::Map(a), HValue::Map(b)) => {
+            a.iter().count() == b.iter().count()
+                && a.iter()
+                    .all(|(k, a)| b.get(k).map(|b| same_value(&a.value, b)).unwrap_or(false))
+        }
+        _ => false,
+    }
+}
+
+struct ListDiffHook<'a> {
+    doc: &'a mut Automerge,
+    tx: &'a mut TransactionInner,
+    patch_log: &'a mut PatchLog,
+    old: &'a [(Value<'static>, ExId)],
+    new: &'a [&'a crate::hydrate::Value],
+    obj: &'a ExId,
+    idx: usize,
+    /// Whether elements were matched by key, in which case matched elements may still differ
+    /// and must be updated in place
+    keyed: bool,
+    options: &'a UpdateOptions<'a>,
+}
+
+impl<'a> ListDiffHook<'a> {
+    fn update_in_place(
+        &mut self,
+        old_index: usize,
+        new_index: usize,
+    ) -> Result<(), AutomergeError> {
+        let (value, id) = self.old[old_index].clone();
+        self.tx.update_value(
+            self.doc,
+            self.patch_log,
+            self.obj,
+            Prop::Seq(self.idx),
+            self.new[new_index],
+            Some((id, value)),
+            self.options,
+        )?;
+        self.idx += 1;
+        Ok(())
+    }
+}
+
+impl<'a> myers::DiffHook for ListDiffHook<'a> {
+    type Error = AutomergeError;
+
+    fn equal(&mut self, old_index: usize, new_index: usize, len: usize) -> Result<(), Self::Error> {
+        if self.keyed {
+            for i in 0..len {
+                self.update_in_place(old_index + i, new_index + i)?;
+            }
+        } else {
+            self.idx += len;
+        }
+        Ok(())
+    }
+
+    fn delete(
+        &mut self,
+        _old_index: usize,
+        old_len: usize,
+        _new_index: usize,
+    ) -> Result<(), Self::Error> {
+        self.tx.splice(
+            self.doc,
+            self.patch_log,
+            self.obj,
+            self.idx,
+            old_len as isize,
+            std::iter::empty(),
+        )
+    }
+
+    fn insert(
+        &mut self,
+        _old_index: usize,
+        new_index: usize,
+        new_len: usize,
+    ) -> Result<(), Self::Error> {
+        for value in &self.new[new_index..new_index + new_len] {
+            self.tx.update_value(
+                self.doc,
+                self.patch_log,
+                self.obj,
+                Prop::Seq(self.idx),
+                value,
+                None,
+                self.options,
+            )?;
+            self.idx += 1;
+        }
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        old_index: usize,
+        old_len: usize,
+        new_index: usize,
+        new_len: usize,
+    ) -> Result<(), Self::Error> {
+        // Elements which were matched by key are distinct elements, so a replacement is a deletion
+        // followed by an insertion. Otherwise pair up the replaced elements and update them in
+        // place so that the objects they contain keep their identity.
+        let paired = if self.keyed { 0 } else { old_len.min(new_len) };
+        for i in 0..paired {
+            self.update_in_place(old_index + i, new_index + i)?;
+        }
+        if old_len > paired {
+            self.delete(old_index + paired, old_len - paired, new_index + paired)?;
+        }
+        if new_len > paired {
+            self.insert(old_index + old_len, new_index + paired, new_len - paired)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
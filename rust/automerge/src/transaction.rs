@@ -3,6 +3,7 @@ mod inner;
 mod manual_transaction;
 mod result;
 mod transactable;
+mod update;
 
 pub use self::commit::CommitOptions;
 pub use self::transactable::{BlockOrText, Transactable};
@@ -10,5 +11,7 @@ pub(crate) use inner::{TransactionArgs, TransactionInner};
 pub use manual_transaction::Transaction;
 pub use result::Failure;
 pub use result::Success;
+pub(crate) use update::fill;
+pub use update::{ListMatching, UpdateOptions};
 
 pub type Result<O, E> = std::result::Result<Success<O>, Failure<E>>;
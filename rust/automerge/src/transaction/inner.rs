@@ -9,6 +9,7 @@ use crate::op_set::{ChangeOpIter, OpIdx, OpIdxRange};
 use crate::patches::{PatchLog, TextRepresentation};
 use crate::query::{self, OpIdSearch};
 use crate::storage::Change as StoredChange;
+use crate::transaction::{ListMatching, UpdateOptions};
 use crate::types::{Clock, Key, ListEncoding, ObjMeta, OpId};
 use crate::{op_tree::OpSetData, types::OpBuilder, Automerge, Change, ChangeHash, Prop};
 use crate::{AutomergeError, ObjType, OpType, ReadDoc, ScalarValue};
@@ -954,14 +955,15 @@ impl TransactionInner {
         patch_log: &mut PatchLog,
         obj: &ExId,
         new_value: &crate::hydrate::Value,
+        options: &UpdateOptions<'_>,
     ) -> Result<(), crate::error::UpdateObjectError> {
         let obj_meta = doc.exid_to_obj(obj)?;
         match (obj_meta.typ, new_value) {
             (ObjType::Map, crate::hydrate::Value::Map(map)) => {
-                Ok(self.update_map(doc, patch_log, obj, map)?)
+                Ok(self.update_map(doc, patch_log, obj, map, options)?)
             }
             (ObjType::List, crate::hydrate::Value::List(list)) => {
-                Ok(self.update_list(doc, patch_log, obj, list)?)
+                Ok(self.update_list(doc, patch_log, obj, list, options)?)
             }
             (ObjType::Text, crate::hydrate::Value::Text(new_text)) => {
                 Ok(crate::text_diff::myers_diff(
@@ -982,6 +984,7 @@ impl TransactionInner {
         patch_log: &mut PatchLog,
         map: &crate::ObjId,
         new_value: &crate::hydrate::Map,
+        options: &UpdateOptions<'_>,
     ) -> Result<(), AutomergeError> {
         let mut delenda = HashSet::new();
         let obj = doc.exid_to_obj(map)?;
@@ -1002,6 +1005,7 @@ impl TransactionInner {
                     key.into(),
                     new_value,
                     Some((id, value)),
+                    options,
                 )?,
                 None => {
                     delenda.insert(key.clone());
@@ -1010,7 +1014,15 @@ impl TransactionInner {
         }
         for (key, new_value) in new_value.iter() {
             if !present_keys.contains(key) {
-                self.update_value(doc, patch_log, map, key.into(), &new_value.value, None)?;
+                self.update_value(
+                    doc,
+                    patch_log,
+                    map,
+                    key.into(),
+                    &new_value.value,
+                    None,
+                    options,
+                )?;
             }
         }
         for key in delenda {
@@ -1025,7 +1037,13 @@ impl TransactionInner {
         patch_log: &mut PatchLog,
         list: &crate::ObjId,
         new_value: &crate::hydrate::List,
+        options: &UpdateOptions<'_>,
     ) -> Result<(), AutomergeError> {
+        if !matches!(options.list_matching, ListMatching::Positional) {
+            return crate::text_diff::myers_list_diff(
+                doc, self, patch_log, list, new_value, options,
+            );
+        }
         let old_items = doc
             .list_range(list, ..)
             .map(|ListRangeItem { value, id, .. }| Some((value.into_owned(), id)))
@@ -1048,6 +1066,7 @@ impl TransactionInner {
                         Prop::Seq(index),
                         &new_value.value,
                         Some((id, value)),
+                        options,
                     )?;
                 }
                 (Some(_), None) => {
@@ -1061,6 +1080,7 @@ impl TransactionInner {
                         Prop::Seq(index),
                         &new_value.value,
                         None,
+                        options,
                     )?;
                 }
                 (None, None) => {
@@ -1074,7 +1094,8 @@ impl TransactionInner {
         Ok(())
     }
 
-    fn update_value(
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn update_value(
         &mut self,
         doc: &mut Automerge,
         patch_log: &mut PatchLog,
@@ -1082,13 +1103,21 @@ impl TransactionInner {
         key: Prop,
         new_value: &crate::hydrate::Value,
         old_value: Option<(ExId, crate::Value<'_>)>,
+        options: &UpdateOptions<'_>,
     ) -> Result<(), AutomergeError> {
         match (old_value, new_value) {
+            // re-putting a conflicted value resolves the conflict, so only skip unconflicted ones
+            (Some((_, crate::Value::Scalar(old))), crate::hydrate::Value::Scalar(new))
+                if old.as_ref() == new
+                    && matches!(doc.get_all(parent, key.clone()).as_deref(), Ok([_])) =>
+            {
+                Ok(())
+            }
             (Some((id, crate::Value::Object(ObjType::Map))), crate::hydrate::Value::Map(new)) => {
-                self.update_map(doc, patch_log, &id, new)
+                self.update_map(doc, patch_log, &id, new, options)
             }
             (Some((id, crate::Value::Object(ObjType::List))), crate::hydrate::Value::List(new)) => {
-                self.update_list(doc, patch_log, &id, new)
+                self.update_list(doc, patch_log, &id, new, options)
             }
             (Some((id, crate::Value::Object(ObjType::Text))), crate::hydrate::Value::Text(new)) => {
                 crate::text_diff::myers_diff(doc, self, patch_log, &id, new.to_string().as_str())
@@ -1105,12 +1134,12 @@ impl TransactionInner {
                 match new {
                     crate::hydrate::Value::Map(new) => {
                         let map_id = make_obj(ObjType::Map)?;
-                        self.update_map(doc, patch_log, &map_id, new)
+                        self.update_map(doc, patch_log, &map_id, new, options)
                     }
 
                     crate::hydrate::Value::List(new) => {
                         let list_id = make_obj(ObjType::List)?;
-                        self.update_list(doc, patch_log, &list_id, new)
+                        self.update_list(doc, patch_log, &list_id, new, options)
                     }
 
                     crate::hydrate::Value::Text(new) => {
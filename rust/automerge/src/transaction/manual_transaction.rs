@@ -6,10 +6,10 @@ use crate::iter::{Keys, ListRange, MapRange, Values};
 use crate::marks::{ExpandMark, Mark, MarkSet};
 use crate::patches::PatchLog;
 use crate::types::Clock;
-use crate::{hydrate, AutomergeError};
+use crate::{hydrate, AutomergeError, BlameSpan, HistoryEntry};
 use crate::{Automerge, ChangeHash, Cursor, ObjType, Parents, Prop, ReadDoc, ScalarValue, Value};
 
-use super::{CommitOptions, Transactable, TransactionArgs, TransactionInner};
+use super::{CommitOptions, Transactable, TransactionArgs, TransactionInner, UpdateOptions};
 
 /// A transaction on a document.
 /// Transactions group operations into a single change so that no other operations can happen
@@ -319,6 +319,52 @@ impl<'a> ReadDoc for Transaction<'a> {
             .parents_for(obj.as_ref(), self.get_scope(Some(heads)))
     }
 
+    fn history<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(self.doc, obj.as_ref(), prop.into(), self.get_scope(None))
+    }
+
+    fn history_at<O: AsRef<ExId>, P: Into<Prop>>(
+        &self,
+        obj: O,
+        prop: P,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<HistoryEntry>, AutomergeError> {
+        crate::history::history(
+            self.doc,
+            obj.as_ref(),
+            prop.into(),
+            self.get_scope(Some(heads)),
+        )
+    }
+
+    fn object_changes<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(self.doc, obj.as_ref(), self.get_scope(None))
+    }
+
+    fn object_changes_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<ChangeHash>, AutomergeError> {
+        crate::history::object_changes(self.doc, obj.as_ref(), self.get_scope(Some(heads)))
+    }
+
+    fn blame<O: AsRef<ExId>>(&self, obj: O) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(self.doc, obj.as_ref(), self.get_scope(None))
+    }
+
+    fn blame_at<O: AsRef<ExId>>(
+        &self,
+        obj: O,
+        heads: &[ChangeHash],
+    ) -> Result<Vec<BlameSpan>, AutomergeError> {
+        crate::history::blame(self.doc, obj.as_ref(), self.get_scope(Some(heads)))
+    }
+
     fn get_missing_deps(&self, heads: &[ChangeHash]) -> Vec<ChangeHash> {
         self.doc.get_missing_deps(heads)
     }
@@ -490,7 +536,26 @@ impl<'a> Transactable for Transaction<'a> {
         obj: O,
         new_value: &crate::hydrate::Value,
     ) -> Result<(), crate::error::UpdateObjectError> {
-        self.do_tx(move |tx, doc, hist| tx.update_object(doc, hist, obj.as_ref(), new_value))
+        self.do_tx(move |tx, doc, hist| {
+            tx.update_object(
+                doc,
+                hist,
+                obj.as_ref(),
+                new_value,
+                &UpdateOptions::default(),
+            )
+        })
+    }
+
+    fn update_object_with<O: AsRef<ExId>>(
+        &mut self,
+        obj: O,
+        new_value: &crate::hydrate::Value,
+        options: UpdateOptions<'_>,
+    ) -> Result<(), crate::error::UpdateObjectError> {
+        self.do_tx(move |tx, doc, hist| {
+            tx.update_object(doc, hist, obj.as_ref(), new_value, &options)
+        })
     }
 }
 
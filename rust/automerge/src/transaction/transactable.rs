@@ -1,7 +1,10 @@
 use std::borrow::Cow;
 
+use crate::error::PathError;
 use crate::exid::ExId;
 use crate::marks::{ExpandMark, Mark};
+use crate::path::{Intermediates, IntoPath};
+use crate::transaction::UpdateOptions;
 use crate::{AutomergeError, ChangeHash, ObjType, Prop, ReadDoc, ScalarValue};
 
 /// A way of mutating a document within a single change.
@@ -43,6 +46,54 @@ pub trait Transactable: ReadDoc {
         object: ObjType,
     ) -> Result<ExId, AutomergeError>;
 
+    /// Set the value at `path` inside `obj` to `value`
+    ///
+    /// `path` is either a sequence of [`Prop`]s or an RFC 6901 JSON pointer, see [`crate::path`].
+    /// If the final segment is an index equal to the length of a list (or the pointer segment
+    /// `-`) the value is appended to the list. `intermediates` controls whether missing maps on
+    /// the way to the value are created.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error identifying the segment of the path which could not be resolved.
+    fn put_path<O: AsRef<ExId>, P: IntoPath, V: Into<ScalarValue>>(
+        &mut self,
+        obj: O,
+        path: P,
+        value: V,
+        intermediates: Intermediates,
+    ) -> Result<(), PathError> {
+        crate::path::put_path(self, obj, path, value.into(), intermediates)
+    }
+
+    /// Set the value at `path` inside `obj` to a new object, see [`Self::put_path()`]
+    ///
+    /// # Returns
+    ///
+    /// The id of the object which was created.
+    fn put_object_path<O: AsRef<ExId>, P: IntoPath>(
+        &mut self,
+        obj: O,
+        path: P,
+        object: ObjType,
+        intermediates: Intermediates,
+    ) -> Result<ExId, PathError> {
+        crate::path::put_object_path(self, obj, path, object, intermediates)
+    }
+
+    /// Delete the value at `path` inside `obj`, see [`Self::put_path()`]
+    ///
+    /// # Errors
+    ///
+    /// Unlike [`Self::delete()`] this returns an error if the value does not exist.
+    fn delete_path<O: AsRef<ExId>, P: IntoPath>(
+        &mut self,
+        obj: O,
+        path: P,
+    ) -> Result<(), PathError> {
+        crate::path::delete_path(self, obj, path)
+    }
+
     /// Insert a value into a list at the given index.
     fn insert<O: AsRef<ExId>, V: Into<ScalarValue>>(
         &mut self,
@@ -160,6 +211,39 @@ pub trait Transactable: ReadDoc {
         obj: O,
         new_value: &crate::hydrate::Value,
     ) -> Result<(), crate::error::UpdateObjectError>;
+
+    /// Update an object to match `new_value`, using `options` to decide how list elements are
+    /// matched
+    ///
+    /// [`Self::update_object`] matches list elements by position, which means that inserting an
+    /// element at the front of a list rewrites every element after it. Setting
+    /// [`UpdateOptions::list_matching`] to [`ListMatching::Diff`], [`ListMatching::Key`] or
+    /// [`ListMatching::KeyFn`] instead matches elements using a diff, so that unchanged elements
+    /// keep their element IDs and concurrent edits to them are preserved.
+    ///
+    /// [`ListMatching::Diff`]: crate::transaction::ListMatching::Diff
+    /// [`ListMatching::Key`]: crate::transaction::ListMatching::Key
+    /// [`ListMatching::KeyFn`]: crate::transaction::ListMatching::KeyFn
+    ///
+    /// # Errors
+    ///
+    /// The default implementation only supports [`ListMatching::Positional`], for which it calls
+    /// [`Self::update_object`], and returns [`UpdateObjectError::UnsupportedListMatching`] for the
+    /// other matchings. The implementations in this crate support all of them.
+    ///
+    /// [`ListMatching::Positional`]: crate::transaction::ListMatching::Positional
+    /// [`UpdateObjectError::UnsupportedListMatching`]: crate::error::UpdateObjectError::UnsupportedListMatching
+    fn update_object_with<O: AsRef<ExId>>(
+        &mut self,
+        obj: O,
+        new_value: &crate::hydrate::Value,
+        options: UpdateOptions<'_>,
+    ) -> Result<(), crate::error::UpdateObjectError> {
+        match options.list_matching {
+            crate::transaction::ListMatching::Positional => self.update_object(obj, new_value),
+            _ => Err(crate::error::UpdateObjectError::UnsupportedListMatching),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
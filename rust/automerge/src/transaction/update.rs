@@ -0,0 +1,92 @@
+use crate::error::UpdateObjectError;
+use crate::exid::ExId;
+use crate::transaction::Transactable;
+use crate::{hydrate, AutomergeError, ScalarValue};
+
+/// Options for [`crate::transaction::Transactable::update_object_with`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UpdateOptions<'a> {
+    pub list_matching: ListMatching<'a>,
+}
+
+impl<'a> UpdateOptions<'a> {
+    /// Set how elements of existing lists are matched with elements of the new lists
+    pub fn with_list_matching(mut self, list_matching: ListMatching<'a>) -> Self {
+        self.list_matching = list_matching;
+        self
+    }
+
+    /// Set how elements of existing lists are matched with elements of the new lists
+    pub fn set_list_matching(&mut self, list_matching: ListMatching<'a>) -> &mut Self {
+        self.list_matching = list_matching;
+        self
+    }
+}
+
+/// How elements of a list in the document are matched with elements of the new list when updating
+/// an object
+///
+/// Matched elements keep their element IDs and are updated in place (recursively, if they are
+/// objects). Elements in the document which are not matched are deleted and elements of the new
+/// list which are not matched are inserted. Moving an element is therefore represented as a
+/// deletion followed by an insertion.
+#[derive(Clone, Copy, Default)]
+pub enum ListMatching<'a> {
+    /// Match elements by their index. Elements past the end of the new list are deleted. This
+    /// produces the fewest operations when elements are only ever appended or edited in place,
+    /// but an insertion at the front of the list rewrites every element.
+    #[default]
+    Positional,
+    /// Match elements using a Myers diff over the element values. Runs of changed elements which
+    /// occupy the same position in both lists are updated in place.
+    Diff,
+    /// Match map elements which have the same value for the given key using a Myers diff over
+    /// the keys. Elements which are not maps, or which do not have the key, never match.
+    Key(&'a str),
+    /// Match elements for which the function returns the same key using a Myers diff over the
+    /// keys. Elements for which the function returns `None` never match.
+    KeyFn(&'a dyn Fn(&hydrate::Value) -> Option<ScalarValue>),
+}
+
+impl<'a> std::fmt::Debug for ListMatching<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListMatching::Positional => write!(f, "Positional"),
+            ListMatching::Diff => write!(f, "Diff"),
+            ListMatching::Key(k) => f.debug_tuple("Key").field(k).finish(),
+            ListMatching::KeyFn(_) => write!(f, "KeyFn(..)"),
+        }
+    }
+}
+
+impl<'a> ListMatching<'a> {
+    /// The key used to match `value`, if any
+    pub(crate) fn key(&self, value: &hydrate::Value) -> Option<ScalarValue> {
+        match self {
+            ListMatching::Positional | ListMatching::Diff => None,
+            ListMatching::Key(k) => match value {
+                hydrate::Value::Map(m) => match m.get(k) {
+                    Some(hydrate::Value::Scalar(s)) => Some(s.clone()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            ListMatching::KeyFn(f) => f(value),
+        }
+    }
+}
+
+/// Fill the newly created object `obj` with the contents of `value`
+pub(crate) fn fill<T: Transactable>(
+    doc: &mut T,
+    obj: &ExId,
+    value: &hydrate::Value,
+) -> Result<(), AutomergeError> {
+    doc.update_object(obj, value).map_err(|e| match e {
+        UpdateObjectError::Automerge(e) => e,
+        // `update_object` matches lists by position so it never reports an unsupported matching
+        UpdateObjectError::ChangeType | UpdateObjectError::UnsupportedListMatching => {
+            AutomergeError::NotAnObject
+        }
+    })
+}
@@ -0,0 +1,1136 @@
+//! Undoing and redoing local changes
+//!
+//! An [`UndoManager`] keeps stacks of the changes a document's own actor has made and undoes them
+//! by creating new changes which restore what they modified, so that undoing merges with
+//! concurrent edits like any other change. [`Automerge::revert()`] uses the same machinery to
+//! reverse any single change in the history.
+
+use std::collections::HashSet;
+use std::iter;
+
+use crate::columnar::Key as EncodedKey;
+use crate::exid::ExId;
+use crate::marks::{ExpandMark, Mark};
+use crate::patches::TextRepresentation;
+use crate::transaction::{fill, Transactable};
+use crate::types::{Clock, ElemId, Key, ListEncoding, ObjId, OpId, OpType};
+use crate::{
+    hydrate, AutoCommit, Automerge, AutomergeError, ChangeHash, Cursor, ObjType, Prop, ReadDoc,
+    ScalarValue, Value,
+};
+
+/// Records the local changes made to a document so that they can be undone and redone
+///
+/// A manager only records changes made after it was created with [`Self::new()`], the history
+/// the document already had at that point cannot be undone. Each local change (i.e. each commit
+/// made with the document's own actor ID) becomes one undo step, unless it is made between
+/// [`Self::begin_group()`] and [`Self::end_group()`] in which case all the changes made in that
+/// time form one step. Changes made by other actors, such as those
+/// received via [`Automerge::apply_changes`] or a sync message, are never undone.
+///
+/// Undoing a step creates a new change which restores the values, counters, list elements, text
+/// and marks which the step modified, as they were before the step. Edits made by other actors in
+/// the meantime are preserved: positions in sequences are tracked by element rather than by
+/// index, values which someone else has since overwritten are left alone and elements which
+/// someone else has since deleted are not resurrected. Redoing creates a change which reverses the
+/// undo.
+///
+/// ```
+/// # use automerge::{AutoCommit, ReadDoc, UndoManager, ROOT, transaction::Transactable};
+/// let mut doc = AutoCommit::new();
+/// let mut undo = UndoManager::new(&mut doc);
+/// doc.put(ROOT, "color", "red")?;
+/// doc.commit();
+/// doc.put(ROOT, "color", "blue")?;
+/// doc.commit();
+///
+/// undo.undo(&mut doc)?;
+/// assert_eq!(doc.get(ROOT, "color")?.unwrap().0.into_string().unwrap(), "red");
+/// undo.redo(&mut doc)?;
+/// assert_eq!(doc.get(ROOT, "color")?.unwrap().0.into_string().unwrap(), "blue");
+/// # Ok::<(), automerge::AutomergeError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct UndoManager {
+    undo: Vec<Vec<ChangeHash>>,
+    redo: Vec<Vec<ChangeHash>>,
+    /// The heads of the document when we last looked for new local changes
+    seen: Vec<ChangeHash>,
+    /// Changes created by undoing or redoing, which are not themselves recorded as new steps
+    own: HashSet<ChangeHash>,
+    group: Option<Vec<ChangeHash>>,
+}
+
+/// A document which can be used with an [`UndoManager`]
+///
+/// This is implemented for [`Automerge`] and [`AutoCommit`].
+pub trait UndoDoc: private::Sealed {}
+
+impl UndoDoc for Automerge {}
+impl UndoDoc for AutoCommit {}
+
+mod private {
+    use super::RevertResult;
+    use crate::{AutoCommit, Automerge, AutomergeError, ChangeHash};
+
+    pub trait Sealed {
+        /// The document with any pending transaction committed
+        fn committed(&mut self) -> &Automerge;
+
+        /// Create a single change which reverses `changes`
+        ///
+        /// If `local` is true values written since by the document's own actor are treated as
+        /// part of `changes` rather than as having been overwritten.
+        fn apply_inverse(
+            &mut self,
+            changes: &[ChangeHash],
+            local: bool,
+        ) -> Result<RevertResult, AutomergeError>;
+    }
+
+    impl Sealed for Automerge {
+        fn committed(&mut self) -> &Automerge {
+            self
+        }
+
+        fn apply_inverse(
+            &mut self,
+            changes: &[ChangeHash],
+            local: bool,
+        ) -> Result<RevertResult, AutomergeError> {
+            let (inverse, unrestored) = super::inverse(self, changes, local)?;
+            let mut tx = self.transaction();
+            super::apply(&mut tx, inverse)?;
+            Ok(RevertResult {
+                change: tx.commit().0,
+                unrestored,
+            })
+        }
+    }
+
+    impl Sealed for AutoCommit {
+        fn committed(&mut self) -> &Automerge {
+            self.committed_doc()
+        }
+
+        fn apply_inverse(
+            &mut self,
+            changes: &[ChangeHash],
+            local: bool,
+        ) -> Result<RevertResult, AutomergeError> {
+            let (inverse, unrestored) = super::inverse(self.committed_doc(), changes, local)?;
+            if let Err(e) = super::apply(self, inverse) {
+                self.rollback();
+                return Err(e);
+            }
+            Ok(RevertResult {
+                change: self.commit(),
+                unrestored,
+            })
+        }
+    }
+}
+
+impl UndoManager {
+    /// Create a manager which records the local changes made to `doc` from now on
+    ///
+    /// If `doc` has a pending transaction it is committed first and is not recorded.
+    pub fn new<D: UndoDoc>(doc: &mut D) -> Self {
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            seen: doc.committed().get_heads(),
+            own: HashSet::new(),
+            group: None,
+        }
+    }
+
+    /// Record any local changes made to `doc` since it was last recorded
+    ///
+    /// This is called by [`Self::undo()`], [`Self::redo()`], [`Self::begin_group()`] and
+    /// [`Self::end_group()`] so it only needs to be called directly in order for
+    /// [`Self::can_undo()`] to reflect the latest changes. Recording a new change clears the redo
+    /// stack.
+    pub fn record<D: UndoDoc>(&mut self, doc: &mut D) {
+        let doc = doc.committed();
+        let actor = doc.get_actor();
+        let new = doc
+            .get_changes(&self.seen)
+            .into_iter()
+            .filter(|c| c.actor_id() == actor && !self.own.contains(&c.hash()))
+            .map(|c| c.hash())
+            .collect::<Vec<_>>();
+        self.seen = doc.get_heads();
+        if new.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        match &mut self.group {
+            Some(group) => group.extend(new),
+            None => self.undo.extend(new.into_iter().map(|h| vec![h])),
+        }
+    }
+
+    /// Start grouping local changes into a single undo step
+    ///
+    /// Changes made before this call are recorded as their own steps.
+    pub fn begin_group<D: UndoDoc>(&mut self, doc: &mut D) {
+        self.end_group(doc);
+        self.group = Some(Vec::new());
+    }
+
+    /// Finish the current group, recording all the local changes made since
+    /// [`Self::begin_group()`] as a single undo step
+    pub fn end_group<D: UndoDoc>(&mut self, doc: &mut D) {
+        self.record(doc);
+        if let Some(group) = self.group.take() {
+            if !group.is_empty() {
+                self.undo.push(group);
+            }
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Undo the most recent undo step, ending the current group if there is one
+    ///
+    /// Returns the hash of the change which was created, or `None` if there was nothing to undo
+    /// or the step had already been entirely overwritten by other changes.
+    pub fn undo<D: UndoDoc>(&mut self, doc: &mut D) -> Result<Option<ChangeHash>, AutomergeError> {
+        self.end_group(doc);
+        let Some(step) = self.undo.pop() else {
+            return Ok(None);
+        };
+        let hash = self.apply(doc, &step)?;
+        if let Some(hash) = hash {
+            self.redo.push(vec![hash]);
+        }
+        Ok(hash)
+    }
+
+    /// Redo the most recently undone step
+    ///
+    /// Returns the hash of the change which was created, or `None` if there was nothing to redo.
+    pub fn redo<D: UndoDoc>(&mut self, doc: &mut D) -> Result<Option<ChangeHash>, AutomergeError> {
+        self.end_group(doc);
+        let Some(step) = self.redo.pop() else {
+            return Ok(None);
+        };
+        let hash = self.apply(doc, &step)?;
+        if let Some(hash) = hash {
+            self.undo.push(vec![hash]);
+        }
+        Ok(hash)
+    }
+
+    /// Forget all undo and redo steps
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.group = None;
+    }
+
+    fn apply<D: UndoDoc>(
+        &mut self,
+        doc: &mut D,
+        step: &[ChangeHash],
+    ) -> Result<Option<ChangeHash>, AutomergeError> {
+        let hash = doc.apply_inverse(step, true)?.change;
+        if let Some(hash) = hash {
+            self.own.insert(hash);
+        }
+        self.seen = doc.committed().get_heads();
+        Ok(hash)
+    }
+}
+
+/// Create a change which reverses `hash`, see [`Automerge::revert`]
+pub(crate) fn revert<D: UndoDoc>(
+    doc: &mut D,
+    hash: &ChangeHash,
+) -> Result<RevertResult, AutomergeError> {
+    doc.apply_inverse(&[*hash], false)
+}
+
+/// An operation which reverses part of a change
+#[derive(Debug)]
+enum Inverse {
+    /// Set a key in a map back to a value, or delete it if the value is `None`
+    PutKey {
+        obj: ExId,
+        key: String,
+        value: Option<hydrate::Value>,
+    },
+    /// Set a list element back to a value
+    PutElem {
+        obj: ExId,
+        elem: Cursor,
+        value: hydrate::Value,
+    },
+    /// Insert new elements with the values of a run of adjacent deleted elements, at the position
+    /// of the first of them
+    Reinsert {
+        obj: ExId,
+        at: Cursor,
+        values: Vec<hydrate::Value>,
+        /// The index (counting elements, not characters) just past the run as at the heads before
+        /// the change
+        next: usize,
+    },
+    /// Delete an inserted list element
+    DeleteElem {
+        obj: ExId,
+        elem: Cursor,
+    },
+    Increment {
+        obj: ExId,
+        prop: Target,
+        by: i64,
+    },
+    /// Reset the mark `name` between two anchors to the values it had before
+    Marks {
+        obj: ExId,
+        name: String,
+        begin: Cursor,
+        end: Cursor,
+        prior: Vec<PriorMark>,
+    },
+}
+
+#[derive(Debug)]
+enum Target {
+    Key(String),
+    Elem(Cursor),
+}
+
+#[derive(Debug)]
+struct PriorMark {
+    first: Cursor,
+    last: Cursor,
+    /// Whether `last` is still visible, in which case the mark extends past it
+    last_visible: bool,
+    value: ScalarValue,
+}
+
+/// Something which [`Automerge::revert`] could not restore
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unrestored {
+    /// The object containing the value
+    pub obj: ExId,
+    /// The key or current index of the value
+    pub prop: Prop,
+    pub reason: UnrestoredReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnrestoredReason {
+    /// The value has been modified by a later change, so it was left as it is
+    Overwritten,
+    /// The key or list element has been deleted by a later change. For a list element the index is
+    /// where the element would be.
+    Deleted,
+}
+
+impl Unrestored {
+    fn overwritten(obj: ExId, prop: Prop) -> Self {
+        Unrestored {
+            obj,
+            prop,
+            reason: UnrestoredReason::Overwritten,
+        }
+    }
+
+    fn deleted(obj: ExId, prop: Prop) -> Self {
+        Unrestored {
+            obj,
+            prop,
+            reason: UnrestoredReason::Deleted,
+        }
+    }
+}
+
+/// The result of [`Automerge::revert`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RevertResult {
+    /// The change which was created, or `None` if there was nothing to revert
+    pub change: Option<ChangeHash>,
+    /// The parts of the reverted change which have been modified since and so were not restored
+    pub unrestored: Vec<Unrestored>,
+}
+
+/// An operation from one of the changes being reversed
+struct ReversedOp {
+    id: OpId,
+    obj: ObjId,
+    key: Option<String>,
+    elem: Option<OpId>,
+    insert: bool,
+    action: OpType,
+    /// The index of the heads the change which contains this op was based on in `befores`
+    before: usize,
+}
+
+/// Calculate the operations which reverse `changes` in the current state of `doc`, along with
+/// the parts of the changes which cannot be reversed because later changes have modified the same
+/// values
+///
+/// The changes are treated as one unit, so an element which was inserted by one of them and
+/// deleted by another is left alone. If `local` is true values written by the document's own
+/// actor are never considered overwritten, the undo stack takes care of ordering those.
+fn inverse(
+    doc: &Automerge,
+    changes: &[ChangeHash],
+    local: bool,
+) -> Result<(Vec<Inverse>, Vec<Unrestored>), AutomergeError> {
+    let mut befores = Vec::new();
+    let mut ops = Vec::new();
+    for hash in changes {
+        let change = doc
+            .get_change_by_hash(hash)
+            .ok_or(AutomergeError::InvalidHash(*hash))?;
+        let actors = iter::once(change.actor_id())
+            .chain(change.other_actor_ids())
+            .map(|a| doc.osd().actors.lookup(a))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(AutomergeError::InvalidHash(*hash))?;
+        let opid = |o: &OpId| OpId::new(o.counter(), actors[o.actor()]);
+        let before = befores.len();
+        befores.push((change.deps().to_vec(), doc.clock_at(change.deps())));
+        ops.extend(change.iter_ops().enumerate().map(|(i, op)| {
+            let (key, elem) = match op.key {
+                EncodedKey::Prop(name) => (Some(name.to_string()), None),
+                EncodedKey::Elem(e) if e.is_head() => (None, None),
+                EncodedKey::Elem(e) => (None, Some(opid(&e.0))),
+            };
+            ReversedOp {
+                id: OpId::new(change.start_op().get() + i as u64, actors[0]),
+                obj: if op.obj.is_root() {
+                    ObjId::root()
+                } else {
+                    ObjId(opid(op.obj.opid()))
+                },
+                key,
+                elem,
+                insert: op.insert,
+                action: OpType::from_action_and_value(op.action, op.val, op.mark_name, op.expand),
+                before,
+            }
+        }));
+    }
+
+    let ours = ops.iter().map(|op| op.id).collect::<HashSet<_>>();
+    let local = local
+        .then(|| doc.osd().actors.lookup(doc.get_actor()))
+        .flatten();
+    let is_ours = |id: &OpId| ours.contains(id) || Some(id.actor()) == local;
+    let created = ops
+        .iter()
+        .filter(|op| matches!(op.action, OpType::Make(_)))
+        .map(|op| ObjId(op.id))
+        .collect::<HashSet<_>>();
+    let inserted = ops
+        .iter()
+        .filter(|op| op.insert)
+        .map(|op| op.id)
+        .collect::<HashSet<_>>();
+
+    let mut done_keys = HashSet::new();
+    let mut done_elems = HashSet::new();
+    let mut result = Vec::new();
+    let mut unrestored = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        if created.contains(&op.obj) {
+            continue;
+        }
+        let (before, before_clock) = &befores[op.before];
+        let obj = doc.id_to_exid(op.obj.0);
+        let cursor = |id: OpId| Cursor::new(id, doc.osd());
+        match (&op.key, op.elem, op.insert, &op.action) {
+            (Some(key), _, _, OpType::Increment(by)) => {
+                if is_counter(doc, &obj, key.as_str().into())? {
+                    result.push(Inverse::Increment {
+                        obj,
+                        prop: Target::Key(key.clone()),
+                        by: -by,
+                    })
+                } else {
+                    unrestored.push(Unrestored::overwritten(obj, key.as_str().into()));
+                }
+            }
+            (Some(key), ..) => {
+                if !done_keys.insert((op.obj, key.clone())) {
+                    continue;
+                }
+                let current = doc
+                    .get_all(&obj, key.as_str())?
+                    .into_iter()
+                    .map(|(_, id)| doc.exid_to_opid(&id))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if current.is_empty() {
+                    // the key was deleted, which is only ours to undo if every delete since the
+                    // change was
+                    if deletes_since(doc, &op.obj, key, before_clock)
+                        .iter()
+                        .all(is_ours)
+                    {
+                        result.push(Inverse::PutKey {
+                            value: value_at(doc, &obj, key.as_str().into(), before)?,
+                            obj,
+                            key: key.clone(),
+                        })
+                    } else {
+                        unrestored.push(Unrestored::deleted(obj, key.as_str().into()));
+                    }
+                } else if current.iter().all(is_ours) {
+                    result.push(Inverse::PutKey {
+                        value: value_at(doc, &obj, key.as_str().into(), before)?,
+                        obj,
+                        key: key.clone(),
+                    })
+                } else {
+                    unrestored.push(Unrestored::overwritten(obj, key.as_str().into()));
+                }
+            }
+            (_, _, true, OpType::MarkBegin(_, data)) => {
+                let Some(ReversedOp {
+                    id: end,
+                    action: OpType::MarkEnd(_),
+                    ..
+                }) = ops.get(i + 1)
+                else {
+                    continue;
+                };
+                let prior = prior_marks(doc, &obj, op.id, *end, &data.name, before_clock)?;
+                result.push(Inverse::Marks {
+                    obj,
+                    name: data.name.to_string(),
+                    begin: cursor(op.id),
+                    end: cursor(*end),
+                    prior,
+                })
+            }
+            (_, _, true, OpType::MarkEnd(_)) => {}
+            (_, _, true, _) => {
+                if let Some((_, true)) = elem_index(doc, &obj, op.id, None)? {
+                    result.push(Inverse::DeleteElem {
+                        obj,
+                        elem: cursor(op.id),
+                    })
+                }
+            }
+            (_, Some(elem), false, _) if inserted.contains(&elem) => {}
+            (_, Some(elem), false, OpType::Increment(by)) => {
+                match elem_index(doc, &obj, elem, None)? {
+                    Some((index, true)) if is_counter(doc, &obj, index.into())? => {
+                        result.push(Inverse::Increment {
+                            obj,
+                            prop: Target::Elem(cursor(elem)),
+                            by: -by,
+                        })
+                    }
+                    Some((index, true)) => {
+                        unrestored.push(Unrestored::overwritten(obj, index.into()))
+                    }
+                    Some((index, false)) => unrestored.push(Unrestored::deleted(obj, index.into())),
+                    None => {}
+                }
+            }
+            (_, Some(elem), false, _) => {
+                if !done_elems.insert(elem) {
+                    continue;
+                }
+                let Some((index, true)) = elem_index(doc, &obj, elem, Some(before_clock))? else {
+                    continue;
+                };
+                let Some(value) = value_at(doc, &obj, index.into(), before)? else {
+                    continue;
+                };
+                match elem_index(doc, &obj, elem, None)? {
+                    Some((index, true)) if matches!(op.action, OpType::Delete) => {
+                        // the element was deleted but a concurrent change updated it, so it is
+                        // still there
+                        unrestored.push(Unrestored::overwritten(obj, index.into()));
+                        continue;
+                    }
+                    Some((index, true)) => {
+                        let current = elem_ops(doc, &op.obj, elem, index)?;
+                        if !current.is_empty() && current.iter().all(is_ours) {
+                            result.push(Inverse::PutElem {
+                                obj,
+                                elem: cursor(elem),
+                                value,
+                            });
+                        } else {
+                            unrestored.push(Unrestored::overwritten(obj, index.into()));
+                        }
+                        continue;
+                    }
+                    Some((index, false)) if !matches!(op.action, OpType::Delete) => {
+                        unrestored.push(Unrestored::deleted(obj, index.into()));
+                        continue;
+                    }
+                    _ => {}
+                }
+                // Deleting a range of elements produces one op per element, reinsert them as a
+                // single run so that they come back in the same order
+                let index = doc
+                    .ops()
+                    .seek_list_opid(&op.obj, elem, ListEncoding::List, Some(before_clock))
+                    .map(|found| found.index)
+                    .unwrap_or_default();
+                if let Some(Inverse::Reinsert {
+                    obj: run_obj,
+                    values,
+                    next,
+                    ..
+                }) = result.last_mut()
+                {
+                    if *run_obj == obj && *next == index {
+                        values.push(value);
+                        *next = index + 1;
+                        continue;
+                    }
+                }
+                result.push(Inverse::Reinsert {
+                    obj,
+                    at: cursor(elem),
+                    values: vec![value],
+                    next: index + 1,
+                })
+            }
+            (None, None, false, _) => {}
+        }
+    }
+    result.reverse();
+    Ok((result, unrestored))
+}
+
+fn is_counter(doc: &Automerge, obj: &ExId, prop: Prop) -> Result<bool, AutomergeError> {
+    Ok(matches!(doc.get(obj, prop)?, Some((Value::Scalar(s), _)) if s.is_counter()))
+}
+
+/// The ids of the ops which deleted values of `key` in `obj` and which are not covered by `before`
+fn deletes_since(doc: &Automerge, obj: &ObjId, key: &String, before: &Clock) -> Vec<OpId> {
+    let Some(prop) = doc.osd().props.lookup(key) else {
+        return Vec::new();
+    };
+    doc.ops()
+        .ops_for_key(obj, Key::Map(prop))
+        .iter()
+        .flat_map(|op| op.succ())
+        .filter(|succ| succ.is_delete() && !before.covers(succ.id()))
+        .map(|succ| *succ.id())
+        .collect()
+}
+
+/// The ids of the visible ops for the element `elem`, which is at `index` in `obj`
+fn elem_ops(
+    doc: &Automerge,
+    obj: &ObjId,
+    elem: OpId,
+    index: usize,
+) -> Result<Vec<OpId>, AutomergeError> {
+    let typ = doc.get_obj_meta(*obj)?.typ;
+    Ok(doc
+        .ops()
+        .seek_ops_by_prop(
+            obj,
+            index.into(),
+            TextRepresentation::String.encoding(typ),
+            None,
+        )
+        .ops
+        .iter()
+        .filter(|op| op.elemid_or_key() == Key::Seq(ElemId(elem)))
+        .map(|op| *op.id())
+        .collect())
+}
+
+/// The position of the element `elem` in `obj` and whether it is visible, as at `clock`
+fn elem_index(
+    doc: &Automerge,
+    obj: &ExId,
+    elem: OpId,
+    clock: Option<&Clock>,
+) -> Result<Option<(usize, bool)>, AutomergeError> {
+    let obj = doc.exid_to_obj(obj)?;
+    let encoding = TextRepresentation::String.encoding(obj.typ);
+    let Some(found) = doc.ops().seek_list_opid(&obj.id, elem, encoding, clock) else {
+        return Ok(None);
+    };
+    // `found.visible` is only true if `elem` itself is visible, but the element is also visible
+    // if it was overwritten by an op which is visible
+    let visible = found.visible
+        || doc
+            .ops()
+            .seek_ops_by_prop(&obj.id, found.index.into(), encoding, clock)
+            .ops
+            .iter()
+            .any(|op| op.elemid_or_key() == Key::Seq(ElemId(elem)));
+    Ok(Some((found.index, visible)))
+}
+
+fn value_at(
+    doc: &Automerge,
+    obj: &ExId,
+    prop: Prop,
+    heads: &[ChangeHash],
+) -> Result<Option<hydrate::Value>, AutomergeError> {
+    Ok(match doc.get_at(obj, prop, heads)? {
+        None => None,
+        Some((Value::Scalar(s), _)) => Some(hydrate::Value::Scalar(s.into_owned())),
+        Some((Value::Object(_), id)) => Some(doc.hydrate_obj(&id, Some(heads))?),
+    })
+}
+
+/// The spans of the mark `name` between the anchors `begin` and `end` before they were inserted
+fn prior_marks(
+    doc: &Automerge,
+    obj: &ExId,
+    begin: OpId,
+    end: OpId,
+    name: &str,
+    before: &Clock,
+) -> Result<Vec<PriorMark>, AutomergeError> {
+    let (Some((start, _)), Some((end, _))) = (
+        elem_index(doc, obj, begin, Some(before))?,
+        elem_index(doc, obj, end, Some(before))?,
+    ) else {
+        return Ok(Vec::new());
+    };
+    let mut result = Vec::new();
+    for mark in doc.marks_for(obj, Some(before.clone()))? {
+        let (first, last) = (mark.start.max(start), mark.end.min(end));
+        if mark.name() != name || first >= last || mark.value().is_null() {
+            continue;
+        }
+        let first = doc.get_cursor_for(obj, first, Some(before.clone()))?;
+        let last = doc.get_cursor_for(obj, last - 1, Some(before.clone()))?;
+        let last_visible = doc
+            .cursor_to_opid(&last, None)
+            .and_then(|id| elem_index(doc, obj, id, None))?
+            .map(|(_, visible)| visible)
+            .unwrap_or(false);
+        result.push(PriorMark {
+            first,
+            last,
+            last_visible,
+            value: mark.value().clone(),
+        });
+    }
+    Ok(result)
+}
+
+fn apply<T: Transactable>(doc: &mut T, inverse: Vec<Inverse>) -> Result<(), AutomergeError> {
+    for op in inverse {
+        match op {
+            Inverse::PutKey { obj, key, value } => match value {
+                Some(value) => put_value(doc, &obj, key.into(), &value)?,
+                None => {
+                    if doc.get(&obj, key.as_str())?.is_some() {
+                        doc.delete(&obj, key)?;
+                    }
+                }
+            },
+            Inverse::PutElem { obj, elem, value } => {
+                let index = doc.get_cursor_position(&obj, &elem, None)?;
+                put_value(doc, &obj, index.into(), &value)?;
+            }
+            Inverse::Reinsert {
+                obj, at, values, ..
+            } => {
+                let mut index = doc.get_cursor_position(&obj, &at, None)?;
+                for value in values {
+                    let len = doc.length(&obj);
+                    insert_value(doc, &obj, index, &value)?;
+                    index += doc.length(&obj) - len;
+                }
+            }
+            Inverse::DeleteElem { obj, elem } => {
+                let index = doc.get_cursor_position(&obj, &elem, None)?;
+                doc.delete(&obj, index)?;
+            }
+            Inverse::Increment { obj, prop, by } => {
+                let prop = match prop {
+                    Target::Key(key) => Prop::Map(key),
+                    Target::Elem(elem) => doc.get_cursor_position(&obj, &elem, None)?.into(),
+                };
+                if let Some((Value::Scalar(s), _)) = doc.get(&obj, prop.clone())? {
+                    if s.is_counter() {
+                        doc.increment(&obj, prop, by)?;
+                    }
+                }
+            }
+            Inverse::Marks {
+                obj,
+                name,
+                begin,
+                end,
+                prior,
+            } => {
+                let start = doc.get_cursor_position(&obj, &begin, None)?;
+                let end = doc.get_cursor_position(&obj, &end, None)?;
+                if start < end {
+                    doc.unmark(&obj, &name, start, end, ExpandMark::None)?;
+                }
+                for mark in prior {
+                    let first = doc.get_cursor_position(&obj, &mark.first, None)?;
+                    let mut last = doc.get_cursor_position(&obj, &mark.last, None)?;
+                    if mark.last_visible {
+                        last += 1;
+                    }
+                    if first < last {
+                        let mark = Mark::new(name.clone(), mark.value, first, last);
+                        doc.mark(&obj, mark, ExpandMark::None)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn put_value<T: Transactable>(
+    doc: &mut T,
+    obj: &ExId,
+    prop: Prop,
+    value: &hydrate::Value,
+) -> Result<(), AutomergeError> {
+    let typ = match value {
+        hydrate::Value::Scalar(s) => return doc.put(obj, prop, s.clone()),
+        hydrate::Value::Map(_) => ObjType::Map,
+        hydrate::Value::List(_) => ObjType::List,
+        hydrate::Value::Text(_) => ObjType::Text,
+    };
+    let id = doc.put_object(obj, prop, typ)?;
+    fill(doc, &id, value)
+}
+
+fn insert_value<T: Transactable>(
+    doc: &mut T,
+    obj: &ExId,
+    index: usize,
+    value: &hydrate::Value,
+) -> Result<(), AutomergeError> {
+    let typ = match value {
+        hydrate::Value::Scalar(ScalarValue::Str(s)) if doc.object_type(obj)? == ObjType::Text => {
+            return doc.splice_text(obj, index, 0, s)
+        }
+        hydrate::Value::Scalar(s) => return doc.insert(obj, index, s.clone()),
+        hydrate::Value::Map(_) => ObjType::Map,
+        hydrate::Value::List(_) => ObjType::List,
+        hydrate::Value::Text(_) => ObjType::Text,
+    };
+    let id = doc.insert_object(obj, index, typ)?;
+    fill(doc, &id, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transaction::Transactable, ROOT};
+
+    fn get_str(doc: &AutoCommit, obj: &ExId, prop: &str) -> Option<String> {
+        doc.get(obj, prop)
+            .unwrap()
+            .map(|(v, _)| v.into_string().unwrap())
+    }
+
+    #[test]
+    fn undo_and_redo_map_values() {
+        let mut doc = AutoCommit::new();
+        let mut undo = UndoManager::new(&mut doc);
+        doc.put(ROOT, "a", "one").unwrap();
+        doc.commit();
+        doc.put(ROOT, "a", "two").unwrap();
+        doc.put_object(ROOT, "b", ObjType::Map).unwrap();
+        doc.commit();
+
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(get_str(&doc, &ROOT, "a").as_deref(), Some("one"));
+        assert!(doc.get(ROOT, "b").unwrap().is_none());
+
+        undo.redo(&mut doc).unwrap();
+        assert_eq!(get_str(&doc, &ROOT, "a").as_deref(), Some("two"));
+        assert!(doc.get(ROOT, "b").unwrap().is_some());
+
+        undo.undo(&mut doc).unwrap();
+        undo.undo(&mut doc).unwrap();
+        assert!(doc.get(ROOT, "a").unwrap().is_none());
+        assert!(!undo.can_undo());
+        assert!(undo.undo(&mut doc).unwrap().is_none());
+    }
+
+    #[test]
+    fn changes_before_the_manager_are_not_recorded() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "a", "one").unwrap();
+        doc.commit();
+        doc.put(ROOT, "a", "two").unwrap();
+
+        let mut undo = UndoManager::new(&mut doc);
+        assert!(!undo.can_undo());
+        assert!(undo.undo(&mut doc).unwrap().is_none());
+        assert_eq!(get_str(&doc, &ROOT, "a").as_deref(), Some("two"));
+
+        doc.put(ROOT, "a", "three").unwrap();
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(get_str(&doc, &ROOT, "a").as_deref(), Some("two"));
+        assert!(!undo.can_undo());
+    }
+
+    #[test]
+    fn undo_restores_deleted_objects() {
+        let mut doc = AutoCommit::new();
+        let mut undo = UndoManager::new(&mut doc);
+        let list = doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        let item = doc.insert_object(&list, 0, ObjType::Map).unwrap();
+        doc.put(&item, "title", "hello").unwrap();
+        doc.commit();
+        doc.delete(&list, 0).unwrap();
+        doc.commit();
+
+        undo.undo(&mut doc).unwrap();
+        let (_, item) = doc.get(&list, 0).unwrap().unwrap();
+        assert_eq!(get_str(&doc, &item, "title").as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn undo_preserves_concurrent_text_edits() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello world").unwrap();
+        doc.commit();
+
+        let mut undo = UndoManager::new(&mut doc);
+        let mut remote = doc.fork();
+
+        doc.splice_text(&text, 5, 6, "").unwrap();
+        doc.splice_text(&text, 5, 0, " there").unwrap();
+        doc.commit();
+        remote.splice_text(&text, 0, 0, ">> ").unwrap();
+        doc.merge(&mut remote).unwrap();
+        assert_eq!(doc.text(&text).unwrap(), ">> hello there");
+
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(doc.text(&text).unwrap(), ">> hello world");
+        undo.redo(&mut doc).unwrap();
+        assert_eq!(doc.text(&text).unwrap(), ">> hello there");
+    }
+
+    #[test]
+    fn undo_does_not_resurrect_remotely_deleted_elements() {
+        let mut doc = AutoCommit::new();
+        let list = doc.put_object(ROOT, "list", ObjType::List).unwrap();
+        doc.insert(&list, 0, 1).unwrap();
+        doc.commit();
+
+        let mut undo = UndoManager::new(&mut doc);
+        let mut remote = doc.fork();
+        doc.put(&list, 0, 2).unwrap();
+        doc.insert(&list, 1, 3).unwrap();
+        doc.commit();
+        remote.delete(&list, 0).unwrap();
+        doc.merge(&mut remote).unwrap();
+        assert_eq!(doc.length(&list), 2);
+
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(doc.length(&list), 1);
+        assert_eq!(doc.get(&list, 0).unwrap().unwrap().0, Value::int(1));
+    }
+
+    #[test]
+    fn undo_does_not_restore_remotely_deleted_keys() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "x", 0).unwrap();
+        doc.commit();
+
+        let mut undo = UndoManager::new(&mut doc);
+        doc.put(ROOT, "x", 1).unwrap();
+        doc.commit();
+        let mut remote = doc.fork();
+        remote.delete(ROOT, "x").unwrap();
+        doc.merge(&mut remote).unwrap();
+
+        undo.undo(&mut doc).unwrap();
+        assert!(doc.get(ROOT, "x").unwrap().is_none());
+
+        doc.put(ROOT, "y", 1).unwrap();
+        doc.commit();
+        doc.delete(ROOT, "y").unwrap();
+        doc.commit();
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(doc.get(ROOT, "y").unwrap().unwrap().0, Value::int(1));
+    }
+
+    #[test]
+    fn undo_counters_and_marks() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "abcdef").unwrap();
+        doc.put(ROOT, "count", ScalarValue::counter(1)).unwrap();
+        doc.mark(
+            &text,
+            Mark::new("bold".into(), true, 0, 2),
+            ExpandMark::None,
+        )
+        .unwrap();
+        doc.commit();
+
+        let mut undo = UndoManager::new(&mut doc);
+        let mut remote = doc.fork();
+        doc.increment(ROOT, "count", 5).unwrap();
+        doc.mark(
+            &text,
+            Mark::new("bold".into(), true, 1, 4),
+            ExpandMark::None,
+        )
+        .unwrap();
+        doc.commit();
+        remote.increment(ROOT, "count", 10).unwrap();
+        doc.merge(&mut remote).unwrap();
+
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(
+            doc.get(ROOT, "count").unwrap().unwrap().0,
+            Value::counter(11)
+        );
+        let marks = doc.marks(&text).unwrap();
+        assert_eq!(marks.len(), 1);
+        assert_eq!((marks[0].start, marks[0].end), (0, 2));
+    }
+
+    #[test]
+    fn groups_and_manual_transactions() {
+        let mut doc = Automerge::new();
+        let mut undo = UndoManager::new(&mut doc);
+        undo.begin_group(&mut doc);
+        let mut tx = doc.transaction();
+        tx.put(ROOT, "a", 1).unwrap();
+        tx.commit();
+        let mut tx = doc.transaction();
+        tx.put(ROOT, "b", 2).unwrap();
+        tx.commit();
+        undo.end_group(&mut doc);
+
+        let mut tx = doc.transaction();
+        tx.put(ROOT, "c", 3).unwrap();
+        tx.commit();
+
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(doc.keys(ROOT).collect::<Vec<_>>(), vec!["a", "b"]);
+        undo.undo(&mut doc).unwrap();
+        assert_eq!(doc.length(ROOT), 0);
+        assert!(!undo.can_undo());
+        undo.redo(&mut doc).unwrap();
+        assert_eq!(doc.keys(ROOT).collect::<Vec<_>>(), vec!["a", "b"]);
+
+        // a new local change clears the redo stack
+        let mut tx = doc.transaction();
+        tx.put(ROOT, "d", 4).unwrap();
+        tx.commit();
+        undo.record(&mut doc);
+        assert!(!undo.can_redo());
+    }
+
+    #[test]
+    fn revert_a_historical_change() {
+        let mut doc = AutoCommit::new();
+        let text = doc.put_object(ROOT, "text", ObjType::Text).unwrap();
+        doc.splice_text(&text, 0, 0, "hello").unwrap();
+        doc.put(ROOT, "count", ScalarValue::counter(0)).unwrap();
+        doc.put(ROOT, "a", 1).unwrap();
+        doc.commit();
+
+        doc.put(ROOT, "a", 2).unwrap();
+        doc.put(ROOT, "b", 2).unwrap();
+        doc.splice_text(&text, 0, 1, "J").unwrap();
+        doc.increment(ROOT, "count", 3).unwrap();
+        let middle = doc.commit().unwrap();
+
+        doc.put(ROOT, "a", 3).unwrap();
+        doc.splice_text(&text, 5, 0, "!").unwrap();
+        doc.increment(ROOT, "count", 1).unwrap();
+        doc.commit();
+
+        let result = doc.revert(&middle).unwrap();
+        assert!(result.change.is_some());
+        assert_eq!(
+            result.unrestored,
+            vec![Unrestored {
+                obj: ROOT,
+                prop: "a".into(),
+                reason: UnrestoredReason::Overwritten,
+            }]
+        );
+        assert_eq!(doc.get(ROOT, "a").unwrap().unwrap().0, Value::int(3));
+        assert!(doc.get(ROOT, "b").unwrap().is_none());
+        assert_eq!(doc.text(&text).unwrap(), "hello!");
+        assert_eq!(
+            doc.get(ROOT, "count").unwrap().unwrap().0,
+            Value::counter(1)
+        );
+    }
+
+    #[test]
+    fn revert_reports_remotely_deleted_keys() {
+        let mut doc = AutoCommit::new();
+        doc.put(ROOT, "x", 1).unwrap();
+        let put = doc.commit().unwrap();
+        let mut remote = doc.fork();
+        remote.delete(ROOT, "x").unwrap();
+        doc.merge(&mut remote).unwrap();
+
+        let result = doc.revert(&put).unwrap();
+        assert_eq!(result.change, None);
+        assert_eq!(
+            result.unrestored,
+            vec![Unrestored {
+                obj: ROOT,
+                prop: "x".into(),
+                reason: UnrestoredReason::Deleted,
+            }]
+        );
+        assert!(doc.get(ROOT, "x").unwrap().is_none());
+    }
+
+    #[test]
+    fn revert_reports_deleted_elements() {
+        let mut doc = Automerge::new();
+        let mut tx = doc.transaction();
+        let list = tx.put_object(ROOT, "list", ObjType::List).unwrap();
+        tx.insert(&list, 0, "a").unwrap();
+        tx.insert(&list, 1, "b").unwrap();
+        tx.commit();
+        let mut tx = doc.transaction();
+        tx.put(&list, 1, "B").unwrap();
+        let (put, _) = tx.commit();
+        let mut tx = doc.transaction();
+        tx.delete(&list, 1).unwrap();
+        tx.commit();
+
+        let result = doc.revert(&put.unwrap()).unwrap();
+        assert_eq!(result.change, None);
+        assert_eq!(
+            result.unrestored,
+            vec![Unrestored {
+                obj: list.clone(),
+                prop: 1.into(),
+                reason: UnrestoredReason::Deleted,
+            }]
+        );
+        assert_eq!(doc.length(&list), 1);
+
+        let missing = ChangeHash([0; 32]);
+        assert!(matches!(
+            doc.revert(&missing),
+            Err(AutomergeError::InvalidHash(_))
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use automerge_test::sim::{Config, NetworkConfig, Simulation, Stats};
+
+fn run(config: Config, edits: usize) -> Stats {
+    let mut sim = Simulation::new(config);
+    for peer in 0..config.peers {
+        sim.random_edits(peer, edits);
+    }
+    let stats = sim
+        .run_until_converged()
+        .unwrap_or_else(|e| panic!("seed {}: {}", config.seed, e));
+    assert!(sim.converged());
+    stats
+}
+
+#[test]
+fn peers_converge_over_a_perfect_network() {
+    for seed in 0..10 {
+        let stats = run(
+            Config {
+                seed,
+                peers: 4,
+                ..Config::default()
+            },
+            20,
+        );
+        assert_eq!(stats.reconnects, 0);
+        // Each link needs an opening message, a reply with changes and an acknowledgement
+        let links = 4 * 3;
+        assert!(
+            stats.messages_sent <= links * 3,
+            "seed {}: sent {} messages",
+            seed,
+            stats.messages_sent
+        );
+    }
+}
+
+#[test]
+fn peers_converge_over_a_lossy_network() {
+    for seed in 0..20 {
+        let stats = run(
+            Config {
+                seed,
+                peers: 4,
+                network: NetworkConfig::lossy(),
+                ..Config::default()
+            },
+            20,
+        );
+        let links = 4 * 3;
+        assert!(
+            stats.messages_sent <= links * 4 * (stats.reconnects + 2),
+            "seed {}: sent {} messages with {} reconnects",
+            seed,
+            stats.messages_sent,
+            stats.reconnects
+        );
+    }
+}
+
+#[test]
+fn peers_converge_when_restarting() {
+    for seed in 0..20 {
+        run(
+            Config {
+                seed,
+                peers: 3,
+                network: NetworkConfig::lossy(),
+                restart: 0.1,
+                ..Config::default()
+            },
+            20,
+        );
+    }
+}
+
+#[test]
+fn edits_during_sync_converge() {
+    let config = Config {
+        seed: 42,
+        peers: 3,
+        network: NetworkConfig::lossy(),
+        ..Config::default()
+    };
+    let mut sim = Simulation::new(config);
+    for step in 0..50 {
+        sim.random_edits(step % 3, 1);
+        sim.step().unwrap();
+    }
+    sim.run_until_converged().unwrap();
+}
+
+#[test]
+fn runs_are_deterministic() {
+    let config = Config {
+        seed: 3,
+        peers: 3,
+        network: NetworkConfig::lossy(),
+        restart: 0.05,
+        ..Config::default()
+    };
+    let first = run(config, 10);
+    let second = run(config, 10);
+    assert_eq!(first, second);
+}
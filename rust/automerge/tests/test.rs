@@ -1309,6 +1309,31 @@ fn save_and_load_incremented_counter() {
     assert_eq!(changes1, changes2);
 }
 
+#[test]
+fn incremented_counter_is_visible_at_heads() {
+    let mut doc = AutoCommit::new();
+    doc.put(ROOT, "counter", ScalarValue::counter(1)).unwrap();
+    doc.commit();
+    doc.increment(ROOT, "counter", 2).unwrap();
+    doc.commit();
+    let heads = doc.get_heads();
+    doc.increment(ROOT, "counter", 3).unwrap();
+    doc.commit();
+
+    assert_eq!(
+        doc.get_at(ROOT, "counter", &heads).unwrap().unwrap().0,
+        Value::counter(3)
+    );
+    assert_eq!(
+        doc.keys_at(ROOT, &heads).collect::<Vec<_>>(),
+        vec!["counter"]
+    );
+    assert_eq!(
+        doc.get(ROOT, "counter").unwrap().unwrap().0,
+        Value::counter(6)
+    );
+}
+
 #[test]
 fn load_incremental_with_corrupted_tail() {
     let mut doc = AutoCommit::new();
@@ -2228,3 +2253,322 @@ fn allows_empty_keys_in_mappings() {
         }
     );
 }
+
+fn todo_list(items: &[(&str, &str)]) -> automerge::hydrate::Value {
+    automerge::hydrate::Value::List(
+        items
+            .iter()
+            .map(|(id, title)| automerge::hydrate_map! { "id" => *id, "title" => *title }.into())
+            .collect::<Vec<automerge::hydrate::Value>>()
+            .into(),
+    )
+}
+
+fn list_elem_ids(doc: &AutoCommit, list: &ObjId) -> Vec<ObjId> {
+    doc.list_range(list, ..).map(|item| item.id).collect()
+}
+
+#[test]
+fn update_object_with_key_preserves_elements_on_front_insert() {
+    let mut doc = AutoCommit::new();
+    let list = doc.put_object(ROOT, "todos", ObjType::List).unwrap();
+    doc.update_object(&list, &todo_list(&[("a", "one"), ("b", "two")]))
+        .unwrap();
+    doc.commit();
+    let before = list_elem_ids(&doc, &list);
+
+    let options = automerge::transaction::UpdateOptions::default()
+        .with_list_matching(automerge::transaction::ListMatching::Key("id"));
+    doc.update_object_with(
+        &list,
+        &todo_list(&[("c", "zero"), ("a", "one"), ("b", "two")]),
+        options,
+    )
+    .unwrap();
+    let hash = doc.commit().unwrap();
+
+    let after = list_elem_ids(&doc, &list);
+    assert_eq!(&after[1..], &before[..]);
+    // one op to insert the map and two to fill it in
+    assert_eq!(doc.get_change_by_hash(&hash).unwrap().len(), 3);
+}
+
+#[test]
+fn update_object_with_key_keeps_concurrent_edits() {
+    let mut doc1 = AutoCommit::new();
+    let list = doc1.put_object(ROOT, "todos", ObjType::List).unwrap();
+    doc1.update_object(&list, &todo_list(&[("a", "one"), ("b", "two")]))
+        .unwrap();
+    let mut doc2 = doc1.fork();
+
+    let b = doc2.get(&list, 1).unwrap().unwrap().1;
+    doc2.put(&b, "title", "TWO").unwrap();
+
+    let options = automerge::transaction::UpdateOptions::default()
+        .with_list_matching(automerge::transaction::ListMatching::Key("id"));
+    doc1.update_object_with(
+        &list,
+        &todo_list(&[("c", "zero"), ("a", "one"), ("b", "two")]),
+        options,
+    )
+    .unwrap();
+
+    doc1.merge(&mut doc2).unwrap();
+    let b = doc1.get(&list, 2).unwrap().unwrap().1;
+    assert_eq!(
+        doc1.get(&b, "title").unwrap().unwrap().0,
+        Value::from("TWO")
+    );
+}
+
+#[test]
+fn update_object_with_key_fn_handles_moves_and_deletes() {
+    let mut doc = AutoCommit::new();
+    let list = doc.put_object(ROOT, "todos", ObjType::List).unwrap();
+    doc.update_object(
+        &list,
+        &todo_list(&[("a", "one"), ("b", "two"), ("c", "three")]),
+    )
+    .unwrap();
+    doc.commit();
+    let before = list_elem_ids(&doc, &list);
+
+    let key_fn = |value: &automerge::hydrate::Value| match value {
+        automerge::hydrate::Value::Map(m) => match m.get("id") {
+            Some(automerge::hydrate::Value::Scalar(s)) => Some(s.clone()),
+            _ => None,
+        },
+        _ => None,
+    };
+    let options = automerge::transaction::UpdateOptions::default()
+        .with_list_matching(automerge::transaction::ListMatching::KeyFn(&key_fn));
+    let expected = todo_list(&[("b", "two"), ("c", "THREE"), ("a", "one")]);
+    doc.update_object_with(&list, &expected, options).unwrap();
+    doc.commit();
+
+    // "b" and "c" keep their identity, "a" is moved by deleting and reinserting it
+    let after = list_elem_ids(&doc, &list);
+    assert_eq!(after[0..2], before[1..3]);
+    assert_ne!(after[2], before[0]);
+    assert_eq!(doc.hydrate(&list, None).unwrap(), expected);
+}
+
+#[test]
+fn update_object_with_diff_only_touches_changed_elements() {
+    let mut doc = AutoCommit::new();
+    let list = doc.put_object(ROOT, "numbers", ObjType::List).unwrap();
+    for (i, n) in [1, 2, 3, 4].into_iter().enumerate() {
+        doc.insert(&list, i, n).unwrap();
+    }
+    doc.commit();
+    let before = list_elem_ids(&doc, &list);
+
+    let options = automerge::transaction::UpdateOptions::default()
+        .with_list_matching(automerge::transaction::ListMatching::Diff);
+    doc.update_object_with(
+        &list,
+        &automerge::hydrate_list![0, 1, 2, 4, 5].into(),
+        options,
+    )
+    .unwrap();
+    let hash = doc.commit().unwrap();
+
+    let after = list_elem_ids(&doc, &list);
+    assert_eq!(after[1..3], before[0..2]);
+    assert_eq!(after[3], before[3]);
+    // insert 0, delete 3, insert 5
+    assert_eq!(doc.get_change_by_hash(&hash).unwrap().len(), 3);
+    assert_eq!(
+        doc.list_range(&list, ..)
+            .map(|item| item.value.into_owned())
+            .collect::<Vec<_>>(),
+        vec![0, 1, 2, 4, 5]
+            .into_iter()
+            .map(|i: i64| Value::from(i))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn update_object_resolves_conflicts_on_unchanged_values() {
+    let mut doc1 = AutoCommit::new();
+    let mut doc2 = doc1.fork();
+    doc1.put(ROOT, "a", 1).unwrap();
+    doc2.put(ROOT, "a", 2).unwrap();
+    doc1.merge(&mut doc2).unwrap();
+    assert_eq!(doc1.get_all(ROOT, "a").unwrap().len(), 2);
+
+    let (value, _) = doc1.get(ROOT, "a").unwrap().unwrap();
+    let value = automerge::hydrate::Value::Scalar(value.into_scalar().unwrap());
+    doc1.update_object(ROOT, &automerge::hydrate_map! { "a" => value }.into())
+        .unwrap();
+    assert_eq!(doc1.get_all(ROOT, "a").unwrap().len(), 1);
+}
+
+#[test]
+fn change_graph_queries_across_forks() {
+    let mut doc1 = AutoCommit::new();
+    doc1.put(ROOT, "a", 1).unwrap();
+    let base = doc1.get_heads();
+    let mut doc2 = doc1.fork();
+    doc1.put(ROOT, "b", 2).unwrap();
+    doc2.put(ROOT, "c", 3).unwrap();
+    let heads1 = doc1.get_heads();
+    let heads2 = doc2.get_heads();
+    doc1.merge(&mut doc2).unwrap();
+    let merged = doc1.get_heads();
+
+    assert_eq!(
+        doc1.compare_heads(&heads1, &heads2).unwrap(),
+        automerge::HeadsOrdering::Concurrent
+    );
+    assert_eq!(
+        doc1.compare_heads(&base, &merged).unwrap(),
+        automerge::HeadsOrdering::Ancestor
+    );
+    assert_eq!(doc1.merge_base(&heads1, &heads2).unwrap(), base);
+
+    let between = doc1
+        .changes_between(&base, &merged)
+        .unwrap()
+        .into_iter()
+        .map(|c| c.hash())
+        .collect::<Vec<_>>();
+    assert_eq!(between.len(), 2);
+    assert!(between.contains(&heads1[0]) && between.contains(&heads2[0]));
+
+    let missing = automerge::ChangeHash([0; 32]);
+    assert_eq!(
+        doc1.merge_base(&[missing], &heads1),
+        Err(AutomergeError::InvalidHash(missing))
+    );
+}
+
+#[test]
+fn history_of_map_keys_and_list_elements() {
+    use automerge::{HistoryEntry, Replaced};
+
+    let mut doc = Automerge::new();
+    let mut tx = doc.transaction();
+    tx.put(ROOT, "status", "draft").unwrap();
+    let list = tx.put_object(ROOT, "list", ObjType::List).unwrap();
+    tx.insert(&list, 0, "a").unwrap();
+    let (first, _) = tx.commit_with(CommitOptions::default().with_message("first"));
+    let first = first.unwrap();
+
+    let mut tx = doc.transaction();
+    tx.put(ROOT, "status", "review").unwrap();
+    tx.put(&list, 0, "b").unwrap();
+    let second = tx.commit().0.unwrap();
+    let middle = doc.get_heads();
+
+    let mut tx = doc.transaction();
+    tx.delete(ROOT, "status").unwrap();
+    tx.insert(&list, 0, "z").unwrap();
+    let third = tx.commit().0.unwrap();
+
+    let history = doc.history(ROOT, "status").unwrap();
+    assert_eq!(
+        history
+            .iter()
+            .map(|e| (e.value.to_str().unwrap(), e.change, e.replaced))
+            .collect::<Vec<_>>(),
+        vec![
+            ("draft", first, Some(Replaced::Overwritten(second))),
+            ("review", second, Some(Replaced::Deleted(third))),
+        ]
+    );
+    assert_eq!(history[0].message.as_deref(), Some("first"));
+    assert_eq!(&history[0].actor, doc.get_actor());
+
+    let at_middle = doc.history_at(ROOT, "status", &middle).unwrap();
+    assert_eq!(at_middle.len(), 2);
+    assert_eq!(at_middle[1].replaced, None);
+
+    // the element which was at index 0 is now at index 1
+    let element = doc.history(&list, 1).unwrap();
+    assert_eq!(
+        element
+            .iter()
+            .map(|e| (e.value.to_str().unwrap(), e.replaced))
+            .collect::<Vec<_>>(),
+        vec![("a", Some(Replaced::Overwritten(second))), ("b", None)]
+    );
+    assert!(doc.history(ROOT, "nothing").unwrap().is_empty());
+
+    assert_eq!(
+        doc.object_changes(&list).unwrap(),
+        vec![first, second, third]
+    );
+    assert_eq!(
+        doc.object_changes(ROOT).unwrap(),
+        vec![first, second, third]
+    );
+    assert_eq!(
+        doc.object_changes_at(&list, &middle).unwrap(),
+        vec![first, second]
+    );
+
+    // the history is available from anything which implements ReadDoc, such as a transaction
+    fn status_history<R: ReadDoc>(doc: &R) -> Vec<HistoryEntry> {
+        doc.history(ROOT, "status").unwrap()
+    }
+    let tx = doc.transaction();
+    assert_eq!(status_history(&tx), history);
+    tx.rollback();
+}
+
+#[test]
+fn blame_text_by_inserting_change() {
+    let mut doc1 = AutoCommit::new().with_actor(ActorId::from([1]));
+    let text = doc1.put_object(ROOT, "text", ObjType::Text).unwrap();
+    doc1.splice_text(&text, 0, 0, "hello").unwrap();
+    let first = doc1
+        .commit_with(CommitOptions::default().with_message("greeting"))
+        .unwrap();
+    let before = doc1.get_heads();
+
+    let mut doc2 = doc1.fork().with_actor(ActorId::from([2]));
+    doc2.splice_text(&text, 5, 0, " world").unwrap();
+    let second = doc2.commit().unwrap();
+    doc1.merge(&mut doc2).unwrap();
+    // overwrite the "o" of "hello" so the span is split
+    doc1.splice_text(&text, 4, 1, "O").unwrap();
+    let third = doc1.commit().unwrap();
+    doc1.splice_text(&text, 11, 0, "!").unwrap();
+
+    let blame = doc1.blame(&text).unwrap();
+    assert_eq!(
+        blame
+            .iter()
+            .map(|s| (s.text.as_str(), s.start, s.end, s.change))
+            .collect::<Vec<_>>(),
+        vec![
+            ("hell", 0, 4, Some(first)),
+            ("O", 4, 5, Some(third)),
+            (" world", 5, 11, Some(second)),
+            ("!", 11, 12, None),
+        ]
+    );
+    assert_eq!(blame[0].message.as_deref(), Some("greeting"));
+    assert_eq!(blame[2].actor, ActorId::from([2]));
+
+    let at = doc1.blame_at(&text, &before).unwrap();
+    assert_eq!(at.len(), 1);
+    assert_eq!(at[0].text, "hello");
+
+    assert!(doc1.blame(ROOT).is_err());
+
+    // blame is available from anything which implements ReadDoc, such as a transaction
+    fn blamed_text<R: ReadDoc>(doc: &R, text: &ObjId) -> String {
+        doc.blame(text)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.text)
+            .collect()
+    }
+    let mut doc = Automerge::load(&doc1.save()).unwrap();
+    let tx = doc.transaction();
+    assert_eq!(blamed_text(&tx, &text), "hellO world!");
+    tx.rollback();
+}
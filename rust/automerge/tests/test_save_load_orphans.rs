@@ -52,10 +52,9 @@ fn discard_orphans() {
         missing_change,
     } = doc_with_orphans();
 
-    let saved = doc.save_with_options(SaveOptions {
-        retain_orphans: false,
-        ..Default::default()
-    });
+    let mut options = SaveOptions::default();
+    options.retain_orphans = false;
+    let saved = doc.save_with_options(options);
     let mut loaded = AutoCommit::load(&saved).unwrap();
 
     loaded.apply_changes(vec![missing_change]).unwrap();